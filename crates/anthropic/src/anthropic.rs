@@ -93,14 +93,114 @@ pub struct Request {
     pub model: Model,
     pub messages: Vec<RequestMessage>,
     pub stream: bool,
-    pub system: String,
+    /// The system prompt, as text blocks, so that it can be cached.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<RequestContent>,
     pub max_tokens: u32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct RequestMessage {
     pub role: Role,
-    pub content: String,
+    pub content: Vec<RequestContent>,
+}
+
+/// A block of a message's content. Tool results are sent as blocks of a user
+/// message, since there's no role for them.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestContent {
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    Image {
+        source: ImageSource,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+}
+
+impl RequestContent {
+    pub fn text(text: String) -> Self {
+        Self::Text {
+            text,
+            cache_control: None,
+        }
+    }
+
+    /// Where the prompt is cached up to, if it's cached up to and including this
+    /// block.
+    pub fn cache_control_mut(&mut self) -> &mut Option<CacheControl> {
+        match self {
+            Self::Text { cache_control, .. }
+            | Self::Image { cache_control, .. }
+            | Self::ToolUse { cache_control, .. }
+            | Self::ToolResult { cache_control, .. } => cache_control,
+        }
+    }
+}
+
+/// Where an image's data comes from. Images can't be fetched from a URL.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    Base64 {
+        /// E.g. `image/png`.
+        media_type: String,
+        data: String,
+    },
+}
+
+/// Marks the prompt up to and including a block as cacheable. A request can have
+/// at most [`MAX_CACHE_BREAKPOINTS`] of them.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CacheControl {
+    /// Cached for five minutes, refreshed each time it's read.
+    Ephemeral,
+}
+
+pub const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Tool {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The JSON schema of the tool's input.
+    pub input_schema: serde_json::Value,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// The model decides whether to use a tool.
+    Auto,
+    /// The model must use at least one of the tools.
+    Any,
+    /// The model must use the named tool.
+    Tool { name: String },
+    /// The model must not use any tools.
+    None,
 }
 
 #[derive(Deserialize, Debug)]
@@ -116,7 +216,7 @@ pub enum ResponseEvent {
     Ping {},
     ContentBlockDelta {
         index: u32,
-        delta: TextDelta,
+        delta: ContentDelta,
     },
     ContentBlockStop {
         index: u32,
@@ -143,20 +243,40 @@ pub struct ResponseMessage {
 
 #[derive(Deserialize, Debug)]
 pub struct Usage {
+    /// The input tokens that were neither written to nor read from the cache.
     pub input_tokens: Option<u32>,
     pub output_tokens: Option<u32>,
+    pub cache_creation_input_tokens: Option<u32>,
+    pub cache_read_input_tokens: Option<u32>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ContentBlock {
-    Text { text: String },
+    Text {
+        text: String,
+    },
+    /// A tool the model is using. Its input streams as
+    /// [`ContentDelta::InputJsonDelta`]s, so it's usually empty here.
+    ToolUse {
+        id: String,
+        name: String,
+        #[serde(default)]
+        input: serde_json::Value,
+    },
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
-pub enum TextDelta {
-    TextDelta { text: String },
+pub enum ContentDelta {
+    TextDelta {
+        text: String,
+    },
+    /// A fragment of a tool use's input, which is JSON once every fragment has
+    /// been concatenated.
+    InputJsonDelta {
+        partial_json: String,
+    },
 }
 
 pub async fn stream_completion(
@@ -171,7 +291,10 @@ pub async fn stream_completion(
         .method(Method::POST)
         .uri(uri)
         .header("Anthropic-Version", "2023-06-01")
-        .header("Anthropic-Beta", "tools-2024-04-04")
+        .header(
+            "Anthropic-Beta",
+            "tools-2024-04-04,prompt-caching-2024-07-31",
+        )
         .header("X-Api-Key", api_key)
         .header("Content-Type", "application/json");
     if let Some(low_speed_timeout) = low_speed_timeout {
@@ -229,11 +352,13 @@ pub async fn stream_completion(
 //             model: Model::Claude3Opus,
 //             messages: vec![RequestMessage {
 //                 role: Role::User,
-//                 content: "Ping".to_string(),
+//                 content: vec![RequestContent::text("Ping".to_string())],
 //             }],
 //             stream: true,
-//             system: "Respond to ping with pong".to_string(),
+//             system: vec![RequestContent::text("Respond to ping with pong".to_string())],
 //             max_tokens: 4096,
+//             tools: Vec::new(),
+//             tool_choice: None,
 //         };
 
 //         let stream = stream_completion(
@@ -7,7 +7,6 @@ mod model_selector;
 mod prompt_library;
 mod prompts;
 mod slash_command;
-mod streaming_diff;
 mod terminal_inline_assistant;
 
 pub use assistant_panel::{AssistantPanel, AssistantPanelEvent};
@@ -34,8 +33,7 @@ use slash_command::{
     file_command, now_command, project_command, prompt_command, search_command, symbols_command,
     tabs_command, term_command,
 };
-use std::sync::Arc;
-pub(crate) use streaming_diff::*;
+use std::{sync::Arc, time::Duration};
 
 actions!(
     assistant,
@@ -47,6 +45,7 @@ actions!(
         InsertIntoEditor,
         ToggleFocus,
         ResetKey,
+        ReloadModels,
         InsertActivePrompt,
         DeployHistory,
         DeployPromptLibrary,
@@ -209,7 +208,12 @@ pub fn init(fs: Arc<dyn Fs>, client: Arc<Client>, cx: &mut AppContext) {
 
 fn init_completion_provider(client: Arc<Client>, cx: &mut AppContext) {
     let provider = assistant_settings::create_provider_from_settings(client.clone(), 0, cx);
-    cx.set_global(CompletionProvider::new(provider, Some(client)));
+    let mut provider = CompletionProvider::new(provider, Some(client));
+    let settings = AssistantSettings::get_global(cx);
+    provider.set_max_concurrent_requests(settings.max_concurrent_requests);
+    provider.set_stall_timeout(settings.stall_timeout_in_seconds.map(Duration::from_secs));
+    provider.set_moderate_input(settings.moderate_input);
+    cx.set_global(provider);
 
     let mut settings_version = 0;
     cx.observe_global::<SettingsStore>(move |cx| {
@@ -12,7 +12,8 @@ use crate::{
     DebugEditSteps, DeployHistory, DeployPromptLibrary, EditStep, EditStepOperations,
     EditSuggestionGroup, InlineAssist, InlineAssistId, InlineAssistant, InsertIntoEditor,
     MessageStatus, ModelSelector, PendingSlashCommand, PendingSlashCommandStatus, QuoteSelection,
-    RemoteContextMetadata, ResetKey, SavedContextMetadata, Split, ToggleFocus, ToggleModelSelector,
+    ReloadModels, RemoteContextMetadata, ResetKey, SavedContextMetadata, Split, ToggleFocus,
+    ToggleModelSelector,
 };
 use anyhow::{anyhow, Result};
 use assistant_slash_command::{SlashCommand, SlashCommandOutputSection};
@@ -48,12 +49,12 @@ use multi_buffer::MultiBufferRow;
 use picker::{Picker, PickerDelegate};
 use project::{Project, ProjectLspAdapterDelegate};
 use search::{buffer_search::DivRegistrar, BufferSearchBar};
-use settings::Settings;
+use settings::{Settings, SettingsLocation, SettingsStore};
 use std::{
     cmp::{self, Ordering},
     fmt::Write,
     ops::Range,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
@@ -69,10 +70,11 @@ use util::ResultExt;
 use workspace::{
     dock::{DockPosition, Panel, PanelEvent},
     item::{self, BreadcrumbText, FollowableItem, Item, ItemHandle},
-    notifications::NotifyTaskExt,
+    notifications::{NotificationId, NotifyTaskExt},
     pane::{self, SaveIntent},
     searchable::{SearchEvent, SearchableItem},
-    Pane, Save, ToggleZoom, ToolbarItemEvent, ToolbarItemLocation, ToolbarItemView, Workspace,
+    Pane, Save, Toast, ToggleZoom, ToolbarItemEvent, ToolbarItemLocation, ToolbarItemView,
+    Workspace,
 };
 use workspace::{searchable::SearchableItemHandle, NewFile};
 
@@ -367,9 +369,15 @@ impl AssistantPanel {
                     prev_settings_version = CompletionProvider::global(cx).settings_version();
                 }
             }),
+            cx.observe_global::<SettingsStore>(|this, cx| this.apply_project_api_key(cx)),
+            cx.on_release(|_, window, cx| {
+                cx.update_global::<CompletionProvider, _>(|provider, cx| {
+                    provider.set_api_key_override(window, None, cx)
+                });
+            }),
         ];
 
-        Self {
+        let mut this = Self {
             pane,
             workspace: workspace.weak_handle(),
             width: None,
@@ -381,7 +389,47 @@ impl AssistantPanel {
             subscriptions,
             authentication_prompt: None,
             model_selector_menu_handle,
-        }
+        };
+        this.apply_project_api_key(cx);
+        this
+    }
+
+    /// Makes completions requested from this panel's window use the API key the
+    /// project configures in its settings, if any, so that each project uses its
+    /// own key. A key configured by any of the project's worktrees takes precedence
+    /// over the global setting.
+    fn apply_project_api_key(&mut self, cx: &mut ViewContext<Self>) {
+        let global_api_key_env_var = AssistantSettings::get_global(cx).api_key_env_var.clone();
+        let api_key_env_var = self
+            .project
+            .read(cx)
+            .visible_worktrees(cx)
+            .find_map(|worktree| {
+                let api_key_env_var = &AssistantSettings::get(
+                    Some(SettingsLocation {
+                        worktree_id: worktree.read(cx).id().into(),
+                        path: Path::new(""),
+                    }),
+                    cx,
+                )
+                .api_key_env_var;
+                (*api_key_env_var != global_api_key_env_var).then(|| api_key_env_var.clone())
+            })
+            .unwrap_or(global_api_key_env_var);
+        let api_key = api_key_env_var.and_then(|api_key_env_var| {
+            let api_key = std::env::var(&api_key_env_var)
+                .ok()
+                .filter(|api_key| !api_key.is_empty());
+            if api_key.is_none() {
+                log::warn!("{api_key_env_var} is not set, so the saved API key is used instead");
+            }
+            api_key
+        });
+
+        let window = cx.window_handle();
+        cx.update_global::<CompletionProvider, _>(|provider, cx| {
+            provider.set_api_key_override(window, api_key, cx)
+        });
     }
 
     fn handle_pane_event(
@@ -686,11 +734,30 @@ impl AssistantPanel {
     }
 
     fn reset_credentials(&mut self, _: &ResetKey, cx: &mut ViewContext<Self>) {
-        CompletionProvider::global(cx)
-            .reset_credentials(cx)
+        cx.update_global::<CompletionProvider, _>(|provider, cx| provider.reset_credentials(cx))
             .detach_and_log_err(cx);
     }
 
+    /// Reloads the models the provider offers, and reports how many it offers now.
+    fn reload_models(&mut self, _: &ReloadModels, cx: &mut ViewContext<Self>) {
+        let reload = CompletionProvider::global(cx).reload_models(cx);
+        let workspace = self.workspace.clone();
+        cx.spawn(|_, mut cx| async move {
+            let message = match reload.await {
+                Ok(1) => "Found 1 model".to_string(),
+                Ok(count) => format!("Found {count} models"),
+                Err(error) => format!("Failed to refresh the models: {error}"),
+            };
+            workspace.update(&mut cx, |workspace, cx| {
+                struct ReloadModelsToast;
+
+                let id = NotificationId::unique::<ReloadModelsToast>();
+                workspace.show_toast(Toast::new(id, message), cx);
+            })
+        })
+        .detach_and_log_err(cx);
+    }
+
     fn toggle_model_selector(&mut self, _: &ToggleModelSelector, cx: &mut ViewContext<Self>) {
         self.model_selector_menu_handle.toggle(cx);
     }
@@ -847,6 +914,7 @@ impl AssistantPanel {
             .on_action(cx.listener(AssistantPanel::deploy_history))
             .on_action(cx.listener(AssistantPanel::deploy_prompt_library))
             .on_action(cx.listener(AssistantPanel::reset_credentials))
+            .on_action(cx.listener(AssistantPanel::reload_models))
             .on_action(cx.listener(AssistantPanel::toggle_model_selector))
             .child(registrar.size_full().child(self.pane.clone()))
     }
@@ -1825,6 +1893,7 @@ impl ContextEditor {
                                     Role::User => Label::new("You").color(Color::Default),
                                     Role::Assistant => Label::new("Assistant").color(Color::Info),
                                     Role::System => Label::new("System").color(Color::Warning),
+                                    Role::Tool => Label::new("Tool").color(Color::Muted),
                                 })
                                 .tooltip(|cx| {
                                     Tooltip::with_meta(
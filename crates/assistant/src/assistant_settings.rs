@@ -2,14 +2,18 @@ use std::{sync::Arc, time::Duration};
 
 use anthropic::Model as AnthropicModel;
 use client::Client;
+use collections::HashMap;
 use completion::{
-    AnthropicCompletionProvider, CloudCompletionProvider, CompletionProvider,
-    LanguageModelCompletionProvider, OllamaCompletionProvider, OpenAiCompletionProvider,
+    AnthropicCompletionProvider, ApiKeySource, CloudCompletionProvider, CompletionProvider,
+    EmptyResponseBehavior, GeminiCompletionProvider, LanguageModelCompletionProvider,
+    OllamaCompletionProvider, OpenAiCompatibleCompletionProvider, OpenAiCompletionProvider,
+    DEFAULT_MAX_RETRIES, DEFAULT_RETRY_BASE_DELAY,
 };
+use google_ai::Model as GoogleModel;
 use gpui::{AppContext, Pixels};
 use language_model::{CloudModel, LanguageModel};
 use ollama::Model as OllamaModel;
-use open_ai::Model as OpenAiModel;
+use open_ai::{AuthHeaderStyle, Model as OpenAiModel};
 use parking_lot::RwLock;
 use schemars::{schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
@@ -34,6 +38,12 @@ pub enum AssistantProvider {
         api_url: String,
         low_speed_timeout_in_seconds: Option<u64>,
         available_models: Vec<OpenAiModel>,
+        organization_id: Option<String>,
+        api_key_source: ApiKeySource,
+        max_retries: Option<u32>,
+        retry_base_delay_in_milliseconds: Option<u64>,
+        empty_response_behavior: EmptyResponseBehavior,
+        default_max_tokens: HashMap<String, Option<u32>>,
     },
     Anthropic {
         model: AnthropicModel,
@@ -45,6 +55,17 @@ pub enum AssistantProvider {
         api_url: String,
         low_speed_timeout_in_seconds: Option<u64>,
     },
+    Google {
+        model: GoogleModel,
+        api_url: String,
+    },
+    OpenAiCompatible {
+        model: OpenAiModel,
+        api_url: String,
+        auth_header: AuthHeaderStyle,
+        low_speed_timeout_in_seconds: Option<u64>,
+        available_models: Vec<OpenAiModel>,
+    },
 }
 
 impl Default for AssistantProvider {
@@ -54,6 +75,12 @@ impl Default for AssistantProvider {
             api_url: open_ai::OPEN_AI_API_URL.into(),
             low_speed_timeout_in_seconds: None,
             available_models: Default::default(),
+            organization_id: None,
+            api_key_source: ApiKeySource::default(),
+            max_retries: None,
+            retry_base_delay_in_milliseconds: None,
+            empty_response_behavior: EmptyResponseBehavior::default(),
+            default_max_tokens: HashMap::default(),
         }
     }
 }
@@ -69,6 +96,35 @@ pub enum AssistantProviderContent {
         api_url: Option<String>,
         low_speed_timeout_in_seconds: Option<u64>,
         available_models: Option<Vec<OpenAiModel>>,
+        /// The OpenAI organization to make requests on behalf of, for accounts that
+        /// belong to several.
+        organization_id: Option<String>,
+        /// Where to look for the API key: `env` for only the `OPENAI_API_KEY` and
+        /// `OPENAI_API_KEY_FILE` environment variables, `keyring` for only the key
+        /// saved in the keyring, or `auto` for the environment and then the keyring.
+        ///
+        /// Default: auto
+        api_key_source: Option<ApiKeySource>,
+        /// How many times a request that fails with a rate limit or server error is
+        /// retried before the completion fails.
+        ///
+        /// Default: 3
+        max_retries: Option<u32>,
+        /// The delay before the first retry of a failed request, which doubles with
+        /// each subsequent retry. A `Retry-After` sent by the server takes precedence.
+        ///
+        /// Default: 1000
+        retry_base_delay_in_milliseconds: Option<u64>,
+        /// What to do when a completion finishes without any content: `return_empty`
+        /// to finish with no text, or `error` to fail so that it can be retried.
+        ///
+        /// Default: return_empty
+        empty_response_behavior: Option<EmptyResponseBehavior>,
+        /// The `max_tokens` sent for each model, by name, when the request doesn't
+        /// set its own. `null` leaves a model uncapped, as does leaving it out.
+        ///
+        /// Default: {}
+        default_max_tokens: Option<HashMap<String, Option<u32>>>,
     },
     #[serde(rename = "anthropic")]
     Anthropic {
@@ -82,6 +138,27 @@ pub enum AssistantProviderContent {
         api_url: Option<String>,
         low_speed_timeout_in_seconds: Option<u64>,
     },
+    #[serde(rename = "google")]
+    Google {
+        default_model: Option<GoogleModel>,
+        api_url: Option<String>,
+    },
+    /// Any API that serves OpenAI's chat completions endpoint, such as Mistral,
+    /// Together, Groq, Perplexity or OpenRouter.
+    #[serde(rename = "openai_compatible")]
+    OpenAiCompatible {
+        default_model: Option<OpenAiModel>,
+        /// The API's base URL, e.g. `https://api.mistral.ai/v1`.
+        api_url: Option<String>,
+        /// The header the API key is sent in.
+        ///
+        /// Default: bearer
+        auth_header: Option<AuthHeaderStyle>,
+        low_speed_timeout_in_seconds: Option<u64>,
+        /// The models the API serves, usually as `custom` models with the API's
+        /// names for them.
+        available_models: Option<Vec<OpenAiModel>>,
+    },
 }
 
 #[derive(Debug, Default)]
@@ -91,6 +168,10 @@ pub struct AssistantSettings {
     pub dock: AssistantDockPosition,
     pub default_width: Pixels,
     pub default_height: Pixels,
+    pub max_concurrent_requests: usize,
+    pub stall_timeout_in_seconds: Option<u64>,
+    pub moderate_input: bool,
+    pub api_key_env_var: Option<String>,
     pub provider: AssistantProvider,
 }
 
@@ -134,12 +215,22 @@ impl AssistantSettingsContent {
                 dock: settings.dock,
                 default_width: settings.default_width,
                 default_height: settings.default_height,
+                max_concurrent_requests: None,
+                stall_timeout_in_seconds: None,
+                moderate_input: None,
+                api_key_env_var: None,
                 provider: if let Some(open_ai_api_url) = settings.openai_api_url.as_ref() {
                     Some(AssistantProviderContent::OpenAi {
                         default_model: settings.default_open_ai_model.clone(),
                         api_url: Some(open_ai_api_url.clone()),
                         low_speed_timeout_in_seconds: None,
                         available_models: Some(Default::default()),
+                        organization_id: None,
+                        api_key_source: None,
+                        max_retries: None,
+                        retry_base_delay_in_milliseconds: None,
+                        empty_response_behavior: None,
+                        default_max_tokens: None,
                     })
                 } else {
                     settings.default_open_ai_model.clone().map(|open_ai_model| {
@@ -148,6 +239,12 @@ impl AssistantSettingsContent {
                             api_url: None,
                             low_speed_timeout_in_seconds: None,
                             available_models: Some(Default::default()),
+                            organization_id: None,
+                            api_key_source: None,
+                            max_retries: None,
+                            retry_base_delay_in_milliseconds: None,
+                            empty_response_behavior: None,
+                            default_max_tokens: None,
                         }
                     })
                 },
@@ -203,6 +300,22 @@ impl AssistantSettingsContent {
                             *model = Some(new_model);
                         }
                     }
+                    Some(AssistantProviderContent::Google {
+                        default_model: model,
+                        ..
+                    }) => {
+                        if let LanguageModel::Google(new_model) = new_model {
+                            *model = Some(new_model);
+                        }
+                    }
+                    Some(AssistantProviderContent::OpenAiCompatible {
+                        default_model: model,
+                        ..
+                    }) => {
+                        if let LanguageModel::OpenAi(new_model) = new_model {
+                            *model = Some(new_model);
+                        }
+                    }
                     provider => match new_model {
                         LanguageModel::Cloud(model) => {
                             *provider = Some(AssistantProviderContent::ZedDotDev {
@@ -215,6 +328,12 @@ impl AssistantSettingsContent {
                                 api_url: None,
                                 low_speed_timeout_in_seconds: None,
                                 available_models: Some(Default::default()),
+                                organization_id: None,
+                                api_key_source: None,
+                                max_retries: None,
+                                retry_base_delay_in_milliseconds: None,
+                                empty_response_behavior: None,
+                                default_max_tokens: None,
                             })
                         }
                         LanguageModel::Anthropic(model) => {
@@ -231,6 +350,12 @@ impl AssistantSettingsContent {
                                 low_speed_timeout_in_seconds: None,
                             })
                         }
+                        LanguageModel::Google(model) => {
+                            *provider = Some(AssistantProviderContent::Google {
+                                default_model: Some(model),
+                                api_url: None,
+                            })
+                        }
                     },
                 },
             },
@@ -258,6 +383,10 @@ impl Default for VersionedAssistantSettingsContent {
             dock: None,
             default_width: None,
             default_height: None,
+            max_concurrent_requests: None,
+            stall_timeout_in_seconds: None,
+            moderate_input: None,
+            api_key_env_var: None,
             provider: None,
         })
     }
@@ -285,6 +414,30 @@ pub struct AssistantSettingsContentV1 {
     ///
     /// Default: 320
     default_height: Option<f32>,
+    /// The most completions to request at once. Any more wait for one of them to
+    /// finish, to stay under the provider's rate limits.
+    ///
+    /// Default: 4
+    max_concurrent_requests: Option<usize>,
+    /// How many seconds to wait for the next chunk of a streaming completion before
+    /// failing it, so that a stream that stalls mid-completion can be retried. This
+    /// is separate from the provider's connection timeout.
+    ///
+    /// Default: none
+    stall_timeout_in_seconds: Option<u64>,
+    /// Whether to check each message sent to the assistant with the provider's
+    /// moderation API first, refusing to send messages it flags. Only OpenAI's own
+    /// API supports it; other providers send messages unmoderated.
+    ///
+    /// Default: false
+    moderate_input: Option<bool>,
+    /// The name of an environment variable holding an API key to use instead of the
+    /// one saved for the provider. This is meant for a project's `.zed/settings.json`,
+    /// so that the project's completions are billed to its own account without
+    /// committing the key itself. Only the OpenAI providers support it.
+    ///
+    /// Default: none
+    api_key_env_var: Option<String>,
     /// The provider of the assistant service.
     ///
     /// This can either be the internal `zed.dev` service or an external `openai` service,
@@ -344,6 +497,19 @@ impl Settings for AssistantSettings {
                 &mut settings.default_height,
                 value.default_height.map(Into::into),
             );
+            merge(
+                &mut settings.max_concurrent_requests,
+                value.max_concurrent_requests,
+            );
+            merge(
+                &mut settings.stall_timeout_in_seconds,
+                value.stall_timeout_in_seconds.map(Some),
+            );
+            merge(&mut settings.moderate_input, value.moderate_input);
+            merge(
+                &mut settings.api_key_env_var,
+                value.api_key_env_var.map(Some),
+            );
             if let Some(provider) = value.provider.clone() {
                 match (&mut settings.provider, provider) {
                     (
@@ -360,17 +526,41 @@ impl Settings for AssistantSettings {
                             api_url,
                             low_speed_timeout_in_seconds,
                             available_models,
+                            organization_id,
+                            api_key_source,
+                            max_retries,
+                            retry_base_delay_in_milliseconds,
+                            empty_response_behavior,
+                            default_max_tokens,
                         },
                         AssistantProviderContent::OpenAi {
                             default_model: model_override,
                             api_url: api_url_override,
                             low_speed_timeout_in_seconds: low_speed_timeout_in_seconds_override,
                             available_models: available_models_override,
+                            organization_id: organization_id_override,
+                            api_key_source: api_key_source_override,
+                            max_retries: max_retries_override,
+                            retry_base_delay_in_milliseconds:
+                                retry_base_delay_in_milliseconds_override,
+                            empty_response_behavior: empty_response_behavior_override,
+                            default_max_tokens: default_max_tokens_override,
                         },
                     ) => {
                         merge(model, model_override);
                         merge(api_url, api_url_override);
                         merge(available_models, available_models_override);
+                        merge(api_key_source, api_key_source_override);
+                        merge(max_retries, max_retries_override.map(Some));
+                        merge(
+                            retry_base_delay_in_milliseconds,
+                            retry_base_delay_in_milliseconds_override.map(Some),
+                        );
+                        merge(empty_response_behavior, empty_response_behavior_override);
+                        merge(default_max_tokens, default_max_tokens_override);
+                        if let Some(organization_id_override) = organization_id_override {
+                            *organization_id = Some(organization_id_override);
+                        }
                         if let Some(low_speed_timeout_in_seconds_override) =
                             low_speed_timeout_in_seconds_override
                         {
@@ -420,6 +610,43 @@ impl Settings for AssistantSettings {
                                 Some(low_speed_timeout_in_seconds_override);
                         }
                     }
+                    (
+                        AssistantProvider::Google { model, api_url },
+                        AssistantProviderContent::Google {
+                            default_model: model_override,
+                            api_url: api_url_override,
+                        },
+                    ) => {
+                        merge(model, model_override);
+                        merge(api_url, api_url_override);
+                    }
+                    (
+                        AssistantProvider::OpenAiCompatible {
+                            model,
+                            api_url,
+                            auth_header,
+                            low_speed_timeout_in_seconds,
+                            available_models,
+                        },
+                        AssistantProviderContent::OpenAiCompatible {
+                            default_model: model_override,
+                            api_url: api_url_override,
+                            auth_header: auth_header_override,
+                            low_speed_timeout_in_seconds: low_speed_timeout_in_seconds_override,
+                            available_models: available_models_override,
+                        },
+                    ) => {
+                        merge(model, model_override);
+                        merge(api_url, api_url_override);
+                        merge(auth_header, auth_header_override);
+                        merge(available_models, available_models_override);
+                        if let Some(low_speed_timeout_in_seconds_override) =
+                            low_speed_timeout_in_seconds_override
+                        {
+                            *low_speed_timeout_in_seconds =
+                                Some(low_speed_timeout_in_seconds_override);
+                        }
+                    }
                     (provider, provider_override) => {
                         *provider = match provider_override {
                             AssistantProviderContent::ZedDotDev {
@@ -432,11 +659,24 @@ impl Settings for AssistantSettings {
                                 api_url,
                                 low_speed_timeout_in_seconds,
                                 available_models,
+                                organization_id,
+                                api_key_source,
+                                max_retries,
+                                retry_base_delay_in_milliseconds,
+                                empty_response_behavior,
+                                default_max_tokens,
                             } => AssistantProvider::OpenAi {
                                 model: model.unwrap_or_default(),
                                 api_url: api_url.unwrap_or_else(|| open_ai::OPEN_AI_API_URL.into()),
                                 low_speed_timeout_in_seconds,
                                 available_models: available_models.unwrap_or_default(),
+                                organization_id,
+                                api_key_source: api_key_source.unwrap_or_default(),
+                                max_retries,
+                                retry_base_delay_in_milliseconds,
+                                empty_response_behavior: empty_response_behavior
+                                    .unwrap_or_default(),
+                                default_max_tokens: default_max_tokens.unwrap_or_default(),
                             },
                             AssistantProviderContent::Anthropic {
                                 default_model: model,
@@ -457,6 +697,26 @@ impl Settings for AssistantSettings {
                                 api_url: api_url.unwrap_or_else(|| ollama::OLLAMA_API_URL.into()),
                                 low_speed_timeout_in_seconds,
                             },
+                            AssistantProviderContent::Google {
+                                default_model: model,
+                                api_url,
+                            } => AssistantProvider::Google {
+                                model: model.unwrap_or_default(),
+                                api_url: api_url.unwrap_or_else(|| google_ai::API_URL.into()),
+                            },
+                            AssistantProviderContent::OpenAiCompatible {
+                                default_model: model,
+                                api_url,
+                                auth_header,
+                                low_speed_timeout_in_seconds,
+                                available_models,
+                            } => AssistantProvider::OpenAiCompatible {
+                                model: model.unwrap_or_default(),
+                                api_url: api_url.unwrap_or_default(),
+                                auth_header: auth_header.unwrap_or_default(),
+                                low_speed_timeout_in_seconds,
+                                available_models: available_models.unwrap_or_default(),
+                            },
                         };
                     }
                 }
@@ -478,7 +738,12 @@ pub fn update_completion_provider_settings(
     version: usize,
     cx: &mut AppContext,
 ) {
-    let updated = match &AssistantSettings::get_global(cx).provider {
+    let was_authenticated = provider.is_authenticated();
+    let settings = AssistantSettings::get_global(cx);
+    provider.set_max_concurrent_requests(settings.max_concurrent_requests);
+    provider.set_stall_timeout(settings.stall_timeout_in_seconds.map(Duration::from_secs));
+    provider.set_moderate_input(settings.moderate_input);
+    let updated = match &settings.provider {
         AssistantProvider::ZedDotDev { model } => provider
             .update_current_as::<_, CloudCompletionProvider>(|provider| {
                 provider.update(model.clone(), version);
@@ -488,6 +753,12 @@ pub fn update_completion_provider_settings(
             api_url,
             low_speed_timeout_in_seconds,
             available_models,
+            organization_id,
+            api_key_source,
+            max_retries,
+            retry_base_delay_in_milliseconds,
+            empty_response_behavior,
+            default_max_tokens,
         } => provider.update_current_as::<_, OpenAiCompletionProvider>(|provider| {
             provider.update(
                 choose_openai_model(&model, &available_models),
@@ -495,6 +766,15 @@ pub fn update_completion_provider_settings(
                 low_speed_timeout_in_seconds.map(Duration::from_secs),
                 version,
             );
+            provider.set_organization_id(organization_id.clone());
+            provider.set_api_key_source(*api_key_source);
+            provider.set_max_retries(max_retries.unwrap_or(DEFAULT_MAX_RETRIES));
+            provider.set_retry_base_delay(
+                retry_base_delay_in_milliseconds
+                    .map_or(DEFAULT_RETRY_BASE_DELAY, Duration::from_millis),
+            );
+            provider.set_empty_response_behavior(*empty_response_behavior);
+            provider.set_default_max_tokens(default_max_tokens.clone());
         }),
         AssistantProvider::Anthropic {
             model,
@@ -521,12 +801,37 @@ pub fn update_completion_provider_settings(
                 cx,
             );
         }),
+        AssistantProvider::Google { model, api_url } => provider
+            .update_current_as::<_, GeminiCompletionProvider>(|provider| {
+                provider.update(model.clone(), api_url.clone(), version);
+            }),
+        AssistantProvider::OpenAiCompatible {
+            model,
+            api_url,
+            auth_header,
+            low_speed_timeout_in_seconds,
+            available_models,
+        } => provider.update_current_as::<_, OpenAiCompatibleCompletionProvider>(|provider| {
+            provider.update(
+                choose_openai_model(&model, &available_models),
+                api_url.clone(),
+                auth_header.clone(),
+                available_models.clone(),
+                low_speed_timeout_in_seconds.map(Duration::from_secs),
+                version,
+            );
+        }),
     };
 
     // Previously configured provider was changed to another one
     if updated.is_none() {
         provider.update_provider(|client| create_provider_from_settings(client, version, cx));
     }
+
+    // Changing the API URL forgets the credentials, so load the new API's.
+    if was_authenticated && !provider.is_authenticated() {
+        provider.authenticate(cx).detach_and_log_err(cx);
+    }
 }
 
 pub(crate) fn create_provider_from_settings(
@@ -543,14 +848,32 @@ pub(crate) fn create_provider_from_settings(
             api_url,
             low_speed_timeout_in_seconds,
             available_models,
-        } => Arc::new(RwLock::new(OpenAiCompletionProvider::new(
-            choose_openai_model(&model, &available_models),
-            api_url.clone(),
-            client.http_client(),
-            low_speed_timeout_in_seconds.map(Duration::from_secs),
-            settings_version,
-            available_models.clone(),
-        ))),
+            organization_id,
+            api_key_source,
+            max_retries,
+            retry_base_delay_in_milliseconds,
+            empty_response_behavior,
+            default_max_tokens,
+        } => {
+            let mut provider = OpenAiCompletionProvider::new(
+                choose_openai_model(&model, &available_models),
+                api_url.clone(),
+                client.http_client(),
+                low_speed_timeout_in_seconds.map(Duration::from_secs),
+                settings_version,
+                available_models.clone(),
+            );
+            provider.set_organization_id(organization_id.clone());
+            provider.set_api_key_source(*api_key_source);
+            provider.set_max_retries(max_retries.unwrap_or(DEFAULT_MAX_RETRIES));
+            provider.set_retry_base_delay(
+                retry_base_delay_in_milliseconds
+                    .map_or(DEFAULT_RETRY_BASE_DELAY, Duration::from_millis),
+            );
+            provider.set_empty_response_behavior(*empty_response_behavior);
+            provider.set_default_max_tokens(default_max_tokens.clone());
+            Arc::new(RwLock::new(provider))
+        }
         AssistantProvider::Anthropic {
             model,
             api_url,
@@ -574,6 +897,29 @@ pub(crate) fn create_provider_from_settings(
             settings_version,
             cx,
         ))),
+        AssistantProvider::Google { model, api_url } => {
+            Arc::new(RwLock::new(GeminiCompletionProvider::new(
+                model.clone(),
+                api_url.clone(),
+                client.http_client(),
+                settings_version,
+            )))
+        }
+        AssistantProvider::OpenAiCompatible {
+            model,
+            api_url,
+            auth_header,
+            low_speed_timeout_in_seconds,
+            available_models,
+        } => Arc::new(RwLock::new(OpenAiCompatibleCompletionProvider::new(
+            choose_openai_model(&model, &available_models),
+            api_url.clone(),
+            auth_header.clone(),
+            available_models.clone(),
+            client.http_client(),
+            low_speed_timeout_in_seconds.map(Duration::from_secs),
+            settings_version,
+        ))),
     }
 }
 
@@ -612,6 +958,12 @@ mod tests {
                 api_url: open_ai::OPEN_AI_API_URL.into(),
                 low_speed_timeout_in_seconds: None,
                 available_models: Default::default(),
+                organization_id: None,
+                api_key_source: ApiKeySource::Auto,
+                max_retries: None,
+                retry_base_delay_in_milliseconds: None,
+                empty_response_behavior: EmptyResponseBehavior::ReturnEmpty,
+                default_max_tokens: HashMap::default(),
             }
         );
 
@@ -635,6 +987,12 @@ mod tests {
                 api_url: "test-url".into(),
                 low_speed_timeout_in_seconds: None,
                 available_models: Default::default(),
+                organization_id: None,
+                api_key_source: ApiKeySource::Auto,
+                max_retries: None,
+                retry_base_delay_in_milliseconds: None,
+                empty_response_behavior: EmptyResponseBehavior::ReturnEmpty,
+                default_max_tokens: HashMap::default(),
             }
         );
         SettingsStore::update_global(cx, |store, cx| {
@@ -656,6 +1014,12 @@ mod tests {
                 api_url: open_ai::OPEN_AI_API_URL.into(),
                 low_speed_timeout_in_seconds: None,
                 available_models: Default::default(),
+                organization_id: None,
+                api_key_source: ApiKeySource::Auto,
+                max_retries: None,
+                retry_base_delay_in_milliseconds: None,
+                empty_response_behavior: EmptyResponseBehavior::ReturnEmpty,
+                default_max_tokens: HashMap::default(),
             }
         );
 
@@ -682,5 +1046,102 @@ mod tests {
                 model: CloudModel::Custom("custom".into())
             }
         );
+
+        SettingsStore::update_global(cx, |store, cx| {
+            store
+                .set_user_settings(
+                    r#"{
+                        "assistant": {
+                            "version": "1",
+                            "provider": {
+                                "name": "google",
+                                "default_model": "gemini-1.5-flash"
+                            }
+                        }
+                    }"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        assert_eq!(
+            AssistantSettings::get_global(cx).provider,
+            AssistantProvider::Google {
+                model: GoogleModel::Gemini15Flash,
+                api_url: google_ai::API_URL.into(),
+            }
+        );
+
+        SettingsStore::update_global(cx, |store, cx| {
+            store
+                .set_user_settings(
+                    r#"{
+                        "assistant": {
+                            "version": "1",
+                            "provider": {
+                                "name": "openai_compatible",
+                                "api_url": "https://api.mistral.ai/v1",
+                                "auth_header": { "header": "x-api-key" },
+                                "available_models": [
+                                    { "custom": { "name": "mistral-large-latest", "max_tokens": 128000 } }
+                                ]
+                            }
+                        }
+                    }"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        assert_eq!(
+            AssistantSettings::get_global(cx).provider,
+            AssistantProvider::OpenAiCompatible {
+                model: OpenAiModel::default(),
+                api_url: "https://api.mistral.ai/v1".into(),
+                auth_header: AuthHeaderStyle::Header("x-api-key".into()),
+                low_speed_timeout_in_seconds: None,
+                available_models: vec![OpenAiModel::Custom {
+                    name: "mistral-large-latest".into(),
+                    max_tokens: 128000,
+                    tokenizer: None,
+                }],
+            }
+        );
+
+        SettingsStore::update_global(cx, |store, cx| {
+            store
+                .set_user_settings(
+                    r#"{
+                        "assistant": {
+                            "version": "1",
+                            "provider": {
+                                "name": "openai",
+                                "max_retries": 5,
+                                "retry_base_delay_in_milliseconds": 250,
+                                "empty_response_behavior": "error",
+                                "default_max_tokens": { "gpt-4o": 1024, "gpt-4": null }
+                            }
+                        }
+                    }"#,
+                    cx,
+                )
+                .unwrap();
+        });
+        assert_eq!(
+            AssistantSettings::get_global(cx).provider,
+            AssistantProvider::OpenAi {
+                model: OpenAiModel::FourOmni,
+                api_url: open_ai::OPEN_AI_API_URL.into(),
+                low_speed_timeout_in_seconds: None,
+                available_models: Default::default(),
+                organization_id: None,
+                api_key_source: ApiKeySource::Auto,
+                max_retries: Some(5),
+                retry_base_delay_in_milliseconds: Some(250),
+                empty_response_behavior: EmptyResponseBehavior::Error,
+                default_max_tokens: HashMap::from_iter([
+                    ("gpt-4o".into(), Some(1024)),
+                    ("gpt-4".into(), None),
+                ]),
+            }
+        );
     }
 }
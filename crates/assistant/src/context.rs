@@ -323,6 +323,10 @@ impl Message {
         LanguageModelRequestMessage {
             role: self.role,
             content: buffer.text_for_range(self.offset_range.clone()).collect(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
         }
     }
 }
@@ -1305,6 +1309,10 @@ impl Context {
             request.messages.push(LanguageModelRequestMessage {
                 role: Role::User,
                 content: prompt,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
             });
 
             let raw_output = cx
@@ -1721,16 +1729,37 @@ impl Context {
     }
 
     pub fn to_completion_request(&self, cx: &AppContext) -> LanguageModelRequest {
-        let messages = self
+        let mut messages = self
             .messages(cx)
             .filter(|message| matches!(message.status, MessageStatus::Done))
-            .map(|message| message.to_request_message(self.buffer.read(cx)));
+            .map(|message| message.to_request_message(self.buffer.read(cx)))
+            .collect::<Vec<_>>();
+        // The system prompt at the start of the context is sent again with every
+        // request, so it's worth caching with the providers that support it.
+        if let Some(system_prompt) = messages
+            .iter_mut()
+            .take_while(|message| message.role == Role::System)
+            .last()
+        {
+            system_prompt.cache = true;
+        }
 
         LanguageModelRequest {
             model: CompletionProvider::global(cx).model(),
-            messages: messages.collect(),
+            messages,
             stop: vec![],
             temperature: 1.0,
+            max_tokens: None,
+            tools: Vec::new(),
+            tool_choice: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_format: None,
+            seed: None,
+            logit_bias: None,
+            logprobs: false,
+            top_logprobs: None,
         }
     }
 
@@ -1980,12 +2009,27 @@ impl Context {
                 .chain(Some(LanguageModelRequestMessage {
                     role: Role::User,
                     content: "Summarize the context into a short title without punctuation.".into(),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: false,
                 }));
             let request = LanguageModelRequest {
                 model: CompletionProvider::global(cx).model(),
                 messages: messages.collect(),
                 stop: vec![],
                 temperature: 1.0,
+                max_tokens: None,
+                tools: Vec::new(),
+                tool_choice: None,
+                top_p: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+                response_format: None,
+                seed: None,
+                logit_bias: None,
+                logprobs: false,
+                top_logprobs: None,
             };
 
             let stream = CompletionProvider::global(cx).stream_completion(request, cx);
@@ -2630,6 +2674,38 @@ mod tests {
         );
     }
 
+    #[gpui::test]
+    fn test_caching_system_prompt(cx: &mut AppContext) {
+        let settings_store = SettingsStore::test(cx);
+        FakeCompletionProvider::setup_test(cx);
+        cx.set_global(settings_store);
+        assistant_panel::init(cx);
+        let registry = Arc::new(LanguageRegistry::test(cx.background_executor().clone()));
+        let context = cx.new_model(|cx| Context::local(registry, None, cx));
+        let buffer = context.read(cx).buffer.clone();
+
+        let message_1 = context.read(cx).message_anchors[0].clone();
+        buffer.update(cx, |buffer, cx| {
+            buffer.edit([(0..0, "Be brief.")], None, cx)
+        });
+        context.update(cx, |context, cx| {
+            context.update_metadata(message_1.id, cx, |metadata| metadata.role = Role::System);
+            context
+                .insert_message_after(message_1.id, Role::User, MessageStatus::Done, cx)
+                .unwrap();
+        });
+
+        let request = context.read(cx).to_completion_request(cx);
+        assert_eq!(
+            request
+                .messages
+                .iter()
+                .map(|message| (message.role, message.cache))
+                .collect::<Vec<_>>(),
+            vec![(Role::System, true), (Role::User, false)]
+        );
+    }
+
     #[gpui::test]
     fn test_message_splitting(cx: &mut AppContext) {
         let settings_store = SettingsStore::test(cx);
@@ -1,10 +1,11 @@
 use crate::{
     assistant_settings::AssistantSettings, humanize_token_count, prompts::generate_content_prompt,
-    AssistantPanel, AssistantPanelEvent, CompletionProvider, Hunk, StreamingDiff,
+    AssistantPanel, AssistantPanelEvent, CompletionProvider,
 };
 use anyhow::{anyhow, Context as _, Result};
 use client::telemetry::Telemetry;
 use collections::{hash_map, HashMap, HashSet, VecDeque};
+use completion::{Hunk, StreamingDiff};
 use editor::{
     actions::{MoveDown, MoveUp, SelectAll},
     display_map::{
@@ -970,6 +971,10 @@ impl InlineAssistant {
             messages.push(LanguageModelRequestMessage {
                 role: Role::User,
                 content: prompt,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
             });
 
             Ok(LanguageModelRequest {
@@ -977,6 +982,17 @@ impl InlineAssistant {
                 messages,
                 stop: vec!["|END|>".to_string()],
                 temperature,
+                max_tokens: None,
+                tools: Vec::new(),
+                tool_choice: None,
+                top_p: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+                response_format: None,
+                seed: None,
+                logit_bias: None,
+                logprobs: false,
+                top_logprobs: None,
             })
         })
     }
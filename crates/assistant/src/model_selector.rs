@@ -1,6 +1,9 @@
 use std::sync::Arc;
 
-use crate::{assistant_settings::AssistantSettings, CompletionProvider, ToggleModelSelector};
+use crate::{
+    assistant_settings::AssistantSettings, CompletionProvider, ReloadModels, ToggleModelSelector,
+};
+use completion::model_capabilities;
 use fs::Fs;
 use settings::update_settings_file;
 use ui::{prelude::*, ButtonLike, ContextMenu, PopoverMenu, PopoverMenuHandle, Tooltip};
@@ -22,12 +25,32 @@ impl RenderOnce for ModelSelector {
         PopoverMenu::new("model-switcher")
             .with_handle(self.handle)
             .menu(move |cx| {
+                // Refreshing is handled by the panel the menu is opened from.
+                let focus_handle = cx.focused();
                 ContextMenu::build(cx, |mut menu, cx| {
                     for model in CompletionProvider::global(cx).available_models() {
                         menu = menu.custom_entry(
                             {
                                 let model = model.clone();
-                                move |_| Label::new(model.display_name()).into_any_element()
+                                move |_| {
+                                    let capabilities = model_capabilities(&model);
+                                    h_flex()
+                                        .w_full()
+                                        .justify_between()
+                                        .gap_2()
+                                        .child(Label::new(model.display_name()))
+                                        .child(
+                                            h_flex()
+                                                .gap_1()
+                                                .when(capabilities.supports_tools, |this| {
+                                                    this.child(capability_label("tools"))
+                                                })
+                                                .when(capabilities.supports_vision, |this| {
+                                                    this.child(capability_label("images"))
+                                                }),
+                                        )
+                                        .into_any_element()
+                                }
                             },
                             {
                                 let fs = self.fs.clone();
@@ -43,7 +66,11 @@ impl RenderOnce for ModelSelector {
                             },
                         );
                     }
-                    menu
+                    if let Some(focus_handle) = focus_handle {
+                        menu = menu.context(focus_handle);
+                    }
+                    menu.separator()
+                        .action("Refresh Models", Box::new(ReloadModels))
                 })
                 .into()
             })
@@ -80,3 +107,11 @@ impl RenderOnce for ModelSelector {
             .attach(gpui::AnchorCorner::BottomLeft)
     }
 }
+
+/// Marks a model in the menu as supporting a feature, so that users can tell which
+/// models they can e.g. attach images for.
+fn capability_label(capability: &'static str) -> Label {
+    Label::new(capability)
+        .size(LabelSize::XSmall)
+        .color(Color::Muted)
+}
@@ -744,9 +744,24 @@ impl PromptLibrary {
                                     messages: vec![LanguageModelRequestMessage {
                                         role: Role::System,
                                         content: body.to_string(),
+                                        tool_calls: Vec::new(),
+                                        tool_call_id: None,
+                                        images: Vec::new(),
+                                        cache: false,
                                     }],
                                     stop: Vec::new(),
                                     temperature: 1.,
+                                    max_tokens: None,
+                                    tools: Vec::new(),
+                                    tool_choice: None,
+                                    top_p: None,
+                                    frequency_penalty: None,
+                                    presence_penalty: None,
+                                    response_format: None,
+                                    seed: None,
+                                    logit_bias: None,
+                                    logprobs: false,
+                                    top_logprobs: None,
                                 },
                                 cx,
                             )
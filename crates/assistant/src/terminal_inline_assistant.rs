@@ -265,6 +265,10 @@ impl TerminalInlineAssistant {
         messages.push(LanguageModelRequestMessage {
             role: Role::User,
             content: prompt,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
         });
 
         Ok(LanguageModelRequest {
@@ -272,6 +276,17 @@ impl TerminalInlineAssistant {
             messages,
             stop: Vec::new(),
             temperature: 1.0,
+            max_tokens: None,
+            tools: Vec::new(),
+            tool_choice: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            response_format: None,
+            seed: None,
+            logit_bias: None,
+            logprobs: false,
+            top_logprobs: None,
         })
     }
 
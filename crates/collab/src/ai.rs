@@ -1,6 +1,6 @@
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, Result};
+use language_model::{LanguageModelTool, LanguageModelToolChoice};
 use rpc::proto;
-use util::ResultExt as _;
 
 pub fn language_model_request_to_open_ai(
     request: proto::CompleteWithLanguageModel,
@@ -16,7 +16,7 @@ pub fn language_model_request_to_open_ai(
 
                 let openai_message = match role {
                     proto::LanguageModelRole::LanguageModelUser => open_ai::RequestMessage::User {
-                        content: message.content,
+                        content: message.content.into(),
                     },
                     proto::LanguageModelRole::LanguageModelAssistant => {
                         open_ai::RequestMessage::Assistant {
@@ -59,34 +59,33 @@ pub fn language_model_request_to_open_ai(
             })
             .collect::<Result<Vec<open_ai::RequestMessage>>>()?,
         stream: true,
+        stream_options: None,
         stop: request.stop,
         temperature: request.temperature,
+        max_tokens: request.max_tokens,
         tools: request
             .tools
             .into_iter()
-            .filter_map(|tool| {
-                Some(match tool.variant? {
-                    proto::chat_completion_tool::Variant::Function(f) => {
-                        open_ai::ToolDefinition::Function {
-                            function: open_ai::FunctionDefinition {
-                                name: f.name,
-                                description: f.description,
-                                parameters: if let Some(params) = &f.parameters {
-                                    Some(
-                                        serde_json::from_str(params)
-                                            .context("failed to deserialize tool parameters")
-                                            .log_err()?,
-                                    )
-                                } else {
-                                    None
-                                },
-                            },
-                        }
-                    }
-                })
+            .map(|tool| {
+                Ok(completion::open_ai_tool_definition(
+                    LanguageModelTool::from_proto(tool)?,
+                )?)
             })
-            .collect(),
-        tool_choice: request.tool_choice,
+            .collect::<Result<_>>()?,
+        tool_choice: request.tool_choice.map(|tool_choice| {
+            completion::open_ai_tool_choice(LanguageModelToolChoice::from_proto(
+                tool_choice,
+                request.tool_choice_names_tool,
+            ))
+        }),
+        top_p: request.top_p,
+        frequency_penalty: None,
+        presence_penalty: None,
+        response_format: None,
+        seed: None,
+        logit_bias: None,
+        logprobs: false,
+        top_logprobs: None,
     })
 }
 
@@ -99,6 +98,7 @@ pub fn language_model_request_to_google_ai(
             .into_iter()
             .map(language_model_request_message_to_google_ai)
             .collect::<Result<Vec<_>>>()?,
+        system_instruction: None,
         generation_config: None,
         safety_settings: None,
     })
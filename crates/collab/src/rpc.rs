@@ -4559,6 +4559,8 @@ async fn complete_with_open_ai(
         &api_key,
         crate::ai::language_model_request_to_open_ai(request)?,
         None,
+        open_ai::HttpVersionPreference::Negotiate,
+        None,
     )
     .await
     .context("open_ai::stream_completion request failed within collab")?;
@@ -4673,53 +4675,28 @@ async fn complete_with_anthropic(
     api_key: Arc<str>,
 ) -> Result<()> {
     let model = anthropic::Model::from_id(&request.model)?;
-
-    let mut system_message = String::new();
-    let messages = request
-        .messages
-        .into_iter()
-        .filter_map(|message| {
-            match message.role() {
-                LanguageModelRole::LanguageModelUser => Some(anthropic::RequestMessage {
-                    role: anthropic::Role::User,
-                    content: message.content,
-                }),
-                LanguageModelRole::LanguageModelAssistant => Some(anthropic::RequestMessage {
-                    role: anthropic::Role::Assistant,
-                    content: message.content,
-                }),
-                // Anthropic's API breaks system instructions out as a separate field rather
-                // than having a system message role.
-                LanguageModelRole::LanguageModelSystem => {
-                    if !system_message.is_empty() {
-                        system_message.push_str("\n\n");
-                    }
-                    system_message.push_str(&message.content);
-
-                    None
-                }
-                // We don't yet support tool calls for Anthropic
-                LanguageModelRole::LanguageModelTool => None,
-            }
-        })
-        .collect();
+    // Requests are converted the same way as when the client talks to Anthropic
+    // directly, so that tool calls and their results are sent as tool_use and
+    // tool_result blocks.
+    let request = language_model::LanguageModelRequest::from_proto(
+        request,
+        language_model::LanguageModel::Anthropic(model.clone()),
+    )?;
+    let request = completion::to_anthropic_request(request, model).map_err(anyhow::Error::from)?;
 
     let mut stream = anthropic::stream_completion(
         session.http_client.as_ref(),
         anthropic::ANTHROPIC_API_URL,
         &api_key,
-        anthropic::Request {
-            model,
-            messages,
-            stream: true,
-            system: system_message,
-            max_tokens: 4092,
-        },
+        request,
         None,
     )
     .await?;
 
     let mut current_role = proto::LanguageModelRole::LanguageModelAssistant;
+    // Tool uses are indexed by their content block, and numbered in the order they
+    // started, as OpenAI numbers tool calls.
+    let mut tool_call_indices = HashMap::<u32, u32>::default();
 
     while let Some(event) = stream.next().await {
         let event = event?;
@@ -4734,27 +4711,45 @@ async fn complete_with_anthropic(
                     }
                 }
             }
-            anthropic::ResponseEvent::ContentBlockStart { content_block, .. } => {
-                match content_block {
-                    anthropic::ContentBlock::Text { text } => {
-                        if !text.is_empty() {
-                            response.send(proto::LanguageModelResponse {
-                                choices: vec![proto::LanguageModelChoiceDelta {
-                                    index: 0,
-                                    delta: Some(proto::LanguageModelResponseMessage {
-                                        role: Some(current_role as i32),
-                                        content: Some(text),
-                                        tool_calls: Vec::new(),
-                                    }),
-                                    finish_reason: None,
-                                }],
-                            })?;
-                        }
+            anthropic::ResponseEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => match content_block {
+                anthropic::ContentBlock::Text { text } => {
+                    if !text.is_empty() {
+                        response.send(proto::LanguageModelResponse {
+                            choices: vec![proto::LanguageModelChoiceDelta {
+                                index: 0,
+                                delta: Some(proto::LanguageModelResponseMessage {
+                                    role: Some(current_role as i32),
+                                    content: Some(text),
+                                    tool_calls: Vec::new(),
+                                }),
+                                finish_reason: None,
+                            }],
+                        })?;
                     }
                 }
-            }
-            anthropic::ResponseEvent::ContentBlockDelta { delta, .. } => match delta {
-                anthropic::TextDelta::TextDelta { text } => {
+                anthropic::ContentBlock::ToolUse { id, name, .. } => {
+                    let tool_call_index = tool_call_indices.len() as u32;
+                    tool_call_indices.insert(index, tool_call_index);
+                    response.send(tool_call_delta(
+                        current_role,
+                        proto::ToolCallDelta {
+                            index: tool_call_index,
+                            id: Some(id),
+                            variant: Some(proto::tool_call_delta::Variant::Function(
+                                proto::tool_call_delta::FunctionCallDelta {
+                                    name: Some(name),
+                                    arguments: None,
+                                },
+                            )),
+                        },
+                    ))?;
+                }
+            },
+            anthropic::ResponseEvent::ContentBlockDelta { index, delta } => match delta {
+                anthropic::ContentDelta::TextDelta { text } => {
                     response.send(proto::LanguageModelResponse {
                         choices: vec![proto::LanguageModelChoiceDelta {
                             index: 0,
@@ -4767,6 +4762,23 @@ async fn complete_with_anthropic(
                         }],
                     })?;
                 }
+                anthropic::ContentDelta::InputJsonDelta { partial_json } => {
+                    if let Some(tool_call_index) = tool_call_indices.get(&index) {
+                        response.send(tool_call_delta(
+                            current_role,
+                            proto::ToolCallDelta {
+                                index: *tool_call_index,
+                                id: None,
+                                variant: Some(proto::tool_call_delta::Variant::Function(
+                                    proto::tool_call_delta::FunctionCallDelta {
+                                        name: None,
+                                        arguments: Some(partial_json),
+                                    },
+                                )),
+                            },
+                        ))?;
+                    }
+                }
             },
             anthropic::ResponseEvent::MessageDelta { delta, .. } => {
                 if let Some(stop_reason) = delta.stop_reason {
@@ -4788,6 +4800,23 @@ async fn complete_with_anthropic(
     Ok(())
 }
 
+fn tool_call_delta(
+    role: proto::LanguageModelRole,
+    tool_call: proto::ToolCallDelta,
+) -> proto::LanguageModelResponse {
+    proto::LanguageModelResponse {
+        choices: vec![proto::LanguageModelChoiceDelta {
+            index: 0,
+            delta: Some(proto::LanguageModelResponseMessage {
+                role: Some(role as i32),
+                content: None,
+                tool_calls: vec![tool_call],
+            }),
+            finish_reason: None,
+        }],
+    }
+}
+
 struct CountTokensWithLanguageModelRateLimit;
 
 impl RateLimit for CountTokensWithLanguageModelRateLimit {
@@ -4829,10 +4858,12 @@ async fn count_tokens_with_language_model(
 
     let api_key = google_ai_api_key
         .ok_or_else(|| anyhow!("no Google AI API key configured on the server"))?;
+    let model = request.model.clone();
     let tokens_response = google_ai::count_tokens(
         session.http_client.as_ref(),
         google_ai::API_URL,
         &api_key,
+        &model,
         crate::ai::count_tokens_request_to_google_ai(request)?,
     )
     .await?;
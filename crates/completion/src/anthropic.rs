@@ -1,20 +1,38 @@
-use crate::{count_open_ai_tokens, LanguageModelCompletionProvider};
-use crate::{CompletionProvider, LanguageModel, LanguageModelRequest};
-use anthropic::{stream_completion, Model as AnthropicModel, Request, RequestMessage};
+use crate::{
+    api_key_prompt::ApiKeyPrompt, count_open_ai_tokens, normalize_api_key,
+    LanguageModelCompletionProvider,
+};
+use crate::{
+    CompletionError, CompletionEvent, CompletionProvider, LanguageModel, LanguageModelRequest,
+    ModelCapabilities, StreamEnd, TokenPrice, TokenUsage,
+};
+use anthropic::{
+    stream_completion, CacheControl, ContentBlock, ContentDelta, ImageSource,
+    Model as AnthropicModel, Request, RequestContent, RequestMessage, ResponseEvent, Tool,
+    ToolChoice, MAX_CACHE_BREAKPOINTS,
+};
 use anyhow::{anyhow, Result};
-use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
-use gpui::{AnyView, AppContext, Task, TextStyle, View};
+use collections::{HashMap, HashSet};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use gpui::{AnyView, AppContext, Task};
 use http::HttpClient;
-use language_model::Role;
-use settings::Settings;
+use language_model::{
+    LanguageModelImage, LanguageModelRequestMessage, LanguageModelToolCall,
+    LanguageModelToolChoice, Role,
+};
 use std::time::Duration;
-use std::{env, sync::Arc};
+use std::{env, mem, sync::Arc};
 use strum::IntoEnumIterator;
-use theme::ThemeSettings;
 use ui::prelude::*;
 use util::ResultExt;
 
+/// The most tokens a completion is requested to have.
+const MAX_OUTPUT_TOKENS: u32 = 4092;
+
 pub struct AnthropicCompletionProvider {
     api_key: Option<String>,
     api_url: String,
@@ -46,7 +64,7 @@ impl LanguageModelCompletionProvider for AnthropicCompletionProvider {
             let api_url = self.api_url.clone();
             cx.spawn(|mut cx| async move {
                 let api_key = if let Ok(api_key) = env::var("ANTHROPIC_API_KEY") {
-                    api_key
+                    normalize_api_key(&api_key).to_string()
                 } else {
                     let (_, api_key) = cx
                         .update(|cx| cx.read_credentials(&api_url))?
@@ -76,8 +94,22 @@ impl LanguageModelCompletionProvider for AnthropicCompletionProvider {
     }
 
     fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
-        cx.new_view(|cx| AuthenticationPrompt::new(self.api_url.clone(), cx))
-            .into()
+        cx.new_view(|cx| {
+            ApiKeyPrompt::new(
+                self.api_url.clone(),
+                "Anthropic",
+                "https://console.anthropic.com/settings/keys",
+                "ANTHROPIC_API_KEY",
+                "sk-000000000000000000000000000000000000000000000000",
+                |provider, api_key| {
+                    provider.update_current_as::<_, AnthropicCompletionProvider>(|provider| {
+                        provider.api_key = Some(api_key);
+                    });
+                },
+                cx,
+            )
+        })
+        .into()
     }
 
     fn model(&self) -> LanguageModel {
@@ -96,36 +128,21 @@ impl LanguageModelCompletionProvider for AnthropicCompletionProvider {
         &self,
         request: LanguageModelRequest,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
-        let request = self.to_anthropic_request(request);
-
-        let http_client = self.http_client.clone();
-        let api_key = self.api_key.clone();
-        let api_url = self.api_url.clone();
-        let low_speed_timeout = self.low_speed_timeout;
+        let response = self.send_request(request);
         async move {
-            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            let request = stream_completion(
-                http_client.as_ref(),
-                &api_url,
-                &api_key,
-                request,
-                low_speed_timeout,
-            );
-            let response = request.await?;
             let stream = response
+                .await?
                 .filter_map(|response| async move {
                     match response {
                         Ok(response) => match response {
-                            anthropic::ResponseEvent::ContentBlockStart {
-                                content_block, ..
-                            } => match content_block {
-                                anthropic::ContentBlock::Text { text } => Some(Ok(text)),
-                            },
-                            anthropic::ResponseEvent::ContentBlockDelta { delta, .. } => {
-                                match delta {
-                                    anthropic::TextDelta::TextDelta { text } => Some(Ok(text)),
-                                }
-                            }
+                            ResponseEvent::ContentBlockStart {
+                                content_block: ContentBlock::Text { text },
+                                ..
+                            } => Some(Ok(text)),
+                            ResponseEvent::ContentBlockDelta {
+                                delta: ContentDelta::TextDelta { text },
+                                ..
+                            } => Some(Ok(text)),
                             _ => None,
                         },
                         Err(error) => Some(Err(error)),
@@ -137,6 +154,14 @@ impl LanguageModelCompletionProvider for AnthropicCompletionProvider {
         .boxed()
     }
 
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        let response = self.send_request(request);
+        async move { Ok(anthropic_events(response.await?)) }.boxed()
+    }
+
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
@@ -173,146 +198,792 @@ impl AnthropicCompletionProvider {
         self.settings_version = settings_version;
     }
 
-    fn to_anthropic_request(&self, mut request: LanguageModelRequest) -> Request {
-        request.preprocess_anthropic();
+    fn send_request(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<ResponseEvent>>>> {
+        let request = to_anthropic_request(request, self.model.clone());
+
+        let http_client = self.http_client.clone();
+        let api_key = self.api_key.clone();
+        let api_url = self.api_url.clone();
+        let low_speed_timeout = self.low_speed_timeout;
+        async move {
+            let request = request?;
+            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
+            stream_completion(
+                http_client.as_ref(),
+                &api_url,
+                &api_key,
+                request,
+                low_speed_timeout,
+            )
+            .await
+        }
+        .boxed()
+    }
+}
+
+/// Converts `request` into a request to Anthropic's Messages API, asking the model
+/// it names, or else `default_model`. Cloud requests for Claude are converted on
+/// the server the same way.
+pub fn to_anthropic_request(
+    request: LanguageModelRequest,
+    default_model: AnthropicModel,
+) -> Result<Request, CompletionError> {
+    let model = match request.model {
+        LanguageModel::Anthropic(model) => model,
+        _ => default_model,
+    };
+    validate_tool_call_ids(&request.messages)?;
 
-        let model = match request.model {
-            LanguageModel::Anthropic(model) => model,
-            _ => self.model.clone(),
+    let mut system = Vec::new();
+    let mut messages = Vec::<RequestMessage>::new();
+    for message in request.messages {
+        let (role, mut content) = match message.role {
+            // Anthropic's API breaks system instructions out as a separate field
+            // rather than having a system message role.
+            Role::System => {
+                let mut content = text_content(message.content);
+                if message.cache {
+                    cache_up_to_last(&mut content);
+                }
+                system.extend(content);
+                continue;
+            }
+            Role::User => {
+                // Images go first, as Anthropic recommends.
+                let mut content = message
+                    .images
+                    .iter()
+                    .map(image_content)
+                    .collect::<Result<Vec<_>, _>>()?;
+                content.extend(text_content(message.content));
+                (anthropic::Role::User, content)
+            }
+            Role::Assistant => {
+                let mut content = text_content(message.content);
+                for tool_call in message.tool_calls {
+                    content.push(tool_use_content(tool_call)?);
+                }
+                (anthropic::Role::Assistant, content)
+            }
+            // There's no role for tool results either; they're sent as blocks of
+            // a user message.
+            Role::Tool => (
+                anthropic::Role::User,
+                vec![RequestContent::ToolResult {
+                    // Validated above.
+                    tool_use_id: message.tool_call_id.unwrap_or_default(),
+                    content: message.content,
+                    cache_control: None,
+                }],
+            ),
         };
+        if content.is_empty() {
+            continue;
+        }
+        if message.cache {
+            cache_up_to_last(&mut content);
+        }
 
-        let mut system_message = String::new();
-        if request
-            .messages
-            .first()
-            .map_or(false, |message| message.role == Role::System)
-        {
-            system_message = request.messages.remove(0).content;
+        // Messages have to alternate between the user and the assistant, so
+        // neighbouring messages from the same role are merged.
+        match messages.last_mut() {
+            Some(last_message) if last_message.role == role => {
+                last_message.content.append(&mut content)
+            }
+            _ => messages.push(RequestMessage { role, content }),
         }
+    }
 
-        Request {
-            model,
-            messages: request
-                .messages
-                .iter()
-                .map(|msg| RequestMessage {
-                    role: match msg.role {
-                        Role::User => anthropic::Role::User,
-                        Role::Assistant => anthropic::Role::Assistant,
-                        Role::System => unreachable!("filtered out by preprocess_request"),
-                    },
-                    content: msg.content.clone(),
-                })
-                .collect(),
-            stream: true,
-            system: system_message,
-            max_tokens: 4092,
+    // The earliest breakpoints are kept, since the prompts they end are the most
+    // likely to be repeated.
+    system
+        .iter_mut()
+        .chain(messages.iter_mut().flat_map(|message| &mut message.content))
+        .map(RequestContent::cache_control_mut)
+        .filter(|cache_control| cache_control.is_some())
+        .skip(MAX_CACHE_BREAKPOINTS)
+        .for_each(|cache_control| *cache_control = None);
+
+    // Tool results have to come before anything else in their message.
+    for message in &mut messages {
+        if message.role == anthropic::Role::User {
+            message
+                .content
+                .sort_by_key(|content| !matches!(content, RequestContent::ToolResult { .. }));
         }
     }
+
+    Ok(Request {
+        model,
+        messages,
+        stream: true,
+        system,
+        max_tokens: MAX_OUTPUT_TOKENS,
+        tools: request
+            .tools
+            .into_iter()
+            .map(|tool| Tool {
+                name: tool.name,
+                description: tool.description,
+                input_schema: tool
+                    .parameters
+                    .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} })),
+            })
+            .collect(),
+        tool_choice: request.tool_choice.map(|tool_choice| match tool_choice {
+            LanguageModelToolChoice::Auto => ToolChoice::Auto,
+            LanguageModelToolChoice::None => ToolChoice::None,
+            LanguageModelToolChoice::Required => ToolChoice::Any,
+            LanguageModelToolChoice::Specific(name) => ToolChoice::Tool { name },
+        }),
+    })
 }
 
-struct AuthenticationPrompt {
-    api_key: View<Editor>,
-    api_url: String,
+fn text_content(text: String) -> Vec<RequestContent> {
+    if text.is_empty() {
+        Vec::new()
+    } else {
+        vec![RequestContent::text(text)]
+    }
 }
 
-impl AuthenticationPrompt {
-    fn new(api_url: String, cx: &mut WindowContext) -> Self {
-        Self {
-            api_key: cx.new_view(|cx| {
-                let mut editor = Editor::single_line(cx);
-                editor.set_placeholder_text(
-                    "sk-000000000000000000000000000000000000000000000000",
-                    cx,
-                );
-                editor
-            }),
-            api_url,
+/// Caches the prompt up to and including the last of `content`.
+fn cache_up_to_last(content: &mut [RequestContent]) {
+    if let Some(last) = content.last_mut() {
+        *last.cache_control_mut() = Some(CacheControl::Ephemeral);
+    }
+}
+
+/// What `model` supports.
+pub fn anthropic_model_capabilities(model: &AnthropicModel) -> ModelCapabilities {
+    ModelCapabilities {
+        supports_tools: true,
+        supports_vision: true,
+        supports_json_mode: false,
+        // Requests ask for at most this many, see `to_anthropic_request`.
+        max_output_tokens: Some(MAX_OUTPUT_TOKENS),
+        context_window: model.max_token_count(),
+    }
+}
+
+/// The list price of `model`'s tokens. Writing to the cache costs a quarter more
+/// than prompt tokens normally do, and reading from it a tenth as much.
+pub fn anthropic_token_price(model: &AnthropicModel) -> TokenPrice {
+    let (prompt, completion) = match model {
+        AnthropicModel::Claude3_5Sonnet | AnthropicModel::Claude3Sonnet => (3., 15.),
+        AnthropicModel::Claude3Opus => (15., 75.),
+        AnthropicModel::Claude3Haiku => (0.25, 1.25),
+    };
+    TokenPrice {
+        prompt,
+        completion,
+        cache_write: prompt * 1.25,
+        cache_read: prompt * 0.1,
+    }
+}
+
+/// Anthropic takes tool calls' input as JSON rather than a string, so calls whose
+/// arguments aren't valid JSON, e.g. because they were cut off, can't be sent.
+fn tool_use_content(tool_call: LanguageModelToolCall) -> Result<RequestContent, CompletionError> {
+    // A tool that takes no input streams none.
+    let input = if tool_call.arguments.is_empty() {
+        serde_json::json!({})
+    } else {
+        serde_json::from_str(&tool_call.arguments).map_err(|_| {
+            CompletionError::InvalidToolCallArguments {
+                id: tool_call.id.clone(),
+                name: tool_call.name.clone(),
+            }
+        })?
+    };
+    Ok(RequestContent::ToolUse {
+        id: tool_call.id,
+        name: tool_call.name,
+        input,
+        cache_control: None,
+    })
+}
+
+/// Anthropic can't fetch images, so only those encoded in `data:` URLs can be sent.
+fn image_content(image: &LanguageModelImage) -> Result<RequestContent, CompletionError> {
+    let (media_type, data) = image.as_base64().ok_or_else(|| {
+        CompletionError::UnsupportedContent(format!(
+            "Anthropic only accepts base64-encoded images, not {}",
+            image.url
+        ))
+    })?;
+    Ok(RequestContent::Image {
+        source: ImageSource::Base64 {
+            media_type: media_type.into(),
+            data: data.into(),
+        },
+        cache_control: None,
+    })
+}
+
+/// Checks that each tool result names a tool call made earlier in the
+/// conversation, and that no two tool calls share an id, since Anthropic rejects
+/// requests that don't pair them up. OpenAI's Responses API is checked the same way.
+pub(crate) fn validate_tool_call_ids(
+    messages: &[LanguageModelRequestMessage],
+) -> Result<(), CompletionError> {
+    let mut tool_call_ids = HashSet::default();
+    for message in messages {
+        match message.role {
+            Role::Assistant => {
+                for tool_call in &message.tool_calls {
+                    if !tool_call_ids.insert(tool_call.id.as_str()) {
+                        return Err(CompletionError::DuplicateToolCallId {
+                            id: tool_call.id.clone(),
+                        });
+                    }
+                }
+            }
+            Role::Tool => {
+                let tool_call_id = message
+                    .tool_call_id
+                    .as_deref()
+                    .filter(|id| !id.is_empty())
+                    .ok_or(CompletionError::MissingToolCallId)?;
+                if !tool_call_ids.contains(tool_call_id) {
+                    return Err(CompletionError::OrphanedToolResult {
+                        tool_call_id: tool_call_id.into(),
+                    });
+                }
+            }
+            Role::User | Role::System => {}
         }
     }
+    Ok(())
+}
+
+/// Stop reasons are reported as OpenAI would name them, so that e.g.
+/// [`StreamEnd::is_truncated`] applies to either.
+fn finish_reason(stop_reason: String) -> String {
+    match stop_reason.as_str() {
+        "end_turn" | "stop_sequence" => "stop".into(),
+        "max_tokens" => "length".into(),
+        "tool_use" => "tool_calls".into(),
+        _ => stop_reason,
+    }
+}
 
-    fn save_api_key(&mut self, _: &menu::Confirm, cx: &mut ViewContext<Self>) {
-        let api_key = self.api_key.read(cx).text(cx);
-        if api_key.is_empty() {
-            return;
+/// Turns the events streamed back from the Messages API into [`CompletionEvent`]s,
+/// assembling tool uses from their input's deltas and ending with a [`StreamEnd`].
+fn anthropic_events(
+    response: impl 'static + Send + Stream<Item = Result<ResponseEvent>>,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    // Tool uses are indexed by their content block, and numbered in the order they
+    // started.
+    let mut tool_calls = HashMap::<u32, (usize, LanguageModelToolCall)>::default();
+    let mut tool_call_count = 0;
+    let mut stream_end = StreamEnd::default();
+    let mut usage = TokenUsage::default();
+    response
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |response| {
+            let mut events = Vec::new();
+            match response {
+                Some(Ok(ResponseEvent::MessageStart { message })) => {
+                    if let Some(message_usage) = message.usage {
+                        usage.prompt_tokens = message_usage.input_tokens.unwrap_or_default();
+                        usage.cache_creation_tokens = message_usage
+                            .cache_creation_input_tokens
+                            .unwrap_or_default();
+                        usage.cache_read_tokens =
+                            message_usage.cache_read_input_tokens.unwrap_or_default();
+                        stream_end.usage = Some(usage);
+                    }
+                }
+                Some(Ok(ResponseEvent::ContentBlockStart {
+                    index,
+                    content_block,
+                })) => match content_block {
+                    ContentBlock::Text { text } => {
+                        if !text.is_empty() {
+                            events.push(Ok(CompletionEvent::Text(text)));
+                        }
+                    }
+                    ContentBlock::ToolUse { id, name, .. } => {
+                        events.push(Ok(CompletionEvent::ToolCallDelta {
+                            index: tool_call_count,
+                            id: Some(id.clone()),
+                            name: Some(name.clone()),
+                            arguments_fragment: String::new(),
+                        }));
+                        tool_calls.insert(
+                            index,
+                            (
+                                tool_call_count,
+                                LanguageModelToolCall {
+                                    id,
+                                    name,
+                                    arguments: String::new(),
+                                },
+                            ),
+                        );
+                        tool_call_count += 1;
+                    }
+                },
+                Some(Ok(ResponseEvent::ContentBlockDelta { index, delta })) => match delta {
+                    ContentDelta::TextDelta { text } => {
+                        events.push(Ok(CompletionEvent::Text(text)));
+                    }
+                    ContentDelta::InputJsonDelta { partial_json } => {
+                        if let Some((tool_call_index, tool_call)) = tool_calls.get_mut(&index) {
+                            tool_call.arguments.push_str(&partial_json);
+                            events.push(Ok(CompletionEvent::ToolCallDelta {
+                                index: *tool_call_index,
+                                id: None,
+                                name: None,
+                                arguments_fragment: partial_json,
+                            }));
+                        }
+                    }
+                },
+                Some(Ok(ResponseEvent::ContentBlockStop { index })) => {
+                    if let Some((_, mut tool_call)) = tool_calls.remove(&index) {
+                        // A tool that takes no input streams none.
+                        if tool_call.arguments.is_empty() {
+                            tool_call.arguments = "{}".into();
+                        }
+                        let is_complete =
+                            serde_json::from_str::<serde_json::Value>(&tool_call.arguments).is_ok();
+                        events.push(Ok(CompletionEvent::ToolCall {
+                            tool_call,
+                            is_complete,
+                        }));
+                    }
+                }
+                Some(Ok(ResponseEvent::MessageDelta {
+                    delta,
+                    usage: delta_usage,
+                })) => {
+                    if let Some(stop_reason) = delta.stop_reason {
+                        stream_end.finish_reason = Some(finish_reason(stop_reason));
+                    }
+                    if let Some(output_tokens) = delta_usage.output_tokens {
+                        usage.completion_tokens = output_tokens;
+                        stream_end.usage = Some(usage);
+                    }
+                }
+                Some(Ok(ResponseEvent::Ping {} | ResponseEvent::MessageStop {})) => {}
+                Some(Err(error)) => events.push(Err(error)),
+                None => {
+                    // Tool uses whose blocks never stopped were cut off.
+                    let mut cut_off = tool_calls.drain().map(|(_, call)| call).collect::<Vec<_>>();
+                    cut_off.sort_by_key(|(index, _)| *index);
+                    for (_, tool_call) in cut_off {
+                        events.push(Err(CompletionError::IncompleteToolCall {
+                            id: tool_call.id,
+                            name: tool_call.name,
+                        }
+                        .into()));
+                    }
+                    events.push(Ok(CompletionEvent::StreamEnd(mem::take(&mut stream_end))));
+                }
+            }
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{FakeHttpClient, Response};
+    use language_model::{LanguageModelRequestMessage, LanguageModelTool};
+    use serde_json::json;
+
+    fn message(role: Role, content: &str) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
         }
+    }
 
-        let write_credentials = cx.write_credentials(&self.api_url, "Bearer", api_key.as_bytes());
-        cx.spawn(|_, mut cx| async move {
-            write_credentials.await?;
-            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
-                provider.update_current_as::<_, AnthropicCompletionProvider>(|provider| {
-                    provider.api_key = Some(api_key);
-                });
+    #[gpui::test]
+    async fn test_tool_use() {
+        let events = [
+            json!({ "type": "message_start", "message": { "usage": { "input_tokens": 12 } } }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "Looking." } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({ "type": "content_block_start", "index": 1, "content_block": { "type": "tool_use", "id": "toolu_2", "name": "get_weather", "input": {} } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "{\"city\":" } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "\"Paris\"}" } }),
+            json!({ "type": "content_block_stop", "index": 1 }),
+            json!({ "type": "message_delta", "delta": { "stop_reason": "tool_use" }, "usage": { "output_tokens": 34 } }),
+            json!({ "type": "message_stop" }),
+        ];
+        let body = events
+            .iter()
+            .map(|event| {
+                format!(
+                    "event: {}\ndata: {event}\n\n",
+                    event["type"].as_str().unwrap()
+                )
             })
-        })
-        .detach_and_log_err(cx);
+            .collect::<String>();
+        let http_client = FakeHttpClient::create(move |_| {
+            let body = body.clone();
+            async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+        });
+        let mut provider = AnthropicCompletionProvider::new(
+            AnthropicModel::Claude3_5Sonnet,
+            anthropic::ANTHROPIC_API_URL.into(),
+            http_client,
+            None,
+            0,
+        );
+        provider.api_key = Some("sk-ant-test".into());
+
+        let request = LanguageModelRequest {
+            model: provider.model(),
+            messages: vec![
+                message(Role::System, "Be brief."),
+                message(Role::User, "What's the weather in Paris?"),
+                LanguageModelRequestMessage {
+                    tool_calls: vec![LanguageModelToolCall {
+                        id: "toolu_1".into(),
+                        name: "get_weather".into(),
+                        arguments: r#"{"city":"Paris"}"#.into(),
+                    }],
+                    ..message(Role::Assistant, "")
+                },
+                LanguageModelRequestMessage {
+                    tool_call_id: Some("toolu_1".into()),
+                    ..message(Role::Tool, "Sunny")
+                },
+                message(Role::User, "And tomorrow?"),
+            ],
+            tools: vec![LanguageModelTool {
+                name: "get_weather".into(),
+                description: Some("Gets the weather in a city".into()),
+                parameters: Some(json!({
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                })),
+            }],
+            tool_choice: Some(LanguageModelToolChoice::Required),
+            ..Default::default()
+        };
+        let anthropic_request =
+            to_anthropic_request(request.clone(), provider.model.clone()).unwrap();
+        assert_eq!(
+            anthropic_request.system,
+            vec![RequestContent::text("Be brief.".into())]
+        );
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.messages).unwrap(),
+            json!([
+                {
+                    "role": "user",
+                    "content": [{ "type": "text", "text": "What's the weather in Paris?" }],
+                },
+                {
+                    "role": "assistant",
+                    "content": [{
+                        "type": "tool_use",
+                        "id": "toolu_1",
+                        "name": "get_weather",
+                        "input": { "city": "Paris" },
+                    }],
+                },
+                {
+                    "role": "user",
+                    "content": [
+                        { "type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny" },
+                        { "type": "text", "text": "And tomorrow?" },
+                    ],
+                },
+            ])
+        );
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.tools).unwrap(),
+            json!([{
+                "name": "get_weather",
+                "description": "Gets the weather in a city",
+                "input_schema": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                },
+            }])
+        );
+        assert_eq!(anthropic_request.tool_choice, Some(ToolChoice::Any));
+
+        let events = provider
+            .stream_completion_events(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Text("Looking.".into()),
+                CompletionEvent::ToolCallDelta {
+                    index: 0,
+                    id: Some("toolu_2".into()),
+                    name: Some("get_weather".into()),
+                    arguments_fragment: String::new(),
+                },
+                CompletionEvent::ToolCallDelta {
+                    index: 0,
+                    id: None,
+                    name: None,
+                    arguments_fragment: "{\"city\":".into(),
+                },
+                CompletionEvent::ToolCallDelta {
+                    index: 0,
+                    id: None,
+                    name: None,
+                    arguments_fragment: "\"Paris\"}".into(),
+                },
+                CompletionEvent::ToolCall {
+                    tool_call: LanguageModelToolCall {
+                        id: "toolu_2".into(),
+                        name: "get_weather".into(),
+                        arguments: r#"{"city":"Paris"}"#.into(),
+                    },
+                    is_complete: true,
+                },
+                CompletionEvent::StreamEnd(StreamEnd {
+                    finish_reason: Some("tool_calls".into()),
+                    usage: Some(TokenUsage {
+                        prompt_tokens: 12,
+                        completion_tokens: 34,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+            ]
+        );
     }
 
-    fn render_api_key_editor(&self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        let settings = ThemeSettings::get_global(cx);
-        let text_style = TextStyle {
-            color: cx.theme().colors().text,
-            font_family: settings.ui_font.family.clone(),
-            font_features: settings.ui_font.features.clone(),
-            font_size: rems(0.875).into(),
-            font_weight: settings.ui_font.weight,
-            line_height: relative(1.3),
+    #[test]
+    fn test_images_and_invalid_tool_calls() {
+        let convert = |messages| {
+            to_anthropic_request(
+                LanguageModelRequest {
+                    messages,
+                    ..Default::default()
+                },
+                AnthropicModel::Claude3_5Sonnet,
+            )
+        };
+        let tool_call = |arguments: &str| LanguageModelRequestMessage {
+            tool_calls: vec![LanguageModelToolCall {
+                id: "toolu_1".into(),
+                name: "get_weather".into(),
+                arguments: arguments.into(),
+            }],
+            ..message(Role::Assistant, "")
+        };
+        let tool_result = |tool_call_id: Option<&str>| LanguageModelRequestMessage {
+            tool_call_id: tool_call_id.map(Into::into),
+            ..message(Role::Tool, "Sunny")
+        };
+
+        let anthropic_request = convert(vec![LanguageModelRequestMessage {
+            images: vec![LanguageModelImage::from_base64("image/png", "aGk=")],
+            ..message(Role::User, "What's this?")
+        }])
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.messages[0].content).unwrap(),
+            json!([
+                {
+                    "type": "image",
+                    "source": { "type": "base64", "media_type": "image/png", "data": "aGk=" },
+                },
+                { "type": "text", "text": "What's this?" },
+            ])
+        );
+
+        let error = convert(vec![LanguageModelRequestMessage {
+            images: vec![LanguageModelImage {
+                url: "https://example.com/cat.png".into(),
+                detail: None,
+                size: None,
+            }],
+            ..message(Role::User, "What's this?")
+        }])
+        .unwrap_err();
+        assert!(matches!(error, CompletionError::UnsupportedContent(_)));
+
+        let error = convert(vec![tool_call(r#"{"city":"Par"#)]).unwrap_err();
+        assert!(matches!(
+            error,
+            CompletionError::InvalidToolCallArguments { .. }
+        ));
+
+        let error = convert(vec![tool_call("{}"), tool_result(None)]).unwrap_err();
+        assert!(matches!(error, CompletionError::MissingToolCallId));
+
+        let error = convert(vec![tool_call("{}"), tool_result(Some("toolu_2"))]).unwrap_err();
+        assert!(matches!(
+            error,
+            CompletionError::OrphanedToolResult { tool_call_id } if tool_call_id == "toolu_2"
+        ));
+
+        // A tool that takes no input may have been called with no arguments at all.
+        let anthropic_request = convert(vec![tool_call(""), tool_result(Some("toolu_1"))]).unwrap();
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.messages[0].content).unwrap()[0]["input"],
+            json!({})
+        );
+    }
+
+    #[test]
+    fn test_empty_tool_result() {
+        let mut request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, "Clear the log."),
+                LanguageModelRequestMessage {
+                    tool_calls: vec![LanguageModelToolCall {
+                        id: "toolu_1".into(),
+                        name: "clear_log".into(),
+                        arguments: "{}".into(),
+                    }],
+                    ..message(Role::Assistant, "")
+                },
+                // The tool succeeded without any output.
+                LanguageModelRequestMessage {
+                    tool_call_id: Some("toolu_1".into()),
+                    ..message(Role::Tool, "")
+                },
+                message(Role::Assistant, ""),
+            ],
             ..Default::default()
         };
-        EditorElement::new(
-            &self.api_key,
-            EditorStyle {
-                background: cx.theme().colors().editor_background,
-                local_player: cx.theme().players().local(),
-                text: text_style,
-                ..Default::default()
-            },
-        )
+        request.preprocess_anthropic();
+        assert_eq!(request.messages.len(), 3);
+
+        let anthropic_request =
+            to_anthropic_request(request, AnthropicModel::Claude3_5Sonnet).unwrap();
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.messages[2]).unwrap(),
+            json!({
+                "role": "user",
+                "content": [{ "type": "tool_result", "tool_use_id": "toolu_1", "content": "" }],
+            })
+        );
     }
-}
 
-impl Render for AuthenticationPrompt {
-    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        const INSTRUCTIONS: [&str; 4] = [
-            "To use the assistant panel or inline assistant, you need to add your Anthropic API key.",
-            "You can create an API key at: https://console.anthropic.com/settings/keys",
-            "",
-            "Paste your Anthropic API key below and hit enter to use the assistant:",
+    #[gpui::test]
+    async fn test_prompt_caching() {
+        let events = [
+            json!({ "type": "message_start", "message": { "usage": {
+                "input_tokens": 10,
+                "cache_creation_input_tokens": 2000,
+                "cache_read_input_tokens": 0,
+            } } }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "Hi" } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn" }, "usage": { "output_tokens": 1 } }),
+            json!({ "type": "message_stop" }),
         ];
+        let body = events
+            .iter()
+            .map(|event| format!("data: {event}\n\n"))
+            .collect::<String>();
+        let http_client = FakeHttpClient::create(move |_| {
+            let body = body.clone();
+            async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+        });
+        let mut provider = AnthropicCompletionProvider::new(
+            AnthropicModel::Claude3_5Sonnet,
+            anthropic::ANTHROPIC_API_URL.into(),
+            http_client,
+            None,
+            0,
+        );
+        provider.api_key = Some("sk-ant-test".into());
 
-        v_flex()
-            .p_4()
-            .size_full()
-            .on_action(cx.listener(Self::save_api_key))
-            .children(
-                INSTRUCTIONS.map(|instruction| Label::new(instruction).size(LabelSize::Small)),
-            )
-            .child(
-                h_flex()
-                    .w_full()
-                    .my_2()
-                    .px_2()
-                    .py_1()
-                    .bg(cx.theme().colors().editor_background)
-                    .rounded_md()
-                    .child(self.render_api_key_editor(cx)),
-            )
-            .child(
-                Label::new(
-                    "You can also assign the ANTHROPIC_API_KEY environment variable and restart Zed.",
-                )
-                .size(LabelSize::Small),
-            )
-            .child(
-                h_flex()
-                    .gap_2()
-                    .child(Label::new("Click on").size(LabelSize::Small))
-                    .child(Icon::new(IconName::ZedAssistant).size(IconSize::XSmall))
-                    .child(
-                        Label::new("in the status bar to close this panel.").size(LabelSize::Small),
-                    ),
-            )
-            .into_any()
+        let cached = |role, content: &str| LanguageModelRequestMessage {
+            cache: true,
+            ..message(role, content)
+        };
+        let request = LanguageModelRequest {
+            model: provider.model(),
+            messages: vec![
+                cached(Role::System, "A long system prompt."),
+                cached(Role::User, "One"),
+                cached(Role::Assistant, "Two"),
+                cached(Role::User, "Three"),
+                cached(Role::Assistant, "Four"),
+                message(Role::User, "Five"),
+            ],
+            ..Default::default()
+        };
+        let anthropic_request =
+            to_anthropic_request(request.clone(), provider.model.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.system).unwrap(),
+            json!([{
+                "type": "text",
+                "text": "A long system prompt.",
+                "cache_control": { "type": "ephemeral" },
+            }])
+        );
+        // Only the first four breakpoints are kept.
+        let cached_texts = anthropic_request
+            .messages
+            .iter()
+            .flat_map(|message| &message.content)
+            .filter_map(|content| match content {
+                RequestContent::Text {
+                    text,
+                    cache_control: Some(CacheControl::Ephemeral),
+                } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cached_texts, ["One", "Two", "Three"]);
+
+        let events = provider
+            .stream_completion_events(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let Some(Ok(CompletionEvent::StreamEnd(StreamEnd {
+            usage: Some(usage), ..
+        }))) = events.last()
+        else {
+            panic!("expected the stream to end with usage, got {events:?}");
+        };
+        assert_eq!(
+            *usage,
+            TokenUsage {
+                prompt_tokens: 10,
+                completion_tokens: 1,
+                cache_creation_tokens: 2000,
+                cache_read_tokens: 0,
+            }
+        );
+        assert_eq!(usage.total_prompt_tokens(), 2010);
+
+        let price = anthropic_token_price(&AnthropicModel::Claude3_5Sonnet);
+        let cost = price.cost(*usage);
+        assert!((cost - (10. * 3. + 15. + 2000. * 3.75) / 1_000_000.).abs() < 1e-12);
+        let cost_when_read = price.cost(TokenUsage {
+            cache_creation_tokens: 0,
+            cache_read_tokens: 2000,
+            ..*usage
+        });
+        assert!((cost_when_read - (10. * 3. + 15. + 2000. * 0.3) / 1_000_000.).abs() < 1e-12);
     }
 }
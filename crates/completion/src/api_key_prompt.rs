@@ -0,0 +1,133 @@
+use crate::CompletionProvider;
+use editor::{Editor, EditorElement, EditorStyle};
+use gpui::{TextStyle, View};
+use settings::Settings;
+use theme::ThemeSettings;
+use ui::prelude::*;
+
+/// Prompts for the API key of a provider that only needs one, saving it to the
+/// credentials of the provider's API URL.
+pub(crate) struct ApiKeyPrompt {
+    api_key: View<Editor>,
+    api_url: String,
+    provider_name: &'static str,
+    api_key_url: &'static str,
+    api_key_var: &'static str,
+    /// Hands the saved key to the current provider.
+    set_api_key: fn(&mut CompletionProvider, String),
+}
+
+impl ApiKeyPrompt {
+    /// Creates a prompt for the key of `provider_name`, which can be created at
+    /// `api_key_url` or read from the `api_key_var` environment variable.
+    pub fn new(
+        api_url: String,
+        provider_name: &'static str,
+        api_key_url: &'static str,
+        api_key_var: &'static str,
+        placeholder: &'static str,
+        set_api_key: fn(&mut CompletionProvider, String),
+        cx: &mut WindowContext,
+    ) -> Self {
+        Self {
+            api_key: cx.new_view(|cx| {
+                let mut editor = Editor::single_line(cx);
+                editor.set_placeholder_text(placeholder, cx);
+                editor
+            }),
+            api_url,
+            provider_name,
+            api_key_url,
+            api_key_var,
+            set_api_key,
+        }
+    }
+
+    fn save_api_key(&mut self, _: &menu::Confirm, cx: &mut ViewContext<Self>) {
+        let api_key = self.api_key.read(cx).text(cx);
+        if api_key.is_empty() {
+            return;
+        }
+
+        let write_credentials = cx.write_credentials(&self.api_url, "Bearer", api_key.as_bytes());
+        let set_api_key = self.set_api_key;
+        cx.spawn(|_, mut cx| async move {
+            write_credentials.await?;
+            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
+                set_api_key(provider, api_key);
+            })
+        })
+        .detach_and_log_err(cx);
+    }
+
+    fn render_api_key_editor(&self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let settings = ThemeSettings::get_global(cx);
+        let text_style = TextStyle {
+            color: cx.theme().colors().text,
+            font_family: settings.ui_font.family.clone(),
+            font_features: settings.ui_font.features.clone(),
+            font_size: rems(0.875).into(),
+            font_weight: settings.ui_font.weight,
+            line_height: relative(1.3),
+            ..Default::default()
+        };
+        EditorElement::new(
+            &self.api_key,
+            EditorStyle {
+                background: cx.theme().colors().editor_background,
+                local_player: cx.theme().players().local(),
+                text: text_style,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+impl Render for ApiKeyPrompt {
+    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let provider_name = self.provider_name;
+        let instructions = [
+            format!(
+                "To use the assistant panel or inline assistant, you need to add your {provider_name} API key."
+            ),
+            format!("You can create an API key at: {}", self.api_key_url),
+            String::new(),
+            format!("Paste your {provider_name} API key below and hit enter to use the assistant:"),
+        ];
+
+        v_flex()
+            .p_4()
+            .size_full()
+            .on_action(cx.listener(Self::save_api_key))
+            .children(
+                instructions.map(|instruction| Label::new(instruction).size(LabelSize::Small)),
+            )
+            .child(
+                h_flex()
+                    .w_full()
+                    .my_2()
+                    .px_2()
+                    .py_1()
+                    .bg(cx.theme().colors().editor_background)
+                    .rounded_md()
+                    .child(self.render_api_key_editor(cx)),
+            )
+            .child(
+                Label::new(format!(
+                    "You can also assign the {} environment variable and restart Zed.",
+                    self.api_key_var
+                ))
+                .size(LabelSize::Small),
+            )
+            .child(
+                h_flex()
+                    .gap_2()
+                    .child(Label::new("Click on").size(LabelSize::Small))
+                    .child(Icon::new(IconName::ZedAssistant).size(IconSize::XSmall))
+                    .child(
+                        Label::new("in the status bar to close this panel.").size(LabelSize::Small),
+                    ),
+            )
+            .into_any()
+    }
+}
@@ -1,13 +1,18 @@
 use crate::{
-    count_open_ai_tokens, CompletionProvider, LanguageModel, LanguageModelCompletionProvider,
-    LanguageModelRequest,
+    anthropic_model_capabilities, count_open_ai_tokens, open_ai_model_capabilities,
+    CompletionError, CompletionEvent, CompletionProvider, LanguageModel,
+    LanguageModelCompletionProvider, LanguageModelRequest, ModelCapabilities, StreamEnd,
 };
 use anyhow::{anyhow, Result};
 use client::{proto, Client};
-use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt, TryFutureExt};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryFutureExt, TryStreamExt,
+};
 use gpui::{AnyView, AppContext, Task};
-use language_model::CloudModel;
-use std::{future, sync::Arc};
+use language_model::{AnthropicModel, CloudModel, LanguageModelToolCall, OpenAiModel};
+use std::{future, mem, sync::Arc};
 use strum::IntoEnumIterator;
 use ui::prelude::*;
 
@@ -136,33 +141,21 @@ impl LanguageModelCompletionProvider for CloudCompletionProvider {
         }
     }
 
+    fn capabilities(&self) -> ModelCapabilities {
+        cloud_model_capabilities(&self.model)
+    }
+
     fn stream_completion(
         &self,
-        mut request: LanguageModelRequest,
+        request: LanguageModelRequest,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
-        request.preprocess();
-
-        let request = proto::CompleteWithLanguageModel {
-            model: request.model.id().to_string(),
-            messages: request
-                .messages
-                .iter()
-                .map(|message| message.to_proto())
-                .collect(),
-            stop: request.stop,
-            temperature: request.temperature,
-            tools: Vec::new(),
-            tool_choice: None,
-        };
-
-        self.client
-            .request_stream(request)
-            .map_ok(|stream| {
-                stream
-                    .filter_map(|response| async move {
-                        match response {
-                            Ok(mut response) => Some(Ok(response.choices.pop()?.delta?.content?)),
-                            Err(error) => Some(Err(error)),
+        self.stream_completion_events(request)
+            .map_ok(|events| {
+                events
+                    .try_filter_map(|event| async move {
+                        match event {
+                            CompletionEvent::Text(text) => Ok(Some(text)),
+                            _ => Ok(None),
                         }
                     })
                     .boxed()
@@ -170,11 +163,133 @@ impl LanguageModelCompletionProvider for CloudCompletionProvider {
             .boxed()
     }
 
+    fn stream_completion_events(
+        &self,
+        mut request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        request.preprocess();
+        self.client
+            .request_stream(request.to_proto())
+            .map_ok(|responses| completion_events(responses.boxed()))
+            .boxed()
+    }
+
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
 }
 
+/// What `model` supports when served by zed.dev: the same as when requested from
+/// its own provider, except that neither images nor JSON mode are sent to the
+/// server, and the server doesn't pass tools on to Google's models.
+pub fn cloud_model_capabilities(model: &CloudModel) -> ModelCapabilities {
+    let capabilities = match model {
+        CloudModel::Gpt3Point5Turbo => {
+            open_ai_model_capabilities(&OpenAiModel::ThreePointFiveTurbo)
+        }
+        CloudModel::Gpt4 => open_ai_model_capabilities(&OpenAiModel::Four),
+        CloudModel::Gpt4Turbo => open_ai_model_capabilities(&OpenAiModel::FourTurbo),
+        CloudModel::Gpt4Omni => open_ai_model_capabilities(&OpenAiModel::FourOmni),
+        CloudModel::Gpt4OmniMini => open_ai_model_capabilities(&OpenAiModel::FourOmniMini),
+        CloudModel::Claude3_5Sonnet => {
+            anthropic_model_capabilities(&AnthropicModel::Claude3_5Sonnet)
+        }
+        CloudModel::Claude3Opus => anthropic_model_capabilities(&AnthropicModel::Claude3Opus),
+        CloudModel::Claude3Sonnet => anthropic_model_capabilities(&AnthropicModel::Claude3Sonnet),
+        CloudModel::Claude3Haiku => anthropic_model_capabilities(&AnthropicModel::Claude3Haiku),
+        CloudModel::Gemini15Pro | CloudModel::Gemini15Flash | CloudModel::Custom(_) => {
+            ModelCapabilities::unknown(model.max_token_count())
+        }
+    };
+    ModelCapabilities {
+        supports_vision: false,
+        supports_json_mode: false,
+        context_window: model.max_token_count(),
+        ..capabilities
+    }
+}
+
+/// Maps the responses streamed by the server, which follow OpenAI's format whatever
+/// the model, to completion events. Tool calls are assembled from their deltas and
+/// emitted once the stream ends.
+fn completion_events(
+    responses: BoxStream<'static, Result<proto::LanguageModelResponse>>,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut tool_calls = Vec::<LanguageModelToolCall>::new();
+    let mut stream_end = StreamEnd::default();
+    responses
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |response| {
+            let mut events = Vec::new();
+            match response {
+                Some(Ok(response)) => {
+                    for choice in response.choices {
+                        if let Some(finish_reason) = choice.finish_reason {
+                            stream_end.finish_reason = Some(finish_reason);
+                        }
+                        let Some(delta) = choice.delta else {
+                            continue;
+                        };
+                        if let Some(content) = delta.content.filter(|content| !content.is_empty()) {
+                            events.push(Ok(CompletionEvent::Text(content)));
+                        }
+                        for chunk in delta.tool_calls {
+                            let index = chunk.index as usize;
+                            if tool_calls.len() <= index {
+                                tool_calls.resize_with(index + 1, Default::default);
+                            }
+                            let tool_call = &mut tool_calls[index];
+                            let (name, arguments_fragment) = match chunk.variant {
+                                Some(proto::tool_call_delta::Variant::Function(function)) => {
+                                    (function.name, function.arguments.unwrap_or_default())
+                                }
+                                None => (None, String::new()),
+                            };
+                            if let Some(id) = &chunk.id {
+                                tool_call.id.clone_from(id);
+                            }
+                            if let Some(name) = &name {
+                                tool_call.name.push_str(name);
+                            }
+                            tool_call.arguments.push_str(&arguments_fragment);
+                            events.push(Ok(CompletionEvent::ToolCallDelta {
+                                index,
+                                id: chunk.id,
+                                name,
+                                arguments_fragment,
+                            }));
+                        }
+                    }
+                }
+                Some(Err(error)) => events.push(Err(error)),
+                None => {
+                    for mut tool_call in tool_calls.drain(..) {
+                        // A tool that takes no arguments may stream none.
+                        if tool_call.arguments.is_empty() {
+                            tool_call.arguments = "{}".into();
+                        }
+                        if serde_json::from_str::<serde_json::Value>(&tool_call.arguments).is_ok() {
+                            events.push(Ok(CompletionEvent::ToolCall {
+                                tool_call,
+                                is_complete: true,
+                            }));
+                        } else {
+                            events.push(Err(CompletionError::IncompleteToolCall {
+                                id: tool_call.id,
+                                name: tool_call.name,
+                            }
+                            .into()));
+                        }
+                    }
+                    events.push(Ok(CompletionEvent::StreamEnd(mem::take(&mut stream_end))));
+                }
+            }
+            stream::iter(events)
+        })
+        .boxed()
+}
+
 struct AuthenticationPrompt;
 
 impl Render for AuthenticationPrompt {
@@ -207,3 +322,84 @@ impl Render for AuthenticationPrompt {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_model_capabilities() {
+        let claude = cloud_model_capabilities(&CloudModel::Claude3_5Sonnet);
+        assert!(claude.supports_tools);
+        assert!(!claude.supports_vision);
+        assert_eq!(claude.context_window, 200000);
+        assert!(cloud_model_capabilities(&CloudModel::Gpt4Omni).supports_tools);
+        assert!(!cloud_model_capabilities(&CloudModel::Gemini15Pro).supports_tools);
+        assert_eq!(
+            cloud_model_capabilities(&CloudModel::Custom("llama-3".into())),
+            ModelCapabilities::unknown(4096)
+        );
+    }
+
+    #[gpui::test]
+    async fn test_completion_events() {
+        let response = |content: Option<&str>, tool_call: Option<proto::ToolCallDelta>| {
+            Ok(proto::LanguageModelResponse {
+                choices: vec![proto::LanguageModelChoiceDelta {
+                    index: 0,
+                    delta: Some(proto::LanguageModelResponseMessage {
+                        role: None,
+                        content: content.map(Into::into),
+                        tool_calls: tool_call.into_iter().collect(),
+                    }),
+                    finish_reason: None,
+                }],
+            })
+        };
+        let tool_call_delta =
+            |id: Option<&str>, name: Option<&str>, arguments: &str| proto::ToolCallDelta {
+                index: 0,
+                id: id.map(Into::into),
+                variant: Some(proto::tool_call_delta::Variant::Function(
+                    proto::tool_call_delta::FunctionCallDelta {
+                        name: name.map(Into::into),
+                        arguments: Some(arguments.into()),
+                    },
+                )),
+            };
+        let responses = stream::iter(vec![
+            response(Some("Searching"), None),
+            response(
+                None,
+                Some(tool_call_delta(
+                    Some("call_1"),
+                    Some("search"),
+                    "{\"query\":",
+                )),
+            ),
+            response(None, Some(tool_call_delta(None, None, "\"zed\"}"))),
+        ])
+        .boxed();
+
+        let events = completion_events(responses)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[0], CompletionEvent::Text("Searching".into()));
+        assert_eq!(
+            events[3],
+            CompletionEvent::ToolCall {
+                tool_call: LanguageModelToolCall {
+                    id: "call_1".into(),
+                    name: "search".into(),
+                    arguments: "{\"query\":\"zed\"}".into(),
+                },
+                is_complete: true,
+            }
+        );
+        assert!(matches!(events[4], CompletionEvent::StreamEnd(_)));
+    }
+}
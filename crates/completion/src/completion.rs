@@ -1,38 +1,322 @@
 mod anthropic;
+mod api_key_prompt;
 mod cloud;
+mod error;
 #[cfg(any(test, feature = "test-support"))]
 mod fake;
+mod fallback;
+mod google_ai;
+mod history;
+mod metrics;
 mod ollama;
 mod open_ai;
+mod open_ai_compatible;
+mod recording;
+mod streaming_diff;
+mod tokenizer;
+mod tool_arguments;
+mod transform;
+mod usage;
 
+use ::open_ai::ModerationResult;
 pub use anthropic::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use client::Client;
 pub use cloud::*;
+use collections::HashMap;
+pub use error::*;
 #[cfg(any(test, feature = "test-support"))]
 pub use fake::*;
-use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
-use gpui::{AnyView, AppContext, Task, WindowContext};
-use language_model::{LanguageModel, LanguageModelRequest};
+pub use fallback::*;
+use futures::{
+    future::{self, AbortHandle, Abortable, BoxFuture, Either},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryFutureExt, TryStreamExt,
+};
+pub use google_ai::*;
+use gpui::{AnyView, AnyWindowHandle, AppContext, BackgroundExecutor, Task, WindowContext};
+pub use history::*;
+use language_model::{LanguageModel, LanguageModelRequest, LanguageModelToolCall};
+pub use metrics::*;
 pub use ollama::*;
 pub use open_ai::*;
+pub use open_ai_compatible::*;
 use parking_lot::RwLock;
+pub use recording::*;
 use smol::lock::{Semaphore, SemaphoreGuardArc};
-use std::{any::Any, pin::Pin, sync::Arc, task::Poll};
+use std::{any::Any, pin::Pin, sync::Arc, task::Poll, time::Duration};
+pub use streaming_diff::*;
+pub use tokenizer::*;
+pub use tool_arguments::*;
+pub use transform::*;
+pub use usage::*;
 
-pub struct CompletionResponse {
-    inner: BoxStream<'static, Result<String>>,
-    _lock: SemaphoreGuardArc,
+/// An event streamed back from a completion.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompletionEvent {
+    /// A chunk of the completion's text.
+    Text(String),
+    /// A chunk of the model's reasoning, streamed separately from its answer.
+    Reasoning(String),
+    /// A fragment of a tool call, emitted as it streams so the call can be shown
+    /// before it finishes. `id` and `name` are only set on the first delta for each
+    /// `index`. The assembled call follows as a [`CompletionEvent::ToolCall`].
+    ToolCallDelta {
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments_fragment: String,
+    },
+    /// A tool call made by the model, emitted once its arguments have finished
+    /// streaming.
+    ToolCall {
+        tool_call: LanguageModelToolCall,
+        /// Whether the call's arguments are valid JSON. They can only be incomplete
+        /// if the stream ended early and the provider is configured to emit
+        /// incomplete tool calls rather than fail.
+        is_complete: bool,
+    },
+    /// A running estimate of what the completion has cost so far, see
+    /// [`with_cost_estimates`].
+    Cost(CostEstimate),
+    /// The log probabilities of the tokens of the preceding [`CompletionEvent::Text`],
+    /// streamed only when the request asked for [`LanguageModelRequest::logprobs`].
+    Logprobs(Vec<TokenLogprob>),
+    /// Emitted exactly once per stream, after every other event.
+    StreamEnd(StreamEnd),
 }
 
-impl futures::Stream for CompletionResponse {
-    type Item = Result<String>;
+/// How likely the model considered a token it generated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    /// The most likely tokens at the same position, with their log probabilities,
+    /// if the request asked for [`LanguageModelRequest::top_logprobs`].
+    pub top_logprobs: Vec<(String, f32)>,
+}
+
+/// Metadata describing how a completion stream ended.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamEnd {
+    /// Why the model stopped generating, as reported by the provider (e.g. `stop` or `length`).
+    pub finish_reason: Option<String>,
+    pub usage: Option<TokenUsage>,
+    /// Identifies the backend configuration that served the completion.
+    pub system_fingerprint: Option<String>,
+    pub path: CompletionPath,
+    /// Identifies the request that produced the completion, for correlating it
+    /// with logs elsewhere.
+    pub request_id: Option<String>,
+}
+
+impl StreamEnd {
+    /// Whether the model stopped because it reached the completion's token limit
+    /// rather than finishing its answer, so the output is cut off.
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("length")
+    }
+}
+
+/// Describes how a provider arrived at a completion's output. A provider that
+/// doesn't take one of these paths leaves the corresponding field at its default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompletionPath {
+    /// How many times a failed request was retried.
+    pub retries: u32,
+    /// The model that served the completion after the requested one failed.
+    pub fallback_model: Option<LanguageModel>,
+    /// Whether the completion was requested without streaming, after streaming it
+    /// failed.
+    pub non_streaming: bool,
+    /// How many times the completion was continued after failing mid-stream.
+    pub continuations: u32,
+    /// How many old messages were dropped from the request to fit it in the
+    /// model's context window, see [`TruncationStrategy`].
+    pub dropped_messages: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    /// The prompt tokens that were neither written to nor read from a cache.
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// The prompt tokens written to the cache, which providers charge extra for.
+    /// Only Anthropic reports these, for messages marked to be cached.
+    pub cache_creation_tokens: u32,
+    /// The prompt tokens read from the cache, which are charged at a discount.
+    pub cache_read_tokens: u32,
+}
+
+impl TokenUsage {
+    /// Every prompt token, whether or not it was cached.
+    pub fn total_prompt_tokens(&self) -> u32 {
+        self.prompt_tokens + self.cache_creation_tokens + self.cache_read_tokens
+    }
+}
+
+/// The outcome of [`CompletionProvider::ensure_authenticated`].
+pub enum Authentication {
+    Authenticated,
+    /// The user must provide credentials using the given view.
+    NeedsPrompt(AnyView),
+}
+
+/// The outcome of [`CompletionProvider::check_connection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// The provider's API responded, and didn't reject its credentials.
+    Ok,
+    /// The provider has no credentials, or the API rejected them.
+    Unauthorized,
+    /// The API couldn't be reached, didn't respond in time, or isn't served at the
+    /// configured URL.
+    Unreachable,
+}
+
+/// What a model supports, for deciding which features to offer with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    pub supports_tools: bool,
+    /// Whether images attached to messages are sent to the model.
+    pub supports_vision: bool,
+    pub supports_json_mode: bool,
+    /// The most tokens a single completion can have, if it's known.
+    pub max_output_tokens: Option<u32>,
+    /// The most tokens the prompt and completion can have between them.
+    pub context_window: usize,
+}
+
+impl ModelCapabilities {
+    /// The capabilities of a model that nothing is known about besides its context
+    /// window, which are assumed to be as few as possible.
+    pub fn unknown(context_window: usize) -> Self {
+        Self {
+            supports_tools: false,
+            supports_vision: false,
+            supports_json_mode: false,
+            max_output_tokens: None,
+            context_window,
+        }
+    }
+}
+
+/// What `model` supports. Only OpenAI's and Anthropic's built-in models, and those
+/// served by zed.dev, are known about; any other model reports
+/// [`ModelCapabilities::unknown`].
+pub fn model_capabilities(model: &LanguageModel) -> ModelCapabilities {
+    match model {
+        LanguageModel::OpenAi(model) => open_ai_model_capabilities(model),
+        LanguageModel::Anthropic(model) => anthropic_model_capabilities(model),
+        LanguageModel::Cloud(model) => cloud_model_capabilities(model),
+        model => ModelCapabilities::unknown(model.max_token_count()),
+    }
+}
+
+/// The price of a model's tokens, in dollars per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TokenPrice {
+    pub prompt: f64,
+    pub completion: f64,
+    /// The price of prompt tokens written to the cache.
+    pub cache_write: f64,
+    /// The price of prompt tokens read from the cache.
+    pub cache_read: f64,
+}
+
+impl TokenPrice {
+    /// The cost, in dollars, of the tokens a completion reported using.
+    pub fn cost(&self, usage: TokenUsage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt
+            + usage.completion_tokens as f64 * self.completion
+            + usage.cache_creation_tokens as f64 * self.cache_write
+            + usage.cache_read_tokens as f64 * self.cache_read)
+            / 1_000_000.
+    }
+}
+
+/// The cost of a completion so far, in dollars.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostEstimate {
+    pub cost: f64,
+    /// Whether the cost was computed from the usage reported by the provider, rather
+    /// than estimated from the text streamed so far.
+    pub is_final: bool,
+}
+
+/// What to do when a request is estimated to cost more than a threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostGuard {
+    /// The estimated cost, in dollars, above which the guard applies.
+    pub threshold: f64,
+    /// Whether to refuse such requests with [`CompletionError::CostLimitExceeded`],
+    /// rather than just logging a warning.
+    pub refuse: bool,
+}
+
+impl CostGuard {
+    pub fn check(&self, estimated_cost: f64) -> Result<(), CompletionError> {
+        if estimated_cost <= self.threshold {
+            Ok(())
+        } else if self.refuse {
+            Err(CompletionError::CostLimitExceeded {
+                estimated_cost,
+                threshold: self.threshold,
+            })
+        } else {
+            log::warn!(
+                "request is estimated to cost ${estimated_cost:.4}, above the threshold of ${:.4}",
+                self.threshold
+            );
+            Ok(())
+        }
+    }
+}
+
+pub struct CompletionResponse<T = String> {
+    inner: Option<Abortable<BoxStream<'static, Result<T>>>>,
+    abort_handle: AbortHandle,
+    _lock: Option<SemaphoreGuardArc>,
+}
+
+impl<T> CompletionResponse<T> {
+    fn new(inner: BoxStream<'static, Result<T>>, lock: SemaphoreGuardArc) -> Self {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        Self {
+            inner: Some(Abortable::new(inner, abort_registration)),
+            abort_handle,
+            _lock: Some(lock),
+        }
+    }
+
+    /// Returns a handle that aborts the completion, e.g. when the user cancels it.
+    /// Once aborted, the response ends the next time it's polled, which is when
+    /// its request is torn down and its slot freed. To tear it down right away,
+    /// drop the response instead.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort_handle.clone()
+    }
+}
+
+impl<T> futures::Stream for CompletionResponse<T> {
+    type Item = Result<T>;
 
     fn poll_next(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.inner).poll_next(cx)
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let poll = Pin::new(inner).poll_next(cx);
+        if self.abort_handle.is_aborted() {
+            // Drop the underlying stream, closing its connection, and free up its
+            // slot for other completions.
+            self.inner = None;
+            self._lock = None;
+            return Poll::Ready(None);
+        }
+        poll
     }
 }
 
@@ -43,7 +327,44 @@ pub trait LanguageModelCompletionProvider: Send + Sync {
     fn authenticate(&self, cx: &AppContext) -> Task<Result<()>>;
     fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView;
     fn reset_credentials(&self, cx: &AppContext) -> Task<Result<()>>;
+
+    /// Makes requests with `api_key` instead of the provider's stored credentials,
+    /// e.g. because the current workspace configures its own key. Providers that
+    /// don't authenticate with API keys ignore it.
+    fn set_api_key_override(&mut self, _api_key: Option<String>) {}
+
+    /// Lists the models the provider can serve again, e.g. after its account gains
+    /// access to new ones, and offers them. Resolves to how many were found.
+    fn reload_models(&self, _cx: &AppContext) -> Task<Result<usize>> {
+        Task::ready(Err(anyhow!("this provider's models can't be reloaded")))
+    }
+
+    /// Checks whether the provider's API can be reached, and accepts its
+    /// credentials, with a request that doesn't spend any tokens. Unlike
+    /// [`Self::is_authenticated`], this finds out whether the credentials work.
+    fn check_connection(&self, _cx: &AppContext) -> Task<Result<ConnectionStatus>> {
+        Task::ready(Err(anyhow!("this provider's connection can't be checked")))
+    }
+
+    /// Classifies `text` with the provider's moderation API, reporting a score for
+    /// each category so that callers can apply their own thresholds.
+    fn moderate(&self, _text: String, _cx: &AppContext) -> Task<Result<ModerationResult>> {
+        Task::ready(Err(anyhow!("this provider can't moderate input")))
+    }
+
+    /// When enabled, the latest user message of each completion request is
+    /// moderated first, and requests whose message is flagged fail with
+    /// [`CompletionError::Blocked`] without being sent. Providers that can't
+    /// moderate input ignore it.
+    fn set_moderate_input(&mut self, _moderate_input: bool) {}
+
     fn model(&self) -> LanguageModel;
+
+    /// What the current model supports.
+    fn capabilities(&self) -> ModelCapabilities {
+        model_capabilities(&self.model())
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -54,15 +375,129 @@ pub trait LanguageModelCompletionProvider: Send + Sync {
         request: LanguageModelRequest,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>>;
 
+    /// Like [`Self::stream_completion`], but also yields the metadata that accompanies
+    /// the completion's text. Providers that don't report any metadata can rely on
+    /// the default implementation, which ends every stream with an empty [`StreamEnd`].
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_completion(request)
+            .map_ok(|stream| {
+                stream
+                    .map_ok(CompletionEvent::Text)
+                    .chain(stream::once(async {
+                        Ok(CompletionEvent::StreamEnd(StreamEnd::default()))
+                    }))
+                    .boxed()
+            })
+            .boxed()
+    }
+
+    /// Like [`Self::stream_completion`], but made with `api_key` rather than the
+    /// provider's credentials, if one is given. Unlike
+    /// [`Self::set_api_key_override`], this doesn't change the key of any other
+    /// request. Providers that don't authenticate with API keys ignore it.
+    fn stream_completion_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        _api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.stream_completion(request)
+    }
+
+    /// Like [`Self::stream_completion_events`], but made with `api_key`, as with
+    /// [`Self::stream_completion_with_api_key`].
+    fn stream_completion_events_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        _api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_completion_events(request)
+    }
+
     fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
+/// How many completions can be requested at once, unless configured otherwise.
 const MAX_CONCURRENT_COMPLETION_REQUESTS: usize = 4;
 
+/// Strips the whitespace and surrounding quotes that keys set in environment
+/// variables sometimes carry, e.g. from `OPENAI_API_KEY="sk-..."`. Keys read from
+/// the keychain are stored as entered and don't need this.
+fn normalize_api_key(api_key: &str) -> &str {
+    let api_key = api_key.trim();
+    for quote in ['"', '\'', '`'] {
+        if let Some(unquoted) = api_key
+            .strip_prefix(quote)
+            .and_then(|api_key| api_key.strip_suffix(quote))
+        {
+            return unquoted.trim();
+        }
+    }
+    api_key
+}
+
+/// Refuses requests that use features the model doesn't support, which providers
+/// would otherwise drop without the model knowing.
+fn check_capabilities(
+    request: &LanguageModelRequest,
+    capabilities: ModelCapabilities,
+) -> Result<(), CompletionError> {
+    if !request.tools.is_empty() && !capabilities.supports_tools {
+        return Err(CompletionError::UnsupportedContent(
+            "the model can't call tools".into(),
+        ));
+    }
+    let has_images = request
+        .messages
+        .iter()
+        .any(|message| !message.images.is_empty());
+    if has_images && !capabilities.supports_vision {
+        return Err(CompletionError::UnsupportedContent(
+            "the model can't read images".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Ends `stream` with [`CompletionError::StreamStalled`] if `timeout` passes without
+/// it yielding anything. The timer restarts with each item.
+fn with_stall_timeout<T: 'static + Send>(
+    stream: BoxStream<'static, Result<T>>,
+    timeout: Duration,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<T>> {
+    stream::unfold(Some(stream), move |stream| {
+        let executor = executor.clone();
+        async move {
+            let mut stream = stream?;
+            let next = match future::select(stream.next(), executor.timer(timeout)).await {
+                Either::Left((next, _)) => Some(next),
+                Either::Right(_) => None,
+            };
+            match next {
+                Some(Some(item)) => Some((item, Some(stream))),
+                Some(None) => None,
+                None => Some((Err(CompletionError::StreamStalled { timeout }.into()), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
 pub struct CompletionProvider {
     provider: Arc<RwLock<dyn LanguageModelCompletionProvider>>,
     client: Option<Arc<Client>>,
     request_limiter: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    stall_timeout: Option<Duration>,
+    api_key_overrides: HashMap<AnyWindowHandle, String>,
+    /// The override last applied to the provider, which is re-applied when it's
+    /// replaced.
+    api_key_override: Option<String>,
+    moderate_input: bool,
+    usage_tracker: UsageTracker,
 }
 
 impl CompletionProvider {
@@ -74,9 +509,80 @@ impl CompletionProvider {
             provider,
             client,
             request_limiter: Arc::new(Semaphore::new(MAX_CONCURRENT_COMPLETION_REQUESTS)),
+            max_concurrent_requests: MAX_CONCURRENT_COMPLETION_REQUESTS,
+            stall_timeout: None,
+            api_key_overrides: HashMap::default(),
+            api_key_override: None,
+            moderate_input: false,
+            usage_tracker: UsageTracker::new(),
+        }
+    }
+
+    /// Limits how many completions can stream at once. Any more are queued until
+    /// one finishes, and leave the queue if they're dropped while waiting.
+    /// Completions already streaming or queued keep counting against the previous
+    /// limit, so it can briefly be exceeded after being lowered.
+    pub fn set_max_concurrent_requests(&mut self, max_concurrent_requests: usize) {
+        let max_concurrent_requests = max_concurrent_requests.max(1);
+        if max_concurrent_requests != self.max_concurrent_requests {
+            self.max_concurrent_requests = max_concurrent_requests;
+            self.request_limiter = Arc::new(Semaphore::new(max_concurrent_requests));
         }
     }
 
+    /// Fails completions with [`CompletionError::StreamStalled`] when `stall_timeout`
+    /// passes between chunks, so a stream that hangs mid-completion can be retried
+    /// rather than waited on forever. This is separate from the providers' connection
+    /// timeouts, and only starts once the response has started streaming.
+    pub fn set_stall_timeout(&mut self, stall_timeout: Option<Duration>) {
+        self.stall_timeout = stall_timeout;
+    }
+
+    /// Uses `api_key` in place of the stored credentials for completions requested
+    /// while `window` is active, so that each project can use its own key. The
+    /// override applies to the current provider and any it's replaced with, until
+    /// it's cleared or the credentials are reset.
+    pub fn set_api_key_override(
+        &mut self,
+        window: AnyWindowHandle,
+        api_key: Option<String>,
+        cx: &AppContext,
+    ) {
+        match api_key {
+            Some(api_key) => self.api_key_overrides.insert(window, api_key),
+            None => self.api_key_overrides.remove(&window),
+        };
+        self.apply_api_key_override(self.api_key_override(cx).map(ToOwned::to_owned));
+    }
+
+    /// The API key used in place of the stored credentials for completions
+    /// requested from the active window, if any.
+    pub fn api_key_override(&self, cx: &AppContext) -> Option<&str> {
+        cx.active_window()
+            .and_then(|window| self.api_key_overrides.get(&window))
+            .map(String::as_str)
+    }
+
+    fn apply_api_key_override(&mut self, api_key: Option<String>) {
+        self.provider.write().set_api_key_override(api_key.clone());
+        self.api_key_override = api_key;
+    }
+
+    /// Moderates the input of the current provider's completions, and those of any
+    /// provider it's replaced with. See
+    /// [`LanguageModelCompletionProvider::set_moderate_input`].
+    pub fn set_moderate_input(&mut self, moderate_input: bool) {
+        self.provider.write().set_moderate_input(moderate_input);
+        self.moderate_input = moderate_input;
+    }
+
+    /// The usage of the completions streamed this session, which is recorded as
+    /// each of them finishes. Only [`Self::stream_completion_events`] is tracked,
+    /// since text-only streams don't report how they ended.
+    pub fn usage_tracker(&self) -> &UsageTracker {
+        &self.usage_tracker
+    }
+
     pub fn available_models(&self) -> Vec<LanguageModel> {
         self.provider.read().available_models()
     }
@@ -97,14 +603,60 @@ impl CompletionProvider {
         self.provider.read().authentication_prompt(cx)
     }
 
-    pub fn reset_credentials(&self, cx: &AppContext) -> Task<Result<()>> {
+    /// Forgets the current provider's credentials, along with any API key
+    /// overrides.
+    pub fn reset_credentials(&mut self, cx: &AppContext) -> Task<Result<()>> {
+        self.api_key_overrides.clear();
+        self.apply_api_key_override(None);
         self.provider.read().reset_credentials(cx)
     }
 
+    pub fn reload_models(&self, cx: &AppContext) -> Task<Result<usize>> {
+        self.provider.read().reload_models(cx)
+    }
+
+    pub fn check_connection(&self, cx: &AppContext) -> Task<Result<ConnectionStatus>> {
+        self.provider.read().check_connection(cx)
+    }
+
+    pub fn moderate(&self, text: String, cx: &AppContext) -> Task<Result<ModerationResult>> {
+        self.provider.read().moderate(text, cx)
+    }
+
+    /// Authenticates with the current provider if necessary, e.g. by loading saved
+    /// credentials. If that isn't enough, resolves to the view prompting the user
+    /// for credentials, which callers should display before streaming completions.
+    pub fn ensure_authenticated(cx: &mut WindowContext) -> Task<Result<Authentication>> {
+        let provider = Self::global(cx);
+        if provider.is_authenticated() {
+            return Task::ready(Ok(Authentication::Authenticated));
+        }
+
+        let authenticate = provider.authenticate(cx);
+        cx.spawn(|mut cx| async move {
+            if let Err(error) = authenticate.await {
+                log::info!("failed to authenticate with saved credentials: {error:?}");
+            }
+            cx.update(|cx| {
+                cx.update_global::<Self, _>(|provider, cx| {
+                    if provider.is_authenticated() {
+                        Authentication::Authenticated
+                    } else {
+                        Authentication::NeedsPrompt(provider.authentication_prompt(cx))
+                    }
+                })
+            })
+        })
+    }
+
     pub fn model(&self) -> LanguageModel {
         self.provider.read().model()
     }
 
+    pub fn capabilities(&self) -> ModelCapabilities {
+        self.provider.read().capabilities()
+    }
+
     pub fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -118,19 +670,88 @@ impl CompletionProvider {
         request: LanguageModelRequest,
         cx: &AppContext,
     ) -> Task<Result<CompletionResponse>> {
+        if let Err(error) = check_capabilities(&request, self.capabilities()) {
+            return Task::ready(Err(error.into()));
+        }
         let rate_limiter = self.request_limiter.clone();
         let provider = self.provider.clone();
+        let api_key_override = self.api_key_override(cx).map(ToOwned::to_owned);
+        let stall_timeout = self.stall_timeout;
+        let executor = cx.background_executor().clone();
         cx.foreground_executor().spawn(async move {
             let lock = rate_limiter.acquire_arc().await;
-            let response = provider.read().stream_completion(request);
-            let response = response.await?;
-            Ok(CompletionResponse {
-                inner: response,
-                _lock: lock,
-            })
+            // The key is passed with the request rather than set on the provider,
+            // which other windows share.
+            let response = provider
+                .read()
+                .stream_completion_with_api_key(request, api_key_override);
+            let mut response = response.await?;
+            if let Some(stall_timeout) = stall_timeout {
+                response = with_stall_timeout(response, stall_timeout, executor);
+            }
+            Ok(CompletionResponse::new(response, lock))
         })
     }
 
+    pub fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> Task<Result<CompletionResponse<CompletionEvent>>> {
+        if let Err(error) = check_capabilities(&request, self.capabilities()) {
+            return Task::ready(Err(error.into()));
+        }
+        let rate_limiter = self.request_limiter.clone();
+        let provider = self.provider.clone();
+        let api_key_override = self.api_key_override(cx).map(ToOwned::to_owned);
+        let stall_timeout = self.stall_timeout;
+        let executor = cx.background_executor().clone();
+        let usage_tracker = self.usage_tracker.clone();
+        let model = request.model.clone();
+        cx.foreground_executor().spawn(async move {
+            let lock = rate_limiter.acquire_arc().await;
+            let response = provider
+                .read()
+                .stream_completion_events_with_api_key(request, api_key_override);
+            let mut response = response
+                .await?
+                .inspect_ok(move |event| {
+                    if let CompletionEvent::StreamEnd(stream_end) = event {
+                        let model = stream_end.path.fallback_model.as_ref().unwrap_or(&model);
+                        usage_tracker.record(model, stream_end.usage);
+                    }
+                })
+                .boxed();
+            if let Some(stall_timeout) = stall_timeout {
+                response = with_stall_timeout(response, stall_timeout, executor);
+            }
+            Ok(CompletionResponse::new(response, lock))
+        })
+    }
+
+    /// Starts a completion for each of `requests`, returning a stream per request in
+    /// the same order. The requests share the concurrency limit with all other
+    /// completions, and an error in one stream doesn't affect the others. Dropping a
+    /// stream cancels its request.
+    pub fn stream_batch(
+        &self,
+        requests: Vec<LanguageModelRequest>,
+        cx: &AppContext,
+    ) -> Vec<BoxStream<'static, Result<String>>> {
+        requests
+            .into_iter()
+            .map(|request| {
+                let response = self.stream_completion(request, cx);
+                stream::once(response)
+                    .flat_map(|response| match response {
+                        Ok(response) => response.boxed(),
+                        Err(error) => stream::once(async { Err(error) }).boxed(),
+                    })
+                    .boxed()
+            })
+            .collect()
+    }
+
     pub fn complete(&self, request: LanguageModelRequest, cx: &AppContext) -> Task<Result<String>> {
         let response = self.stream_completion(request, cx);
         cx.foreground_executor().spawn(async move {
@@ -150,6 +771,9 @@ impl CompletionProvider {
     ) {
         if let Some(client) = &self.client {
             self.provider = get_provider(Arc::clone(client));
+            let mut provider = self.provider.write();
+            provider.set_api_key_override(self.api_key_override.clone());
+            provider.set_moderate_input(self.moderate_input);
         } else {
             log::warn!("completion provider cannot be updated because its client was not set");
         }
@@ -178,16 +802,20 @@ impl CompletionProvider {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Duration};
 
-    use gpui::AppContext;
+    use gpui::{AppContext, EmptyView, TestAppContext};
     use parking_lot::RwLock;
     use settings::SettingsStore;
     use smol::stream::StreamExt;
 
     use crate::{
-        CompletionProvider, FakeCompletionProvider, LanguageModelRequest,
-        MAX_CONCURRENT_COMPLETION_REQUESTS,
+        normalize_api_key, AssistantMessageBuilder, Authentication, CompletionError,
+        CompletionEvent, CompletionProvider, CompletionResponse, FakeCompletionProvider,
+        LanguageModelRequest, ScriptedEvent, MAX_CONCURRENT_COMPLETION_REQUESTS,
+    };
+    use language_model::{
+        LanguageModelImage, LanguageModelRequestMessage, LanguageModelTool, Role,
     };
 
     #[gpui::test]
@@ -268,4 +896,408 @@ mod tests {
 
         assert_eq!(fake_provider.completion_count(), 0);
     }
+
+    #[gpui::test]
+    fn test_max_concurrent_requests(cx: &mut AppContext) {
+        SettingsStore::test(cx);
+        let fake_provider = FakeCompletionProvider::setup_test(cx);
+        let mut provider =
+            CompletionProvider::new(Arc::new(RwLock::new(fake_provider.clone())), None);
+        provider.set_max_concurrent_requests(1);
+
+        let request = |i: usize| LanguageModelRequest {
+            temperature: i as f32 / 10.0,
+            ..Default::default()
+        };
+        let first = provider.stream_completion(request(0), cx);
+        let cancelled = provider.stream_completion(request(1), cx);
+        let queued = provider.stream_completion(request(2), cx);
+        cx.background_executor().run_until_parked();
+        assert_eq!(fake_provider.completion_count(), 1);
+
+        // A request that's dropped while queued gives up its place in the queue.
+        drop(cancelled);
+        let first = cx.background_executor().block(first).unwrap();
+        fake_provider.finish_completion(&request(0));
+        drop(first);
+        cx.background_executor().run_until_parked();
+        assert_eq!(
+            fake_provider
+                .pending_completions()
+                .into_iter()
+                .map(|request| request.temperature)
+                .collect::<Vec<_>>(),
+            vec![request(2).temperature]
+        );
+        assert!(cx.background_executor().block(queued).is_ok());
+    }
+
+    #[gpui::test]
+    fn test_abort_handle(cx: &mut AppContext) {
+        SettingsStore::test(cx);
+        let fake_provider = FakeCompletionProvider::setup_test(cx);
+        let provider = CompletionProvider::new(Arc::new(RwLock::new(fake_provider.clone())), None);
+
+        let request = |i: usize| LanguageModelRequest {
+            temperature: i as f32 / 10.0,
+            ..Default::default()
+        };
+        let mut responses = (0..MAX_CONCURRENT_COMPLETION_REQUESTS)
+            .map(|i| provider.stream_completion(request(i), cx))
+            .collect::<Vec<_>>();
+        let queued = provider.stream_completion(request(MAX_CONCURRENT_COMPLETION_REQUESTS), cx);
+        cx.background_executor().run_until_parked();
+        assert_eq!(
+            fake_provider.completion_count(),
+            MAX_CONCURRENT_COMPLETION_REQUESTS
+        );
+
+        let mut response = cx.background_executor().block(responses.remove(0)).unwrap();
+        fake_provider.send_completion_chunk(&request(0), "Hello".into());
+        assert_eq!(
+            cx.background_executor()
+                .block(response.next())
+                .unwrap()
+                .unwrap(),
+            "Hello"
+        );
+
+        // Once polled, an aborted response ends and lets the queued request start,
+        // even while it's still held.
+        response.abort_handle().abort();
+        assert!(cx.background_executor().block(response.next()).is_none());
+        cx.background_executor().run_until_parked();
+        assert_eq!(
+            fake_provider.completion_count(),
+            MAX_CONCURRENT_COMPLETION_REQUESTS + 1
+        );
+        assert!(cx.background_executor().block(queued).is_ok());
+    }
+
+    #[gpui::test]
+    fn test_stall_timeout(cx: &mut AppContext) {
+        SettingsStore::test(cx);
+        let fake_provider = FakeCompletionProvider::setup_test(cx);
+        let mut provider =
+            CompletionProvider::new(Arc::new(RwLock::new(fake_provider.clone())), None);
+        provider.set_stall_timeout(Some(Duration::from_secs(30)));
+
+        let request = LanguageModelRequest::default();
+        let response = provider.stream_completion(request.clone(), cx);
+        let mut response = cx.background_executor().block(response).unwrap();
+
+        // Each chunk restarts the timer, so a completion can take longer than the
+        // timeout as long as its chunks keep coming.
+        let executor = cx.background_executor().clone();
+        let next_chunk = move |mut response: CompletionResponse| {
+            executor.spawn(async move { (response.next().await, response) })
+        };
+        for _ in 0..2 {
+            let next = next_chunk(response);
+            cx.background_executor().run_until_parked();
+            cx.background_executor()
+                .advance_clock(Duration::from_secs(20));
+            fake_provider.send_completion_chunk(&request, "Hello".into());
+            let (chunk, next_response) = cx.background_executor().block(next);
+            assert_eq!(chunk.unwrap().unwrap(), "Hello");
+            response = next_response;
+        }
+
+        let next = next_chunk(response);
+        cx.background_executor().run_until_parked();
+        cx.background_executor()
+            .advance_clock(Duration::from_secs(30));
+        let (chunk, mut response) = cx.background_executor().block(next);
+        let error = chunk.unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::StreamStalled { timeout }) if *timeout == Duration::from_secs(30)
+        ));
+        assert!(cx.background_executor().block(response.next()).is_none());
+    }
+
+    #[gpui::test]
+    fn test_usage_tracking(cx: &mut AppContext) {
+        SettingsStore::test(cx);
+        let fake_provider = FakeCompletionProvider::setup_test(cx);
+        let provider = CompletionProvider::new(Arc::new(RwLock::new(fake_provider.clone())), None);
+
+        let request = LanguageModelRequest::default();
+        let response = provider.stream_completion(request.clone(), cx);
+        cx.background_executor().run_until_parked();
+        fake_provider.send_completion_chunk(&request, "Hello".into());
+        fake_provider.finish_completion(&request);
+        let response = cx.background_executor().block(response).unwrap();
+        let chunks = cx.background_executor().block(response.collect::<Vec<_>>());
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(provider.usage_tracker().total().requests, 0);
+
+        let response = provider.stream_completion_events(request.clone(), cx);
+        cx.background_executor().run_until_parked();
+        fake_provider.send_completion_chunk(&request, "Hello".into());
+        fake_provider.finish_completion(&request);
+        let response = cx.background_executor().block(response).unwrap();
+        let events = cx.background_executor().block(response.collect::<Vec<_>>());
+        assert_eq!(events.len(), 2);
+
+        // Completions are counted once they finish, even if they don't report their
+        // token usage.
+        let usage = provider.usage_tracker().usage_by_model();
+        assert_eq!(usage[request.model.id()].requests, 1);
+        assert_eq!(usage[request.model.id()].total_tokens(), 0);
+        provider.usage_tracker().reset();
+        assert_eq!(provider.usage_tracker().total().requests, 0);
+    }
+
+    #[gpui::test]
+    fn test_unsupported_features(cx: &mut AppContext) {
+        SettingsStore::test(cx);
+        let fake_provider = FakeCompletionProvider::setup_test(cx);
+        let provider = CompletionProvider::new(Arc::new(RwLock::new(fake_provider.clone())), None);
+
+        // The fake serves zed.dev's default model, which can call tools but isn't
+        // sent images.
+        let request = LanguageModelRequest {
+            tools: vec![LanguageModelTool {
+                name: "search".into(),
+                description: None,
+                parameters: None,
+            }],
+            ..Default::default()
+        };
+        let response = provider.stream_completion_events(request.clone(), cx);
+        cx.background_executor().run_until_parked();
+        assert_eq!(fake_provider.completion_count(), 1);
+        fake_provider.finish_completion(&request);
+        cx.background_executor().block(response).unwrap();
+
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "What's this?".into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: vec![LanguageModelImage::from_base64("image/png", "iVBORw0KGgo=")],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let error = cx
+            .background_executor()
+            .block(provider.stream_completion(request, cx))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::UnsupportedContent(_))
+        ));
+        assert_eq!(fake_provider.completion_count(), 0);
+    }
+
+    #[test]
+    fn test_normalize_api_key() {
+        assert_eq!(normalize_api_key("sk-abc123"), "sk-abc123");
+        assert_eq!(normalize_api_key("  sk-abc123\n"), "sk-abc123");
+        assert_eq!(normalize_api_key("\"sk-abc123\""), "sk-abc123");
+        assert_eq!(normalize_api_key(" 'sk-abc123 ' "), "sk-abc123");
+        // Only matching quotes are stripped.
+        assert_eq!(normalize_api_key("\"sk-abc123'"), "\"sk-abc123'");
+        assert_eq!(normalize_api_key("\""), "\"");
+    }
+
+    #[gpui::test]
+    fn test_stream_batch(cx: &mut AppContext) {
+        SettingsStore::test(cx);
+        let fake_provider = FakeCompletionProvider::setup_test(cx);
+        let provider = CompletionProvider::new(Arc::new(RwLock::new(fake_provider.clone())), None);
+
+        let request = |i: usize| LanguageModelRequest {
+            temperature: i as f32 / 10.0,
+            ..Default::default()
+        };
+        let requests = (0..3).map(request).collect::<Vec<_>>();
+        let outputs = provider
+            .stream_batch((0..3).map(request).collect(), cx)
+            .into_iter()
+            .map(|stream| cx.background_executor().spawn(stream.collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        cx.background_executor().run_until_parked();
+        assert_eq!(fake_provider.completion_count(), 3);
+
+        for (i, request) in requests.iter().enumerate() {
+            fake_provider.send_completion_chunk(request, format!("response {i}"));
+        }
+        fake_provider.send_completion_error(&requests[1], anyhow::anyhow!("request failed"));
+        for request in &requests {
+            fake_provider.finish_completion(request);
+        }
+        cx.background_executor().run_until_parked();
+
+        let outputs = outputs
+            .into_iter()
+            .map(|output| cx.background_executor().block(output))
+            .collect::<Vec<_>>();
+        for (i, output) in outputs.iter().enumerate() {
+            assert_eq!(output[0].as_ref().unwrap(), &format!("response {i}"));
+        }
+        assert_eq!(outputs[0].len(), 1);
+        assert_eq!(outputs[1].len(), 2);
+        assert!(outputs[1][1].is_err());
+        assert_eq!(outputs[2].len(), 1);
+    }
+
+    #[gpui::test]
+    async fn test_ensure_authenticated(cx: &mut TestAppContext) {
+        cx.update(SettingsStore::test);
+        let fake_provider = cx.update(FakeCompletionProvider::setup_test);
+        fake_provider.set_authenticated(false);
+
+        let cx = cx.add_empty_window();
+        let authentication = cx
+            .update(CompletionProvider::ensure_authenticated)
+            .await
+            .unwrap();
+        assert!(matches!(authentication, Authentication::NeedsPrompt(_)));
+
+        // e.g. the user entered their credentials into the prompt.
+        fake_provider.set_authenticated(true);
+        let authentication = cx
+            .update(CompletionProvider::ensure_authenticated)
+            .await
+            .unwrap();
+        assert!(matches!(authentication, Authentication::Authenticated));
+    }
+
+    #[gpui::test]
+    async fn test_api_key_override_per_window(cx: &mut TestAppContext) {
+        cx.update(SettingsStore::test);
+        let fake_provider = cx.update(FakeCompletionProvider::setup_test);
+        let window_a = cx.add_window(|_| EmptyView);
+        let window_b = cx.add_window(|_| EmptyView);
+        window_a.update(cx, |_, cx| cx.activate_window()).unwrap();
+        cx.update(|cx| {
+            cx.update_global::<CompletionProvider, _>(|provider, cx| {
+                provider.set_api_key_override(window_a.into(), Some("sk-a".into()), cx);
+                provider.set_api_key_override(window_b.into(), Some("sk-b".into()), cx);
+            })
+        });
+        assert_eq!(fake_provider.api_key_override().as_deref(), Some("sk-a"));
+
+        // Each completion is made with the key of the window it was requested from,
+        // without changing the key the provider is left with.
+        let mut streams = Vec::new();
+        for window in [window_b, window_a, window_b] {
+            window.update(cx, |_, cx| cx.activate_window()).unwrap();
+            let response = cx.update(|cx| {
+                CompletionProvider::global(cx)
+                    .stream_completion(LanguageModelRequest::default(), cx)
+            });
+            streams.push(response.await.unwrap());
+        }
+        assert_eq!(
+            fake_provider.request_api_keys(),
+            vec![
+                Some("sk-b".to_string()),
+                Some("sk-a".to_string()),
+                Some("sk-b".to_string())
+            ]
+        );
+        assert_eq!(fake_provider.api_key_override().as_deref(), Some("sk-a"));
+    }
+
+    #[gpui::test]
+    async fn test_scripted_tool_calls(cx: &mut TestAppContext) {
+        cx.update(SettingsStore::test);
+        let fake_provider = cx.update(FakeCompletionProvider::setup_test);
+
+        // A minimal agent loop, which runs tools until the model replies without calling any.
+        let agent = cx.spawn(|cx| async move {
+            let mut request = LanguageModelRequest {
+                messages: vec![LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "What's the weather in Paris?".into(),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: false,
+                }],
+                ..Default::default()
+            };
+            loop {
+                let mut events = cx
+                    .update(|cx| {
+                        CompletionProvider::global(cx).stream_completion_events(request.clone(), cx)
+                    })?
+                    .await?;
+                let mut message = AssistantMessageBuilder::new();
+                let mut tool_results = Vec::new();
+                while let Some(event) = events.next().await {
+                    match event? {
+                        CompletionEvent::Text(text) => message.push_text(&text),
+                        CompletionEvent::ToolCall { tool_call, .. } => {
+                            assert_eq!(tool_call.name, "get_weather");
+                            let arguments: serde_json::Value =
+                                serde_json::from_str(&tool_call.arguments)?;
+                            tool_results
+                                .push(format!("{}: sunny in {}", tool_call.id, arguments["city"]));
+                            message.push_tool_call(tool_call);
+                        }
+                        CompletionEvent::Reasoning(_)
+                        | CompletionEvent::ToolCallDelta { .. }
+                        | CompletionEvent::Cost(_)
+                        | CompletionEvent::Logprobs(_)
+                        | CompletionEvent::StreamEnd(_) => {}
+                    }
+                }
+                let message = message.build();
+                if tool_results.is_empty() {
+                    return anyhow::Ok(message.content);
+                }
+                request.messages.push(message);
+                request.messages.push(LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: tool_results.join("\n"),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: false,
+                });
+            }
+        });
+
+        cx.run_until_parked();
+        let first_request = fake_provider.pending_completions().pop().unwrap();
+        fake_provider.send_script(
+            &first_request,
+            [
+                ScriptedEvent::Text("Let me check. ".into()),
+                ScriptedEvent::ToolCall {
+                    name: "get_weather".into(),
+                    arguments: serde_json::json!({ "city": "Paris" }),
+                },
+            ],
+        );
+
+        cx.run_until_parked();
+        let second_request = fake_provider.pending_completions().pop().unwrap();
+        let assistant_message = &second_request.messages[1];
+        assert_eq!(assistant_message.content, "Let me check. ");
+        assert_eq!(assistant_message.tool_calls.len(), 1);
+        assert_eq!(assistant_message.tool_calls[0].id, "call_0");
+        assert_eq!(
+            assistant_message.tool_calls[0].arguments,
+            r#"{"city":"Paris"}"#
+        );
+        assert_eq!(
+            second_request.messages[2].content,
+            r#"call_0: sunny in "Paris""#
+        );
+        fake_provider.send_script(
+            &second_request,
+            [ScriptedEvent::Text("It's sunny in Paris.".into())],
+        );
+
+        assert_eq!(agent.await.unwrap(), "It's sunny in Paris.");
+        assert_eq!(fake_provider.completion_count(), 0);
+    }
 }
@@ -0,0 +1,192 @@
+use futures::channel::oneshot;
+use open_ai::RequestError;
+use std::{io, time::Duration};
+use thiserror::Error;
+
+/// Errors surfaced by completion providers that callers may want to handle
+/// specifically, rather than just displaying.
+///
+/// These are returned wrapped in an [`anyhow::Error`], so callers can recover
+/// them with [`anyhow::Error::downcast_ref`].
+#[derive(Clone, Debug, Error)]
+pub enum CompletionError {
+    #[error("the model returned an empty response")]
+    EmptyResponse,
+    #[error("the tool choice names {name:?}, but the request only declares {declared:?}")]
+    UnknownToolChoice {
+        name: String,
+        /// The names of the tools the request declares.
+        declared: Vec<String>,
+    },
+    #[error("the stream ended before the arguments of tool call {name:?} ({id}) were complete")]
+    IncompleteToolCall { id: String, name: String },
+    #[error("the session's budget of {budget} tokens has been spent")]
+    BudgetExceeded { budget: u64 },
+    #[error(
+        "the request's estimated cost of ${estimated_cost:.4} exceeds the limit of ${threshold:.4}"
+    )]
+    CostLimitExceeded { estimated_cost: f64, threshold: f64 },
+    #[error("the tool result for {tool_call_id:?} doesn't follow a tool call with that id")]
+    OrphanedToolResult { tool_call_id: String },
+    #[error("more than one tool call has the id {id:?}")]
+    DuplicateToolCallId { id: String },
+    #[error("a tool result doesn't say which tool call it's for")]
+    MissingToolCallId,
+    #[error("the arguments of tool call {name:?} ({id}) aren't valid JSON")]
+    InvalidToolCallArguments { id: String, name: String },
+    /// The request has content that the provider can't send, such as an image it
+    /// has no way to encode.
+    #[error("{0}")]
+    UnsupportedContent(String),
+    #[error(
+        "the prompt's {prompt_tokens} tokens plus the {max_tokens} requested for the completion \
+         exceed the model's context window of {context_window} tokens"
+    )]
+    ContextWindowExceeded {
+        prompt_tokens: usize,
+        max_tokens: u32,
+        context_window: usize,
+    },
+    #[error("the request has {count} stop sequences, but at most {max} are supported")]
+    TooManyStopSequences { count: usize, max: usize },
+    #[error("stop sequences can't be empty")]
+    EmptyStopSequence,
+    #[error("the input was flagged by moderation for: {}", categories.join(", "))]
+    Blocked { categories: Vec<String> },
+    #[error(
+        "JSON mode was requested, but none of the messages mention JSON, which OpenAI requires"
+    )]
+    JsonModeWithoutJsonPrompt,
+    #[error("the stream stalled, with no new chunks for {timeout:?}")]
+    StreamStalled { timeout: Duration },
+    /// The API key is missing or was rejected.
+    #[error("{message}")]
+    Authentication { message: String },
+    #[error("{message}")]
+    RateLimited {
+        /// How long the server asked for the request not to be retried.
+        retry_after: Option<Duration>,
+        message: String,
+    },
+    /// The server rejected the request, so sending it again won't help.
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// The server rejected the request as longer than the model's context window.
+    /// Unlike [`CompletionError::ContextWindowExceeded`], this is only known once
+    /// the request has been sent.
+    #[error("{message}")]
+    ContextLengthExceeded { message: String },
+    #[error("{message}")]
+    ServerError { status: u16, message: String },
+    /// The server couldn't be reached, or the connection to it broke.
+    #[error("{message}")]
+    Network { message: String },
+    #[error("{message}")]
+    Timeout { message: String },
+    #[error("the completion was cancelled")]
+    Cancelled,
+}
+
+impl CompletionError {
+    /// Classifies a request that the server responded to with an error status,
+    /// from the status and the error code in its body.
+    pub fn from_request_error(error: &RequestError) -> Self {
+        let message = error.to_string();
+        match (error.status, error.code.as_deref()) {
+            (_, Some("context_length_exceeded")) => {
+                CompletionError::ContextLengthExceeded { message }
+            }
+            (401 | 403, _) | (_, Some("invalid_api_key")) => {
+                CompletionError::Authentication { message }
+            }
+            (408, _) => CompletionError::Timeout { message },
+            (429, _) => CompletionError::RateLimited {
+                retry_after: error.retry_after,
+                message,
+            },
+            (500..=599, _) => CompletionError::ServerError {
+                status: error.status,
+                message,
+            },
+            _ => CompletionError::InvalidRequest(message),
+        }
+    }
+
+    /// Attaches the [`CompletionError`] describing why a request failed to `error`,
+    /// so that callers can tell failures apart with [`anyhow::Error::downcast_ref`].
+    /// The underlying error, such as a [`RequestError`], can still be downcast to,
+    /// and the error's message is unchanged. Errors that aren't recognized are
+    /// returned as they are.
+    pub fn classify(error: anyhow::Error) -> anyhow::Error {
+        if error.downcast_ref::<CompletionError>().is_some() {
+            return error;
+        }
+        match Self::recognize(&error) {
+            Some(classified) => error.context(classified),
+            None => error,
+        }
+    }
+
+    /// The [`CompletionError`] describing why a request failed, whether or not it
+    /// has been attached with [`CompletionError::classify`]. Errors that aren't
+    /// recognized have none.
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        match error.downcast_ref::<CompletionError>() {
+            Some(completion_error) => Some(completion_error.clone()),
+            None => Self::recognize(error),
+        }
+    }
+
+    fn recognize(error: &anyhow::Error) -> Option<Self> {
+        let message = error.to_string();
+        let classified = if let Some(request_error) = error.downcast_ref::<RequestError>() {
+            CompletionError::from_request_error(request_error)
+        } else if let Some(http_error) = error.downcast_ref::<http::Error>() {
+            if http_error.is_timeout() {
+                CompletionError::Timeout { message }
+            } else {
+                CompletionError::Network { message }
+            }
+        } else if let Some(io_error) = error.downcast_ref::<io::Error>() {
+            if io_error.kind() == io::ErrorKind::TimedOut {
+                CompletionError::Timeout { message }
+            } else {
+                CompletionError::Network { message }
+            }
+        } else if error.downcast_ref::<oneshot::Canceled>().is_some() {
+            CompletionError::Cancelled
+        } else {
+            return None;
+        };
+        Some(classified)
+    }
+
+    /// Whether the failure is likely transient, so the request may succeed if sent
+    /// again later. This decides both which requests are retried and which make a
+    /// [`crate::FallbackCompletionProvider`] move on to its next provider.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            CompletionError::RateLimited { .. }
+                | CompletionError::ServerError { .. }
+                | CompletionError::Network { .. }
+                | CompletionError::Timeout { .. }
+                | CompletionError::StreamStalled { .. }
+        )
+    }
+}
+
+/// Why the arguments of a finished tool call were rejected, see
+/// [`crate::parse_tool_arguments`].
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ToolArgumentError {
+    #[error("the arguments to {tool:?} aren't valid JSON: {message}")]
+    InvalidJson { tool: String, message: String },
+    #[error("the arguments to {tool:?} don't match its schema at {path}: {message}")]
+    SchemaViolation {
+        tool: String,
+        /// A JSON pointer to the argument that doesn't match.
+        path: String,
+        message: String,
+    },
+}
@@ -1,15 +1,116 @@
 use anyhow::Result;
 use collections::HashMap;
-use futures::{channel::mpsc, future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
-use gpui::{AnyView, AppContext, Task};
-use std::sync::Arc;
+use futures::{
+    channel::mpsc,
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use gpui::{AnyView, AppContext, BackgroundExecutor, EmptyView, Task, VisualContext};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
 use ui::WindowContext;
 
-use crate::{LanguageModel, LanguageModelCompletionProvider, LanguageModelRequest};
+use crate::{
+    CompletionEvent, LanguageModel, LanguageModelCompletionProvider, LanguageModelRequest,
+    LanguageModelToolCall, StreamEnd,
+};
 
-#[derive(Clone, Default)]
+/// A step in a scripted completion, see [`FakeCompletionProvider::send_script`].
+#[derive(Clone, Debug)]
+pub enum ScriptedEvent {
+    Text(String),
+    ToolCall {
+        name: String,
+        arguments: serde_json::Value,
+    },
+}
+
+impl From<&str> for ScriptedEvent {
+    fn from(text: &str) -> Self {
+        Self::Text(text.into())
+    }
+}
+
+/// A response queued with [`FakeCompletionProvider::push_response`], which answers
+/// a completion without the test sending each chunk itself. This suits tests of
+/// code that consumes whole completions, such as retries or cancellation.
+pub struct ScriptedResponse {
+    events: Vec<ScriptedEvent>,
+    chunk_delay: Option<(Duration, BackgroundExecutor)>,
+    start_error: Option<anyhow::Error>,
+    stream_error: Option<anyhow::Error>,
+}
+
+impl ScriptedResponse {
+    /// A response that streams `events` and then finishes.
+    pub fn new(events: impl IntoIterator<Item = impl Into<ScriptedEvent>>) -> Self {
+        Self {
+            events: events.into_iter().map(Into::into).collect(),
+            chunk_delay: None,
+            start_error: None,
+            stream_error: None,
+        }
+    }
+
+    /// A response that fails with `error` before it starts streaming.
+    pub fn error(error: anyhow::Error) -> Self {
+        Self {
+            start_error: Some(error),
+            ..Self::new(Vec::<ScriptedEvent>::new())
+        }
+    }
+
+    /// Waits `chunk_delay` on `executor` before streaming each event, so that in
+    /// tests the delays elapse with `advance_clock`.
+    pub fn with_chunk_delay(mut self, chunk_delay: Duration, executor: BackgroundExecutor) -> Self {
+        self.chunk_delay = Some((chunk_delay, executor));
+        self
+    }
+
+    /// Fails the stream with `error` after its events, instead of finishing it.
+    pub fn then_fail(mut self, error: anyhow::Error) -> Self {
+        self.stream_error = Some(error);
+        self
+    }
+}
+
+#[derive(Clone)]
 pub struct FakeCompletionProvider {
-    current_completion_txs: Arc<parking_lot::Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>,
+    current_completion_txs:
+        Arc<parking_lot::Mutex<HashMap<String, mpsc::UnboundedSender<Result<CompletionEvent>>>>>,
+    queued_responses: Arc<parking_lot::Mutex<VecDeque<ScriptedResponse>>>,
+    requests: Arc<parking_lot::Mutex<Vec<LanguageModelRequest>>>,
+    request_api_keys: Arc<parking_lot::Mutex<Vec<Option<String>>>>,
+    next_tool_call_id: Arc<AtomicUsize>,
+    is_authenticated: Arc<AtomicBool>,
+    next_completion_error: Arc<parking_lot::Mutex<Option<anyhow::Error>>>,
+    token_count: Arc<AtomicUsize>,
+    api_key_override: Arc<parking_lot::Mutex<Option<String>>>,
+    moderate_input: Arc<AtomicBool>,
+}
+
+impl Default for FakeCompletionProvider {
+    fn default() -> Self {
+        Self {
+            current_completion_txs: Default::default(),
+            queued_responses: Default::default(),
+            requests: Default::default(),
+            request_api_keys: Default::default(),
+            next_tool_call_id: Default::default(),
+            is_authenticated: Arc::new(AtomicBool::new(true)),
+            next_completion_error: Default::default(),
+            token_count: Default::default(),
+            api_key_override: Default::default(),
+            moderate_input: Default::default(),
+        }
+    }
 }
 
 impl FakeCompletionProvider {
@@ -23,6 +124,49 @@ impl FakeCompletionProvider {
         this
     }
 
+    pub fn set_authenticated(&self, is_authenticated: bool) {
+        self.is_authenticated.store(is_authenticated, SeqCst);
+    }
+
+    /// Makes the next completion fail with `error` before it starts streaming.
+    pub fn fail_next_completion(&self, error: anyhow::Error) {
+        *self.next_completion_error.lock() = Some(error);
+    }
+
+    /// Queues `response` to answer a completion after those already queued. Once
+    /// the queue is empty, completions are driven by the test again.
+    pub fn push_response(&self, response: ScriptedResponse) {
+        self.queued_responses.lock().push_back(response);
+    }
+
+    /// The token count reported for every request.
+    pub fn set_token_count(&self, token_count: usize) {
+        self.token_count.store(token_count, SeqCst);
+    }
+
+    /// The key last passed to
+    /// [`LanguageModelCompletionProvider::set_api_key_override`].
+    pub fn api_key_override(&self) -> Option<String> {
+        self.api_key_override.lock().clone()
+    }
+
+    /// Whether input moderation was last turned on with
+    /// [`LanguageModelCompletionProvider::set_moderate_input`].
+    pub fn moderates_input(&self) -> bool {
+        self.moderate_input.load(SeqCst)
+    }
+
+    /// The requests completions were started for, in order.
+    pub fn requests(&self) -> Vec<LanguageModelRequest> {
+        self.requests.lock().clone()
+    }
+
+    /// The key each completion in [`Self::requests`] was made with: the one passed
+    /// with the request, or else the override.
+    pub fn request_api_keys(&self) -> Vec<Option<String>> {
+        self.request_api_keys.lock().clone()
+    }
+
     pub fn pending_completions(&self) -> Vec<LanguageModelRequest> {
         self.current_completion_txs
             .lock()
@@ -36,12 +180,73 @@ impl FakeCompletionProvider {
     }
 
     pub fn send_completion_chunk(&self, request: &LanguageModelRequest, chunk: String) {
+        self.send_completion_event(request, CompletionEvent::Text(chunk));
+    }
+
+    /// Sends a complete tool call, in the same assembled form that live providers
+    /// emit once all of a call's deltas have arrived. Calls are given sequential ids.
+    pub fn send_completion_tool_call(
+        &self,
+        request: &LanguageModelRequest,
+        name: impl Into<String>,
+        arguments: serde_json::Value,
+    ) {
+        let event = self.scripted_event(ScriptedEvent::ToolCall {
+            name: name.into(),
+            arguments,
+        });
+        self.send_completion_event(request, event);
+    }
+
+    /// Replays `script` as the response to `request`, in order, then finishes it.
+    pub fn send_script(
+        &self,
+        request: &LanguageModelRequest,
+        script: impl IntoIterator<Item = ScriptedEvent>,
+    ) {
+        for event in script {
+            let event = self.scripted_event(event);
+            self.send_completion_event(request, event);
+        }
+        self.finish_completion(request);
+    }
+
+    /// The event `event` is streamed as. Tool calls are complete, as live providers
+    /// emit them once all of a call's deltas have arrived, and given sequential ids.
+    fn scripted_event(&self, event: ScriptedEvent) -> CompletionEvent {
+        match event {
+            ScriptedEvent::Text(text) => CompletionEvent::Text(text),
+            ScriptedEvent::ToolCall { name, arguments } => {
+                let id = self.next_tool_call_id.fetch_add(1, SeqCst);
+                CompletionEvent::ToolCall {
+                    tool_call: LanguageModelToolCall {
+                        id: format!("call_{id}"),
+                        name,
+                        arguments: arguments.to_string(),
+                    },
+                    is_complete: true,
+                }
+            }
+        }
+    }
+
+    fn send_completion_event(&self, request: &LanguageModelRequest, event: CompletionEvent) {
         let json = serde_json::to_string(request).unwrap();
         self.current_completion_txs
             .lock()
             .get(&json)
             .unwrap()
-            .unbounded_send(chunk)
+            .unbounded_send(Ok(event))
+            .unwrap();
+    }
+
+    pub fn send_completion_error(&self, request: &LanguageModelRequest, error: anyhow::Error) {
+        let json = serde_json::to_string(request).unwrap();
+        self.current_completion_txs
+            .lock()
+            .get(&json)
+            .unwrap()
+            .unbounded_send(Err(error))
             .unwrap();
     }
 
@@ -59,6 +264,71 @@ impl FakeCompletionProvider {
     pub fn finish_last_completion(&self) {
         self.finish_completion(self.pending_completions().last().unwrap());
     }
+
+    /// Starts a completion for `request`, answered by the next queued response if
+    /// there is one, or else by the events the test sends.
+    fn start_completion(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> Result<BoxStream<'static, Result<CompletionEvent>>> {
+        if let Some(error) = self.next_completion_error.lock().take() {
+            return Err(error);
+        }
+        self.requests.lock().push(request.clone());
+        self.request_api_keys
+            .lock()
+            .push(api_key.or_else(|| self.api_key_override()));
+        if let Some(response) = self.queued_responses.lock().pop_front() {
+            return self.respond(response);
+        }
+
+        let (tx, rx) = mpsc::unbounded();
+        self.current_completion_txs
+            .lock()
+            .insert(serde_json::to_string(&request).unwrap(), tx);
+        Ok(rx
+            .chain(stream::once(async {
+                Ok(CompletionEvent::StreamEnd(StreamEnd::default()))
+            }))
+            .boxed())
+    }
+
+    fn respond(
+        &self,
+        response: ScriptedResponse,
+    ) -> Result<BoxStream<'static, Result<CompletionEvent>>> {
+        if let Some(error) = response.start_error {
+            return Err(error);
+        }
+
+        let events = response
+            .events
+            .into_iter()
+            .map(|event| self.scripted_event(event))
+            .collect::<Vec<_>>();
+        let chunk_delay = response.chunk_delay;
+        let events = stream::iter(events).then(move |event| {
+            let chunk_delay = chunk_delay.clone();
+            async move {
+                if let Some((chunk_delay, executor)) = chunk_delay {
+                    executor.timer(chunk_delay).await;
+                }
+                Ok(event)
+            }
+        });
+        let stream_error = response.stream_error;
+        let end = stream::once(async move {
+            match stream_error {
+                Some(error) => Err(error),
+                None => Ok(CompletionEvent::StreamEnd(StreamEnd {
+                    finish_reason: Some("stop".into()),
+                    ..Default::default()
+                })),
+            }
+        });
+        Ok(events.chain(end).boxed())
+    }
 }
 
 impl LanguageModelCompletionProvider for FakeCompletionProvider {
@@ -71,21 +341,33 @@ impl LanguageModelCompletionProvider for FakeCompletionProvider {
     }
 
     fn is_authenticated(&self) -> bool {
-        true
+        self.is_authenticated.load(SeqCst)
     }
 
     fn authenticate(&self, _cx: &AppContext) -> Task<Result<()>> {
-        Task::ready(Ok(()))
+        if self.is_authenticated() {
+            Task::ready(Ok(()))
+        } else {
+            Task::ready(Err(anyhow::anyhow!("not authenticated")))
+        }
     }
 
-    fn authentication_prompt(&self, _cx: &mut WindowContext) -> AnyView {
-        unimplemented!()
+    fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
+        cx.new_view(|_| EmptyView).into()
     }
 
     fn reset_credentials(&self, _cx: &AppContext) -> Task<Result<()>> {
         Task::ready(Ok(()))
     }
 
+    fn set_api_key_override(&mut self, api_key: Option<String>) {
+        *self.api_key_override.lock() = api_key;
+    }
+
+    fn set_moderate_input(&mut self, moderate_input: bool) {
+        self.moderate_input.store(moderate_input, SeqCst);
+    }
+
     fn model(&self) -> LanguageModel {
         LanguageModel::default()
     }
@@ -95,21 +377,118 @@ impl LanguageModelCompletionProvider for FakeCompletionProvider {
         _request: LanguageModelRequest,
         _cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        futures::future::ready(Ok(0)).boxed()
+        futures::future::ready(Ok(self.token_count.load(SeqCst))).boxed()
     }
 
     fn stream_completion(
         &self,
-        _request: LanguageModelRequest,
+        request: LanguageModelRequest,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
-        let (tx, rx) = mpsc::unbounded();
-        self.current_completion_txs
-            .lock()
-            .insert(serde_json::to_string(&_request).unwrap(), tx);
-        async move { Ok(rx.map(Ok).boxed()) }.boxed()
+        self.stream_completion_with_api_key(request, None)
+    }
+
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_completion_events_with_api_key(request, None)
+    }
+
+    fn stream_completion_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        let chunks = self.start_completion(request, api_key).map(|events| {
+            events
+                .filter_map(|event| {
+                    future::ready(match event {
+                        Ok(CompletionEvent::Text(chunk)) => Some(Ok(chunk)),
+                        Ok(_) => None,
+                        Err(error) => Some(Err(error)),
+                    })
+                })
+                .boxed()
+        });
+        future::ready(chunks).boxed()
+    }
+
+    fn stream_completion_events_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        future::ready(self.start_completion(request, api_key)).boxed()
     }
 
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    async fn test_scripted_responses(cx: &mut TestAppContext) {
+        let provider = FakeCompletionProvider::default();
+        provider.push_response(
+            ScriptedResponse::new(["Hello", ", world"])
+                .with_chunk_delay(Duration::from_secs(1), cx.executor()),
+        );
+        provider.push_response(ScriptedResponse::error(anyhow!("rate limited")));
+        provider.push_response(ScriptedResponse::new(["Hi"]).then_fail(anyhow!("disconnected")));
+
+        let mut stream = provider
+            .stream_completion(LanguageModelRequest::default())
+            .await
+            .unwrap();
+        assert!(stream.next().now_or_never().is_none());
+        cx.executor().advance_clock(Duration::from_millis(500));
+        assert!(stream.next().now_or_never().is_none());
+        cx.executor().advance_clock(Duration::from_millis(500));
+        assert_eq!(stream.next().await.unwrap().unwrap(), "Hello");
+        assert!(stream.next().now_or_never().is_none());
+        cx.executor().advance_clock(Duration::from_secs(1));
+        assert_eq!(stream.next().await.unwrap().unwrap(), ", world");
+        assert!(stream.next().await.is_none());
+
+        let error = provider
+            .stream_completion(LanguageModelRequest::default())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "rate limited");
+
+        let events = provider
+            .stream_completion_events(LanguageModelRequest::default())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].as_ref().unwrap_err().to_string(), "disconnected");
+
+        // Once the queue is empty, the test drives completions again.
+        let request = LanguageModelRequest::default();
+        let stream = provider.stream_completion(request.clone()).await.unwrap();
+        assert_eq!(provider.completion_count(), 1);
+        provider.send_completion_chunk(&request, "Bye".into());
+        provider.finish_completion(&request);
+        assert_eq!(stream.collect::<Vec<_>>().await.len(), 1);
+        assert_eq!(provider.requests().len(), 4);
+    }
+
+    #[gpui::test]
+    fn test_token_count(cx: &mut AppContext) {
+        let provider = FakeCompletionProvider::default();
+        provider.set_token_count(42);
+        let count = provider
+            .count_tokens(LanguageModelRequest::default(), cx)
+            .now_or_never();
+        assert_eq!(count.unwrap().unwrap(), 42);
+    }
+}
@@ -0,0 +1,498 @@
+use crate::{
+    CompletionError, CompletionEvent, ConnectionStatus, LanguageModel,
+    LanguageModelCompletionProvider, LanguageModelRequest,
+};
+use anyhow::{anyhow, Result};
+use futures::{
+    future::{self, BoxFuture},
+    stream::BoxStream,
+    FutureExt, StreamExt, TryStreamExt,
+};
+use gpui::{AnyView, AppContext, Task, WindowContext};
+use open_ai::ModerationResult;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// How [`FallbackCompletionProvider`] treats an error starting a completion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The provider couldn't serve the request, e.g. because it isn't authenticated,
+    /// is rate limited or can't be reached, but another provider might.
+    Unavailable,
+    /// The request itself is at fault, so it would fail with any provider.
+    Fatal,
+}
+
+impl ErrorClass {
+    /// Classifies an error returned when starting a completion. Errors that aren't
+    /// known to be the request's fault, such as a missing API key or a refused
+    /// connection, are considered [`ErrorClass::Unavailable`].
+    pub fn of(error: &anyhow::Error) -> Self {
+        match CompletionError::of(error) {
+            Some(CompletionError::Authentication { .. }) => ErrorClass::Unavailable,
+            Some(error) if error.is_retryable() => ErrorClass::Unavailable,
+            Some(_) => ErrorClass::Fatal,
+            None => ErrorClass::Unavailable,
+        }
+    }
+}
+
+/// Wraps an ordered list of providers, starting each completion with the first one
+/// that can serve it. When a provider fails with an [`ErrorClass::Unavailable`]
+/// error, the completion moves on to the next; [`ErrorClass::Fatal`] errors are
+/// returned as is. Providers that aren't authenticated are skipped.
+///
+/// Only starting a completion falls back. Once a provider has started streaming,
+/// errors in its stream are passed on to the caller. When a provider other than
+/// the primary serves a completion, its model is reported in the stream's
+/// [`CompletionPath::fallback_model`](crate::CompletionPath::fallback_model).
+///
+/// Settings such as an API key override or input moderation are applied to every
+/// provider, so they hold whichever one serves a completion.
+pub struct FallbackCompletionProvider {
+    providers: Vec<Arc<RwLock<dyn LanguageModelCompletionProvider>>>,
+}
+
+impl FallbackCompletionProvider {
+    /// Creates a provider that falls back through `providers` in order. The first
+    /// one is the primary, whose model and token counts are used.
+    pub fn new(providers: Vec<Arc<RwLock<dyn LanguageModelCompletionProvider>>>) -> Self {
+        assert!(
+            !providers.is_empty(),
+            "a fallback provider needs at least one provider"
+        );
+        Self { providers }
+    }
+
+    fn primary(&self) -> &Arc<RwLock<dyn LanguageModelCompletionProvider>> {
+        &self.providers[0]
+    }
+
+    /// Starts `request` with each provider in turn, until one succeeds or fails
+    /// fatally. If all of them are unavailable, the last error is returned. `start`
+    /// is passed the model of the provider when it isn't the primary.
+    fn start_with_fallback<T, F>(
+        &self,
+        request: LanguageModelRequest,
+        start: F,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<T>>>>
+    where
+        T: Send + 'static,
+        F: Fn(
+                &dyn LanguageModelCompletionProvider,
+                LanguageModelRequest,
+                Option<LanguageModel>,
+            ) -> BoxFuture<'static, Result<BoxStream<'static, Result<T>>>>
+            + Send
+            + 'static,
+    {
+        let primary = self.primary().clone();
+        let mut providers = self
+            .providers
+            .iter()
+            .filter(|provider| provider.read().is_authenticated())
+            .cloned()
+            .collect::<Vec<_>>();
+        // If none are authenticated, try the primary anyway so that its error is
+        // surfaced.
+        if providers.is_empty() {
+            providers.push(self.primary().clone());
+        }
+
+        async move {
+            let mut last_error = None;
+            for provider in providers {
+                let started = {
+                    let provider_ref = provider.read();
+                    let fallback_model =
+                        (!Arc::ptr_eq(&provider, &primary)).then(|| provider_ref.model());
+                    start(&*provider_ref, request.clone(), fallback_model)
+                };
+                match started.await {
+                    Ok(stream) => return Ok(stream),
+                    Err(error) if ErrorClass::of(&error) == ErrorClass::Fatal => return Err(error),
+                    Err(error) => {
+                        log::warn!("completion provider unavailable, falling back: {error:?}");
+                        last_error = Some(error);
+                    }
+                }
+            }
+            Err(last_error.unwrap_or_else(|| anyhow!("no completion providers")))
+        }
+        .boxed()
+    }
+}
+
+impl LanguageModelCompletionProvider for FallbackCompletionProvider {
+    fn available_models(&self) -> Vec<LanguageModel> {
+        let mut models = Vec::new();
+        for model in self
+            .providers
+            .iter()
+            .flat_map(|provider| provider.read().available_models())
+        {
+            if !models.contains(&model) {
+                models.push(model);
+            }
+        }
+        models
+    }
+
+    fn settings_version(&self) -> usize {
+        self.providers
+            .iter()
+            .map(|provider| provider.read().settings_version())
+            .sum()
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.providers
+            .iter()
+            .any(|provider| provider.read().is_authenticated())
+    }
+
+    fn authenticate(&self, cx: &AppContext) -> Task<Result<()>> {
+        let tasks = self
+            .providers
+            .iter()
+            .map(|provider| provider.read().authenticate(cx))
+            .collect::<Vec<_>>();
+        cx.foreground_executor().spawn(async move {
+            // Succeed if any provider could authenticate, since it can serve
+            // completions on its own.
+            let mut first_error = None;
+            for result in future::join_all(tasks).await {
+                match result {
+                    Ok(()) => return Ok(()),
+                    Err(error) => {
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+            Err(first_error.unwrap_or_else(|| anyhow!("no completion providers")))
+        })
+    }
+
+    fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
+        self.primary().read().authentication_prompt(cx)
+    }
+
+    fn reset_credentials(&self, cx: &AppContext) -> Task<Result<()>> {
+        let tasks = self
+            .providers
+            .iter()
+            .map(|provider| provider.read().reset_credentials(cx))
+            .collect::<Vec<_>>();
+        cx.foreground_executor().spawn(async move {
+            future::join_all(tasks)
+                .await
+                .into_iter()
+                .collect::<Result<()>>()
+        })
+    }
+
+    fn set_api_key_override(&mut self, api_key: Option<String>) {
+        for provider in &self.providers {
+            provider.write().set_api_key_override(api_key.clone());
+        }
+    }
+
+    /// Resolves to how many models all the providers offer, if any of them could
+    /// reload their models.
+    fn reload_models(&self, cx: &AppContext) -> Task<Result<usize>> {
+        let tasks = self
+            .providers
+            .iter()
+            .map(|provider| provider.read().reload_models(cx))
+            .collect::<Vec<_>>();
+        cx.foreground_executor().spawn(async move {
+            let mut first_error = None;
+            let mut model_count = None;
+            for result in future::join_all(tasks).await {
+                match result {
+                    Ok(count) => *model_count.get_or_insert(0) += count,
+                    Err(error) => {
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+            model_count
+                .ok_or_else(|| first_error.unwrap_or_else(|| anyhow!("no completion providers")))
+        })
+    }
+
+    /// Resolves to [`ConnectionStatus::Ok`] if any provider can be reached, since it
+    /// can serve completions on its own, and otherwise to the primary's status.
+    fn check_connection(&self, cx: &AppContext) -> Task<Result<ConnectionStatus>> {
+        let tasks = self
+            .providers
+            .iter()
+            .map(|provider| provider.read().check_connection(cx))
+            .collect::<Vec<_>>();
+        cx.foreground_executor().spawn(async move {
+            let mut statuses = future::join_all(tasks).await.into_iter();
+            let primary = statuses
+                .next()
+                .unwrap_or_else(|| Err(anyhow!("no completion providers")));
+            if statuses.any(|status| matches!(status, Ok(ConnectionStatus::Ok))) {
+                Ok(ConnectionStatus::Ok)
+            } else {
+                primary
+            }
+        })
+    }
+
+    /// Moderates `text` with the first provider that can.
+    fn moderate(&self, text: String, cx: &AppContext) -> Task<Result<ModerationResult>> {
+        let tasks = self
+            .providers
+            .iter()
+            .map(|provider| provider.read().moderate(text.clone(), cx))
+            .collect::<Vec<_>>();
+        cx.foreground_executor().spawn(async move {
+            let mut first_error = None;
+            for task in tasks {
+                match task.await {
+                    Ok(result) => return Ok(result),
+                    Err(error) => {
+                        first_error.get_or_insert(error);
+                    }
+                }
+            }
+            Err(first_error.unwrap_or_else(|| anyhow!("no completion providers")))
+        })
+    }
+
+    fn set_moderate_input(&mut self, moderate_input: bool) {
+        for provider in &self.providers {
+            provider.write().set_moderate_input(moderate_input);
+        }
+    }
+
+    fn model(&self) -> LanguageModel {
+        self.primary().read().model()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        self.primary().read().count_tokens(request, cx)
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.stream_completion_with_api_key(request, None)
+    }
+
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_completion_events_with_api_key(request, None)
+    }
+
+    fn stream_completion_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.start_with_fallback(request, move |provider, request, _| {
+            provider.stream_completion_with_api_key(request, api_key.clone())
+        })
+    }
+
+    fn stream_completion_events_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.start_with_fallback(request, move |provider, request, fallback_model| {
+            let response = provider.stream_completion_events_with_api_key(request, api_key.clone());
+            async move {
+                let stream = response.await?;
+                let Some(fallback_model) = fallback_model else {
+                    return Ok(stream);
+                };
+                Ok(stream
+                    .map_ok(move |event| match event {
+                        CompletionEvent::StreamEnd(mut stream_end) => {
+                            stream_end.path.fallback_model = Some(fallback_model.clone());
+                            CompletionEvent::StreamEnd(stream_end)
+                        }
+                        event => event,
+                    })
+                    .boxed())
+            }
+            .boxed()
+        })
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompletionPath, FakeCompletionProvider};
+    use open_ai::RequestError;
+
+    fn fallback(
+        providers: &[&FakeCompletionProvider],
+    ) -> (FallbackCompletionProvider, LanguageModelRequest) {
+        let providers = providers
+            .iter()
+            .map(|&provider| {
+                Arc::new(RwLock::new(provider.clone()))
+                    as Arc<RwLock<dyn LanguageModelCompletionProvider>>
+            })
+            .collect();
+        (
+            FallbackCompletionProvider::new(providers),
+            LanguageModelRequest::default(),
+        )
+    }
+
+    #[test]
+    fn test_error_classes() {
+        let class = |error: anyhow::Error| ErrorClass::of(&error);
+        // Errors are classified the same whether or not a `CompletionError` has
+        // been attached to them.
+        for status in [401, 403, 408, 429, 500, 502, 503, 504] {
+            assert_eq!(
+                class(RequestError::new(status, "failed").into()),
+                ErrorClass::Unavailable,
+                "{status}"
+            );
+            assert_eq!(
+                class(CompletionError::classify(
+                    RequestError::new(status, "failed").into()
+                )),
+                ErrorClass::Unavailable,
+                "{status}"
+            );
+        }
+        for status in [400, 404, 422] {
+            assert_eq!(
+                class(RequestError::new(status, "failed").into()),
+                ErrorClass::Fatal,
+                "{status}"
+            );
+            assert_eq!(
+                class(CompletionError::classify(
+                    RequestError::new(status, "failed").into()
+                )),
+                ErrorClass::Fatal,
+                "{status}"
+            );
+        }
+        assert_eq!(
+            class(CompletionError::JsonModeWithoutJsonPrompt.into()),
+            ErrorClass::Fatal
+        );
+        assert_eq!(class(anyhow!("missing api key")), ErrorClass::Unavailable);
+        assert_eq!(
+            class(anyhow!("connection refused").context("failed to send request")),
+            ErrorClass::Unavailable
+        );
+    }
+
+    #[gpui::test]
+    async fn test_falls_back_when_unavailable() {
+        let primary = FakeCompletionProvider::default();
+        let secondary = FakeCompletionProvider::default();
+        let (provider, request) = fallback(&[&primary, &secondary]);
+
+        primary.fail_next_completion(RequestError::new(429, "rate limited").into());
+        let mut stream = provider.stream_completion(request.clone()).await.unwrap();
+        assert_eq!(primary.completion_count(), 0);
+        assert_eq!(secondary.completion_count(), 1);
+        secondary.send_completion_chunk(&request, "Hello".into());
+        secondary.finish_completion(&request);
+        assert_eq!(stream.next().await.unwrap().unwrap(), "Hello");
+        assert!(stream.next().await.is_none());
+
+        // The fallback's model is reported at the end of the stream.
+        primary.fail_next_completion(RequestError::new(503, "unavailable").into());
+        let stream = provider
+            .stream_completion_events(request.clone())
+            .await
+            .unwrap();
+        secondary.finish_completion(&request);
+        let events = stream.try_collect::<Vec<_>>().await.unwrap();
+        let Some(CompletionEvent::StreamEnd(stream_end)) = events.last() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        assert_eq!(
+            stream_end.path,
+            CompletionPath {
+                fallback_model: Some(secondary.model()),
+                ..Default::default()
+            }
+        );
+
+        // Completions served by the primary report no fallback.
+        let stream = provider
+            .stream_completion_events(request.clone())
+            .await
+            .unwrap();
+        primary.finish_completion(&request);
+        let events = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(events, vec![CompletionEvent::StreamEnd(Default::default())]);
+
+        // Unauthenticated providers are skipped.
+        primary.set_authenticated(false);
+        let _stream = provider.stream_completion(request.clone()).await.unwrap();
+        assert_eq!(primary.completion_count(), 0);
+        assert_eq!(secondary.completion_count(), 1);
+        assert!(provider.is_authenticated());
+    }
+
+    #[gpui::test]
+    async fn test_fatal_errors_dont_fall_back() {
+        let primary = FakeCompletionProvider::default();
+        let secondary = FakeCompletionProvider::default();
+        let (provider, request) = fallback(&[&primary, &secondary]);
+
+        primary.fail_next_completion(RequestError::new(400, "bad request").into());
+        let error = provider
+            .stream_completion_events(request.clone())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 400);
+        assert_eq!(secondary.completion_count(), 0);
+
+        // When every provider is unavailable, the last error is returned.
+        primary.fail_next_completion(anyhow!("connection refused"));
+        secondary.fail_next_completion(RequestError::new(503, "unavailable").into());
+        let error = provider.stream_completion(request).await.err().unwrap();
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 503);
+    }
+
+    #[test]
+    fn test_settings_reach_every_provider() {
+        let primary = FakeCompletionProvider::default();
+        let secondary = FakeCompletionProvider::default();
+        let (mut provider, _) = fallback(&[&primary, &secondary]);
+
+        provider.set_api_key_override(Some("project-key".into()));
+        provider.set_moderate_input(true);
+        for child in [&primary, &secondary] {
+            assert_eq!(child.api_key_override().as_deref(), Some("project-key"));
+            assert!(child.moderates_input());
+        }
+
+        provider.set_api_key_override(None);
+        provider.set_moderate_input(false);
+        for child in [&primary, &secondary] {
+            assert_eq!(child.api_key_override(), None);
+            assert!(!child.moderates_input());
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{count_message_tokens, Tokenizer};
+use language_model::{
+    LanguageModelRequest, LanguageModelRequestMessage, LanguageModelToolCall, Role,
+};
+
+/// Merges the deltas of a streamed assistant turn into a single message that can
+/// be sent back to the model as part of the conversation history.
+///
+/// This only affects the stored message; callers keep displaying the deltas they
+/// receive exactly as they were streamed.
+#[derive(Debug, Default)]
+pub struct AssistantMessageBuilder {
+    content: String,
+    tool_calls: Vec<LanguageModelToolCall>,
+    trim_trailing_whitespace: bool,
+}
+
+impl AssistantMessageBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trailing whitespace left at the end of a turn can make the model behave
+    /// oddly on the next one. When enabled, it is trimmed from the stored message.
+    pub fn trim_trailing_whitespace(mut self, trim_trailing_whitespace: bool) -> Self {
+        self.trim_trailing_whitespace = trim_trailing_whitespace;
+        self
+    }
+
+    pub fn push_text(&mut self, delta: &str) {
+        self.content.push_str(delta);
+    }
+
+    pub fn push_tool_call(&mut self, tool_call: LanguageModelToolCall) {
+        self.tool_calls.push(tool_call);
+    }
+
+    pub fn build(self) -> LanguageModelRequestMessage {
+        let mut content = self.content;
+        if self.trim_trailing_whitespace {
+            content.truncate(content.trim_end().len());
+        }
+        LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content,
+            tool_calls: self.tool_calls,
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
+        }
+    }
+}
+
+/// How old messages are dropped from a request that doesn't fit in the model's
+/// context window, see [`truncate_messages`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TruncationStrategy {
+    /// Drops the oldest messages first, keeping only the first system message.
+    DropOldest,
+    /// Keeps every system message, dropping the oldest of the other messages first.
+    KeepSystemAndRecent,
+}
+
+/// Drops old messages from `request` until its messages fit in `budget` tokens,
+/// returning how many were dropped.
+///
+/// The first system message and the most recent user turn, i.e. the last user
+/// message and everything after it, are never dropped, so the request may still
+/// not fit. Tool results are dropped along with the message that made their calls.
+pub fn truncate_messages(
+    request: &mut LanguageModelRequest,
+    tokenizer: &dyn Tokenizer,
+    budget: usize,
+    strategy: TruncationStrategy,
+) -> usize {
+    let messages = &request.messages;
+    let tokens = messages
+        .iter()
+        .map(|message| count_message_tokens(tokenizer, message))
+        .collect::<Vec<_>>();
+    let mut total = tokens.iter().sum::<usize>();
+    let first_system_message = messages
+        .iter()
+        .position(|message| message.role == Role::System);
+    let recent_turn_start = messages
+        .iter()
+        .rposition(|message| message.role == Role::User)
+        .unwrap_or(messages.len());
+
+    let mut keep = vec![true; messages.len()];
+    let mut dropped = 0;
+    let mut ix = 0;
+    while total > budget && ix < recent_turn_start {
+        let droppable = match (messages[ix].role, strategy) {
+            (Role::System, TruncationStrategy::DropOldest) => Some(ix) != first_system_message,
+            (Role::System, TruncationStrategy::KeepSystemAndRecent) => false,
+            _ => true,
+        };
+        if droppable {
+            let has_tool_calls = !messages[ix].tool_calls.is_empty();
+            loop {
+                keep[ix] = false;
+                total -= tokens[ix];
+                dropped += 1;
+                if !has_tool_calls
+                    || ix + 1 >= recent_turn_start
+                    || messages[ix + 1].role != Role::Tool
+                {
+                    break;
+                }
+                ix += 1;
+            }
+        }
+        ix += 1;
+    }
+
+    let mut keep = keep.into_iter();
+    request.messages.retain(|_| keep.next().unwrap());
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts each word as a token.
+    struct WordTokenizer;
+
+    impl Tokenizer for WordTokenizer {
+        fn count_tokens(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    fn message(role: Role, content: &str) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
+        }
+    }
+
+    #[test]
+    fn test_truncate_messages() {
+        let mut tool_call_message = message(Role::Assistant, "");
+        tool_call_message.tool_calls.push(LanguageModelToolCall {
+            id: "call_1".into(),
+            name: "weather".into(),
+            arguments: "{}".into(),
+        });
+        let history = vec![
+            message(Role::System, "be brief"),
+            message(Role::User, "what's the weather"),
+            tool_call_message,
+            message(Role::Tool, "sunny"),
+            message(Role::System, "the user is in Paris"),
+            message(Role::Assistant, "it is sunny"),
+            message(Role::User, "and tomorrow"),
+        ];
+        let truncate = |budget, strategy| {
+            let mut request = LanguageModelRequest {
+                messages: history.clone(),
+                ..Default::default()
+            };
+            let dropped = truncate_messages(&mut request, &WordTokenizer, budget, strategy);
+            let contents = request
+                .messages
+                .into_iter()
+                .map(|message| message.content)
+                .collect::<Vec<_>>();
+            (dropped, contents)
+        };
+
+        // Requests that fit are left alone.
+        assert_eq!(truncate(100, TruncationStrategy::DropOldest).0, 0);
+
+        // The tool result is dropped along with the call it answers.
+        assert_eq!(
+            truncate(12, TruncationStrategy::DropOldest),
+            (
+                3,
+                vec![
+                    "be brief".into(),
+                    "the user is in Paris".into(),
+                    "it is sunny".into(),
+                    "and tomorrow".into()
+                ]
+            )
+        );
+        assert_eq!(
+            truncate(0, TruncationStrategy::DropOldest),
+            (5, vec!["be brief".into(), "and tomorrow".into()])
+        );
+        assert_eq!(
+            truncate(0, TruncationStrategy::KeepSystemAndRecent),
+            (
+                4,
+                vec![
+                    "be brief".into(),
+                    "the user is in Paris".into(),
+                    "and tomorrow".into()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        let deltas = ["Here is", " the answer.", "\n\n", "  "];
+
+        let mut displayed = String::new();
+        let mut untrimmed = AssistantMessageBuilder::new();
+        let mut trimmed = AssistantMessageBuilder::new().trim_trailing_whitespace(true);
+        for delta in deltas {
+            displayed.push_str(delta);
+            untrimmed.push_text(delta);
+            trimmed.push_text(delta);
+        }
+
+        assert_eq!(displayed, "Here is the answer.\n\n  ");
+        assert_eq!(untrimmed.build().content, "Here is the answer.\n\n  ");
+        let message = trimmed.build();
+        assert_eq!(message.role, Role::Assistant);
+        assert_eq!(message.content, "Here is the answer.");
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{CompletionError, CompletionEvent, StreamEnd, TokenUsage};
+use anyhow::Result;
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use open_ai::RequestError;
+use std::{
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+/// Called with the [`CompletionMetrics`] of each completion once it has finished.
+pub type OnCompletionMetrics = Arc<dyn Fn(CompletionMetrics) + Send + Sync>;
+
+/// Measurements of a single completion, reported once it has ended, failed or been
+/// dropped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompletionMetrics {
+    pub request_id: String,
+    pub model: String,
+    pub started_at: Instant,
+    /// How long it took for the first non-empty chunk of text to arrive, if any did.
+    pub time_to_first_token: Option<Duration>,
+    /// How long the completion took, from the request to the end of its stream.
+    pub duration: Duration,
+    /// The tokens the provider reported using, if it reported any.
+    pub usage: Option<TokenUsage>,
+    pub finish_reason: Option<String>,
+    /// Why the completion failed, if it did.
+    pub error: Option<CompletionErrorKind>,
+}
+
+/// The broad reason a completion failed, for counting error rates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionErrorKind {
+    /// The server responded with the given HTTP status.
+    Http(u16),
+    /// The server rejected the request, but its HTTP status isn't known.
+    Rejected,
+    /// The request was refused before being sent, with a [`CompletionError`].
+    Invalid,
+    /// The stream stopped producing chunks, see [`CompletionError::StreamStalled`].
+    Stalled,
+    /// The stream ended without any output, or partway through a tool call.
+    Incomplete,
+    /// The stream was dropped before it ended, e.g. because the user cancelled it.
+    Cancelled,
+    /// Any other error, such as a connection failure.
+    Other,
+}
+
+impl CompletionErrorKind {
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(error) = error.downcast_ref::<RequestError>() {
+            return CompletionErrorKind::Http(error.status);
+        }
+        let Some(error) = error.downcast_ref::<CompletionError>() else {
+            return CompletionErrorKind::Other;
+        };
+        match error {
+            CompletionError::ServerError { status, .. } => CompletionErrorKind::Http(*status),
+            CompletionError::RateLimited { .. } => CompletionErrorKind::Http(429),
+            CompletionError::Authentication { .. }
+            | CompletionError::InvalidRequest(_)
+            | CompletionError::ContextLengthExceeded { .. } => CompletionErrorKind::Rejected,
+            CompletionError::UnknownToolChoice { .. }
+            | CompletionError::BudgetExceeded { .. }
+            | CompletionError::CostLimitExceeded { .. }
+            | CompletionError::OrphanedToolResult { .. }
+            | CompletionError::DuplicateToolCallId { .. }
+            | CompletionError::MissingToolCallId
+            | CompletionError::InvalidToolCallArguments { .. }
+            | CompletionError::UnsupportedContent(_)
+            | CompletionError::ContextWindowExceeded { .. }
+            | CompletionError::TooManyStopSequences { .. }
+            | CompletionError::EmptyStopSequence
+            | CompletionError::Blocked { .. }
+            | CompletionError::JsonModeWithoutJsonPrompt => CompletionErrorKind::Invalid,
+            CompletionError::StreamStalled { .. } => CompletionErrorKind::Stalled,
+            CompletionError::EmptyResponse | CompletionError::IncompleteToolCall { .. } => {
+                CompletionErrorKind::Incomplete
+            }
+            CompletionError::Cancelled => CompletionErrorKind::Cancelled,
+            CompletionError::Network { .. } | CompletionError::Timeout { .. } => {
+                CompletionErrorKind::Other
+            }
+        }
+    }
+}
+
+/// Accumulates a completion's metrics as its events stream by, and reports them
+/// exactly once.
+pub struct MetricsRecorder {
+    metrics: CompletionMetrics,
+    on_metrics: OnCompletionMetrics,
+    reported: bool,
+}
+
+impl MetricsRecorder {
+    /// Starts measuring a completion that was requested at `started_at`.
+    pub fn new(
+        request_id: String,
+        model: String,
+        started_at: Instant,
+        on_metrics: OnCompletionMetrics,
+    ) -> Self {
+        Self {
+            metrics: CompletionMetrics {
+                request_id,
+                model,
+                started_at,
+                time_to_first_token: None,
+                duration: Duration::ZERO,
+                usage: None,
+                finish_reason: None,
+                error: None,
+            },
+            on_metrics,
+            reported: false,
+        }
+    }
+
+    fn observe(&mut self, event: &CompletionEvent) {
+        match event {
+            CompletionEvent::Text(text)
+                if !text.is_empty() && self.metrics.time_to_first_token.is_none() =>
+            {
+                self.metrics.time_to_first_token = Some(self.metrics.started_at.elapsed());
+            }
+            CompletionEvent::StreamEnd(StreamEnd {
+                finish_reason,
+                usage,
+                request_id,
+                ..
+            }) => {
+                self.metrics.finish_reason.clone_from(finish_reason);
+                self.metrics.usage = *usage;
+                if let Some(request_id) = request_id {
+                    self.metrics.request_id.clone_from(request_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Reports the metrics, with `error` as the reason the completion failed.
+    pub fn finish(mut self, error: Option<CompletionErrorKind>) {
+        self.report(error);
+    }
+
+    fn report(&mut self, error: Option<CompletionErrorKind>) {
+        if self.reported {
+            return;
+        }
+        self.reported = true;
+        self.metrics.duration = self.metrics.started_at.elapsed();
+        self.metrics.error = error;
+        (self.on_metrics)(self.metrics.clone());
+    }
+}
+
+impl Drop for MetricsRecorder {
+    fn drop(&mut self) {
+        self.report(Some(CompletionErrorKind::Cancelled));
+    }
+}
+
+/// Passes `events` through unchanged while `recorder` measures them. The metrics are
+/// reported when the stream ends or fails, or when it's dropped before either.
+pub fn record_metrics(
+    events: impl 'static + Send + Stream<Item = Result<CompletionEvent>>,
+    mut recorder: MetricsRecorder,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut events = events.boxed();
+    stream::poll_fn(move |cx| {
+        let poll = events.poll_next_unpin(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(event))) => recorder.observe(event),
+            Poll::Ready(Some(Err(error))) => recorder.report(Some(CompletionErrorKind::of(error))),
+            Poll::Ready(None) => recorder.report(None),
+            Poll::Pending => {}
+        }
+        poll
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    fn test_recorder() -> (MetricsRecorder, Arc<Mutex<Vec<CompletionMetrics>>>) {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let recorder = MetricsRecorder::new("request".into(), "gpt-4".into(), Instant::now(), {
+            let reported = reported.clone();
+            Arc::new(move |metrics| reported.lock().push(metrics))
+        });
+        (recorder, reported)
+    }
+
+    #[gpui::test]
+    async fn test_record_metrics() {
+        let (recorder, reported) = test_recorder();
+        let events = stream::iter([
+            Ok(CompletionEvent::Text(String::new())),
+            Ok(CompletionEvent::Text("Hello".into())),
+            Ok(CompletionEvent::StreamEnd(StreamEnd {
+                finish_reason: Some("stop".into()),
+                usage: Some(TokenUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 1,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })),
+        ]);
+        let events = record_metrics(events, recorder).collect::<Vec<_>>().await;
+        assert_eq!(events.len(), 3);
+
+        let reported = reported.lock();
+        assert_eq!(reported.len(), 1);
+        let metrics = &reported[0];
+        assert_eq!(metrics.request_id, "request");
+        assert!(metrics.time_to_first_token.unwrap() <= metrics.duration);
+        assert_eq!(metrics.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(metrics.usage.unwrap().completion_tokens, 1);
+        assert_eq!(metrics.error, None);
+    }
+
+    #[gpui::test]
+    async fn test_record_metrics_errors() {
+        let (recorder, reported) = test_recorder();
+        let events = stream::iter([Err(anyhow::Error::from(RequestError::new(500, "failed")))]);
+        let _ = record_metrics(events, recorder).collect::<Vec<_>>().await;
+        assert_eq!(
+            reported.lock()[0].error,
+            Some(CompletionErrorKind::Http(500))
+        );
+
+        // Errors in the stream itself aren't mistaken for requests that were refused.
+        for (error, kind) in [
+            (
+                CompletionError::StreamStalled {
+                    timeout: Duration::from_secs(1),
+                },
+                CompletionErrorKind::Stalled,
+            ),
+            (
+                CompletionError::EmptyResponse,
+                CompletionErrorKind::Incomplete,
+            ),
+            (
+                CompletionError::ServerError {
+                    status: 529,
+                    message: "overloaded".into(),
+                },
+                CompletionErrorKind::Http(529),
+            ),
+            (
+                CompletionError::EmptyStopSequence,
+                CompletionErrorKind::Invalid,
+            ),
+        ] {
+            assert_eq!(CompletionErrorKind::of(&error.into()), kind);
+        }
+
+        // A stream that's dropped early is reported as cancelled, and only once.
+        let (recorder, reported) = test_recorder();
+        let mut events = record_metrics(
+            stream::iter([Ok(CompletionEvent::Text("Hello".into()))]).chain(stream::pending()),
+            recorder,
+        );
+        events.next().await.unwrap().unwrap();
+        drop(events);
+        let reported = reported.lock();
+        assert_eq!(reported.len(), 1);
+        assert!(reported[0].time_to_first_token.is_some());
+        assert_eq!(reported[0].error, Some(CompletionErrorKind::Cancelled));
+    }
+}
@@ -1,8 +1,14 @@
 use crate::LanguageModelCompletionProvider;
-use crate::{CompletionProvider, LanguageModel, LanguageModelRequest};
+use crate::{
+    count_open_ai_tokens, CompletionEvent, CompletionProvider, LanguageModel, LanguageModelRequest,
+    StreamEnd, TokenUsage,
+};
 use anyhow::Result;
-use futures::StreamExt as _;
-use futures::{future::BoxFuture, stream::BoxStream, FutureExt};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, StreamExt, TryFutureExt, TryStreamExt,
+};
 use gpui::{AnyView, AppContext, Task};
 use http::HttpClient;
 use language_model::Role;
@@ -10,8 +16,8 @@ use ollama::Model as OllamaModel;
 use ollama::{
     get_models, preload_model, stream_chat_completion, ChatMessage, ChatOptions, ChatRequest,
 };
-use std::sync::Arc;
 use std::time::Duration;
+use std::{mem, sync::Arc};
 use ui::{prelude::*, ButtonLike, ElevationIndex};
 
 const OLLAMA_DOWNLOAD_URL: &str = "https://ollama.com/download";
@@ -76,24 +82,37 @@ impl LanguageModelCompletionProvider for OllamaCompletionProvider {
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
-        _cx: &AppContext,
+        cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
         // There is no endpoint for this _yet_ in Ollama
         // see: https://github.com/ollama/ollama/issues/1716 and https://github.com/ollama/ollama/issues/3582
-        let token_count = request
-            .messages
-            .iter()
-            .map(|msg| msg.content.chars().count())
-            .sum::<usize>()
-            / 4;
-
-        async move { Ok(token_count) }.boxed()
+        // Tiktoken doesn't know Ollama's models, so this counts tokens as for GPT-4,
+        // which is close enough for most of them.
+        count_open_ai_tokens(request, cx.background_executor())
     }
 
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
     ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.stream_completion_events(request)
+            .map_ok(|events| {
+                events
+                    .try_filter_map(|event| async move {
+                        match event {
+                            CompletionEvent::Text(text) => Ok(Some(text)),
+                            _ => Ok(None),
+                        }
+                    })
+                    .boxed()
+            })
+            .boxed()
+    }
+
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
         let request = self.to_ollama_request(request);
 
         let http_client = self.http_client.clone();
@@ -103,22 +122,44 @@ impl LanguageModelCompletionProvider for OllamaCompletionProvider {
             let request =
                 stream_chat_completion(http_client.as_ref(), &api_url, request, low_speed_timeout);
             let response = request.await?;
-            let stream = response
-                .filter_map(|response| async move {
-                    match response {
-                        Ok(delta) => {
+            let mut stream_end = StreamEnd::default();
+            let events = response
+                .map(Some)
+                .chain(stream::once(async { None }))
+                .flat_map(move |delta| {
+                    let mut events = Vec::new();
+                    match delta {
+                        Some(Ok(delta)) => {
                             let content = match delta.message {
                                 ChatMessage::User { content } => content,
                                 ChatMessage::Assistant { content } => content,
                                 ChatMessage::System { content } => content,
                             };
-                            Some(Ok(content))
+                            if !content.is_empty() {
+                                events.push(Ok(CompletionEvent::Text(content)));
+                            }
+                            if delta.done {
+                                stream_end.finish_reason = delta.done_reason;
+                                if let (Some(prompt_tokens), Some(completion_tokens)) =
+                                    (delta.prompt_eval_count, delta.eval_count)
+                                {
+                                    stream_end.usage = Some(TokenUsage {
+                                        prompt_tokens,
+                                        completion_tokens,
+                                        ..Default::default()
+                                    });
+                                }
+                            }
+                        }
+                        Some(Err(error)) => events.push(Err(error)),
+                        None => {
+                            events.push(Ok(CompletionEvent::StreamEnd(mem::take(&mut stream_end))))
                         }
-                        Err(error) => Some(Err(error)),
                     }
+                    stream::iter(events)
                 })
                 .boxed();
-            Ok(stream)
+            Ok(events)
         }
         .boxed()
     }
@@ -238,7 +279,8 @@ impl OllamaCompletionProvider {
                 .messages
                 .into_iter()
                 .map(|msg| match msg.role {
-                    Role::User => ChatMessage::User {
+                    // Ollama can't call tools, so any tool results are sent as user messages.
+                    Role::User | Role::Tool => ChatMessage::User {
                         content: msg.content,
                     },
                     Role::Assistant => ChatMessage::Assistant {
@@ -345,3 +387,133 @@ impl Render for DownloadOllamaMessage {
             .into_any()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+    use http::{FakeHttpClient, Response};
+    use language_model::LanguageModelRequestMessage;
+    use serde_json::json;
+
+    fn test_provider(chat_response: String, cx: &mut TestAppContext) -> OllamaCompletionProvider {
+        let http_client = FakeHttpClient::create(move |request| {
+            let body = if request.uri().path() == "/api/chat" {
+                chat_response.clone()
+            } else {
+                String::new()
+            };
+            async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+        });
+        cx.update(|cx| {
+            OllamaCompletionProvider::new(
+                OllamaModel::new("llama3"),
+                ollama::OLLAMA_API_URL.into(),
+                http_client,
+                None,
+                0,
+                cx,
+            )
+        })
+    }
+
+    fn user_request(content: &str) -> LanguageModelRequest {
+        LanguageModelRequest {
+            model: LanguageModel::Ollama(OllamaModel::new("llama3")),
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: content.into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn delta(content: &str, done: bool) -> serde_json::Value {
+        json!({
+            "model": "llama3",
+            "created_at": "2024-07-01T00:00:00Z",
+            "message": { "role": "assistant", "content": content },
+            "done": done,
+        })
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_events(cx: &mut TestAppContext) {
+        let mut done = delta("", true);
+        done["done_reason"] = json!("stop");
+        done["prompt_eval_count"] = json!(12);
+        done["eval_count"] = json!(2);
+        // Responses are newline-delimited JSON, which may include blank lines.
+        let chat_response = format!(
+            "{}\n\n{}\n{}\n",
+            delta("Hello", false),
+            delta(", world", false),
+            done
+        );
+        let provider = test_provider(chat_response, cx);
+
+        let events = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Text("Hello".into()),
+                CompletionEvent::Text(", world".into()),
+                CompletionEvent::StreamEnd(StreamEnd {
+                    finish_reason: Some("stop".into()),
+                    usage: Some(TokenUsage {
+                        prompt_tokens: 12,
+                        completion_tokens: 2,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stream_errors(cx: &mut TestAppContext) {
+        // Blank lines between the deltas are skipped.
+        let chat_response = format!(
+            "{}\n\n{}\n",
+            delta("Hello", false),
+            json!({ "error": "model runner has unexpectedly stopped" })
+        );
+        let provider = test_provider(chat_response, cx);
+
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap(), "Hello");
+        assert_eq!(
+            chunks[1].as_ref().unwrap_err().to_string(),
+            "Ollama error: model runner has unexpectedly stopped"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_count_tokens(cx: &mut TestAppContext) {
+        let provider = test_provider(String::new(), cx);
+        let count = cx
+            .update(|cx| provider.count_tokens(user_request("Hello, world"), cx))
+            .await
+            .unwrap();
+        assert!(count > 0);
+    }
+}
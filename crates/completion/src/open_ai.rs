@@ -1,31 +1,261 @@
-use crate::CompletionProvider;
 use crate::LanguageModelCompletionProvider;
+use crate::{
+    count_request_tokens, extract_reasoning, hold_back_stop_sequences, normalize_api_key,
+    record_metrics, truncate_messages, with_cost_estimates, CompletionError, CompletionErrorKind,
+    CompletionEvent, CompletionProvider, CompletionRecorder, ConnectionStatus, CostGuard, MapChunk,
+    MetricsRecorder, ModelCapabilities, ModelUsage, OnCompletionMetrics,
+    OpenAiCompatibleCompletionProvider, ReasoningTags, StreamEnd, TiktokenTokenizer, TokenLogprob,
+    TokenPrice, TokenUsage, Tokenizer, TruncationStrategy,
+};
 use anyhow::{anyhow, Result};
+use collections::{HashMap, HashSet};
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
-use gpui::{AnyView, AppContext, Task, TextStyle, View};
+use futures::{
+    future::{self, BoxFuture, Either, Future},
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt,
+};
+use gpui::{
+    percentage, Animation, AnimationExt, AnyView, AppContext, AsyncAppContext, Task, TextStyle,
+    Transformation, View,
+};
 use http::HttpClient;
-use language_model::{CloudModel, LanguageModel, LanguageModelRequest, Role};
+use language_model::{
+    CloudModel, ImageSize, LanguageModel, LanguageModelImage, LanguageModelRequest,
+    LanguageModelRequestMessage, LanguageModelResponseFormat, LanguageModelTool,
+    LanguageModelToolCall, LanguageModelToolChoice, Role,
+};
 use open_ai::Model as OpenAiModel;
-use open_ai::{stream_completion, Request, RequestMessage};
+use open_ai::{
+    chat_completions_url, complete_with_auth, list_models, moderate, stream_completion_with_auth,
+    stream_response, ApiAuth, AuthHeaderStyle, AzureDeployment, FunctionContent,
+    FunctionDefinition, HttpVersionPreference, ImageDetail, ImageUrl, MessageContent, MessagePart,
+    ModelListing, ModerationResult, Request, RequestError, RequestMessage, ResponseFormat,
+    ResponseInputItem, ResponseStreamEvent, ResponsesRequest, ResponsesStreamEvent, StreamOptions,
+    ToolCall, ToolCallContent, ToolChoice, ToolDefinition,
+};
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use settings::Settings;
-use std::time::Duration;
-use std::{env, sync::Arc};
+use std::collections::{hash_map::DefaultHasher, BTreeMap, HashMap as StdHashMap};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use std::{
+    env, mem,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+};
 use strum::IntoEnumIterator;
 use theme::ThemeSettings;
-use ui::prelude::*;
+use ui::{prelude::*, Tooltip};
 use util::ResultExt;
+use uuid::Uuid;
+
+/// What to do when a completion finishes without producing any content.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyResponseBehavior {
+    /// Treat the completion as successful, yielding no text.
+    #[default]
+    ReturnEmpty,
+    /// Fail the completion with [`CompletionError::EmptyResponse`], so that
+    /// callers can retry or inform the user.
+    Error,
+}
+
+/// What to do with a tool call whose arguments are incomplete because the stream
+/// ended while they were still being streamed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IncompleteToolCallBehavior {
+    /// Fail the completion with [`CompletionError::IncompleteToolCall`].
+    #[default]
+    Error,
+    /// Emit the tool call anyway, flagged as incomplete.
+    EmitIncomplete,
+}
+
+/// How a request that ends with a partial assistant message, such as a prefilled
+/// start of the response, is sent so that the model continues that message rather
+/// than starting a new turn. Either way, only the continuation is streamed back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AssistantPrefill {
+    /// Follow the message with a user message asking the model to continue it.
+    /// OpenAI's models treat a trailing assistant message as a finished turn, so
+    /// they have to be asked.
+    #[default]
+    Prompt,
+    /// Send the message as the last one, for compatible servers that continue a
+    /// trailing assistant message themselves.
+    Native,
+}
+
+/// Where the API key is looked for when authenticating.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeySource {
+    /// The environment, falling back to the keyring if no key is set there.
+    #[default]
+    Auto,
+    /// Only the environment.
+    Env,
+    /// Only the keyring, even if a key is set in the environment.
+    Keyring,
+}
+
+impl ApiKeySource {
+    pub fn uses_env(self) -> bool {
+        matches!(self, Self::Auto | Self::Env)
+    }
+
+    pub fn uses_keyring(self) -> bool {
+        matches!(self, Self::Auto | Self::Keyring)
+    }
+}
+
+/// A function that can modify each request sent to OpenAI, after it has been
+/// built from the [`LanguageModelRequest`] and before it is serialized.
+pub type RequestTransform = Arc<dyn Fn(&mut Request) + Send + Sync>;
+
+/// Which OpenAI endpoint completions are requested from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OpenAiApi {
+    /// The `/chat/completions` endpoint, supported by most compatible servers.
+    #[default]
+    ChatCompletions,
+    /// The newer `/responses` endpoint.
+    Responses,
+}
+
+/// The provider configuration a single completion runs against. It is captured
+/// when the completion is requested, so settings updates that land while the
+/// completion is in flight don't affect it.
+#[derive(Clone)]
+struct RequestConfig {
+    http_client: Arc<dyn HttpClient>,
+    api_keys: Vec<String>,
+    key_rotation: Arc<Mutex<KeyRotation>>,
+    organization_id: Option<String>,
+    api_url: String,
+    low_speed_timeout: Option<Duration>,
+    http_version: HttpVersionPreference,
+    incomplete_tool_call_behavior: IncompleteToolCallBehavior,
+    request_id: String,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    azure_deployment: Option<AzureDeployment>,
+    auth_header: AuthHeaderStyle,
+    recorder: Option<Arc<CompletionRecorder>>,
+    assistant_prefill: AssistantPrefill,
+    non_streaming_fallback: bool,
+}
+
+/// A description of an [`OpenAiCompletionProvider`]'s configuration that is safe
+/// to share, e.g. in a bug report. It never includes the API key.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct ProviderConfigSnapshot {
+    pub model: OpenAiModel,
+    pub api_url: String,
+    pub low_speed_timeout_secs: Option<u64>,
+    pub reasoning_idle_timeout_secs: Option<u64>,
+    pub http_version: HttpVersionPreference,
+    pub available_models: Vec<OpenAiModel>,
+    pub settings_version: usize,
+    pub authenticated: bool,
+}
+
+/// See [`OpenAiCompletionProvider::api_connection`].
+pub struct ApiConnection {
+    pub http_client: Arc<dyn HttpClient>,
+    pub api_url: String,
+    pub api_key: String,
+    pub organization_id: Option<String>,
+    pub auth_header: AuthHeaderStyle,
+}
 
 pub struct OpenAiCompletionProvider {
-    api_key: Option<String>,
+    api_keys: Vec<String>,
+    key_rotation: Arc<Mutex<KeyRotation>>,
+    api_key_override: Option<String>,
+    api_key_source: ApiKeySource,
+    organization_id: Option<String>,
     api_url: String,
     model: OpenAiModel,
     http_client: Arc<dyn HttpClient>,
     low_speed_timeout: Option<Duration>,
     settings_version: usize,
     available_models_from_settings: Vec<OpenAiModel>,
+    fetched_models: Vec<OpenAiModel>,
+    fetch_models_after_authentication: bool,
+    split_tool_call_content: bool,
+    empty_response_behavior: EmptyResponseBehavior,
+    api: OpenAiApi,
+    default_max_tokens: HashMap<String, Option<u32>>,
+    model_low_speed_timeouts: HashMap<String, Duration>,
+    http_version: HttpVersionPreference,
+    map_chunk: Option<MapChunk>,
+    on_completion_metrics: Option<OnCompletionMetrics>,
+    completion_recorder: Option<Arc<CompletionRecorder>>,
+    truncation_strategy: Option<TruncationStrategy>,
+    reasoning_idle_timeout: Option<Duration>,
+    incomplete_tool_call_behavior: IncompleteToolCallBehavior,
+    request_transform: Option<RequestTransform>,
+    session_usage: Arc<Mutex<ModelUsage>>,
+    token_budget: Option<u64>,
+    token_price: Option<TokenPrice>,
+    tokenizer: Option<Arc<dyn Tokenizer>>,
+    merge_system_messages: bool,
+    max_continuations: usize,
+    non_streaming_fallback: bool,
+    cost_guard: Option<CostGuard>,
+    reasoning_tags: Option<ReasoningTags>,
+    context_warning_threshold: f64,
+    stream_cost_estimates: bool,
+    prime_after_authentication: bool,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    azure_deployment: Option<AzureDeployment>,
+    auth_header: AuthHeaderStyle,
+    assistant_prefill: AssistantPrefill,
+    moderate_input: bool,
 }
 
+/// The fraction of the context window at which [`ContextUsage::should_warn`] is set,
+/// unless configured otherwise.
+const DEFAULT_CONTEXT_WARNING_THRESHOLD: f64 = 0.8;
+
+/// How many times a request that fails transiently is retried, unless configured
+/// otherwise.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// How long a rate limited key is skipped for, unless the server says how long to
+/// wait.
+const RATE_LIMITED_KEY_COOL_DOWN: Duration = Duration::from_secs(60);
+
+/// The delay before the first retry of a failed request, unless configured
+/// otherwise. It doubles with each retry.
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The highest temperature OpenAI accepts.
+const MAX_TEMPERATURE: f32 = 2.;
+
+/// The most stop sequences OpenAI accepts in a request.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// The largest bias, either way, that OpenAI accepts for a token.
+const MAX_LOGIT_BIAS: i32 = 100;
+
+/// The most alternatives OpenAI reports for each token.
+const MAX_TOP_LOGPROBS: u8 = 20;
+
+/// Sent after a partial assistant message, whether prefilled or the output of a
+/// failed completion, to have the model pick up where it left off.
+const CONTINUATION_PROMPT: &str =
+    "Your previous response was cut off. Continue it exactly where it left off, without repeating any of it.";
+
 impl OpenAiCompletionProvider {
     pub fn new(
         model: OpenAiModel,
@@ -36,16 +266,56 @@ impl OpenAiCompletionProvider {
         available_models_from_settings: Vec<OpenAiModel>,
     ) -> Self {
         Self {
-            api_key: None,
+            api_keys: Vec::new(),
+            api_key_override: None,
+            key_rotation: Arc::default(),
+            organization_id: None,
             api_url,
             model,
             http_client,
             low_speed_timeout,
             settings_version,
             available_models_from_settings,
+            fetched_models: Vec::new(),
+            fetch_models_after_authentication: false,
+            api_key_source: ApiKeySource::default(),
+            split_tool_call_content: false,
+            empty_response_behavior: EmptyResponseBehavior::default(),
+            api: OpenAiApi::default(),
+            default_max_tokens: HashMap::default(),
+            model_low_speed_timeouts: HashMap::default(),
+            http_version: HttpVersionPreference::default(),
+            map_chunk: None,
+            on_completion_metrics: None,
+            completion_recorder: None,
+            truncation_strategy: None,
+            reasoning_idle_timeout: None,
+            incomplete_tool_call_behavior: IncompleteToolCallBehavior::default(),
+            request_transform: None,
+            session_usage: Arc::default(),
+            token_budget: None,
+            token_price: None,
+            tokenizer: None,
+            merge_system_messages: false,
+            max_continuations: 0,
+            non_streaming_fallback: false,
+            cost_guard: None,
+            reasoning_tags: None,
+            context_warning_threshold: DEFAULT_CONTEXT_WARNING_THRESHOLD,
+            stream_cost_estimates: false,
+            prime_after_authentication: false,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+            azure_deployment: None,
+            auth_header: AuthHeaderStyle::default(),
+            assistant_prefill: AssistantPrefill::default(),
+            moderate_input: false,
         }
     }
 
+    /// Applies updated settings. Credentials are stored per API URL, so when the URL
+    /// changes the stored keys and any override are forgotten, and the provider
+    /// needs authenticating against the new API before it can be used again.
     pub fn update(
         &mut self,
         model: OpenAiModel,
@@ -54,245 +324,2904 @@ impl OpenAiCompletionProvider {
         settings_version: usize,
     ) {
         self.model = model;
+        if api_url != self.api_url {
+            self.clear_api_key();
+            self.api_key_override = None;
+            *self.key_rotation.lock() = KeyRotation::default();
+        }
         self.api_url = api_url;
         self.low_speed_timeout = low_speed_timeout;
         self.settings_version = settings_version;
     }
 
-    fn to_open_ai_request(&self, request: LanguageModelRequest) -> Request {
-        let model = match request.model {
-            LanguageModel::OpenAi(model) => model,
-            _ => self.model.clone(),
-        };
+    /// Replaces the models configured in the settings, which are offered ahead of
+    /// those fetched from the API. When there are neither, the built-in models are
+    /// offered instead.
+    pub fn set_available_models(&mut self, available_models: Vec<OpenAiModel>) {
+        self.available_models_from_settings = available_models;
+    }
 
-        Request {
-            model,
-            messages: request
-                .messages
-                .into_iter()
-                .map(|msg| match msg.role {
-                    Role::User => RequestMessage::User {
-                        content: msg.content,
-                    },
-                    Role::Assistant => RequestMessage::Assistant {
-                        content: Some(msg.content),
-                        tool_calls: Vec::new(),
-                    },
-                    Role::System => RequestMessage::System {
-                        content: msg.content,
-                    },
-                })
-                .collect(),
-            stream: true,
-            stop: request.stop,
-            temperature: request.temperature,
-            tools: Vec::new(),
-            tool_choice: None,
-        }
+    /// Some OpenAI-compatible endpoints reject assistant messages that carry
+    /// both text content and tool calls. When enabled, such a turn is sent as
+    /// a text-only assistant message followed by a tool-call-only one.
+    pub fn set_split_tool_call_content(&mut self, split_tool_call_content: bool) {
+        self.split_tool_call_content = split_tool_call_content;
     }
-}
 
-impl LanguageModelCompletionProvider for OpenAiCompletionProvider {
-    fn available_models(&self) -> Vec<LanguageModel> {
-        if self.available_models_from_settings.is_empty() {
-            let available_models = if matches!(self.model, OpenAiModel::Custom { .. }) {
-                vec![self.model.clone()]
-            } else {
-                OpenAiModel::iter()
-                    .filter(|model| !matches!(model, OpenAiModel::Custom { .. }))
-                    .collect()
-            };
-            available_models
-                .into_iter()
-                .map(LanguageModel::OpenAi)
-                .collect()
-        } else {
-            self.available_models_from_settings
-                .iter()
-                .cloned()
-                .map(LanguageModel::OpenAi)
-                .collect()
-        }
+    /// Some endpoints reject requests with more than one system message. When
+    /// enabled, all system messages are sent as a single one, joined with newlines,
+    /// in place of the first.
+    pub fn set_merge_system_messages(&mut self, merge_system_messages: bool) {
+        self.merge_system_messages = merge_system_messages;
     }
 
-    fn settings_version(&self) -> usize {
-        self.settings_version
+    /// When a chat completion fails after streaming some text, re-requests it up to
+    /// `max_continuations` times with the text received so far, asking the model to
+    /// continue from there. The continuation's text is streamed as if it were part
+    /// of the original completion. Disabled (zero) by default.
+    pub fn set_max_continuations(&mut self, max_continuations: usize) {
+        self.max_continuations = max_continuations;
     }
 
-    fn is_authenticated(&self) -> bool {
-        self.api_key.is_some()
+    /// When a Chat Completions request fails with a server error, even after being
+    /// retried, requests the completion once more without streaming it, for
+    /// servers and proxies that can't stream. The completion is then streamed as a
+    /// single chunk, and reported as non-streaming in the stream's
+    /// [`CompletionPath`](crate::CompletionPath). Disabled by default.
+    pub fn set_non_streaming_fallback(&mut self, non_streaming_fallback: bool) {
+        self.non_streaming_fallback = non_streaming_fallback;
     }
 
-    fn authenticate(&self, cx: &AppContext) -> Task<Result<()>> {
-        if self.is_authenticated() {
-            Task::ready(Ok(()))
-        } else {
-            let api_url = self.api_url.clone();
-            cx.spawn(|mut cx| async move {
-                let api_key = if let Ok(api_key) = env::var("OPENAI_API_KEY") {
-                    api_key
-                } else {
-                    let (_, api_key) = cx
-                        .update(|cx| cx.read_credentials(&api_url))?
-                        .await?
-                        .ok_or_else(|| anyhow!("credentials not found"))?;
-                    String::from_utf8(api_key)?
-                };
-                cx.update_global::<CompletionProvider, _>(|provider, _cx| {
-                    provider.update_current_as::<_, Self>(|provider| {
-                        provider.api_key = Some(api_key);
-                    });
-                })
-            })
-        }
+    /// Sets how requests that end with a partial assistant message are sent, and
+    /// so how a completion is continued after failing mid-stream.
+    pub fn set_assistant_prefill(&mut self, assistant_prefill: AssistantPrefill) {
+        self.assistant_prefill = assistant_prefill;
     }
 
-    fn reset_credentials(&self, cx: &AppContext) -> Task<Result<()>> {
-        let delete_credentials = cx.delete_credentials(&self.api_url);
-        cx.spawn(|mut cx| async move {
-            delete_credentials.await.log_err();
-            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
-                provider.update_current_as::<_, Self>(|provider| {
-                    provider.api_key = None;
-                });
-            })
-        })
+    /// Sets how many times a request that fails with a rate limit or server error
+    /// (429, 500, 502 or 503) is retried before the completion fails. Only sending
+    /// the request is retried; a stream that fails after it started is not. Three by
+    /// default.
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
     }
 
-    fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
-        cx.new_view(|cx| AuthenticationPrompt::new(self.api_url.clone(), cx))
-            .into()
+    /// Sets the delay before the first retry of a failed request, which doubles with
+    /// each subsequent retry. A `Retry-After` sent by the server takes precedence.
+    pub fn set_retry_base_delay(&mut self, retry_base_delay: Duration) {
+        self.retry_base_delay = retry_base_delay;
     }
 
-    fn model(&self) -> LanguageModel {
-        LanguageModel::OpenAi(self.model.clone())
+    /// Requests chat completions from an Azure OpenAI deployment rather than from
+    /// OpenAI. The API URL is then the Azure resource's endpoint, e.g.
+    /// `https://{resource}.openai.azure.com`, and the API key is sent in an
+    /// `api-key` header. The model should still be set to the one the deployment
+    /// serves, so that tokens are counted correctly.
+    pub fn set_azure_deployment(&mut self, azure_deployment: Option<AzureDeployment>) {
+        self.azure_deployment = azure_deployment;
     }
 
-    fn count_tokens(
+    /// Sets the header the API key is sent in, for compatible APIs that don't
+    /// take it as a bearer token. Azure deployments always use their own.
+    pub fn set_auth_header(&mut self, auth_header: AuthHeaderStyle) {
+        self.auth_header = auth_header;
+    }
+
+    /// Some compatible models emit their reasoning inline, wrapped in tags such as
+    /// `<think>`. When set, text between `tags` is streamed as reasoning rather
+    /// than as part of the answer. Disabled by default.
+    pub fn set_reasoning_tags(&mut self, tags: Option<ReasoningTags>) {
+        self.reasoning_tags = tags;
+    }
+
+    /// Sets what happens when a completion finishes without any content. By default
+    /// it succeeds with no text.
+    pub fn set_empty_response_behavior(&mut self, behavior: EmptyResponseBehavior) {
+        self.empty_response_behavior = behavior;
+    }
+
+    /// Sets which endpoint completions are requested from, Chat Completions by
+    /// default. The endpoint is chosen when a completion is requested, so those
+    /// already in progress are unaffected.
+    pub fn set_api(&mut self, api: OpenAiApi) {
+        self.api = api;
+    }
+
+    /// Sets which HTTP version requests are sent with. By default it's negotiated
+    /// with the server, which may choose HTTP/2.
+    pub fn set_http_version(&mut self, http_version: HttpVersionPreference) {
+        self.http_version = http_version;
+    }
+
+    /// Sets a function applied to each chunk of streamed text, before any other
+    /// processing of the response. Stop sequences are looked for in the mapped
+    /// text.
+    pub fn set_map_chunk(&mut self, map_chunk: Option<MapChunk>) {
+        self.map_chunk = map_chunk;
+    }
+
+    /// Sets a function called with the [`CompletionMetrics`] of each completion once
+    /// it has finished. Completions aren't measured when unset.
+    pub fn set_on_completion_metrics(
+        &mut self,
+        on_completion_metrics: Option<OnCompletionMetrics>,
+    ) {
+        self.on_completion_metrics = on_completion_metrics;
+    }
+
+    /// Sets a recorder that captures the requests and streamed responses of this
+    /// provider's Chat Completions, so that they can be replayed later with a
+    /// [`ReplayCompletionProvider`].
+    pub fn set_completion_recorder(&mut self, recorder: Option<Arc<CompletionRecorder>>) {
+        self.completion_recorder = recorder;
+    }
+
+    /// Sets how old messages are dropped from requests that don't fit in the model's
+    /// context window. How many were dropped is reported in the stream's
+    /// [`StreamEnd`]. When unset, such requests fail with
+    /// [`CompletionError::ContextWindowExceeded`].
+    pub fn set_truncation_strategy(&mut self, truncation_strategy: Option<TruncationStrategy>) {
+        self.truncation_strategy = truncation_strategy;
+    }
+
+    /// Sets what happens to a tool call whose arguments were still streaming when
+    /// the stream ended. By default the completion fails.
+    pub fn set_incomplete_tool_call_behavior(&mut self, behavior: IncompleteToolCallBehavior) {
+        self.incomplete_tool_call_behavior = behavior;
+    }
+
+    /// Sets a function that modifies each Chat Completions request before it's sent.
+    /// It runs after the request has been truncated to fit its context window and
+    /// checked against the stop sequence, budget, context window and cost limits,
+    /// so it sees the messages that will be sent. The tool call ids and JSON mode
+    /// are validated after it runs.
+    pub fn set_request_transform(&mut self, request_transform: Option<RequestTransform>) {
+        self.request_transform = request_transform;
+    }
+
+    /// The tokens spent by this provider's completions so far.
+    pub fn session_usage(&self) -> ModelUsage {
+        *self.session_usage.lock()
+    }
+
+    /// Caps the total tokens this provider's completions may spend. Once the cap
+    /// has been reached, new completions fail with [`CompletionError::BudgetExceeded`].
+    pub fn set_token_budget(&mut self, token_budget: Option<u64>) {
+        self.token_budget = token_budget;
+    }
+
+    /// Sets the OpenAI organization that requests are made on behalf of, for users
+    /// who belong to several. Requests are billed to it and can use its models.
+    /// When unset, requests are made on behalf of the key's default organization.
+    pub fn set_organization_id(&mut self, organization_id: Option<String>) {
+        self.organization_id = organization_id;
+    }
+
+    /// Sets the price used to estimate the cost of the tokens spent. The list prices
+    /// of the built-in models are given by [`open_ai_token_price`].
+    pub fn set_token_price(&mut self, token_price: Option<TokenPrice>) {
+        self.token_price = token_price;
+    }
+
+    /// Sets the guard that completions are checked against before they're sent.
+    /// Their cost is estimated from the prompt and the `max_tokens` they request,
+    /// so the guard only applies once a token price is set.
+    pub fn set_cost_guard(&mut self, cost_guard: Option<CostGuard>) {
+        self.cost_guard = cost_guard;
+    }
+
+    /// Estimates the cost of requesting `n` completions of `completion_tokens` each
+    /// for a prompt of `prompt_tokens`, checking it against the cost guard. The
+    /// prompt is paid for once, and the completion tokens for each completion.
+    /// Returns `None` if no token price is configured.
+    pub fn estimate_cost(
+        &self,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        n: u32,
+    ) -> Result<Option<f64>, CompletionError> {
+        let Some(token_price) = self.token_price else {
+            return Ok(None);
+        };
+        let estimated_cost = token_price.cost(TokenUsage {
+            prompt_tokens: prompt_tokens as u32,
+            completion_tokens: completion_tokens as u32 * n.max(1),
+            ..Default::default()
+        });
+        if let Some(cost_guard) = self.cost_guard {
+            cost_guard.check(estimated_cost)?;
+        }
+        Ok(Some(estimated_cost))
+    }
+
+    /// Counts the tokens of `request` with tiktoken, broken down by role.
+    pub fn count_tokens_detailed(
         &self,
         request: LanguageModelRequest,
         cx: &AppContext,
-    ) -> BoxFuture<'static, Result<usize>> {
-        count_open_ai_tokens(request, cx.background_executor())
+    ) -> BoxFuture<'static, Result<TokenBreakdown>> {
+        if let Some(tokenizer) = self.tokenizer.clone() {
+            cx.background_executor()
+                .spawn(async move { Ok(count_tokens_detailed_with(tokenizer.as_ref(), &request)) })
+                .boxed()
+        } else {
+            count_open_ai_tokens_detailed(request, cx.background_executor())
+        }
     }
 
-    fn stream_completion(
+    /// Computes how much of its model's context window `request` would use.
+    pub fn context_usage(
         &self,
         request: LanguageModelRequest,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
-        let request = self.to_open_ai_request(request);
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<ContextUsage>> {
+        let max_tokens = match &request.model {
+            LanguageModel::OpenAi(model) => model.max_token_count(),
+            _ => self.model.max_token_count(),
+        };
+        let warning_threshold = self.context_warning_threshold;
+        self.count_tokens_detailed(request, cx)
+            .map_ok(move |breakdown| {
+                let tokens = breakdown.total();
+                ContextUsage {
+                    tokens,
+                    max_tokens,
+                    should_warn: tokens as f64 >= max_tokens as f64 * warning_threshold,
+                }
+            })
+            .boxed()
+    }
+
+    /// Sets the fraction of the context window a request can use before
+    /// [`ContextUsage::should_warn`] is set. Defaults to 80%.
+    pub fn set_context_warning_threshold(&mut self, threshold: f64) {
+        self.context_warning_threshold = threshold;
+    }
+
+    /// When enabled and a [`TokenPrice`] is set, completions stream running
+    /// estimates of their cost as they are generated. See [`with_cost_estimates`].
+    pub fn set_stream_cost_estimates(&mut self, stream_cost_estimates: bool) {
+        self.stream_cost_estimates = stream_cost_estimates;
+    }
+
+    /// When enabled, a minimal completion is requested in the background as soon as
+    /// an API key is available, so the first real completion is served by a warm
+    /// model. Its output is discarded, and it counts towards the token budget like
+    /// any other completion, so it isn't sent once the budget has been spent.
+    pub fn set_prime_after_authentication(&mut self, prime_after_authentication: bool) {
+        self.prime_after_authentication = prime_after_authentication;
+    }
+
+    /// Controls whether [`LanguageModelCompletionProvider::authenticate`] looks for
+    /// the key in the environment, in the keyring, or in the environment and then
+    /// the keyring.
+    pub fn set_api_key_source(&mut self, api_key_source: ApiKeySource) {
+        self.api_key_source = api_key_source;
+    }
+
+    /// The environment variable the API key is read from.
+    fn api_key_var(&self) -> &'static str {
+        if self.azure_deployment.is_some() {
+            "AZURE_OPENAI_API_KEY"
+        } else {
+            "OPENAI_API_KEY"
+        }
+    }
+
+    /// When enabled, the models the API key can access are fetched as soon as it's
+    /// available. See [`Self::fetch_models`].
+    pub fn set_fetch_models_after_authentication(
+        &mut self,
+        fetch_models_after_authentication: bool,
+    ) {
+        self.fetch_models_after_authentication = fetch_models_after_authentication;
+    }
+
+    /// Fetches the models the API key can access, which are then offered instead
+    /// of the built-in ones, unless models are configured in the settings. If the
+    /// API doesn't list its models, as some proxies don't, this fails and the
+    /// built-in models are still offered.
+    pub fn fetch_models(&self, cx: &AppContext) -> Task<Result<Vec<OpenAiModel>>> {
+        let Some(api_key) = self.active_api_keys().first().cloned() else {
+            return Task::ready(Err(anyhow!("missing api key")));
+        };
+        if self.azure_deployment.is_some() {
+            return Task::ready(Err(anyhow!("Azure deployments don't list their models")));
+        }
 
         let http_client = self.http_client.clone();
-        let api_key = self.api_key.clone();
         let api_url = self.api_url.clone();
+        let organization_id = self.organization_id.clone();
+        let auth_header = self.auth_header.clone();
         let low_speed_timeout = self.low_speed_timeout;
+        cx.spawn(|mut cx| async move {
+            let auth = auth_header.auth(&api_key, organization_id.as_deref());
+            let listings =
+                list_models(http_client.as_ref(), &api_url, auth, low_speed_timeout).await?;
+            let models = models_from_listings(listings);
+            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
+                store_fetched_models(provider, &api_url, models.clone());
+            })?;
+            Ok(models)
+        })
+    }
+
+    /// The API and credentials completions are requested with, so that other
+    /// endpoints of the same API, such as embeddings, can be requested alike. Fails
+    /// if the provider isn't authenticated yet, or is an Azure deployment, whose
+    /// URLs are specific to the deployed model.
+    pub fn api_connection(&self) -> Result<ApiConnection> {
+        let Some(api_key) = self.active_api_keys().first().cloned() else {
+            return Err(anyhow!("missing api key"));
+        };
+        if self.azure_deployment.is_some() {
+            return Err(anyhow!("Azure deployments only serve the deployed model"));
+        }
+
+        Ok(ApiConnection {
+            http_client: self.http_client.clone(),
+            api_url: self.api_url.clone(),
+            api_key,
+            organization_id: self.organization_id.clone(),
+            auth_header: self.auth_header.clone(),
+        })
+    }
+
+    /// Classifies `text` with OpenAI's moderation endpoint, taking turns with the
+    /// API keys and retrying like completions do.
+    fn moderation(
+        &self,
+        text: String,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<ModerationResult>> {
+        if !self.supports_moderation() {
+            return futures::future::ready(Err(anyhow!(
+                "moderation is only available from OpenAI's own API"
+            )))
+            .boxed();
+        }
+        let config = self.request_config(&self.model, Uuid::new_v4().to_string(), api_key);
         async move {
-            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            let request = stream_completion(
-                http_client.as_ref(),
-                &api_url,
-                &api_key,
-                request,
-                low_speed_timeout,
-            );
-            let response = request.await?;
-            let stream = response
-                .filter_map(|response| async move {
-                    match response {
-                        Ok(mut response) => Some(Ok(response.choices.pop()?.delta.content?)),
-                        Err(error) => Some(Err(error)),
-                    }
-                })
-                .boxed();
-            Ok(stream)
+            let (result, _) = with_retries(&config, |api_key| {
+                let config = &config;
+                let text = &text;
+                async move {
+                    let auth = config
+                        .auth_header
+                        .auth(&api_key, config.organization_id.as_deref());
+                    moderate(config.http_client.as_ref(), &config.api_url, auth, text).await
+                }
+            })
+            .await?;
+            Ok(result)
         }
         .boxed()
     }
 
-    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
-        self
+    /// Whether the API has OpenAI's moderation endpoint, which Azure deployments and
+    /// OpenAI-compatible APIs don't.
+    fn supports_moderation(&self) -> bool {
+        self.azure_deployment.is_none() && is_default_api_url(&self.api_url)
     }
-}
 
-pub fn count_open_ai_tokens(
-    request: LanguageModelRequest,
-    background_executor: &gpui::BackgroundExecutor,
-) -> BoxFuture<'static, Result<usize>> {
-    background_executor
-        .spawn(async move {
-            let messages = request
-                .messages
-                .into_iter()
-                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
-                    role: match message.role {
-                        Role::User => "user".into(),
-                        Role::Assistant => "assistant".into(),
-                        Role::System => "system".into(),
-                    },
-                    content: Some(message.content),
-                    name: None,
-                    function_call: None,
-                })
-                .collect::<Vec<_>>();
+    fn should_moderate_input(&self) -> bool {
+        if self.moderate_input && !self.supports_moderation() {
+            log::warn!("not moderating input, which only OpenAI's own API supports");
+            return false;
+        }
+        self.moderate_input
+    }
 
-            match request.model {
-                LanguageModel::Anthropic(_)
-                | LanguageModel::Cloud(CloudModel::Claude3_5Sonnet)
-                | LanguageModel::Cloud(CloudModel::Claude3Opus)
-                | LanguageModel::Cloud(CloudModel::Claude3Sonnet)
-                | LanguageModel::Cloud(CloudModel::Claude3Haiku)
-                | LanguageModel::OpenAi(OpenAiModel::Custom { .. }) => {
-                    // Tiktoken doesn't yet support these models, so we manually use the
-                    // same tokenizer as GPT-4.
-                    tiktoken_rs::num_tokens_from_messages("gpt-4", &messages)
+    /// Stores `api_key`, returning the priming completion to run in the background,
+    /// if there is one. See [`Self::set_prime_after_authentication`].
+    pub(crate) fn set_api_key(&mut self, api_key: String) -> Option<BoxFuture<'static, ()>> {
+        self.set_api_keys(vec![api_key])
+    }
+
+    /// Like [`Self::set_api_key`], but requests take turns with each of `api_keys`,
+    /// to spread load across their rate limits. A key that's rate limited is
+    /// skipped until it cools down.
+    pub(crate) fn set_api_keys(&mut self, api_keys: Vec<String>) -> Option<BoxFuture<'static, ()>> {
+        self.api_keys = api_keys;
+        if !self.prime_after_authentication {
+            return None;
+        }
+
+        let request = LanguageModelRequest {
+            model: LanguageModel::OpenAi(self.model.clone()),
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hi".into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            }],
+            max_tokens: Some(1),
+            ..Default::default()
+        };
+        let response = self.stream_completion_events(request);
+        Some(
+            async move {
+                match response.await {
+                    Ok(mut events) => while events.next().await.is_some() {},
+                    Err(error) => log::debug!("failed to prime the model: {error:?}"),
                 }
-                _ => tiktoken_rs::num_tokens_from_messages(request.model.id(), &messages),
             }
-        })
-        .boxed()
-}
+            .boxed(),
+        )
+    }
 
-struct AuthenticationPrompt {
-    api_key: View<Editor>,
-    api_url: String,
-}
+    /// Prompts for the key of the API, which is referred to as `provider_name`, and
+    /// whose key can be read from `api_key_var` instead, if given.
+    pub(crate) fn authentication_prompt_for(
+        &self,
+        provider_name: SharedString,
+        api_key_var: Option<&'static str>,
+        cx: &mut WindowContext,
+    ) -> AnyView {
+        cx.new_view(|cx| {
+            AuthenticationPrompt::new(
+                self.api_url.clone(),
+                provider_name,
+                api_key_var,
+                self.http_client.clone(),
+                self.organization_id.clone(),
+                self.auth_header.clone(),
+                self.low_speed_timeout,
+                self.azure_deployment.is_none(),
+                cx,
+            )
+        })
+        .into()
+    }
 
-impl AuthenticationPrompt {
-    fn new(api_url: String, cx: &mut WindowContext) -> Self {
-        Self {
-            api_key: cx.new_view(|cx| {
-                let mut editor = Editor::single_line(cx);
-                editor.set_placeholder_text(
-                    "sk-000000000000000000000000000000000000000000000000",
-                    cx,
-                );
-                editor
-            }),
-            api_url,
+    /// The keys requests are made with: the override if one is set, otherwise the
+    /// stored keys.
+    fn active_api_keys(&self) -> &[String] {
+        match &self.api_key_override {
+            Some(api_key) => std::slice::from_ref(api_key),
+            None => &self.api_keys,
         }
     }
 
-    fn save_api_key(&mut self, _: &menu::Confirm, cx: &mut ViewContext<Self>) {
-        let api_key = self.api_key.read(cx).text(cx);
-        if api_key.is_empty() {
-            return;
-        }
+    pub(crate) fn clear_api_key(&mut self) {
+        self.api_keys.clear();
+        self.fetched_models.clear();
+    }
 
-        let write_credentials = cx.write_credentials(&self.api_url, "Bearer", api_key.as_bytes());
-        cx.spawn(|_, mut cx| async move {
-            write_credentials.await?;
-            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
-                provider.update_current_as::<_, OpenAiCompletionProvider>(|provider| {
-                    provider.api_key = Some(api_key);
-                });
-            })
-        })
-        .detach_and_log_err(cx);
+    pub(crate) fn api_url(&self) -> &str {
+        &self.api_url
     }
 
-    fn render_api_key_editor(&self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        let settings = ThemeSettings::get_global(cx);
-        let text_style = TextStyle {
+    /// Sets the tokenizer used to count tokens on the client. When unset, tokens are
+    /// counted with tiktoken.
+    pub fn set_tokenizer(&mut self, tokenizer: Option<Arc<dyn Tokenizer>>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Reasoning models can be silent for a long time before they respond. When set,
+    /// streams from those models tolerate at least this much silence before the
+    /// low speed timeout aborts them.
+    pub fn set_reasoning_idle_timeout(&mut self, reasoning_idle_timeout: Option<Duration>) {
+        self.reasoning_idle_timeout = reasoning_idle_timeout;
+    }
+
+    /// Sets the `max_tokens` applied to requests for each model (keyed by model
+    /// name) that don't specify their own. A `None` entry leaves that model uncapped,
+    /// as does having no entry at all.
+    pub fn set_default_max_tokens(&mut self, default_max_tokens: HashMap<String, Option<u32>>) {
+        self.default_max_tokens = default_max_tokens;
+    }
+
+    /// Sets the low speed timeout for each model (keyed by model name) that needs a
+    /// different one than the provider's, e.g. because it streams more slowly.
+    pub fn set_model_low_speed_timeouts(
+        &mut self,
+        model_low_speed_timeouts: HashMap<String, Duration>,
+    ) {
+        self.model_low_speed_timeouts = model_low_speed_timeouts;
+    }
+
+    pub fn export_config(&self) -> ProviderConfigSnapshot {
+        ProviderConfigSnapshot {
+            model: self.model.clone(),
+            api_url: self.api_url.clone(),
+            low_speed_timeout_secs: self.low_speed_timeout.map(|timeout| timeout.as_secs()),
+            reasoning_idle_timeout_secs: self
+                .reasoning_idle_timeout
+                .map(|timeout| timeout.as_secs()),
+            http_version: self.http_version,
+            available_models: self
+                .available_models()
+                .into_iter()
+                .filter_map(|model| match model {
+                    LanguageModel::OpenAi(model) => Some(model),
+                    _ => None,
+                })
+                .collect(),
+            settings_version: self.settings_version,
+            authenticated: !self.active_api_keys().is_empty(),
+        }
+    }
+
+    fn max_tokens_for(&self, model: &OpenAiModel, requested: Option<u32>) -> Option<u32> {
+        requested.or_else(|| {
+            self.default_max_tokens
+                .get(model_name(model))
+                .copied()
+                .flatten()
+        })
+    }
+
+    fn low_speed_timeout_for(&self, model: &OpenAiModel) -> Option<Duration> {
+        let low_speed_timeout = self
+            .model_low_speed_timeouts
+            .get(model_name(model))
+            .copied()
+            .or(self.low_speed_timeout);
+        match (low_speed_timeout, self.reasoning_idle_timeout) {
+            (Some(low_speed_timeout), Some(reasoning_idle_timeout))
+                if model.is_reasoning_model() =>
+            {
+                Some(low_speed_timeout.max(reasoning_idle_timeout))
+            }
+            (low_speed_timeout, _) => low_speed_timeout,
+        }
+    }
+
+    /// Builds the request that completing `request` with the chat completions API
+    /// would send, after all of the provider's transformations and checks, without
+    /// sending it.
+    pub fn build_effective_request(&self, mut request: LanguageModelRequest) -> Result<Request> {
+        self.truncate_to_fit(&mut request);
+        self.check_request(&request)?;
+        Ok(self.to_open_ai_request(request)?)
+    }
+
+    /// Checks that `request` can be sent, before it is converted for either API.
+    fn check_request(&self, request: &LanguageModelRequest) -> Result<(), CompletionError> {
+        validate_tool_choice(request)?;
+        if request.stop.len() > MAX_STOP_SEQUENCES {
+            return Err(CompletionError::TooManyStopSequences {
+                count: request.stop.len(),
+                max: MAX_STOP_SEQUENCES,
+            });
+        }
+        if request.stop.iter().any(String::is_empty) {
+            return Err(CompletionError::EmptyStopSequence);
+        }
+        if let Some(budget) = self.token_budget {
+            if self.session_usage.lock().total_tokens() >= budget {
+                return Err(CompletionError::BudgetExceeded { budget });
+            }
+        }
+        self.check_context_window(request)?;
+        self.check_cost(request)
+    }
+
+    /// Checks the estimated cost of `request` against the cost guard, if any.
+    fn check_cost(&self, request: &LanguageModelRequest) -> Result<(), CompletionError> {
+        if self.cost_guard.is_none() || self.token_price.is_none() {
+            return Ok(());
+        }
+        let model = match &request.model {
+            LanguageModel::OpenAi(model) => model,
+            _ => &self.model,
+        };
+        let Some(tokenizer) = self.tokenizer_for(model) else {
+            return Ok(());
+        };
+        let prompt_tokens = count_request_tokens(tokenizer.as_ref(), request);
+        let max_tokens = self.max_tokens_for(model, request.max_tokens).unwrap_or(0);
+        // Only a single choice is requested, since only one can be streamed.
+        self.estimate_cost(prompt_tokens, max_tokens as usize, 1)?;
+        Ok(())
+    }
+
+    /// Checks that the prompt fits in the model's context window, leaving room for
+    /// the `max_tokens` requested, if any. The prompt is counted without the few
+    /// tokens of overhead each message carries, so only requests that certainly
+    /// overflow are rejected.
+    fn check_context_window(&self, request: &LanguageModelRequest) -> Result<(), CompletionError> {
+        let model = match &request.model {
+            LanguageModel::OpenAi(model) => model,
+            _ => &self.model,
+        };
+        let max_tokens = self.max_tokens_for(model, request.max_tokens).unwrap_or(0);
+        let Some(tokenizer) = self.tokenizer_for(model) else {
+            return Ok(());
+        };
+        let prompt_tokens = count_request_tokens(tokenizer.as_ref(), request);
+        let context_window = model.max_token_count();
+        if prompt_tokens + max_tokens as usize > context_window {
+            return Err(CompletionError::ContextWindowExceeded {
+                prompt_tokens,
+                max_tokens,
+                context_window,
+            });
+        }
+        Ok(())
+    }
+
+    /// Drops old messages from `request` with the provider's truncation strategy, if
+    /// any, so that it leaves room in the model's context window for the completion.
+    /// Returns how many messages were dropped.
+    fn truncate_to_fit(&self, request: &mut LanguageModelRequest) -> usize {
+        let Some(strategy) = self.truncation_strategy else {
+            return 0;
+        };
+        let model = match &request.model {
+            LanguageModel::OpenAi(model) => model.clone(),
+            _ => self.model.clone(),
+        };
+        let Some(tokenizer) = self.tokenizer_for(&model) else {
+            return 0;
+        };
+        let max_tokens = self.max_tokens_for(&model, request.max_tokens).unwrap_or(0);
+        let budget = model.max_token_count().saturating_sub(max_tokens as usize);
+        let dropped = truncate_messages(request, tokenizer.as_ref(), budget, strategy);
+        if dropped > 0 {
+            log::info!("dropped {dropped} messages to fit the context window");
+        }
+        dropped
+    }
+
+    /// The tokenizer used to count `model`'s tokens on the client.
+    fn tokenizer_for(&self, model: &OpenAiModel) -> Option<Arc<dyn Tokenizer>> {
+        match self.tokenizer.clone() {
+            Some(tokenizer) => Some(tokenizer),
+            None => TiktokenTokenizer::for_model(tiktoken_model(model))
+                .log_err()
+                .map(|tokenizer| Arc::new(tokenizer) as Arc<dyn Tokenizer>),
+        }
+    }
+
+    fn to_open_ai_request(
+        &self,
+        mut request: LanguageModelRequest,
+    ) -> Result<Request, CompletionError> {
+        let model = match request.model {
+            LanguageModel::OpenAi(model) => model,
+            _ => self.model.clone(),
+        };
+        let max_tokens = self.max_tokens_for(&model, request.max_tokens);
+        let prefill = take_assistant_prefill(&mut request.messages);
+
+        let mut messages = Vec::with_capacity(request.messages.len());
+        let mut system_message_ix = None;
+        for msg in request.messages {
+            match msg.role {
+                Role::User => messages.push(RequestMessage::User {
+                    content: user_message_content(msg.content, msg.images),
+                }),
+                Role::Assistant => {
+                    let tool_calls = msg
+                        .tool_calls
+                        .into_iter()
+                        .map(|tool_call| ToolCall {
+                            id: tool_call.id,
+                            content: ToolCallContent::Function {
+                                function: FunctionContent {
+                                    name: tool_call.name,
+                                    arguments: tool_call.arguments,
+                                },
+                            },
+                        })
+                        .collect::<Vec<_>>();
+                    if tool_calls.is_empty() {
+                        messages.push(RequestMessage::Assistant {
+                            content: Some(msg.content),
+                            tool_calls,
+                        });
+                    } else if msg.content.is_empty() {
+                        messages.push(RequestMessage::Assistant {
+                            content: None,
+                            tool_calls,
+                        });
+                    } else if self.split_tool_call_content {
+                        messages.push(RequestMessage::Assistant {
+                            content: Some(msg.content),
+                            tool_calls: Vec::new(),
+                        });
+                        messages.push(RequestMessage::Assistant {
+                            content: None,
+                            tool_calls,
+                        });
+                    } else {
+                        messages.push(RequestMessage::Assistant {
+                            content: Some(msg.content),
+                            tool_calls,
+                        });
+                    }
+                }
+                Role::System => {
+                    if self.merge_system_messages {
+                        if let Some(RequestMessage::System { content }) =
+                            system_message_ix.and_then(|ix| messages.get_mut(ix))
+                        {
+                            content.push('\n');
+                            content.push_str(&msg.content);
+                            continue;
+                        }
+                    }
+                    if system_message_ix.is_none() {
+                        system_message_ix = Some(messages.len());
+                    }
+                    messages.push(RequestMessage::System {
+                        content: msg.content,
+                    });
+                }
+                Role::Tool => messages.push(RequestMessage::Tool {
+                    content: msg.content,
+                    tool_call_id: msg.tool_call_id.unwrap_or_default(),
+                }),
+            }
+        }
+        if let Some(prefill) = prefill {
+            continue_assistant_message(&mut messages, &prefill, self.assistant_prefill);
+        }
+
+        if request.top_p.is_some() && request.temperature != 1. {
+            log::warn!(
+                "both temperature and top_p are set; OpenAI recommends adjusting only one of them"
+            );
+        }
+
+        let mut open_ai_request = Request {
+            model,
+            messages,
+            stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            stop: request.stop,
+            temperature: clamp_temperature(request.temperature),
+            max_tokens,
+            tools: request
+                .tools
+                .into_iter()
+                .map(open_ai_tool_definition)
+                .collect::<Result<_, _>>()?,
+            tool_choice: request.tool_choice.map(open_ai_tool_choice),
+            top_p: request.top_p,
+            frequency_penalty: request.frequency_penalty,
+            presence_penalty: request.presence_penalty,
+            response_format: request.response_format.map(|format| match format {
+                LanguageModelResponseFormat::Text => ResponseFormat::Text,
+                LanguageModelResponseFormat::JsonObject => ResponseFormat::JsonObject,
+            }),
+            seed: request.seed,
+            logit_bias: request.logit_bias.map(clamp_logit_bias),
+            logprobs: request.logprobs || request.top_logprobs.is_some(),
+            top_logprobs: request
+                .top_logprobs
+                .map(|top_logprobs| top_logprobs.min(MAX_TOP_LOGPROBS)),
+        };
+        if let Some(request_transform) = &self.request_transform {
+            request_transform(&mut open_ai_request);
+        }
+        validate_tool_call_ids(&open_ai_request.messages)?;
+        if open_ai_request.response_format == Some(ResponseFormat::JsonObject)
+            && !mentions_json(&open_ai_request.messages)
+        {
+            return Err(CompletionError::JsonModeWithoutJsonPrompt);
+        }
+        Ok(open_ai_request)
+    }
+
+    /// Builds a request for the Responses API, which is only sent text and the tool
+    /// calls made earlier in the conversation. Requests that offer tools or attach
+    /// images are rejected rather than sent without them, as are Azure deployments,
+    /// which don't serve the API.
+    fn to_responses_request(
+        &self,
+        request: LanguageModelRequest,
+    ) -> Result<ResponsesRequest, CompletionError> {
+        if self.azure_deployment.is_some() {
+            return Err(CompletionError::UnsupportedContent(
+                "Azure deployments don't serve the Responses API".into(),
+            ));
+        }
+        if !request.tools.is_empty() || request.tool_choice.is_some() {
+            return Err(CompletionError::UnsupportedContent(
+                "tools can't be offered through the Responses API".into(),
+            ));
+        }
+        if request
+            .messages
+            .iter()
+            .any(|message| !message.images.is_empty())
+        {
+            return Err(CompletionError::UnsupportedContent(
+                "images can't be sent through the Responses API".into(),
+            ));
+        }
+        crate::anthropic::validate_tool_call_ids(&request.messages)?;
+
+        let model = match request.model {
+            LanguageModel::OpenAi(model) => model,
+            _ => self.model.clone(),
+        };
+        let max_output_tokens = self.max_tokens_for(&model, request.max_tokens);
+
+        let mut input = Vec::with_capacity(request.messages.len());
+        for msg in request.messages {
+            if msg.role == Role::Tool {
+                input.push(ResponseInputItem::FunctionCallOutput {
+                    call_id: msg.tool_call_id.unwrap_or_default(),
+                    output: msg.content,
+                });
+                continue;
+            }
+            if !msg.content.is_empty() || msg.tool_calls.is_empty() {
+                input.push(ResponseInputItem::Message {
+                    role: msg.role.into(),
+                    content: msg.content,
+                });
+            }
+            input.extend(msg.tool_calls.into_iter().map(|tool_call| {
+                ResponseInputItem::FunctionCall {
+                    call_id: tool_call.id,
+                    name: tool_call.name,
+                    arguments: tool_call.arguments,
+                }
+            }));
+        }
+        if self.assistant_prefill == AssistantPrefill::Prompt
+            && matches!(
+                input.last(),
+                Some(ResponseInputItem::Message {
+                    role: open_ai::Role::Assistant,
+                    content,
+                }) if !content.is_empty()
+            )
+        {
+            input.push(ResponseInputItem::Message {
+                role: open_ai::Role::User,
+                content: CONTINUATION_PROMPT.into(),
+            });
+        }
+
+        Ok(ResponsesRequest {
+            model,
+            input,
+            stream: true,
+            temperature: clamp_temperature(request.temperature),
+            max_output_tokens,
+        })
+    }
+
+    /// The configuration of a request made with `api_key`, if one is given, and
+    /// otherwise with [`Self::active_api_keys`].
+    fn request_config(
+        &self,
+        model: &OpenAiModel,
+        request_id: String,
+        api_key: Option<String>,
+    ) -> RequestConfig {
+        RequestConfig {
+            http_client: self.http_client.clone(),
+            api_keys: match api_key {
+                Some(api_key) => vec![api_key],
+                None => self.active_api_keys().to_vec(),
+            },
+            key_rotation: self.key_rotation.clone(),
+            organization_id: self.organization_id.clone(),
+            api_url: self.api_url.clone(),
+            low_speed_timeout: self.low_speed_timeout_for(model),
+            http_version: self.http_version,
+            incomplete_tool_call_behavior: self.incomplete_tool_call_behavior,
+            request_id,
+            max_retries: self.max_retries,
+            retry_base_delay: self.retry_base_delay,
+            azure_deployment: self.azure_deployment.clone(),
+            auth_header: self.auth_header.clone(),
+            recorder: self.completion_recorder.clone(),
+            assistant_prefill: self.assistant_prefill,
+            non_streaming_fallback: self.non_streaming_fallback,
+        }
+    }
+
+    fn dispatch(
+        &self,
+        request: LanguageModelRequest,
+        request_id: String,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        let model = match &request.model {
+            LanguageModel::OpenAi(model) => model.clone(),
+            _ => self.model.clone(),
+        };
+        let config = self.request_config(&model, request_id, api_key);
+        match self.api {
+            OpenAiApi::ChatCompletions => {
+                let request = match self.to_open_ai_request(request) {
+                    Ok(request) => request,
+                    Err(error) => return futures::future::ready(Err(error.into())).boxed(),
+                };
+                if self.max_continuations > 0 {
+                    Self::stream_chat_completion_with_continuations(
+                        request,
+                        config,
+                        self.max_continuations,
+                    )
+                } else {
+                    Self::stream_chat_completion(request, config)
+                }
+            }
+            OpenAiApi::Responses => match self.to_responses_request(request) {
+                Ok(request) => Self::stream_response(request, config),
+                Err(error) => futures::future::ready(Err(error.into())).boxed(),
+            },
+        }
+    }
+
+    fn stream_chat_completion(
+        request: Request,
+        config: RequestConfig,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        async move {
+            let uri = match &config.azure_deployment {
+                Some(deployment) => deployment.chat_completions_url(&config.api_url)?,
+                None => chat_completions_url(&config.api_url)?,
+            };
+            let send = |stream: bool| {
+                let (uri, request, config) = (&uri, &request, &config);
+                with_retries(config, move |api_key| async move {
+                    let auth = match &config.azure_deployment {
+                        Some(_) => ApiAuth::ApiKey(&api_key),
+                        None => config
+                            .auth_header
+                            .auth(&api_key, config.organization_id.as_deref()),
+                    };
+                    let (client, uri, request) =
+                        (config.http_client.as_ref(), uri.clone(), request.clone());
+                    let request_id = Some(config.request_id.as_str());
+                    if stream {
+                        stream_completion_with_auth(
+                            client,
+                            uri,
+                            auth,
+                            request,
+                            config.low_speed_timeout,
+                            config.http_version,
+                            request_id,
+                        )
+                        .await
+                    } else {
+                        complete_with_auth(
+                            client,
+                            uri,
+                            auth,
+                            request,
+                            config.low_speed_timeout,
+                            config.http_version,
+                            request_id,
+                        )
+                        .await
+                    }
+                })
+            };
+            let response = match send(true).await {
+                Ok((response, retries)) => Ok((response, retries, false)),
+                Err(error) if config.non_streaming_fallback && is_server_error(&error) => {
+                    log::warn!("streaming completion failed, requesting it whole: {error:?}");
+                    send(false)
+                        .await
+                        .map(|(response, retries)| (response, retries, true))
+                }
+                Err(error) => Err(error),
+            };
+            let (response, retries, non_streaming) = response.map_err(|error| {
+                if let Some(recorder) = &config.recorder {
+                    recorder.record_failure(config.request_id.clone(), &request, &error);
+                }
+                error
+            })?;
+            let request_id = response.request_id.clone().unwrap_or(config.request_id);
+            let mut stream_end = StreamEnd {
+                request_id: Some(request_id.clone()),
+                ..Default::default()
+            };
+            stream_end.path.retries = retries;
+            stream_end.path.non_streaming = non_streaming;
+            let response = match &config.recorder {
+                Some(recorder) => recorder.record(request_id.clone(), &request, response),
+                None => response.boxed(),
+            };
+            let stream =
+                chat_completion_events(response, stream_end, config.incomplete_tool_call_behavior)
+                    .map_err(move |error| with_request_id(error, &request_id))
+                    .boxed();
+            Ok(stream)
+        }
+        .boxed()
+    }
+
+    fn stream_chat_completion_with_continuations(
+        request: Request,
+        config: RequestConfig,
+        max_continuations: usize,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        struct State {
+            events: BoxStream<'static, Result<CompletionEvent>>,
+            request: Request,
+            config: RequestConfig,
+            prefix: String,
+            continuations_left: usize,
+            /// The length of `prefix` when the last continuation was requested. A
+            /// continuation is only requested if the prefix has grown since, so a
+            /// request that keeps failing immediately isn't retried in a loop.
+            continued_at_len: usize,
+        }
+
+        async move {
+            let events = Self::stream_chat_completion(request.clone(), config.clone()).await?;
+            let state = State {
+                events,
+                request,
+                config,
+                prefix: String::new(),
+                continuations_left: max_continuations,
+                continued_at_len: 0,
+            };
+            let events = stream::unfold(state, move |mut state| async move {
+                loop {
+                    match state.events.next().await? {
+                        Ok(CompletionEvent::Text(text)) => {
+                            state.prefix.push_str(&text);
+                            return Some((Ok(CompletionEvent::Text(text)), state));
+                        }
+                        Ok(CompletionEvent::StreamEnd(mut stream_end)) => {
+                            stream_end.path.continuations =
+                                (max_continuations - state.continuations_left) as u32;
+                            return Some((Ok(CompletionEvent::StreamEnd(stream_end)), state));
+                        }
+                        Err(error)
+                            if state.continuations_left > 0
+                                && state.prefix.len() > state.continued_at_len =>
+                        {
+                            state.continuations_left -= 1;
+                            state.continued_at_len = state.prefix.len();
+                            log::warn!("completion failed mid-stream, continuing it: {error:?}");
+
+                            let mut request = state.request.clone();
+                            continue_assistant_message(
+                                &mut request.messages,
+                                &state.prefix,
+                                state.config.assistant_prefill,
+                            );
+                            match Self::stream_chat_completion(request, state.config.clone()).await
+                            {
+                                Ok(events) => state.events = events,
+                                Err(continuation_error) => {
+                                    log::error!(
+                                        "failed to continue completion: {continuation_error:?}"
+                                    );
+                                    return Some((Err(error), state));
+                                }
+                            }
+                        }
+                        event => return Some((event, state)),
+                    }
+                }
+            });
+            Ok(events.boxed())
+        }
+        .boxed()
+    }
+
+    fn stream_response(
+        request: ResponsesRequest,
+        config: RequestConfig,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        async move {
+            let (response, retries) = with_retries(&config, |api_key| {
+                let request = request.clone();
+                let config = &config;
+                async move {
+                    stream_response(
+                        config.http_client.as_ref(),
+                        &config.api_url,
+                        config
+                            .auth_header
+                            .auth(&api_key, config.organization_id.as_deref()),
+                        request,
+                        config.low_speed_timeout,
+                        config.http_version,
+                        Some(&config.request_id),
+                    )
+                    .await
+                }
+            })
+            .await?;
+            let request_id = response.request_id.clone().unwrap_or(config.request_id);
+            let mut stream_end = StreamEnd {
+                request_id: Some(request_id.clone()),
+                ..Default::default()
+            };
+            stream_end.path.retries = retries;
+            let stream = response
+                .map(Some)
+                .chain(stream::once(async { None }))
+                .flat_map(move |event| {
+                    let event = match event {
+                        Some(Ok(ResponsesStreamEvent::OutputTextDelta { delta, .. })) => {
+                            Some(Ok(CompletionEvent::Text(delta)))
+                        }
+                        Some(Ok(ResponsesStreamEvent::Completed { response })) => {
+                            stream_end.finish_reason = response.status;
+                            stream_end.usage = response.usage.map(|usage| TokenUsage {
+                                prompt_tokens: usage.input_tokens,
+                                completion_tokens: usage.output_tokens,
+                                ..Default::default()
+                            });
+                            None
+                        }
+                        Some(Ok(ResponsesStreamEvent::Failed { response })) => {
+                            stream_end.finish_reason = response.status;
+                            let message = response
+                                .error
+                                .map_or_else(|| "unknown error".into(), |error| error.message);
+                            Some(Err(anyhow!("OpenAI response failed: {message}")))
+                        }
+                        Some(Ok(ResponsesStreamEvent::Error { message, .. })) => {
+                            Some(Err(anyhow!("OpenAI response failed: {message}")))
+                        }
+                        Some(Ok(ResponsesStreamEvent::Other)) => None,
+                        Some(Err(error)) => Some(Err(error)),
+                        None => Some(Ok(CompletionEvent::StreamEnd(mem::take(&mut stream_end)))),
+                    };
+                    stream::iter(event)
+                })
+                .map_err(move |error| with_request_id(error, &request_id))
+                .boxed();
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+impl LanguageModelCompletionProvider for OpenAiCompletionProvider {
+    fn available_models(&self) -> Vec<LanguageModel> {
+        if self.available_models_from_settings.is_empty() && self.fetched_models.is_empty() {
+            let available_models = if matches!(self.model, OpenAiModel::Custom { .. }) {
+                vec![self.model.clone()]
+            } else {
+                OpenAiModel::iter()
+                    .filter(|model| !matches!(model, OpenAiModel::Custom { .. }))
+                    .collect()
+            };
+            available_models
+                .into_iter()
+                .map(LanguageModel::OpenAi)
+                .collect()
+        } else {
+            // A model configured in the settings takes the place of the fetched one
+            // of the same name, since it may e.g. set a different token limit.
+            let fetched_models = self.fetched_models.iter().filter(|fetched| {
+                !self
+                    .available_models_from_settings
+                    .iter()
+                    .any(|model| model_name(model) == model_name(fetched))
+            });
+            self.available_models_from_settings
+                .iter()
+                .chain(fetched_models)
+                .cloned()
+                .map(LanguageModel::OpenAi)
+                .collect()
+        }
+    }
+
+    fn settings_version(&self) -> usize {
+        self.settings_version
+    }
+
+    fn is_authenticated(&self) -> bool {
+        !self.active_api_keys().is_empty()
+    }
+
+    fn set_api_key_override(&mut self, api_key: Option<String>) {
+        self.api_key_override = api_key;
+    }
+
+    /// Resolves to how many models are offered after the reload, including those
+    /// configured in the settings.
+    fn reload_models(&self, cx: &AppContext) -> Task<Result<usize>> {
+        let fetch_models = self.fetch_models(cx);
+        cx.spawn(|cx| async move {
+            fetch_models.await?;
+            cx.update(|cx| CompletionProvider::global(cx).available_models().len())
+        })
+    }
+
+    fn moderate(&self, text: String, cx: &AppContext) -> Task<Result<ModerationResult>> {
+        cx.background_executor().spawn(self.moderation(text, None))
+    }
+
+    /// Azure deployments and OpenAI-compatible APIs have no moderation endpoint, so
+    /// their input is sent unmoderated.
+    fn set_moderate_input(&mut self, moderate_input: bool) {
+        self.moderate_input = moderate_input;
+    }
+
+    fn check_connection(&self, cx: &AppContext) -> Task<Result<ConnectionStatus>> {
+        let Some(api_key) = self.active_api_keys().first().cloned() else {
+            return Task::ready(Ok(ConnectionStatus::Unauthorized));
+        };
+
+        // Azure deployments don't list their models, and not every OpenAI-compatible
+        // API does, so those are checked by completing a single token instead.
+        let complete_one_token = self.dispatch(
+            LanguageModelRequest {
+                model: LanguageModel::OpenAi(self.model.clone()),
+                messages: vec![LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "Hi".into(),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: false,
+                }],
+                max_tokens: Some(1),
+                ..Default::default()
+            },
+            Uuid::new_v4().to_string(),
+            None,
+        );
+        let lists_models = self.azure_deployment.is_none();
+        let http_client = self.http_client.clone();
+        let api_url = self.api_url.clone();
+        let organization_id = self.organization_id.clone();
+        let auth_header = self.auth_header.clone();
+        let timeout = cx.background_executor().timer(CONNECTION_CHECK_TIMEOUT);
+        cx.background_executor().spawn(async move {
+            let check = async move {
+                if lists_models {
+                    // Listing models is cheap, and doesn't spend any tokens.
+                    let auth = auth_header.auth(&api_key, organization_id.as_deref());
+                    match list_models(http_client.as_ref(), &api_url, auth, None).await {
+                        Ok(_) => return ConnectionStatus::Ok,
+                        Err(error) if !is_not_found(&error) => return connection_status(&error),
+                        Err(_) => {}
+                    }
+                }
+                match complete_one_token.await {
+                    Ok(_) => ConnectionStatus::Ok,
+                    Err(error) => connection_status(&error),
+                }
+            }
+            .boxed();
+            let status = match future::select(check, timeout).await {
+                Either::Left((status, _)) => status,
+                Either::Right(_) => ConnectionStatus::Unreachable,
+            };
+            Ok(status)
+        })
+    }
+
+    fn authenticate(&self, cx: &AppContext) -> Task<Result<()>> {
+        if self.is_authenticated() {
+            Task::ready(Ok(()))
+        } else {
+            let api_url = self.api_url.clone();
+            let api_key_var = self.api_key_var();
+            let api_key_source = self.api_key_source;
+            cx.spawn(|mut cx| async move {
+                let mut api_keys = Vec::new();
+                if api_key_source.uses_env() {
+                    api_keys = api_keys_from_env(api_key_var);
+                    if api_keys.is_empty() {
+                        api_keys.extend(api_key_from_file_env(api_key_var).await?);
+                    }
+                }
+                if api_keys.is_empty() && api_key_source.uses_keyring() {
+                    let credentials = cx.update(|cx| cx.read_credentials(&api_url))?.await?;
+                    if let Some((_, api_key)) = credentials {
+                        api_keys.push(String::from_utf8(api_key)?);
+                    }
+                }
+                if api_keys.is_empty() {
+                    return Err(CompletionError::Authentication {
+                        message: missing_api_key_message(api_key_source, api_key_var),
+                    }
+                    .into());
+                }
+                cx.update_global::<CompletionProvider, _>(|provider, cx| {
+                    let authenticated = provider.update_current_as::<_, Self>(|provider| {
+                        let priming = provider.set_api_keys(api_keys);
+                        let fetch_models = provider
+                            .fetch_models_after_authentication
+                            .then(|| provider.fetch_models(cx));
+                        (priming, fetch_models)
+                    });
+                    let Some((priming, fetch_models)) = authenticated else {
+                        return;
+                    };
+                    if let Some(priming) = priming {
+                        cx.background_executor().spawn(priming).detach();
+                    }
+                    if let Some(fetch_models) = fetch_models {
+                        cx.background_executor()
+                            .spawn(async move {
+                                if let Err(error) = fetch_models.await {
+                                    log::info!(
+                                        "failed to fetch OpenAI models, offering the built-in ones: {error:?}"
+                                    );
+                                }
+                            })
+                            .detach();
+                    }
+                })
+            })
+        }
+    }
+
+    /// Forgets the key, deleting it from the keyring if keys are read from there.
+    /// Keys set in the environment can't be reset, and unless keys are only read
+    /// from the keyring, they're used again the next time the provider
+    /// authenticates.
+    fn reset_credentials(&self, cx: &AppContext) -> Task<Result<()>> {
+        let delete_credentials = self
+            .api_key_source
+            .uses_keyring()
+            .then(|| cx.delete_credentials(&self.api_url));
+        let api_key_var = self.api_key_var();
+        if self.api_key_source == ApiKeySource::Auto && !api_keys_from_env(api_key_var).is_empty() {
+            log::warn!(
+                "{api_key_var} is set, so it will be used instead of a key saved in the keyring. \
+                 Set `api_key_source` to `keyring` to use a saved key instead."
+            );
+        }
+        cx.spawn(|mut cx| async move {
+            if let Some(delete_credentials) = delete_credentials {
+                delete_credentials.await.log_err();
+            }
+            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
+                provider.update_current_as::<_, Self>(|provider| provider.clear_api_key());
+            })
+        })
+    }
+
+    fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
+        let provider_name = if self.azure_deployment.is_some() {
+            "Azure OpenAI"
+        } else {
+            "OpenAI"
+        };
+        self.authentication_prompt_for(provider_name.into(), Some(self.api_key_var()), cx)
+    }
+
+    fn model(&self) -> LanguageModel {
+        LanguageModel::OpenAi(self.model.clone())
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        if let Some(tokenizer) = self.tokenizer.clone() {
+            cx.background_executor()
+                .spawn(async move { Ok(count_request_tokens(tokenizer.as_ref(), &request)) })
+                .boxed()
+        } else {
+            count_open_ai_tokens(request, cx.background_executor())
+        }
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.stream_completion_with_api_key(request, None)
+    }
+
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_completion_events_with_request_id(request, Uuid::new_v4().to_string())
+    }
+
+    fn stream_completion_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.stream_completion_events_with_api_key(request, api_key)
+            .map_ok(|events| {
+                events
+                    .try_filter_map(|event| async move {
+                        match event {
+                            CompletionEvent::Text(text) => Ok(Some(text)),
+                            CompletionEvent::Reasoning(_)
+                            | CompletionEvent::ToolCallDelta { .. }
+                            | CompletionEvent::ToolCall { .. }
+                            | CompletionEvent::Cost(_)
+                            | CompletionEvent::Logprobs(_)
+                            | CompletionEvent::StreamEnd(_) => Ok(None),
+                        }
+                    })
+                    .boxed()
+            })
+            .boxed()
+    }
+
+    fn stream_completion_events_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_events(request, Uuid::new_v4().to_string(), api_key)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+impl OpenAiCompletionProvider {
+    /// Like [`LanguageModelCompletionProvider::stream_completion_events`], but sends
+    /// `request_id` with the request rather than generating one. The id is reported
+    /// in the stream's [`StreamEnd`] and in its errors, unless the server returns
+    /// an id of its own.
+    pub fn stream_completion_events_with_request_id(
+        &self,
+        request: LanguageModelRequest,
+        request_id: String,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.stream_events(request, request_id, None)
+    }
+
+    /// Streams the events of a completion sent with `request_id`, and made with
+    /// `api_key` if one is given.
+    fn stream_events(
+        &self,
+        mut request: LanguageModelRequest,
+        request_id: String,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        let dropped_messages = self.truncate_to_fit(&mut request);
+        let metrics_recorder = self.on_completion_metrics.clone().map(|on_metrics| {
+            let model = match &request.model {
+                LanguageModel::OpenAi(model) => model_name(model),
+                _ => model_name(&self.model),
+            };
+            MetricsRecorder::new(
+                request_id.clone(),
+                model.to_string(),
+                Instant::now(),
+                on_metrics,
+            )
+        });
+        if let Err(error) = self.check_request(&request) {
+            let error = anyhow::Error::from(error);
+            if let Some(metrics_recorder) = metrics_recorder {
+                metrics_recorder.finish(Some(CompletionErrorKind::of(&error)));
+            }
+            return futures::future::ready(Err(error)).boxed();
+        }
+
+        // Everything the completion depends on is read from `self` up front. A
+        // model without a tokenizer is still completed, just without estimates.
+        let cost_estimates = self
+            .token_price
+            .filter(|_| self.stream_cost_estimates)
+            .and_then(|price| {
+                let model = match &request.model {
+                    LanguageModel::OpenAi(model) => model,
+                    _ => &self.model,
+                };
+                Some((price, self.tokenizer_for(model)?))
+            });
+        let moderation = request
+            .messages
+            .iter()
+            .rfind(|message| message.role == Role::User)
+            .filter(|_| self.should_moderate_input())
+            .map(|message| self.moderation(message.content.clone(), api_key.clone()));
+        let stop = request.stop.clone();
+        let response = self.dispatch(request, request_id, api_key);
+        let map_chunk = self.map_chunk.clone();
+        let reasoning_tags = self.reasoning_tags.clone();
+        let empty_response_behavior = self.empty_response_behavior;
+        let session_usage = self.session_usage.clone();
+        let token_price = self.token_price;
+        async move {
+            // Nothing is sent until the input has passed moderation.
+            if let Some(moderation) = moderation {
+                let moderation = moderation.await.and_then(|moderation| {
+                    if moderation.flagged {
+                        Err(CompletionError::Blocked {
+                            categories: moderation.flagged_categories(),
+                        }
+                        .into())
+                    } else {
+                        Ok(())
+                    }
+                });
+                if let Err(error) = moderation {
+                    let error = CompletionError::classify(error);
+                    if let Some(metrics_recorder) = metrics_recorder {
+                        metrics_recorder.finish(Some(CompletionErrorKind::of(&error)));
+                    }
+                    return Err(error);
+                }
+            }
+
+            let response = match response.await {
+                Ok(response) => response,
+                Err(error) => {
+                    let error = CompletionError::classify(error);
+                    if let Some(metrics_recorder) = metrics_recorder {
+                        metrics_recorder.finish(Some(CompletionErrorKind::of(&error)));
+                    }
+                    return Err(error);
+                }
+            };
+            let mut stream = response
+                .inspect_ok(move |event| {
+                    if let CompletionEvent::StreamEnd(stream_end) = event {
+                        session_usage.lock().record(stream_end.usage, token_price);
+                    }
+                })
+                .boxed();
+            if let Some(map_chunk) = map_chunk {
+                stream = stream
+                    .map_ok(move |event| match event {
+                        CompletionEvent::Text(text) => CompletionEvent::Text(map_chunk(text)),
+                        event => event,
+                    })
+                    .boxed();
+            }
+            if dropped_messages > 0 {
+                stream = stream
+                    .map_ok(move |event| match event {
+                        CompletionEvent::StreamEnd(mut stream_end) => {
+                            stream_end.path.dropped_messages = dropped_messages;
+                            CompletionEvent::StreamEnd(stream_end)
+                        }
+                        event => event,
+                    })
+                    .boxed();
+            }
+            if !stop.is_empty() {
+                stream = hold_back_stop_sequences(stream, stop);
+            }
+            if let Some(reasoning_tags) = reasoning_tags {
+                stream = extract_reasoning(stream, reasoning_tags);
+            }
+            if let Some((price, tokenizer)) = cost_estimates {
+                stream = with_cost_estimates(stream, tokenizer, price);
+            }
+            stream = match empty_response_behavior {
+                EmptyResponseBehavior::ReturnEmpty => stream,
+                EmptyResponseBehavior::Error => error_on_empty_response(stream),
+            };
+            stream = stream.map_err(CompletionError::classify).boxed();
+            if let Some(metrics_recorder) = metrics_recorder {
+                stream = record_metrics(stream, metrics_recorder);
+            }
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+/// Turns the events streamed back from the Chat Completions API into
+/// [`CompletionEvent`]s, assembling tool calls from their deltas and ending with
+/// `stream_end`, updated with what the events reported.
+pub(crate) fn chat_completion_events(
+    response: impl 'static + Send + Stream<Item = Result<ResponseStreamEvent>>,
+    mut stream_end: StreamEnd,
+    incomplete_tool_call_behavior: IncompleteToolCallBehavior,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut tool_calls = Vec::<LanguageModelToolCall>::new();
+    response
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |response| {
+            let mut events = Vec::new();
+            match response {
+                Some(Ok(mut response)) => {
+                    if let Some(usage) = response.usage.take() {
+                        stream_end.usage = Some(TokenUsage {
+                            prompt_tokens: usage.prompt_tokens,
+                            completion_tokens: usage.completion_tokens,
+                            ..Default::default()
+                        });
+                    }
+                    if let Some(system_fingerprint) = response.system_fingerprint.take() {
+                        stream_end.system_fingerprint = Some(system_fingerprint);
+                    }
+                    if let Some(choice) = response.choices.pop() {
+                        if let Some(finish_reason) = choice.finish_reason {
+                            stream_end.finish_reason = Some(finish_reason);
+                        }
+                        if let Some(content) = choice.delta.content {
+                            events.push(Ok(CompletionEvent::Text(content)));
+                        }
+                        let logprobs = choice.logprobs.and_then(|logprobs| logprobs.content);
+                        if let Some(logprobs) = logprobs.filter(|logprobs| !logprobs.is_empty()) {
+                            events.push(Ok(CompletionEvent::Logprobs(
+                                logprobs.into_iter().map(token_logprob).collect(),
+                            )));
+                        }
+                        for chunk in choice.delta.tool_calls.into_iter().flatten() {
+                            if tool_calls.len() <= chunk.index {
+                                tool_calls.resize_with(chunk.index + 1, Default::default);
+                            }
+                            let tool_call = &mut tool_calls[chunk.index];
+                            let (name, arguments_fragment) = chunk
+                                .function
+                                .map(|function| {
+                                    (function.name, function.arguments.unwrap_or_default())
+                                })
+                                .unwrap_or_default();
+                            if let Some(id) = &chunk.id {
+                                tool_call.id.clone_from(id);
+                            }
+                            if let Some(name) = &name {
+                                tool_call.name.push_str(name);
+                            }
+                            tool_call.arguments.push_str(&arguments_fragment);
+                            events.push(Ok(CompletionEvent::ToolCallDelta {
+                                index: chunk.index,
+                                id: chunk.id,
+                                name,
+                                arguments_fragment,
+                            }));
+                        }
+                    }
+                }
+                Some(Err(error)) => events.push(Err(error)),
+                None => {
+                    for tool_call in tool_calls.drain(..) {
+                        let is_complete =
+                            serde_json::from_str::<serde_json::Value>(&tool_call.arguments).is_ok();
+                        if is_complete
+                            || incomplete_tool_call_behavior
+                                == IncompleteToolCallBehavior::EmitIncomplete
+                        {
+                            events.push(Ok(CompletionEvent::ToolCall {
+                                tool_call,
+                                is_complete,
+                            }));
+                        } else {
+                            events.push(Err(CompletionError::IncompleteToolCall {
+                                id: tool_call.id,
+                                name: tool_call.name,
+                            }
+                            .into()));
+                        }
+                    }
+                    events.push(Ok(CompletionEvent::StreamEnd(mem::take(&mut stream_end))))
+                }
+            }
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+/// Removes the last of `messages` if it's a partial assistant message to be
+/// continued, returning its text.
+fn take_assistant_prefill(messages: &mut Vec<LanguageModelRequestMessage>) -> Option<String> {
+    let last = messages.last()?;
+    if last.role == Role::Assistant && last.tool_calls.is_empty() && !last.content.is_empty() {
+        messages.pop().map(|message| message.content)
+    } else {
+        None
+    }
+}
+
+/// Has the model continue its response from `prefix`, which is appended to the
+/// partial assistant message `messages` already end with, if there is one, so
+/// that repeated continuations don't pile up messages.
+fn continue_assistant_message(
+    messages: &mut Vec<RequestMessage>,
+    prefix: &str,
+    prefill: AssistantPrefill,
+) {
+    if prefill == AssistantPrefill::Prompt {
+        if let Some(RequestMessage::User { content }) = messages.last() {
+            if *content == MessageContent::from(CONTINUATION_PROMPT) {
+                messages.pop();
+            }
+        }
+    }
+    match messages.last_mut() {
+        Some(RequestMessage::Assistant {
+            content: Some(content),
+            tool_calls,
+        }) if tool_calls.is_empty() => content.push_str(prefix),
+        _ => messages.push(RequestMessage::Assistant {
+            content: Some(prefix.into()),
+            tool_calls: Vec::new(),
+        }),
+    }
+    if prefill == AssistantPrefill::Prompt {
+        messages.push(RequestMessage::User {
+            content: CONTINUATION_PROMPT.into(),
+        });
+    }
+}
+
+fn token_logprob(logprob: open_ai::TokenLogprob) -> TokenLogprob {
+    TokenLogprob {
+        token: logprob.token,
+        logprob: logprob.logprob,
+        top_logprobs: logprob
+            .top_logprobs
+            .into_iter()
+            .map(|top| (top.token, top.logprob))
+            .collect(),
+    }
+}
+
+/// Adds `request_id` to the message of `error`, so that it's reported wherever
+/// the error is.
+fn with_request_id(error: anyhow::Error, request_id: &str) -> anyhow::Error {
+    let message = format!("{error} (request id: {request_id})");
+    error.context(message)
+}
+
+/// Clamps `temperature` into the range OpenAI accepts, rather than letting the
+/// request fail.
+fn clamp_temperature(temperature: f32) -> f32 {
+    if temperature.is_nan() {
+        log::warn!("temperature is NaN, using the default of 1.0");
+        1.
+    } else if !(0. ..=MAX_TEMPERATURE).contains(&temperature) {
+        let clamped = temperature.clamp(0., MAX_TEMPERATURE);
+        log::warn!("temperature {temperature} is out of range, using {clamped}");
+        clamped
+    } else {
+        temperature
+    }
+}
+
+/// Clamps each bias into the range OpenAI accepts, rather than letting the request
+/// fail.
+fn clamp_logit_bias(logit_bias: StdHashMap<u32, i32>) -> StdHashMap<u32, i32> {
+    logit_bias
+        .into_iter()
+        .map(|(token, bias)| (token, bias.clamp(-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS)))
+        .collect()
+}
+
+/// Builds a [`LanguageModelRequest::logit_bias`] that biases each of the tokens that
+/// `words` are encoded as by `model` by `bias`. Words are encoded differently when
+/// they follow a space, so callers usually want to include both `"word"` and
+/// `" word"`.
+pub fn open_ai_logit_bias<'a>(
+    model: &OpenAiModel,
+    words: impl IntoIterator<Item = &'a str>,
+    bias: i32,
+) -> Result<StdHashMap<u32, i32>> {
+    let tokenizer = TiktokenTokenizer::for_model(tiktoken_model(model))?;
+    Ok(words
+        .into_iter()
+        .flat_map(|word| tokenizer.encode(word))
+        .map(|token| (token, bias))
+        .collect())
+}
+
+/// Checks that a [`LanguageModelToolChoice::Specific`] choice names one of the
+/// request's tools.
+fn validate_tool_choice(request: &LanguageModelRequest) -> Result<(), CompletionError> {
+    match &request.tool_choice {
+        Some(LanguageModelToolChoice::Specific(name))
+            if !request.tools.iter().any(|tool| &tool.name == name) =>
+        {
+            Err(CompletionError::UnknownToolChoice {
+                name: name.clone(),
+                declared: request.tools.iter().map(|tool| tool.name.clone()).collect(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that tool call ids are unique, and that every tool result follows the
+/// tool call it answers.
+fn validate_tool_call_ids(messages: &[RequestMessage]) -> Result<(), CompletionError> {
+    let mut tool_call_ids = HashSet::default();
+    for message in messages {
+        match message {
+            RequestMessage::Assistant { tool_calls, .. } => {
+                for tool_call in tool_calls {
+                    if !tool_call_ids.insert(tool_call.id.as_str()) {
+                        return Err(CompletionError::DuplicateToolCallId {
+                            id: tool_call.id.clone(),
+                        });
+                    }
+                }
+            }
+            RequestMessage::Tool { tool_call_id, .. } => {
+                if !tool_call_ids.contains(tool_call_id.as_str()) {
+                    return Err(CompletionError::OrphanedToolResult {
+                        tool_call_id: tool_call_id.clone(),
+                    });
+                }
+            }
+            RequestMessage::User { .. } | RequestMessage::System { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+/// OpenAI takes a tool's parameters as a JSON schema object, so tools whose schema
+/// is anything else, such as `true`, can't be sent. Cloud requests for OpenAI's
+/// models are converted on the server the same way.
+pub fn open_ai_tool_definition(tool: LanguageModelTool) -> Result<ToolDefinition, CompletionError> {
+    let parameters = match tool.parameters {
+        None => None,
+        Some(serde_json::Value::Object(parameters)) => Some(parameters),
+        Some(_) => {
+            return Err(CompletionError::UnsupportedContent(format!(
+                "the parameters of tool {:?} aren't a JSON schema object",
+                tool.name
+            )))
+        }
+    };
+    Ok(ToolDefinition::Function {
+        function: FunctionDefinition {
+            name: tool.name,
+            description: tool.description,
+            parameters,
+        },
+    })
+}
+
+pub fn open_ai_tool_choice(tool_choice: LanguageModelToolChoice) -> ToolChoice {
+    match tool_choice {
+        LanguageModelToolChoice::Auto => ToolChoice::Auto,
+        LanguageModelToolChoice::None => ToolChoice::None,
+        LanguageModelToolChoice::Required => ToolChoice::Required,
+        LanguageModelToolChoice::Specific(name) => ToolChoice::Function { name },
+    }
+}
+
+/// Sends a request by calling `send` until it succeeds or fails with an error that
+/// isn't worth retrying, up to the configured number of retries. Between attempts
+/// it waits as long as the server asked, or else backs off exponentially. Returns
+/// the response along with how many retries it took.
+/// Whether `error` is a server error, which a request that isn't streamed might
+/// avoid.
+fn is_server_error(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<RequestError>()
+        .map_or(false, |error| error.status >= 500)
+}
+
+async fn with_retries<T, F>(
+    config: &RequestConfig,
+    mut send: impl FnMut(String) -> F,
+) -> Result<(T, u32)>
+where
+    F: Future<Output = Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        let api_key = config
+            .key_rotation
+            .lock()
+            .next_key(&config.api_keys, Instant::now())
+            .ok_or_else(|| CompletionError::Authentication {
+                message: "missing api key".into(),
+            })?;
+        let error = match send(api_key.clone()).await {
+            Ok(response) => return Ok((response, retries)),
+            Err(error) => error,
+        };
+        let rate_limited = match error.downcast_ref::<RequestError>() {
+            Some(error) if error.status == 429 => {
+                let cool_down = error.retry_after.unwrap_or(RATE_LIMITED_KEY_COOL_DOWN);
+                config
+                    .key_rotation
+                    .lock()
+                    .cool_down(&api_key, Instant::now() + cool_down);
+                true
+            }
+            _ => false,
+        };
+        let is_retryable = CompletionError::of(&error).map_or(false, |error| error.is_retryable());
+        if !is_retryable || retries >= config.max_retries {
+            return Err(error);
+        }
+        let retry_after = error
+            .downcast_ref::<RequestError>()
+            .and_then(|error| error.retry_after);
+        // When another key isn't rate limited, it can be tried straight away.
+        let delay = if rate_limited
+            && config
+                .key_rotation
+                .lock()
+                .has_usable_key(&config.api_keys, Instant::now())
+        {
+            Duration::ZERO
+        } else {
+            retry_after.unwrap_or_else(|| {
+                config
+                    .retry_base_delay
+                    .saturating_mul(2u32.saturating_pow(retries))
+            })
+        };
+        log::warn!("retrying OpenAI request in {delay:?}: {error}");
+        if !delay.is_zero() {
+            smol::Timer::after(delay).await;
+        }
+        retries += 1;
+    }
+}
+
+/// Which of several API keys the next request is sent with. Requests take turns
+/// with the keys, skipping those that were recently rate limited.
+#[derive(Debug, Default)]
+struct KeyRotation {
+    next: usize,
+    /// When each rate limited key may be used again.
+    cooling_down: HashMap<String, Instant>,
+}
+
+impl KeyRotation {
+    /// The key to send the next request with, if there are any. When every key is
+    /// cooling down, the one that cools down soonest is used anyway.
+    fn next_key(&mut self, keys: &[String], now: Instant) -> Option<String> {
+        if keys.is_empty() {
+            return None;
+        }
+        self.cooling_down.retain(|_, until| *until > now);
+        let start = self.next % keys.len();
+        let ix = (0..keys.len())
+            .map(|offset| (start + offset) % keys.len())
+            .find(|ix| !self.cooling_down.contains_key(&keys[*ix]))
+            .or_else(|| (0..keys.len()).min_by_key(|ix| self.cooling_down.get(&keys[*ix])))?;
+        self.next = ix + 1;
+        Some(keys[ix].clone())
+    }
+
+    fn cool_down(&mut self, key: &str, until: Instant) {
+        self.cooling_down.insert(key.to_string(), until);
+    }
+
+    fn has_usable_key(&self, keys: &[String], now: Instant) -> bool {
+        keys.iter().any(|key| {
+            self.cooling_down
+                .get(key)
+                .map_or(true, |until| *until <= now)
+        })
+    }
+}
+
+/// The keys in the environment variable `name` and in its numbered variants,
+/// `{name}_1`, `{name}_2` and so on, up to the first that isn't set.
+fn api_keys_from_env(name: &str) -> Vec<String> {
+    let numbered = (1..).map_while(|ix| env::var(format!("{name}_{ix}")).ok());
+    env::var(name)
+        .ok()
+        .into_iter()
+        .chain(numbered)
+        .map(|api_key| normalize_api_key(&api_key).to_string())
+        .filter(|api_key| !api_key.is_empty())
+        .collect()
+}
+
+/// Explains where an API key was looked for, in the order `source` looks, when
+/// none was found.
+fn missing_api_key_message(source: ApiKeySource, api_key_var: &str) -> String {
+    let env =
+        format!("the {api_key_var} environment variable, then the file {api_key_var}_FILE names");
+    match source {
+        ApiKeySource::Auto => format!(
+            "no API key found: looked in {env}, then the keyring, since `api_key_source` is `auto`"
+        ),
+        ApiKeySource::Env => {
+            format!("no API key found: looked in {env}, since `api_key_source` is `env`")
+        }
+        ApiKeySource::Keyring => {
+            "no API key found in the keyring, and since `api_key_source` is `keyring`, the environment wasn't checked".into()
+        }
+    }
+}
+
+/// The key in the file named by the environment variable `{name}_FILE`, which is how
+/// Docker and many CI systems provide secrets, or `None` if the variable isn't set.
+async fn api_key_from_file_env(name: &str) -> Result<Option<String>> {
+    let var = format!("{name}_FILE");
+    let Some(path) = env::var_os(&var) else {
+        return Ok(None);
+    };
+    // A key file that's been configured but can't be read is reported, rather than
+    // falling back to the keychain, since that would hide the misconfiguration.
+    match read_api_key_file(Path::new(&path)).await {
+        Ok(api_key) => Ok(Some(api_key)),
+        Err(error) => Err(CompletionError::Authentication {
+            message: format!("couldn't read the API key from {path:?}, set in {var}: {error}"),
+        }
+        .into()),
+    }
+}
+
+async fn read_api_key_file(path: &Path) -> Result<String> {
+    let contents = smol::fs::read_to_string(path).await?;
+    let api_key = normalize_api_key(&contents);
+    if api_key.is_empty() {
+        return Err(anyhow!("the file is empty"));
+    }
+    Ok(api_key.to_string())
+}
+
+/// Sends the images attached to a user message, if there are any, after its text.
+fn user_message_content(text: String, images: Vec<LanguageModelImage>) -> MessageContent {
+    if images.is_empty() {
+        return MessageContent::Plain(text);
+    }
+    let mut parts = Vec::with_capacity(images.len() + 1);
+    if !text.is_empty() {
+        parts.push(MessagePart::Text { text });
+    }
+    parts.extend(images.into_iter().map(|image| MessagePart::ImageUrl {
+        image_url: ImageUrl {
+            url: image.url,
+            detail: image.detail,
+        },
+    }));
+    MessageContent::Multipart(parts)
+}
+
+/// Whether any of `messages` mention JSON. OpenAI rejects requests in JSON mode
+/// that don't, since the model could otherwise generate whitespace indefinitely.
+fn mentions_json(messages: &[RequestMessage]) -> bool {
+    messages.iter().any(|message| {
+        let content = match message {
+            RequestMessage::User { content } => content.text(),
+            RequestMessage::System { content } | RequestMessage::Tool { content, .. } => {
+                content.clone()
+            }
+            RequestMessage::Assistant { content, .. } => content.clone().unwrap_or_default(),
+        };
+        content.to_lowercase().contains("json")
+    })
+}
+
+/// Inserts a [`CompletionError::EmptyResponse`] before the end of `stream` if it
+/// ends without yielding any content or errors.
+fn error_on_empty_response(
+    stream: BoxStream<'static, Result<CompletionEvent>>,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut is_empty = true;
+    stream
+        .flat_map(move |event| {
+            let events = match event {
+                Ok(CompletionEvent::Text(text)) => {
+                    is_empty &= text.is_empty();
+                    vec![Ok(CompletionEvent::Text(text))]
+                }
+                Ok(CompletionEvent::ToolCall {
+                    tool_call,
+                    is_complete,
+                }) => {
+                    is_empty = false;
+                    vec![Ok(CompletionEvent::ToolCall {
+                        tool_call,
+                        is_complete,
+                    })]
+                }
+                Ok(CompletionEvent::StreamEnd(stream_end)) if is_empty => vec![
+                    Err(CompletionError::EmptyResponse.into()),
+                    Ok(CompletionEvent::StreamEnd(stream_end)),
+                ],
+                Ok(event) => vec![Ok(event)],
+                Err(error) => {
+                    is_empty = false;
+                    vec![Err(error)]
+                }
+            };
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+/// The list price of `model`'s tokens. Custom models have no known price, so this
+/// returns `None` for them.
+///
+/// This is the only place prices are kept, so it's what needs updating when OpenAI
+/// changes them.
+pub fn open_ai_token_price(model: &OpenAiModel) -> Option<TokenPrice> {
+    let (prompt, completion) = match model {
+        OpenAiModel::ThreePointFiveTurbo => (0.5, 1.5),
+        OpenAiModel::Four => (30., 60.),
+        OpenAiModel::FourTurbo => (10., 30.),
+        OpenAiModel::FourOmni => (5., 15.),
+        OpenAiModel::FourOmniMini => (0.15, 0.6),
+        OpenAiModel::Custom { .. } => return None,
+    };
+    // OpenAI doesn't report cached tokens, so none are priced as such.
+    Some(TokenPrice {
+        prompt,
+        completion,
+        cache_write: prompt,
+        cache_read: prompt,
+    })
+}
+
+/// What `model` supports. Custom models could be anything, so they only report
+/// their context window.
+pub fn open_ai_model_capabilities(model: &OpenAiModel) -> ModelCapabilities {
+    let (supports_vision, supports_json_mode, max_output_tokens) = match model {
+        OpenAiModel::ThreePointFiveTurbo => (false, true, 4096),
+        OpenAiModel::Four => (false, false, 8192),
+        OpenAiModel::FourTurbo => (false, true, 4096),
+        OpenAiModel::FourOmni => (true, true, 4096),
+        OpenAiModel::FourOmniMini => (true, true, 16384),
+        OpenAiModel::Custom { max_tokens, .. } => {
+            return ModelCapabilities::unknown(*max_tokens);
+        }
+    };
+    ModelCapabilities {
+        supports_tools: true,
+        supports_vision,
+        supports_json_mode,
+        max_output_tokens: Some(max_output_tokens),
+        context_window: model.max_token_count(),
+    }
+}
+
+/// Estimates the cost, in dollars, of the tokens a completion from `model` reported
+/// using, at its list price. Returns `None` for models with no known price.
+pub fn open_ai_usage_cost(model: &OpenAiModel, usage: TokenUsage) -> Option<f64> {
+    open_ai_token_price(model).map(|price| price.cost(usage))
+}
+
+/// Estimates the prompt tokens an image costs, per OpenAI's documented formula.
+/// Images processed at [`ImageDetail::Auto`] are counted as [`ImageDetail::High`],
+/// the more expensive option.
+///
+/// Low detail images have a fixed cost. Otherwise the image is scaled to fit within
+/// 2048x2048, then so its shortest side is at most 768px, and costs a fixed amount
+/// plus an amount per 512px tile needed to cover it.
+pub fn open_ai_image_tokens(width: u32, height: u32, detail: ImageDetail) -> usize {
+    const BASE_TOKENS: usize = 85;
+    const TOKENS_PER_TILE: usize = 170;
+    const TILE_SIZE: f64 = 512.;
+
+    if detail == ImageDetail::Low || width == 0 || height == 0 {
+        return BASE_TOKENS;
+    }
+
+    let (mut width, mut height) = (width as f64, height as f64);
+    let fit_scale = (2048. / width.max(height)).min(1.);
+    width *= fit_scale;
+    height *= fit_scale;
+    let shortest_side_scale = (768. / width.min(height)).min(1.);
+    width *= shortest_side_scale;
+    height *= shortest_side_scale;
+
+    let tiles = (width / TILE_SIZE).ceil() as usize * (height / TILE_SIZE).ceil() as usize;
+    BASE_TOKENS + tiles * TOKENS_PER_TILE
+}
+
+/// Estimates the tokens an attached image costs. When its dimensions aren't known,
+/// such as for a remote URL that hasn't been fetched, this assumes the most an
+/// image can cost at its level of detail.
+pub(crate) fn image_tokens_for(image: &LanguageModelImage) -> usize {
+    let detail = image.detail.unwrap_or_default();
+    match image.size {
+        Some(size) => open_ai_image_tokens(size.width, size.height, detail),
+        // After scaling, the largest images cover 2x4 tiles.
+        None => open_ai_image_tokens(768, 2048, detail),
+    }
+}
+
+/// How long [`OpenAiCompletionProvider::check_connection`] waits for a response.
+const CONNECTION_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The status of a connection whose check failed with `error`.
+fn connection_status(error: &anyhow::Error) -> ConnectionStatus {
+    match error.downcast_ref::<RequestError>() {
+        Some(_) if is_rejected_api_key(error) => ConnectionStatus::Unauthorized,
+        // Nothing is served where the API should be, so the URL is likely wrong.
+        Some(_) if is_not_found(error) => ConnectionStatus::Unreachable,
+        // The API responded, even if it refused the request.
+        Some(error) if error.status < 500 => ConnectionStatus::Ok,
+        _ => ConnectionStatus::Unreachable,
+    }
+}
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<RequestError>()
+        .map_or(false, |error| error.status == 404)
+}
+
+/// Whether listing models failed because the API key was rejected, rather than
+/// because models can't be listed.
+fn is_rejected_api_key(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<RequestError>()
+        .map_or(false, |error| matches!(error.status, 401 | 403))
+}
+
+/// Offers `models`, listed by the API at `api_url`, from the current provider, if
+/// it's an OpenAI or OpenAI-compatible one.
+fn store_fetched_models(
+    provider: &mut CompletionProvider,
+    api_url: &str,
+    models: Vec<OpenAiModel>,
+) {
+    let store = |provider: &mut OpenAiCompletionProvider| {
+        // The listing is only valid for the API it was fetched from.
+        if provider.api_url == api_url {
+            provider.fetched_models = models.clone();
+        }
+    };
+    if provider
+        .update_current_as::<_, OpenAiCompletionProvider>(&store)
+        .is_none()
+    {
+        provider.update_current_as::<_, OpenAiCompatibleCompletionProvider>(|provider| {
+            store(provider.open_ai_provider())
+        });
+    }
+}
+
+/// The context window assumed for listed models that aren't otherwise known.
+const LISTED_MODEL_MAX_TOKENS: usize = 128000;
+
+/// Converts the models listed by the API to the ones offered for completions. The
+/// listing also includes models that can't serve chat completions, such as
+/// embedding, image and audio models, which are left out.
+fn models_from_listings(listings: Vec<ModelListing>) -> Vec<OpenAiModel> {
+    let mut models = listings
+        .into_iter()
+        .filter(|listing| is_chat_model(&listing.id))
+        .map(|listing| {
+            OpenAiModel::from_id(&listing.id).unwrap_or(OpenAiModel::Custom {
+                name: listing.id,
+                max_tokens: LISTED_MODEL_MAX_TOKENS,
+                tokenizer: None,
+            })
+        })
+        .collect::<Vec<_>>();
+    models.sort_by(|a, b| a.display_name().cmp(b.display_name()));
+    models
+}
+
+fn is_chat_model(id: &str) -> bool {
+    let is_chat_family = id.starts_with("gpt-")
+        || id.starts_with("chatgpt-")
+        || ["o1", "o3", "o4"]
+            .iter()
+            .any(|prefix| id == *prefix || id.starts_with(&format!("{prefix}-")));
+    let is_other_modality = [
+        "instruct",
+        "audio",
+        "realtime",
+        "transcribe",
+        "tts",
+        "search",
+    ]
+    .iter()
+    .any(|modality| id.contains(modality));
+    is_chat_family && !is_other_modality
+}
+
+/// The tokens of a request, broken down by where they come from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TokenBreakdown {
+    pub system: usize,
+    pub user: usize,
+    pub assistant: usize,
+    /// The tokens of the tool calls made by the assistant.
+    pub tool: usize,
+    /// The tokens added by the format itself, independent of any message.
+    pub overhead: usize,
+}
+
+impl TokenBreakdown {
+    pub fn total(&self) -> usize {
+        self.system + self.user + self.assistant + self.tool + self.overhead
+    }
+}
+
+/// How much of a model's context window a request uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContextUsage {
+    pub tokens: usize,
+    pub max_tokens: usize,
+    /// Whether the request uses at least the provider's warning threshold of the
+    /// context window.
+    pub should_warn: bool,
+}
+
+impl ContextUsage {
+    pub fn fraction(&self) -> f64 {
+        self.tokens as f64 / self.max_tokens as f64
+    }
+}
+
+pub fn count_open_ai_tokens(
+    request: LanguageModelRequest,
+    background_executor: &gpui::BackgroundExecutor,
+) -> BoxFuture<'static, Result<usize>> {
+    count_open_ai_tokens_detailed(request, background_executor)
+        .map_ok(|breakdown| breakdown.total())
+        .boxed()
+}
+
+pub fn count_open_ai_tokens_detailed(
+    request: LanguageModelRequest,
+    background_executor: &gpui::BackgroundExecutor,
+) -> BoxFuture<'static, Result<TokenBreakdown>> {
+    background_executor
+        .spawn(async move {
+            let model = match &request.model {
+                LanguageModel::OpenAi(model) => tiktoken_model(model),
+                LanguageModel::Anthropic(_)
+                | LanguageModel::Cloud(CloudModel::Claude3_5Sonnet)
+                | LanguageModel::Cloud(CloudModel::Claude3Opus)
+                | LanguageModel::Cloud(CloudModel::Claude3Sonnet)
+                | LanguageModel::Cloud(CloudModel::Claude3Haiku) => {
+                    // Tiktoken doesn't yet support these models, so we manually use the
+                    // same tokenizer as GPT-4.
+                    "gpt-4"
+                }
+                _ => tiktoken_chat_model(request.model.id()),
+            };
+
+            let mut system = Vec::new();
+            let mut user = Vec::new();
+            let mut assistant = Vec::new();
+            let mut tool = Vec::new();
+            let mut image_tokens = 0;
+            for message in request.messages {
+                // Only user messages are sent with their images.
+                if message.role == Role::User {
+                    image_tokens += message.images.iter().map(image_tokens_for).sum::<usize>();
+                }
+                let (role, messages): (String, _) = match message.role {
+                    Role::User => ("user".into(), &mut user),
+                    Role::Assistant => ("assistant".into(), &mut assistant),
+                    Role::System => ("system".into(), &mut system),
+                    Role::Tool => ("tool".into(), &mut tool),
+                };
+                messages.push(tiktoken_rs::ChatCompletionRequestMessage {
+                    role: role.clone(),
+                    content: Some(message.content),
+                    name: None,
+                    function_call: None,
+                });
+                // Count each tool call as its own message, so that both the
+                // function name and its arguments contribute to the total.
+                for tool_call in message.tool_calls {
+                    tool.push(tiktoken_rs::ChatCompletionRequestMessage {
+                        role: role.clone(),
+                        content: Some(tool_call.arguments),
+                        name: Some(tool_call.name),
+                        function_call: None,
+                    });
+                }
+            }
+
+            // Tiktoken counts a fixed number of tokens for priming the reply on top of
+            // the messages' tokens, which is attributed to the overhead.
+            let overhead = count_reply_priming_tokens(model);
+            Ok(TokenBreakdown {
+                system: count_chat_messages(model, &system, overhead),
+                user: count_chat_messages(model, &user, overhead) + image_tokens,
+                assistant: count_chat_messages(model, &assistant, overhead),
+                tool: count_chat_messages(model, &tool, overhead),
+                overhead,
+            })
+        })
+        .boxed()
+}
+
+/// Breaks `request`'s tokens down by role with a custom `tokenizer`, which adds
+/// no framing of its own, so there is no overhead.
+fn count_tokens_detailed_with(
+    tokenizer: &dyn Tokenizer,
+    request: &LanguageModelRequest,
+) -> TokenBreakdown {
+    let mut breakdown = TokenBreakdown::default();
+    for message in &request.messages {
+        let content = tokenizer.count_tokens(&message.content);
+        match message.role {
+            Role::User => {
+                breakdown.user +=
+                    content + message.images.iter().map(image_tokens_for).sum::<usize>()
+            }
+            Role::Assistant => breakdown.assistant += content,
+            Role::System => breakdown.system += content,
+            Role::Tool => breakdown.tool += content,
+        }
+        breakdown.tool += message
+            .tool_calls
+            .iter()
+            .map(|tool_call| {
+                tokenizer.count_tokens(&tool_call.name)
+                    + tokenizer.count_tokens(&tool_call.arguments)
+            })
+            .sum::<usize>();
+    }
+    breakdown
+}
+
+/// Roughly how many characters a token spans, for estimating token counts when
+/// tiktoken can't count them.
+const ESTIMATED_CHARS_PER_TOKEN: usize = 4;
+
+/// The tokens tiktoken counts for priming the reply, and for framing each message,
+/// for the models it supports.
+const ESTIMATED_REPLY_PRIMING_TOKENS: usize = 3;
+const ESTIMATED_TOKENS_PER_MESSAGE: usize = 3;
+
+/// Whether counting tokens with tiktoken has failed, and estimates were used
+/// instead, which is only logged once.
+static TOKEN_COUNTS_ESTIMATED: AtomicBool = AtomicBool::new(false);
+
+fn count_reply_priming_tokens(model: &str) -> usize {
+    cached_token_count(model, None, || {
+        Ok(tiktoken_rs::num_tokens_from_messages(model, &[])?)
+    })
+    .unwrap_or_else(|error| {
+        warn_token_counts_estimated(model, &error);
+        ESTIMATED_REPLY_PRIMING_TOKENS
+    })
+}
+
+/// Counts the tokens `messages` add to a request, excluding its fixed `overhead`.
+/// Each message is counted separately, with the framing tiktoken adds around it,
+/// and cached.
+fn count_chat_messages(
+    model: &str,
+    messages: &[tiktoken_rs::ChatCompletionRequestMessage],
+    overhead: usize,
+) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            cached_token_count(model, Some(message_hash(message)), || {
+                let count =
+                    tiktoken_rs::num_tokens_from_messages(model, std::slice::from_ref(message))?;
+                Ok(count.saturating_sub(overhead))
+            })
+            .unwrap_or_else(|error| {
+                warn_token_counts_estimated(model, &error);
+                estimate_message_tokens(message)
+            })
+        })
+        .sum()
+}
+
+/// Estimates the tokens tiktoken would count for `message` from its length.
+/// Tiktoken's vocabularies are compiled in, so it can count tokens offline, but if
+/// it ever fails to, an estimate is more useful than no count at all, since
+/// requests are checked against the context window with it.
+fn estimate_message_tokens(message: &tiktoken_rs::ChatCompletionRequestMessage) -> usize {
+    let chars = message.role.chars().count()
+        + message
+            .content
+            .as_deref()
+            .map_or(0, |content| content.chars().count())
+        + message
+            .name
+            .as_deref()
+            .map_or(0, |name| name.chars().count());
+    ESTIMATED_TOKENS_PER_MESSAGE + chars.div_ceil(ESTIMATED_CHARS_PER_TOKEN)
+}
+
+fn warn_token_counts_estimated(model: &str, error: &anyhow::Error) {
+    if !TOKEN_COUNTS_ESTIMATED.swap(true, SeqCst) {
+        log::warn!("failed to count tokens for {model:?}, estimating them instead: {error:?}");
+    }
+}
+
+/// How many token counts are cached, across all models.
+const TOKEN_COUNT_CACHE_CAPACITY: usize = 4096;
+
+/// Counting tokens is slow enough that recounting every message of a long
+/// conversation each time the user types is noticeable, while most of them are
+/// unchanged since they were last counted.
+static TOKEN_COUNT_CACHE: Mutex<TokenCountCache> =
+    parking_lot::const_mutex(TokenCountCache::new(TOKEN_COUNT_CACHE_CAPACITY));
+
+/// Token counts by the hash of the model and of the message counted, or `None` for
+/// the fixed overhead of the model's requests. The least recently used counts are
+/// evicted once the cache is full.
+struct TokenCountCache {
+    capacity: usize,
+    /// Each count, and when it was last used.
+    counts: BTreeMap<TokenCountKey, (usize, u64)>,
+    /// The key of each count, by when it was last used.
+    uses: BTreeMap<u64, TokenCountKey>,
+    now: u64,
+}
+
+type TokenCountKey = (u64, Option<u64>);
+
+impl TokenCountCache {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: BTreeMap::new(),
+            uses: BTreeMap::new(),
+            now: 0,
+        }
+    }
+
+    fn key(model: &str, message_hash: Option<u64>) -> TokenCountKey {
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        (hasher.finish(), message_hash)
+    }
+
+    fn get(&mut self, model: &str, message_hash: Option<u64>) -> Option<usize> {
+        let key = Self::key(model, message_hash);
+        let (count, last_used) = self.counts.get_mut(&key)?;
+        self.now += 1;
+        self.uses.remove(&*last_used);
+        self.uses.insert(self.now, key);
+        *last_used = self.now;
+        Some(*count)
+    }
+
+    fn insert(&mut self, model: &str, message_hash: Option<u64>, count: usize) {
+        let key = Self::key(model, message_hash);
+        self.now += 1;
+        if let Some((_, last_used)) = self.counts.insert(key, (count, self.now)) {
+            self.uses.remove(&last_used);
+        }
+        self.uses.insert(self.now, key);
+        if self.counts.len() > self.capacity {
+            if let Some((_, key)) = self.uses.pop_first() {
+                self.counts.remove(&key);
+            }
+        }
+    }
+}
+
+/// Returns the cached token count for `message_hash` with `model`, counting it with
+/// `count` if it isn't cached.
+fn cached_token_count(
+    model: &str,
+    message_hash: Option<u64>,
+    count: impl FnOnce() -> Result<usize>,
+) -> Result<usize> {
+    if let Some(count) = TOKEN_COUNT_CACHE.lock().get(model, message_hash) {
+        return Ok(count);
+    }
+    let count = count()?;
+    TOKEN_COUNT_CACHE.lock().insert(model, message_hash, count);
+    Ok(count)
+}
+
+fn message_hash(message: &tiktoken_rs::ChatCompletionRequestMessage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message.role.hash(&mut hasher);
+    message.content.hash(&mut hasher);
+    message.name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The name `model` is requested by, which also keys per-model configuration.
+fn model_name(model: &OpenAiModel) -> &str {
+    match model {
+        OpenAiModel::Custom { name, .. } => name,
+        model => model.id(),
+    }
+}
+
+/// Returns the model whose tiktoken encoding is used to count `model`'s tokens.
+fn tiktoken_model(model: &OpenAiModel) -> &'static str {
+    match model {
+        OpenAiModel::Custom {
+            tokenizer: Some(tokenizer),
+            ..
+        } => tiktoken_model_for_encoding(tokenizer).unwrap_or_else(|| {
+            log::warn!("unsupported tokenizer {tokenizer:?}, counting tokens as for gpt-4");
+            "gpt-4"
+        }),
+        // Tiktoken doesn't support custom models, so we manually use the same
+        // tokenizer as GPT-4.
+        OpenAiModel::Custom { .. } => "gpt-4",
+        model => model.id(),
+    }
+}
+
+/// Whether tiktoken can count the messages of each model it's been asked about,
+/// so that each model is only checked, and warned about, once.
+static TIKTOKEN_CHAT_MODELS: Mutex<BTreeMap<String, bool>> =
+    parking_lot::const_mutex(BTreeMap::new());
+
+/// Returns `model` if tiktoken can count its messages, or else GPT-4, so that
+/// models released after the bundled tiktoken tables can still be counted.
+fn tiktoken_chat_model(model: &str) -> &str {
+    let mut models = TIKTOKEN_CHAT_MODELS.lock();
+    let is_known = match models.get(model) {
+        Some(is_known) => *is_known,
+        None => {
+            let is_known = tiktoken_rs::num_tokens_from_messages(model, &[]).is_ok();
+            if !is_known {
+                log::warn!(
+                    "tiktoken doesn't recognize model {model:?}, counting tokens as for gpt-4"
+                );
+            }
+            models.insert(model.to_string(), is_known);
+            is_known
+        }
+    };
+    if is_known {
+        model
+    } else {
+        "gpt-4"
+    }
+}
+
+/// Tiktoken selects encodings by model name, so this returns a model that uses
+/// `encoding`. Only the encodings tiktoken can count chat messages with are
+/// supported.
+fn tiktoken_model_for_encoding(encoding: &str) -> Option<&'static str> {
+    match encoding {
+        "cl100k_base" => Some("gpt-4"),
+        "o200k_base" => Some("gpt-4o"),
+        _ => None,
+    }
+}
+
+const OPEN_AI_INSTRUCTIONS: &[&str] = &[
+    "To use the assistant panel or inline assistant, you need to add your OpenAI API key.",
+    " - You can create an API key at: platform.openai.com/api-keys",
+    " - Make sure your OpenAI account has credits",
+    " - Having a subscription for another service like GitHub Copilot won't work.",
+    "",
+    "Paste your OpenAI API key below and hit enter to use the assistant:",
+];
+
+fn is_default_api_url(api_url: &str) -> bool {
+    api_url.trim_end_matches('/') == open_ai::OPEN_AI_API_URL
+}
+
+fn authentication_instructions(api_url: &str, provider_name: &str) -> Vec<SharedString> {
+    if is_default_api_url(api_url) {
+        OPEN_AI_INSTRUCTIONS
+            .iter()
+            .map(|line| (*line).into())
+            .collect()
+    } else {
+        vec![
+            format!("To use the assistant panel or inline assistant, you need to add your {provider_name} API key.").into(),
+            format!(" - See {provider_name}'s documentation for how to obtain a key").into(),
+            " - If the API doesn't require a key, leave this empty and hit enter".into(),
+            "".into(),
+            format!("Paste your {provider_name} API key below and hit enter to use the assistant:").into(),
+        ]
+    }
+}
+
+fn api_key_placeholder(api_url: &str) -> &'static str {
+    if is_default_api_url(api_url) {
+        "sk-000000000000000000000000000000000000000000000000"
+    } else {
+        "API key (optional)"
+    }
+}
+
+/// Checks `api_key` with `validate`, if given, then saves it for `api_url` and
+/// hands it to the current provider. Fails with why the API rejected the key, if
+/// it did, in which case the key isn't saved.
+async fn save_validated_api_key(
+    validate: Option<impl Future<Output = Result<Vec<ModelListing>>>>,
+    api_url: String,
+    api_key: String,
+    provider_name: SharedString,
+    cx: &mut AsyncAppContext,
+) -> Result<(), SharedString> {
+    if let Some(validate) = validate {
+        match validate.await {
+            Err(error) if is_rejected_api_key(&error) => return Err(error.to_string().into()),
+            // Some compatible APIs don't list their models, so other failures don't
+            // prove that the key is wrong.
+            Err(error) => log::info!("couldn't validate the {provider_name} API key: {error:?}"),
+            Ok(_) => {}
+        }
+    }
+
+    let save = async {
+        cx.update(|cx| cx.write_credentials(&api_url, "Bearer", api_key.as_bytes()))?
+            .await?;
+        cx.update_global::<CompletionProvider, _>(|provider, cx| {
+            let priming = provider
+                .update_current_as::<_, OpenAiCompletionProvider>(|provider| {
+                    provider.set_api_key(api_key.clone())
+                })
+                .or_else(|| {
+                    provider.update_current_as::<_, OpenAiCompatibleCompletionProvider>(
+                        |provider| provider.set_api_key(api_key),
+                    )
+                });
+            if let Some(Some(priming)) = priming {
+                cx.background_executor().spawn(priming).detach();
+            }
+        })
+    }
+    .await;
+    save.log_err();
+    Ok(())
+}
+
+struct AuthenticationPrompt {
+    api_key: View<Editor>,
+    api_url: String,
+    /// The name the API is referred to by, e.g. "OpenAI".
+    provider_name: SharedString,
+    /// The environment variable the key can be read from instead, if any.
+    api_key_var: Option<&'static str>,
+    http_client: Arc<dyn HttpClient>,
+    organization_id: Option<String>,
+    auth_header: AuthHeaderStyle,
+    low_speed_timeout: Option<Duration>,
+    /// Azure doesn't list models the way OpenAI does, so keys for it can't be
+    /// validated.
+    validate_api_key: bool,
+    validation: Option<Task<()>>,
+    error: Option<SharedString>,
+    model_refresh: Option<Task<()>>,
+    /// How many models the last refresh found, or why it failed.
+    models_status: Option<Result<usize, SharedString>>,
+    /// The key is masked unless the user asks to see it, so that it isn't exposed
+    /// to anyone watching the screen.
+    api_key_visible: bool,
+}
+
+impl AuthenticationPrompt {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        api_url: String,
+        provider_name: SharedString,
+        api_key_var: Option<&'static str>,
+        http_client: Arc<dyn HttpClient>,
+        organization_id: Option<String>,
+        auth_header: AuthHeaderStyle,
+        low_speed_timeout: Option<Duration>,
+        validate_api_key: bool,
+        cx: &mut WindowContext,
+    ) -> Self {
+        Self {
+            api_key: cx.new_view(|cx| {
+                let mut editor = Editor::single_line(cx);
+                editor.set_placeholder_text(api_key_placeholder(&api_url), cx);
+                editor.set_redact_all(true, cx);
+                editor
+            }),
+            api_url,
+            provider_name,
+            api_key_var,
+            http_client,
+            organization_id,
+            auth_header,
+            low_speed_timeout,
+            validate_api_key,
+            validation: None,
+            error: None,
+            model_refresh: None,
+            models_status: None,
+            api_key_visible: false,
+        }
+    }
+
+    fn toggle_api_key_visibility(&mut self, cx: &mut ViewContext<Self>) {
+        self.api_key_visible = !self.api_key_visible;
+        let redact = !self.api_key_visible;
+        self.api_key
+            .update(cx, |editor, cx| editor.set_redact_all(redact, cx));
+        cx.notify();
+    }
+
+    fn save_api_key(&mut self, _: &menu::Confirm, cx: &mut ViewContext<Self>) {
+        if self.validation.is_some() {
+            return;
+        }
+        let api_key = self.api_key.read(cx).text(cx);
+        // Custom endpoints may not need a key, in which case an empty one is saved.
+        if api_key.is_empty() && is_default_api_url(&self.api_url) {
+            return;
+        }
+
+        // The key is checked by listing the models it can access, which is cheap
+        // and doesn't spend any tokens.
+        let validate = (self.validate_api_key && !api_key.is_empty()).then(|| {
+            let http_client = self.http_client.clone();
+            let api_url = self.api_url.clone();
+            let api_key = api_key.clone();
+            let organization_id = self.organization_id.clone();
+            let auth_header = self.auth_header.clone();
+            let low_speed_timeout = self.low_speed_timeout;
+            async move {
+                let auth = auth_header.auth(&api_key, organization_id.as_deref());
+                list_models(http_client.as_ref(), &api_url, auth, low_speed_timeout).await
+            }
+        });
+        let api_url = self.api_url.clone();
+        let provider_name = self.provider_name.clone();
+        self.error = None;
+        self.validation = Some(cx.spawn(|this, mut cx| async move {
+            let saved =
+                save_validated_api_key(validate, api_url, api_key, provider_name, &mut cx).await;
+            this.update(&mut cx, |this, cx| {
+                this.validation = None;
+                if let Err(error) = saved {
+                    this.error = Some(error);
+                    cx.focus_view(&this.api_key);
+                }
+                cx.notify();
+            })
+            .ok();
+        }));
+        cx.notify();
+    }
+
+    /// Lists the models the entered key can access, or if none has been entered,
+    /// the ones the provider's current key can, and offers them.
+    fn refresh_models(&mut self, cx: &mut ViewContext<Self>) {
+        if self.model_refresh.is_some() {
+            return;
+        }
+        let api_key = self.api_key.read(cx).text(cx);
+        let refresh = if api_key.is_empty() {
+            CompletionProvider::global(cx).reload_models(cx)
+        } else {
+            let http_client = self.http_client.clone();
+            let api_url = self.api_url.clone();
+            let organization_id = self.organization_id.clone();
+            let auth_header = self.auth_header.clone();
+            let low_speed_timeout = self.low_speed_timeout;
+            cx.spawn(|_, mut cx| async move {
+                let auth = auth_header.auth(&api_key, organization_id.as_deref());
+                let listings =
+                    list_models(http_client.as_ref(), &api_url, auth, low_speed_timeout).await?;
+                let models = models_from_listings(listings);
+                cx.update_global::<CompletionProvider, _>(|provider, _cx| {
+                    store_fetched_models(provider, &api_url, models);
+                    provider.available_models().len()
+                })
+            })
+        };
+        self.models_status = None;
+        self.model_refresh = Some(cx.spawn(|this, mut cx| async move {
+            let status = refresh
+                .await
+                .map_err(|error| SharedString::from(error.to_string()));
+            this.update(&mut cx, |this, cx| {
+                this.model_refresh = None;
+                this.models_status = Some(status);
+                cx.notify();
+            })
+            .ok();
+        }));
+        cx.notify();
+    }
+
+    fn render_models_status(&self) -> Option<impl IntoElement> {
+        let label = if self.model_refresh.is_some() {
+            Label::new("Refreshing models…").color(Color::Muted)
+        } else {
+            match self.models_status.as_ref()? {
+                Ok(1) => Label::new("Found 1 model").color(Color::Muted),
+                Ok(count) => Label::new(format!("Found {count} models")).color(Color::Muted),
+                Err(error) => Label::new(error.clone()).color(Color::Error),
+            }
+        };
+        Some(label.size(LabelSize::Small))
+    }
+
+    fn render_api_key_editor(&self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let settings = ThemeSettings::get_global(cx);
+        let text_style = TextStyle {
             color: cx.theme().colors().text,
             font_family: settings.ui_font.family.clone(),
             font_features: settings.ui_font.features.clone(),
@@ -301,61 +3230,3959 @@ impl AuthenticationPrompt {
             line_height: relative(1.3),
             ..Default::default()
         };
-        EditorElement::new(
-            &self.api_key,
-            EditorStyle {
-                background: cx.theme().colors().editor_background,
-                local_player: cx.theme().players().local(),
-                text: text_style,
+        EditorElement::new(
+            &self.api_key,
+            EditorStyle {
+                background: cx.theme().colors().editor_background,
+                local_player: cx.theme().players().local(),
+                text: text_style,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+impl Render for AuthenticationPrompt {
+    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        v_flex()
+            .p_4()
+            .size_full()
+            .on_action(cx.listener(Self::save_api_key))
+            .when(!is_default_api_url(&self.api_url), |this| {
+                this.child(Label::new(format!("Endpoint: {}", self.api_url)).size(LabelSize::Small))
+            })
+            .children(
+                authentication_instructions(&self.api_url, &self.provider_name)
+                    .into_iter()
+                    .map(|instruction| Label::new(instruction).size(LabelSize::Small)),
+            )
+            .child(
+                h_flex()
+                    .w_full()
+                    .my_2()
+                    .px_2()
+                    .py_1()
+                    .gap_2()
+                    .bg(cx.theme().colors().editor_background)
+                    .rounded_md()
+                    .child(self.render_api_key_editor(cx))
+                    .child(
+                        IconButton::new("toggle-api-key-visibility", IconName::Visible)
+                            .icon_size(IconSize::Small)
+                            .selected(self.api_key_visible)
+                            .tooltip({
+                                let label = if self.api_key_visible {
+                                    "Hide API Key"
+                                } else {
+                                    "Show API Key"
+                                };
+                                move |cx| Tooltip::text(label, cx)
+                            })
+                            .on_click(
+                                cx.listener(|this, _, cx| this.toggle_api_key_visibility(cx)),
+                            ),
+                    )
+                    .when(self.validation.is_some(), |this| {
+                        this.child(
+                            Icon::new(IconName::ArrowCircle)
+                                .size(IconSize::Small)
+                                .color(Color::Muted)
+                                .with_animation(
+                                    "validating-api-key",
+                                    Animation::new(Duration::from_secs(2)).repeat(),
+                                    |icon, delta| {
+                                        icon.transform(Transformation::rotate(percentage(delta)))
+                                    },
+                                ),
+                        )
+                    }),
+            )
+            .when_some(self.error.clone(), |this, error| {
+                this.child(Label::new(error).size(LabelSize::Small).color(Color::Error))
+            })
+            .when(self.validate_api_key, |this| {
+                this.child(
+                    h_flex()
+                        .gap_2()
+                        .child(
+                            Button::new("refresh-models", "Refresh Models")
+                                .icon(IconName::ArrowCircle)
+                                .icon_size(IconSize::Small)
+                                .icon_position(IconPosition::Start)
+                                .disabled(self.model_refresh.is_some())
+                                .on_click(cx.listener(|this, _, cx| this.refresh_models(cx))),
+                        )
+                        .children(self.render_models_status()),
+                )
+            })
+            .when_some(self.api_key_var, |this, api_key_var| {
+                this.child(
+                    Label::new(format!(
+                        "You can also assign the {api_key_var} environment variable, or {api_key_var}_FILE to the path of a file containing it, and restart Zed.",
+                    ))
+                    .size(LabelSize::Small),
+                )
+            })
+            .child(
+                h_flex()
+                    .gap_2()
+                    .child(Label::new("Click on").size(LabelSize::Small))
+                    .child(Icon::new(IconName::ZedAssistant).size(IconSize::XSmall))
+                    .child(
+                        Label::new("in the status bar to close this panel.").size(LabelSize::Small),
+                    ),
+            )
+            .into_any()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncWriteExt;
+    use gpui::{TestAppContext, VisualTestContext};
+    use http::{AsyncBody, FakeHttpClient, Response};
+    use language_model::{LanguageModelTool, LanguageModelToolCall};
+    use serde_json::json;
+    use settings::SettingsStore;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    use super::*;
+    use crate::{model_capabilities, CompletionPath, CostEstimate};
+
+    fn test_provider() -> OpenAiCompletionProvider {
+        OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            FakeHttpClient::with_200_response(),
+            None,
+            0,
+            Vec::new(),
+        )
+    }
+
+    /// Returns an authenticated provider whose server streams back `events`.
+    fn provider_with_events(events: Vec<serde_json::Value>) -> OpenAiCompletionProvider {
+        let mut body = String::new();
+        for event in events {
+            body.push_str(&format!("data: {event}\n\n"));
+        }
+        body.push_str("data: [DONE]\n\n");
+
+        let http_client = FakeHttpClient::create(move |_| {
+            let body = body.clone();
+            async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        provider
+    }
+
+    fn content_event(content: &str, finish_reason: Option<&str>) -> serde_json::Value {
+        json!({
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "delta": { "role": "assistant", "content": content },
+                "finish_reason": finish_reason,
+            }],
+        })
+    }
+
+    fn user_request(content: &str) -> LanguageModelRequest {
+        LanguageModelRequest {
+            model: LanguageModel::OpenAi(OpenAiModel::FourOmni),
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: content.into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn tool_call_turn() -> LanguageModelRequest {
+        LanguageModelRequest {
+            model: LanguageModel::OpenAi(OpenAiModel::FourOmni),
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::Assistant,
+                content: "Let me look that up.".into(),
+                tool_calls: vec![LanguageModelToolCall {
+                    id: "call_1".into(),
+                    name: "get_weather".into(),
+                    arguments: r#"{"city":"Paris"}"#.into(),
+                }],
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn weather_tool_call() -> ToolCall {
+        ToolCall {
+            id: "call_1".into(),
+            content: ToolCallContent::Function {
+                function: FunctionContent {
+                    name: "get_weather".into(),
+                    arguments: r#"{"city":"Paris"}"#.into(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_orphaned_tool_result() {
+        let mut provider = test_provider();
+        let answer_tool_call = |tool_call_id: &'static str| -> RequestTransform {
+            Arc::new(move |request: &mut Request| {
+                request.messages.push(RequestMessage::Tool {
+                    content: "sunny".into(),
+                    tool_call_id: tool_call_id.into(),
+                });
+            })
+        };
+
+        provider.set_request_transform(Some(answer_tool_call("call_1")));
+        assert!(provider.to_open_ai_request(tool_call_turn()).is_ok());
+
+        provider.set_request_transform(Some(answer_tool_call("call_2")));
+        let error = provider.to_open_ai_request(tool_call_turn()).unwrap_err();
+        assert!(matches!(
+            error,
+            CompletionError::OrphanedToolResult { tool_call_id } if tool_call_id == "call_2"
+        ));
+    }
+
+    #[gpui::test]
+    async fn test_duplicate_tool_call_id() {
+        let mut request = tool_call_turn();
+        request.messages.extend(tool_call_turn().messages);
+
+        let provider = provider_with_events(vec![content_event("Hi", Some("stop"))]);
+        let error = provider
+            .stream_completion_events(request)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::DuplicateToolCallId { id }) if id == "call_1"
+        ));
+    }
+
+    #[test]
+    fn test_assistant_turn_with_content_and_tool_calls() {
+        let request = test_provider()
+            .to_open_ai_request(tool_call_turn())
+            .unwrap();
+        assert_eq!(
+            request.messages,
+            vec![RequestMessage::Assistant {
+                content: Some("Let me look that up.".into()),
+                tool_calls: vec![weather_tool_call()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let logit_bias = open_ai_logit_bias(&OpenAiModel::Four, ["Hello", " Hello"], -150).unwrap();
+        assert_eq!(logit_bias.len(), 2);
+        assert!(logit_bias.values().all(|bias| *bias == -150));
+
+        let mut request = user_request("Hi");
+        request.logit_bias = Some(logit_bias.clone());
+        let request = test_provider().build_effective_request(request).unwrap();
+        let sent_logit_bias = &serde_json::to_value(&request).unwrap()["logit_bias"];
+        for token in logit_bias.keys() {
+            assert_eq!(sent_logit_bias[token.to_string()], json!(-100));
+        }
+    }
+
+    #[gpui::test]
+    async fn test_logprobs() {
+        let mut request = user_request("Hi");
+        request.top_logprobs = Some(50);
+        let sent = test_provider()
+            .build_effective_request(request.clone())
+            .unwrap();
+        assert!(sent.logprobs);
+        assert_eq!(sent.top_logprobs, Some(20));
+        let sent = serde_json::to_value(
+            test_provider()
+                .build_effective_request(user_request("Hi"))
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(sent.get("logprobs").is_none());
+
+        let mut event = content_event("Hello", None);
+        event["choices"][0]["logprobs"] = json!({
+            "content": [{
+                "token": "Hello",
+                "logprob": -0.25,
+                "bytes": [72, 101, 108, 108, 111],
+                "top_logprobs": [
+                    { "token": "Hello", "logprob": -0.25, "bytes": null },
+                    { "token": "Hi", "logprob": -1.5, "bytes": null },
+                ],
+            }],
+        });
+        let provider = provider_with_events(vec![event, content_event("!", Some("stop"))]);
+        let events = provider
+            .stream_completion_events(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(events[0], CompletionEvent::Text("Hello".into()));
+        assert_eq!(
+            events[1],
+            CompletionEvent::Logprobs(vec![TokenLogprob {
+                token: "Hello".into(),
+                logprob: -0.25,
+                top_logprobs: vec![("Hello".into(), -0.25), ("Hi".into(), -1.5)],
+            }])
+        );
+        // Chunks without logprobs, like the final one, are streamed as before.
+        assert_eq!(events[2], CompletionEvent::Text("!".into()));
+        assert!(matches!(events[3], CompletionEvent::StreamEnd(_)));
+    }
+
+    #[gpui::test]
+    async fn test_assistant_prefill() {
+        let mut request = user_request("Name a fruit.");
+        request.messages.push(LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content: "The fruit is".into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
+        });
+
+        let sent = test_provider()
+            .build_effective_request(request.clone())
+            .unwrap();
+        assert_eq!(
+            sent.messages[1..],
+            [
+                RequestMessage::Assistant {
+                    content: Some("The fruit is".into()),
+                    tool_calls: Vec::new(),
+                },
+                RequestMessage::User {
+                    content: CONTINUATION_PROMPT.into(),
+                },
+            ]
+        );
+
+        let mut provider = test_provider();
+        provider.set_assistant_prefill(AssistantPrefill::Native);
+        let sent = provider.build_effective_request(request.clone()).unwrap();
+        assert_eq!(sent.messages.len(), 2);
+        assert_eq!(
+            sent.messages.last(),
+            Some(&RequestMessage::Assistant {
+                content: Some("The fruit is".into()),
+                tool_calls: Vec::new(),
+            })
+        );
+
+        // Only the continuation is streamed, to be appended to the prefill.
+        let provider = provider_with_events(vec![
+            content_event(" a", None),
+            content_event(" mango.", Some("stop")),
+        ]);
+        let chunks = provider
+            .stream_completion(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            " a mango."
+        );
+    }
+
+    #[gpui::test]
+    async fn test_continuation_extends_prefill() {
+        let sent_bodies = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_bodies = sent_bodies.clone();
+            move |request| {
+                let sent_bodies = sent_bodies.clone();
+                async move {
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    let mut sent_bodies = sent_bodies.lock();
+                    sent_bodies.push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+                    let body = if sent_bodies.len() == 1 {
+                        format!(
+                            "data: {}\n\ndata: {{\"truncated\n\n",
+                            content_event(" a", None)
+                        )
+                    } else {
+                        format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            content_event(" mango.", Some("stop"))
+                        )
+                    };
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        provider.set_max_continuations(1);
+
+        let mut request = user_request("Name a fruit.");
+        request.messages.push(LanguageModelRequestMessage {
+            role: Role::Assistant,
+            content: "The fruit is".into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
+        });
+        let chunks = provider
+            .stream_completion(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            " a mango."
+        );
+
+        // The continuation extends the prefill, rather than adding another message.
+        let sent_bodies = sent_bodies.lock();
+        let messages = sent_bodies[1]["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"], "The fruit is a");
+        assert_eq!(messages[2]["content"], CONTINUATION_PROMPT);
+    }
+
+    #[test]
+    fn test_sampling_parameter_validation() {
+        let provider = test_provider();
+        let temperature = |temperature| {
+            let mut request = user_request("Hi");
+            request.temperature = temperature;
+            provider
+                .build_effective_request(request)
+                .unwrap()
+                .temperature
+        };
+        assert_eq!(temperature(0.7), 0.7);
+        assert_eq!(temperature(3.), 2.);
+        assert_eq!(temperature(-1.), 0.);
+        assert_eq!(temperature(f32::NAN), 1.);
+
+        let mut request = user_request("Hi");
+        request.stop = vec!["a".into(), "b".into(), "c".into(), "d".into()];
+        assert!(provider.build_effective_request(request.clone()).is_ok());
+        request.stop.push("e".into());
+        let error = provider.build_effective_request(request).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::TooManyStopSequences { count: 5, max: 4 })
+        ));
+
+        let mut request = user_request("Hi");
+        request.stop = vec!["a".into(), String::new()];
+        let error = provider.build_effective_request(request).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::EmptyStopSequence)
+        ));
+    }
+
+    #[test]
+    fn test_model_capabilities() {
+        let capabilities = open_ai_model_capabilities(&OpenAiModel::FourOmni);
+        assert!(capabilities.supports_tools);
+        assert!(capabilities.supports_vision);
+        assert!(capabilities.supports_json_mode);
+        assert_eq!(capabilities.context_window, 128000);
+
+        let capabilities = open_ai_model_capabilities(&OpenAiModel::Four);
+        assert!(!capabilities.supports_vision);
+        assert!(!capabilities.supports_json_mode);
+
+        let custom_model = OpenAiModel::Custom {
+            name: "llama-3".into(),
+            max_tokens: 8192,
+            tokenizer: None,
+        };
+        assert_eq!(
+            open_ai_model_capabilities(&custom_model),
+            ModelCapabilities::unknown(8192)
+        );
+
+        let mut provider = test_provider();
+        provider.model = custom_model;
+        assert_eq!(provider.capabilities(), ModelCapabilities::unknown(8192));
+        assert_eq!(
+            model_capabilities(&LanguageModel::OpenAi(OpenAiModel::FourOmniMini)).max_output_tokens,
+            Some(16384)
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stop_sequence_split_across_chunks() {
+        let provider = provider_with_events(vec![
+            content_event("Intro", None),
+            content_event("\n", None),
+            content_event("\nNot a", None),
+            content_event(" rule\n", None),
+            content_event("\n-", None),
+            content_event("-", None),
+            content_event("- Outro", Some("stop")),
+        ]);
+        let mut request = user_request("Hi");
+        request.stop = vec!["\n\n---".into()];
+        let chunks = provider
+            .stream_completion(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        // Nothing that could be the start of the stop sequence is emitted until
+        // it's known not to be, and nothing after it is emitted at all.
+        assert_eq!(chunks, vec!["Intro", "\n\nNot a", " rule"]);
+    }
+
+    #[test]
+    fn test_tool_result() {
+        let mut request = tool_call_turn();
+        request.messages.push(LanguageModelRequestMessage {
+            role: Role::Tool,
+            content: "sunny".into(),
+            tool_calls: Vec::new(),
+            tool_call_id: Some("call_1".into()),
+            images: Vec::new(),
+            cache: false,
+        });
+        let request = test_provider().to_open_ai_request(request).unwrap();
+        assert_eq!(
+            request.messages,
+            vec![
+                RequestMessage::Assistant {
+                    content: Some("Let me look that up.".into()),
+                    tool_calls: vec![weather_tool_call()],
+                },
+                RequestMessage::Tool {
+                    content: "sunny".into(),
+                    tool_call_id: "call_1".into(),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_tool_call_content() {
+        let mut provider = test_provider();
+        provider.set_split_tool_call_content(true);
+        let request = provider.to_open_ai_request(tool_call_turn()).unwrap();
+        assert_eq!(
+            request.messages,
+            vec![
+                RequestMessage::Assistant {
+                    content: Some("Let me look that up.".into()),
+                    tool_calls: Vec::new(),
+                },
+                RequestMessage::Assistant {
+                    content: None,
+                    tool_calls: vec![weather_tool_call()],
+                }
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_count_tokens_includes_tool_calls(cx: &mut TestAppContext) {
+        let mut content_only = tool_call_turn();
+        content_only.messages[0].tool_calls.clear();
+
+        let with_tool_calls = count_open_ai_tokens(tool_call_turn(), &cx.executor())
+            .await
+            .unwrap();
+        let without_tool_calls = count_open_ai_tokens(content_only, &cx.executor())
+            .await
+            .unwrap();
+        assert!(with_tool_calls > without_tool_calls);
+    }
+
+    #[gpui::test]
+    async fn test_custom_tokenizer(cx: &mut TestAppContext) {
+        /// Counts every character as a token.
+        struct CharTokenizer;
+
+        impl Tokenizer for CharTokenizer {
+            fn count_tokens(&self, text: &str) -> usize {
+                text.chars().count()
+            }
+        }
+
+        let mut provider = test_provider();
+        let tiktoken_count = cx
+            .update(|cx| provider.count_tokens(user_request("Hello, world!"), cx))
+            .await
+            .unwrap();
+
+        provider.set_tokenizer(Some(Arc::new(CharTokenizer)));
+        let custom_count = cx
+            .update(|cx| provider.count_tokens(user_request("Hello, world!"), cx))
+            .await
+            .unwrap();
+        assert_eq!(custom_count, 13);
+        assert_ne!(custom_count, tiktoken_count);
+
+        let breakdown = cx
+            .update(|cx| provider.count_tokens_detailed(user_request("Hello, world!"), cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            breakdown,
+            TokenBreakdown {
+                user: 13,
+                ..Default::default()
+            }
+        );
+        let usage = cx
+            .update(|cx| provider.context_usage(user_request("Hello, world!"), cx))
+            .await
+            .unwrap();
+        assert_eq!(usage.tokens, 13);
+
+        let mut request = user_request("Hello, world!");
+        request.messages[0].images = vec![LanguageModelImage {
+            url: "https://example.com/cat.jpg".into(),
+            detail: Some(ImageDetail::Low),
+            size: None,
+        }];
+        let count = cx
+            .update(|cx| provider.count_tokens(request, cx))
+            .await
+            .unwrap();
+        assert_eq!(count, 13 + 85);
+    }
+
+    #[test]
+    fn test_merge_system_messages() {
+        let message = |role, content: &str| LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
+        };
+        let request = || LanguageModelRequest {
+            model: LanguageModel::OpenAi(OpenAiModel::FourOmni),
+            messages: vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::System, "Answer in French."),
+                message(Role::User, "Hello"),
+                message(Role::System, "Be brief."),
+            ],
+            ..Default::default()
+        };
+
+        let mut provider = test_provider();
+        assert_eq!(
+            provider
+                .to_open_ai_request(request())
+                .unwrap()
+                .messages
+                .len(),
+            4
+        );
+
+        provider.set_merge_system_messages(true);
+        assert_eq!(
+            provider.to_open_ai_request(request()).unwrap().messages,
+            vec![
+                RequestMessage::System {
+                    content: "You are a helpful assistant.\nAnswer in French.\nBe brief.".into()
+                },
+                RequestMessage::User {
+                    content: "Hello".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_effective_request() {
+        let message = |role, content: &str| LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            images: Vec::new(),
+            cache: false,
+        };
+        let request = || LanguageModelRequest {
+            model: LanguageModel::OpenAi(OpenAiModel::FourOmni),
+            messages: vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::User, "What's the weather in Paris?"),
+                message(Role::System, "Be brief."),
+            ],
+            stop: vec!["\n\n".into()],
+            tools: vec![weather_tool()],
+            tool_choice: Some(LanguageModelToolChoice::Specific("get_weather".into())),
+            ..Default::default()
+        };
+
+        let mut provider = test_provider();
+        provider.set_merge_system_messages(true);
+        provider.set_default_max_tokens(HashMap::from_iter([("gpt-4o".into(), Some(256))]));
+        provider.set_request_transform(Some(Arc::new(|request: &mut Request| {
+            request.stop.push("<|end|>".into());
+        })));
+
+        let effective_request = provider.build_effective_request(request()).unwrap();
+        assert_eq!(
+            effective_request.messages,
+            vec![
+                RequestMessage::System {
+                    content: "You are a helpful assistant.\nBe brief.".into()
+                },
+                RequestMessage::User {
+                    content: "What's the weather in Paris?".into()
+                },
+            ]
+        );
+        assert_eq!(effective_request.max_tokens, Some(256));
+        assert_eq!(effective_request.stop, vec!["\n\n", "<|end|>"]);
+        assert_eq!(effective_request.tools.len(), 1);
+        assert_eq!(
+            effective_request.tool_choice,
+            Some(ToolChoice::Function {
+                name: "get_weather".into()
+            })
+        );
+        assert!(effective_request.stream);
+
+        // The checks made before sending apply too.
+        let mut unknown_tool_choice = request();
+        unknown_tool_choice.tools.clear();
+        let error = provider
+            .build_effective_request(unknown_tool_choice)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::UnknownToolChoice { .. })
+        ));
+
+        provider.set_token_budget(Some(0));
+        let error = provider.build_effective_request(request()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::BudgetExceeded { budget: 0 })
+        ));
+    }
+
+    #[gpui::test]
+    async fn test_continuation_after_mid_stream_failure() {
+        let sent_bodies = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_bodies = sent_bodies.clone();
+            move |request| {
+                let sent_bodies = sent_bodies.clone();
+                async move {
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    let mut sent_bodies = sent_bodies.lock();
+                    sent_bodies.push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+                    // The first response is cut off by a malformed event.
+                    let body = if sent_bodies.len() == 1 {
+                        format!(
+                            "data: {}\n\ndata: {{\"truncated\n\n",
+                            content_event("The quick brown", None)
+                        )
+                    } else {
+                        format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            content_event(" fox.", Some("stop"))
+                        )
+                    };
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        let chunks = provider
+            .stream_completion(user_request("Write a sentence"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(chunks.iter().any(|chunk| chunk.is_err()));
+        assert_eq!(sent_bodies.lock().len(), 1);
+
+        provider.set_max_continuations(2);
+        sent_bodies.lock().clear();
+        let chunks = provider
+            .stream_completion(user_request("Write a sentence"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "The quick brown fox."
+        );
+
+        let sent_bodies = sent_bodies.lock();
+        assert_eq!(sent_bodies.len(), 2);
+        let messages = sent_bodies[1]["messages"].as_array().unwrap();
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "The quick brown");
+        assert_eq!(messages[2]["content"], CONTINUATION_PROMPT);
+    }
+
+    #[gpui::test]
+    async fn test_count_tokens_detailed(cx: &mut TestAppContext) {
+        let request = || {
+            let mut request = tool_call_turn();
+            request.messages.splice(
+                0..0,
+                [
+                    LanguageModelRequestMessage {
+                        role: Role::System,
+                        content: "You are a helpful assistant.".into(),
+                        tool_calls: Vec::new(),
+                        tool_call_id: None,
+                        images: Vec::new(),
+                        cache: false,
+                    },
+                    LanguageModelRequestMessage {
+                        role: Role::User,
+                        content: "What's the weather in Paris?".into(),
+                        tool_calls: Vec::new(),
+                        tool_call_id: None,
+                        images: Vec::new(),
+                        cache: false,
+                    },
+                ],
+            );
+            request
+        };
+
+        let breakdown = count_open_ai_tokens_detailed(request(), &cx.executor())
+            .await
+            .unwrap();
+        assert!(breakdown.system > 0);
+        assert!(breakdown.user > 0);
+        assert!(breakdown.assistant > 0);
+        assert!(breakdown.tool > 0);
+
+        let total = count_open_ai_tokens(request(), &cx.executor())
+            .await
+            .unwrap();
+        assert_eq!(breakdown.total(), total);
+    }
+
+    #[gpui::test]
+    async fn test_cached_token_counts(cx: &mut TestAppContext) {
+        let message = |role: &str, content: &str| tiktoken_rs::ChatCompletionRequestMessage {
+            role: role.into(),
+            content: Some(content.into()),
+            name: None,
+            function_call: None,
+        };
+        let mut request = user_request("What's the weather in Paris?");
+        request.messages.insert(
+            0,
+            LanguageModelRequestMessage {
+                role: Role::System,
+                content: "You are a helpful assistant.".into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            },
+        );
+
+        // Counts summed from cached messages match counting all of the messages at
+        // once, with the overhead added only once, even as a message changes.
+        for content in [
+            "What's the weather in Paris?",
+            "What's the weather in Rome?",
+        ] {
+            request.messages[1].content = content.into();
+            let expected = tiktoken_rs::num_tokens_from_messages(
+                "gpt-4o",
+                &[
+                    message("system", "You are a helpful assistant."),
+                    message("user", content),
+                ],
+            )
+            .unwrap();
+            for _ in 0..2 {
+                let count = count_open_ai_tokens(request.clone(), &cx.executor())
+                    .await
+                    .unwrap();
+                assert_eq!(count, expected);
+            }
+        }
+
+        let mut cache = TokenCountCache::new(2);
+        cache.insert("gpt-4", Some(1), 10);
+        cache.insert("gpt-4", Some(2), 20);
+        assert_eq!(cache.get("gpt-4", Some(1)), Some(10));
+        cache.insert("gpt-4", Some(3), 30);
+        assert_eq!(cache.get("gpt-4", Some(2)), None);
+        assert_eq!(cache.get("gpt-4", Some(1)), Some(10));
+        assert_eq!(cache.get("gpt-4", Some(3)), Some(30));
+        assert_eq!(cache.get("gpt-4o", Some(3)), None);
+    }
+
+    #[test]
+    fn test_estimated_token_counts() {
+        // Tiktoken can't count tokens for models it has no encoding for, so they're
+        // estimated from the messages' lengths instead of failing.
+        let message = tiktoken_rs::ChatCompletionRequestMessage {
+            role: "user".into(),
+            content: Some("Hello, world!".into()),
+            name: None,
+            function_call: None,
+        };
+        assert_eq!(estimate_message_tokens(&message), 3 + 5);
+        assert_eq!(
+            count_reply_priming_tokens("not-a-model"),
+            ESTIMATED_REPLY_PRIMING_TOKENS
+        );
+        assert_eq!(
+            count_chat_messages(
+                "not-a-model",
+                &[message.clone(), message],
+                ESTIMATED_REPLY_PRIMING_TOKENS
+            ),
+            2 * (3 + 5)
+        );
+    }
+
+    #[gpui::test]
+    async fn test_count_tokens_for_unknown_model(cx: &mut TestAppContext) {
+        let mut request = user_request("Hello, world");
+        request.model = LanguageModel::OpenAi(OpenAiModel::Four);
+        let gpt_4_count = count_open_ai_tokens(request.clone(), &cx.executor())
+            .await
+            .unwrap();
+        request.model = LanguageModel::Cloud(CloudModel::Custom("gpt-9-preview".into()));
+        assert_eq!(
+            count_open_ai_tokens(request, &cx.executor()).await.unwrap(),
+            gpt_4_count
+        );
+    }
+
+    #[gpui::test]
+    async fn test_count_tokens_for_images(cx: &mut TestAppContext) {
+        let text_tokens = count_open_ai_tokens(user_request("Hi"), &cx.executor())
+            .await
+            .unwrap();
+        let image_tokens = |images: Vec<LanguageModelImage>| {
+            let mut request = user_request("Hi");
+            request.messages[0].images = images;
+            let count = count_open_ai_tokens(request, &cx.executor());
+            async move { count.await.unwrap() - text_tokens }
+        };
+        let image = |size, detail| LanguageModelImage {
+            url: "https://example.com/cat.jpg".into(),
+            detail,
+            size,
+        };
+        let size = |width, height| Some(ImageSize { width, height });
+
+        assert_eq!(image_tokens(vec![]).await, 0);
+        assert_eq!(image_tokens(vec![image(size(1024, 1024), None)]).await, 765);
+        assert_eq!(
+            image_tokens(vec![
+                image(size(1024, 1024), Some(ImageDetail::High)),
+                image(size(1024, 1024), Some(ImageDetail::Low)),
+            ])
+            .await,
+            765 + 85
+        );
+
+        // Images of unknown size are assumed to cost as much as any image can.
+        assert_eq!(image_tokens(vec![image(None, None)]).await, 1445);
+        assert_eq!(
+            image_tokens(vec![image(None, Some(ImageDetail::Low))]).await,
+            85
+        );
+    }
+
+    #[gpui::test]
+    async fn test_custom_model_tokenizer(cx: &mut TestAppContext) {
+        let count = |model: OpenAiModel| {
+            let mut request = user_request("नमस्ते, आप कैसे हैं? मुझे उम्मीद है कि आपका दिन अच्छा रहा।");
+            request.model = LanguageModel::OpenAi(model);
+            count_open_ai_tokens(request, &cx.executor())
+        };
+        let custom_model = |tokenizer: Option<&str>| OpenAiModel::Custom {
+            name: "my-model".into(),
+            max_tokens: 128000,
+            tokenizer: tokenizer.map(Into::into),
+        };
+
+        let fallback_count = count(custom_model(None)).await.unwrap();
+        let o200k_count = count(custom_model(Some("o200k_base"))).await.unwrap();
+        assert_ne!(o200k_count, fallback_count);
+        assert_eq!(o200k_count, count(OpenAiModel::FourOmni).await.unwrap());
+        assert_eq!(fallback_count, count(OpenAiModel::Four).await.unwrap());
+        assert_eq!(
+            count(custom_model(Some("cl100k_base"))).await.unwrap(),
+            fallback_count
+        );
+    }
+
+    #[gpui::test]
+    async fn test_context_usage(cx: &mut TestAppContext) {
+        let mut provider = test_provider();
+        let mut request = user_request("Hello there, how are you doing today?");
+        request.model = LanguageModel::OpenAi(OpenAiModel::Custom {
+            name: "small-model".into(),
+            max_tokens: 40,
+            tokenizer: None,
+        });
+
+        let tokens = cx
+            .update(|cx| provider.count_tokens_detailed(request.clone(), cx))
+            .await
+            .unwrap()
+            .total();
+        let usage = cx
+            .update(|cx| provider.context_usage(request.clone(), cx))
+            .await
+            .unwrap();
+        assert_eq!(usage.tokens, tokens);
+        assert_eq!(usage.max_tokens, 40);
+        assert_eq!(usage.fraction(), tokens as f64 / 40.);
+        assert!(!usage.should_warn);
+
+        provider.set_context_warning_threshold(0.25);
+        let usage = cx
+            .update(|cx| provider.context_usage(request, cx))
+            .await
+            .unwrap();
+        assert!(usage.should_warn);
+    }
+
+    #[test]
+    fn test_estimate_cost_scales_with_n() {
+        let mut provider = test_provider();
+        assert_eq!(provider.estimate_cost(1000, 500, 1).unwrap(), None);
+
+        provider.set_token_price(Some(TokenPrice {
+            prompt: 5.,
+            completion: 15.,
+            cache_write: 5.,
+            cache_read: 5.,
+        }));
+        let single = provider.estimate_cost(1000, 500, 1).unwrap().unwrap();
+        let triple = provider.estimate_cost(1000, 500, 3).unwrap().unwrap();
+        assert!((single - 0.0125).abs() < 1e-9);
+        assert!((triple - 0.0275).abs() < 1e-9);
+
+        provider.set_cost_guard(Some(CostGuard {
+            threshold: 0.02,
+            refuse: false,
+        }));
+        assert!(provider.estimate_cost(1000, 500, 3).is_ok());
+
+        provider.set_cost_guard(Some(CostGuard {
+            threshold: 0.02,
+            refuse: true,
+        }));
+        assert!(provider.estimate_cost(1000, 500, 1).is_ok());
+        assert!(matches!(
+            provider.estimate_cost(1000, 500, 3),
+            Err(CompletionError::CostLimitExceeded { .. })
+        ));
+    }
+
+    #[gpui::test]
+    async fn test_cost_guard_refuses_completion() {
+        let mut provider = provider_with_events(vec![content_event("Hi", Some("stop"))]);
+        provider.set_token_price(Some(TokenPrice {
+            prompt: 5.,
+            completion: 15.,
+            cache_write: 5.,
+            cache_read: 5.,
+        }));
+        provider.set_cost_guard(Some(CostGuard {
+            threshold: 0.01,
+            refuse: true,
+        }));
+
+        let mut request = user_request("Hello");
+        request.max_tokens = Some(100);
+        assert!(provider.stream_completion(request.clone()).await.is_ok());
+
+        request.max_tokens = Some(1000);
+        let error = provider.stream_completion(request).await.err().unwrap();
+        assert!(matches!(
+            CompletionError::of(&error),
+            Some(CompletionError::CostLimitExceeded { .. })
+        ));
+    }
+
+    #[gpui::test]
+    async fn test_empty_response_returns_empty_by_default() {
+        let provider = provider_with_events(vec![content_event("", Some("stop"))]);
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks.into_iter().collect::<Result<String>>().unwrap(), "");
+    }
+
+    #[gpui::test]
+    async fn test_empty_response_as_error() {
+        let mut provider = provider_with_events(vec![content_event("", Some("stop"))]);
+        provider.set_empty_response_behavior(EmptyResponseBehavior::Error);
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let error = chunks.into_iter().collect::<Result<String>>().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::EmptyResponse)
+        ));
+
+        let mut provider = provider_with_events(vec![content_event("Hello", Some("stop"))]);
+        provider.set_empty_response_behavior(EmptyResponseBehavior::Error);
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hello"
+        );
+    }
+
+    #[test]
+    fn test_responses_request() {
+        let mut request = tool_call_turn();
+        request.messages.insert(
+            0,
+            LanguageModelRequestMessage {
+                role: Role::System,
+                content: "You are a weather bot.".into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            },
+        );
+        request.messages.insert(
+            1,
+            LanguageModelRequestMessage {
+                role: Role::User,
+                content: "What's the weather in Paris?".into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            },
+        );
+
+        let provider = test_provider();
+        let mut rejected = request.clone();
+        rejected.tools.push(LanguageModelTool {
+            name: "get_weather".into(),
+            description: None,
+            parameters: None,
+        });
+        assert!(matches!(
+            provider.to_responses_request(rejected),
+            Err(CompletionError::UnsupportedContent(_))
+        ));
+        let mut rejected = request.clone();
+        rejected.messages.push(LanguageModelRequestMessage {
+            role: Role::Tool,
+            content: "sunny".into(),
+            tool_calls: Vec::new(),
+            tool_call_id: Some("call_2".into()),
+            images: Vec::new(),
+            cache: false,
+        });
+        assert!(matches!(
+            provider.to_responses_request(rejected),
+            Err(CompletionError::OrphanedToolResult { .. })
+        ));
+
+        let request = provider.to_responses_request(request).unwrap();
+        assert!(request.stream);
+        assert_eq!(
+            request.input,
+            vec![
+                ResponseInputItem::Message {
+                    role: open_ai::Role::System,
+                    content: "You are a weather bot.".into(),
+                },
+                ResponseInputItem::Message {
+                    role: open_ai::Role::User,
+                    content: "What's the weather in Paris?".into(),
+                },
+                ResponseInputItem::Message {
+                    role: open_ai::Role::Assistant,
+                    content: "Let me look that up.".into(),
+                },
+                ResponseInputItem::FunctionCall {
+                    call_id: "call_1".into(),
+                    name: "get_weather".into(),
+                    arguments: r#"{"city":"Paris"}"#.into(),
+                },
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_responses_stream_events() {
+        let mut provider = provider_with_events(vec![
+            json!({ "type": "response.created", "response": { "id": "resp_1" } }),
+            json!({
+                "type": "response.output_text.delta",
+                "output_index": 0,
+                "content_index": 0,
+                "delta": "Hel",
+            }),
+            json!({
+                "type": "response.output_text.delta",
+                "output_index": 0,
+                "content_index": 0,
+                "delta": "lo",
+            }),
+            json!({
+                "type": "response.completed",
+                "response": { "id": "resp_1", "status": "completed" },
+            }),
+        ]);
+        provider.set_api(OpenAiApi::Responses);
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hello"
+        );
+
+        let mut provider = provider_with_events(vec![json!({
+            "type": "response.failed",
+            "response": {
+                "id": "resp_2",
+                "status": "failed",
+                "error": { "code": "server_error", "message": "overloaded" },
+            },
+        })]);
+        provider.set_api(OpenAiApi::Responses);
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let error = chunks.into_iter().collect::<Result<String>>().unwrap_err();
+        assert!(error.to_string().contains("overloaded"));
+    }
+
+    #[gpui::test]
+    async fn test_update_during_stream_uses_original_config() {
+        let sent_requests = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_requests = sent_requests.clone();
+            move |request| {
+                let authorization = request
+                    .headers()
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string());
+                sent_requests
+                    .lock()
+                    .push((request.uri().to_string(), authorization));
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            "https://old.example.com/v1".into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-old".into()];
+
+        let response = provider.stream_completion(user_request("Hi"));
+        provider.update(
+            OpenAiModel::FourOmniMini,
+            "https://new.example.com/v1".into(),
+            None,
+            1,
+        );
+        provider.api_keys = vec!["sk-new".into()];
+
+        let chunks = response.await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hi"
+        );
+        assert_eq!(
+            sent_requests.lock().as_slice(),
+            &[(
+                "https://old.example.com/v1/chat/completions".to_string(),
+                Some("Bearer sk-old".to_string())
+            )]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_api_key_override() {
+        let authorizations = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let authorizations = authorizations.clone();
+            move |request| {
+                authorizations.lock().push(
+                    request
+                        .headers()
+                        .get("Authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| value.to_string()),
+                );
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+
+        // An override authenticates the provider on its own, and takes precedence
+        // over the stored key until it's cleared.
+        provider.set_api_key_override(Some("sk-workspace".into()));
+        assert!(provider.is_authenticated());
+        provider.api_keys = vec!["sk-global".into()];
+        for _ in 0..2 {
+            let response = provider.stream_completion(user_request("Hi"));
+            response.await.unwrap().collect::<Vec<_>>().await;
+        }
+        provider.set_api_key_override(None);
+        let response = provider.stream_completion(user_request("Hi"));
+        response.await.unwrap().collect::<Vec<_>>().await;
+
+        // A key passed with a request is only used for that request.
+        let response =
+            provider.stream_completion_with_api_key(user_request("Hi"), Some("sk-window".into()));
+        response.await.unwrap().collect::<Vec<_>>().await;
+        let response = provider.stream_completion(user_request("Hi"));
+        response.await.unwrap().collect::<Vec<_>>().await;
+
+        assert_eq!(
+            authorizations.lock().as_slice(),
+            &[
+                Some("Bearer sk-workspace".to_string()),
+                Some("Bearer sk-workspace".to_string()),
+                Some("Bearer sk-global".to_string()),
+                Some("Bearer sk-window".to_string()),
+                Some("Bearer sk-global".to_string()),
+            ]
+        );
+        provider.api_keys.clear();
+        assert!(!provider.is_authenticated());
+    }
+
+    #[gpui::test]
+    async fn test_keyring_api_key_source(cx: &mut TestAppContext) {
+        let mut provider = test_provider();
+        provider.set_api_key_source(ApiKeySource::Keyring);
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+
+        // The test platform's keyring is empty, and the environment isn't checked.
+        let error = cx
+            .update(|cx| CompletionProvider::global(cx).authenticate(cx))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            missing_api_key_message(ApiKeySource::Keyring, "OPENAI_API_KEY")
+        );
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::Authentication { .. })
+        ));
+        assert!(!cx.update(|cx| CompletionProvider::global(cx).is_authenticated()));
+    }
+
+    #[gpui::test]
+    async fn test_quoted_env_api_key(cx: &mut TestAppContext) {
+        let api_keys = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let api_keys = api_keys.clone();
+            move |request| {
+                api_keys
+                    .lock()
+                    .push(request.headers()["api-key"].to_str().unwrap().to_string());
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            "https://example.openai.azure.com".into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        // Azure's key variable isn't read by any other test.
+        provider.set_azure_deployment(Some(AzureDeployment {
+            deployment: "gpt-4o".into(),
+            api_version: "2024-02-01".into(),
+        }));
+        provider.set_api_key_source(ApiKeySource::Env);
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+
+        env::set_var("AZURE_OPENAI_API_KEY", "  \"sk-azure\"\n");
+        let authenticated = cx
+            .update(|cx| CompletionProvider::global(cx).authenticate(cx))
+            .await;
+        env::remove_var("AZURE_OPENAI_API_KEY");
+        authenticated.unwrap();
+
+        let response = cx
+            .update(|cx| CompletionProvider::global(cx).stream_completion(user_request("Hi"), cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.try_collect::<Vec<_>>().await.unwrap().concat(),
+            "Hi"
+        );
+        assert_eq!(api_keys.lock().as_slice(), &["sk-azure".to_string()]);
+    }
+
+    #[gpui::test]
+    async fn test_read_api_key_file() {
+        let dir = env::temp_dir().join(format!("zed-api-key-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("key");
+        std::fs::write(&path, "  \"sk-from-file\"\n").unwrap();
+        assert_eq!(read_api_key_file(&path).await.unwrap(), "sk-from-file");
+
+        std::fs::write(&path, "\n").unwrap();
+        assert_eq!(
+            read_api_key_file(&path).await.unwrap_err().to_string(),
+            "the file is empty"
+        );
+
+        assert!(read_api_key_file(&dir.join("missing")).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_update_clears_keys_when_api_url_changes() {
+        let mut provider = test_provider();
+        provider.api_keys = vec!["sk-test".into()];
+        provider.update(
+            OpenAiModel::FourOmniMini,
+            open_ai::OPEN_AI_API_URL.into(),
+            Some(Duration::from_secs(30)),
+            1,
+        );
+        assert!(provider.is_authenticated());
+        assert_eq!(provider.settings_version(), 1);
+
+        provider.api_keys.clear();
+        provider.set_api_key_override(Some("sk-workspace".into()));
+        assert!(provider.is_authenticated());
+
+        provider.update(
+            OpenAiModel::FourOmniMini,
+            "https://proxy.example.com/v1".into(),
+            Some(Duration::from_secs(30)),
+            2,
+        );
+        assert!(!provider.is_authenticated());
+        assert_eq!(provider.settings_version(), 2);
+    }
+
+    #[gpui::test]
+    async fn test_azure_deployment() {
+        let sent_requests = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_requests = sent_requests.clone();
+            move |request| {
+                let header = |name: &str| {
+                    request
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| value.to_string())
+                };
+                sent_requests.lock().push((
+                    request.uri().to_string(),
+                    header("api-key"),
+                    header("Authorization"),
+                ));
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            "https://example.openai.azure.com/".into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["azure-key".into()];
+        provider.set_azure_deployment(Some(AzureDeployment {
+            deployment: "gpt-4o-prod".into(),
+            api_version: "2024-02-01".into(),
+        }));
+
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hi"
+        );
+        assert_eq!(
+            sent_requests.lock().as_slice(),
+            &[(
+                "https://example.openai.azure.com/openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-02-01".to_string(),
+                Some("azure-key".to_string()),
+                None,
+            )]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_organization_id() {
+        let sent_organizations = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_organizations = sent_organizations.clone();
+            move |request| {
+                sent_organizations.lock().push(
+                    request
+                        .headers()
+                        .get("OpenAI-Organization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| value.to_string()),
+                );
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        let complete = |provider: &OpenAiCompletionProvider| {
+            let response = provider.stream_completion(user_request("Hi"));
+            async move { response.await.unwrap().collect::<Vec<_>>().await }
+        };
+        complete(&provider).await;
+        provider.set_organization_id(Some("org-123".into()));
+        complete(&provider).await;
+        provider.set_api(OpenAiApi::Responses);
+        provider.stream_completion(user_request("Hi")).await.ok();
+        assert_eq!(
+            sent_organizations.lock().as_slice(),
+            &[None, Some("org-123".into()), Some("org-123".into())]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_http_version_preference() {
+        let sent_versions = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_versions = sent_versions.clone();
+            move |request| {
+                sent_versions
+                    .lock()
+                    .push(request.extensions().get::<HttpVersionPreference>().copied());
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        for http_version in [
+            HttpVersionPreference::Negotiate,
+            HttpVersionPreference::Http1Only,
+        ] {
+            provider.set_http_version(http_version);
+            let chunks = provider
+                .stream_completion(user_request("Hi"))
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(chunks.len(), 1);
+        }
+
+        assert_eq!(
+            sent_versions.lock().as_slice(),
+            &[
+                Some(HttpVersionPreference::Negotiate),
+                Some(HttpVersionPreference::Http1Only)
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_map_chunk() {
+        let mut provider = provider_with_events(vec![
+            content_event("Hello, ", None),
+            content_event("world", Some("stop")),
+        ]);
+        provider.set_map_chunk(Some(Arc::new(|chunk: String| chunk.to_uppercase())));
+
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<Vec<_>>>().unwrap(),
+            vec!["HELLO, ", "WORLD"]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_map_chunk_with_stop_sequence() {
+        let mut provider = provider_with_events(vec![
+            content_event("Hello, en", None),
+            content_event("d of story", Some("stop")),
+        ]);
+        provider.set_map_chunk(Some(Arc::new(|chunk: String| chunk.to_uppercase())));
+
+        // The map sees the chunks as they were streamed, so the stop sequence only
+        // appears once they've been mapped.
+        let chunks = provider
+            .stream_completion(LanguageModelRequest {
+                stop: vec!["END".into()],
+                ..user_request("Hi")
+            })
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<Vec<_>>>().unwrap(),
+            vec!["HELLO, "]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_completion_metrics() {
+        let mut provider = provider_with_events(vec![
+            content_event("Hello, ", None),
+            content_event("world", Some("stop")),
+        ]);
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        provider.set_on_completion_metrics(Some({
+            let reported = reported.clone();
+            Arc::new(move |metrics| reported.lock().push(metrics))
+        }));
+
+        let chunks = provider
+            .stream_completion_events_with_request_id(user_request("Hi"), "request".into())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(chunks.iter().all(|chunk| chunk.is_ok()));
+        let metrics = reported.lock().pop().unwrap();
+        assert_eq!(metrics.request_id, "request");
+        assert_eq!(metrics.model, "gpt-4o");
+        assert!(metrics.time_to_first_token.is_some());
+        assert_eq!(metrics.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(metrics.error, None);
+
+        // Requests refused before being sent are measured too.
+        provider.set_token_budget(Some(0));
+        assert!(provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .is_err());
+        let metrics = reported.lock().pop().unwrap();
+        assert_eq!(metrics.time_to_first_token, None);
+        assert_eq!(metrics.error, Some(CompletionErrorKind::Invalid));
+        assert!(reported.lock().is_empty());
+    }
+
+    #[gpui::test]
+    async fn test_record_and_replay(cx: &mut TestAppContext) {
+        #[derive(Clone, Default)]
+        struct Recording(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Recording {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut provider = provider_with_events(vec![
+            content_event("Hello, ", None),
+            content_event("world", Some("stop")),
+        ]);
+        let recording = Recording::default();
+        provider.set_completion_recorder(Some(Arc::new(CompletionRecorder::from_writer(
+            recording.clone(),
+        ))));
+        let recorded_events = provider
+            .stream_completion_events_with_request_id(user_request("Hi"), "request".into())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let recording = String::from_utf8(recording.0.lock().clone()).unwrap();
+        // A line for the request, each chunk and the end of the response.
+        assert_eq!(recording.lines().count(), 4);
+        let replay = ReplayCompletionProvider::new(&recording, cx.executor()).unwrap();
+        let replayed_events = replay
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(replayed_events, recorded_events);
+        assert_eq!(replay.remaining_completions(), 0);
+        assert!(replay.stream_completion(user_request("Hi")).await.is_err());
+
+        // Requests that fail before their response starts are recorded too, and
+        // fail the same way when replayed.
+        let http_client = FakeHttpClient::create(|_| async {
+            Ok(Response::builder()
+                .status(400)
+                .body("bad request".into())
+                .unwrap())
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        let recording = Recording::default();
+        provider.set_completion_recorder(Some(Arc::new(CompletionRecorder::from_writer(
+            recording.clone(),
+        ))));
+        assert!(provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .is_err());
+
+        let recording = String::from_utf8(recording.0.lock().clone()).unwrap();
+        assert_eq!(recording.lines().count(), 2);
+        let replay = ReplayCompletionProvider::new(&recording, cx.executor()).unwrap();
+        let error = replay
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 400);
+        assert_eq!(replay.remaining_completions(), 0);
+    }
+
+    #[gpui::test]
+    fn test_replay_errors(cx: &mut TestAppContext) {
+        let recording = [
+            json!({ "type": "request", "completion": "a", "request": {} }),
+            json!({ "type": "chunk", "completion": "a", "elapsed_ms": 10, "chunk": content_event("Hi", None) }),
+            json!({ "type": "error", "completion": "a", "elapsed_ms": 1000, "status": 502, "message": "bad gateway" }),
+        ]
+        .map(|line| line.to_string())
+        .join("\n");
+        let replay = ReplayCompletionProvider::new(&recording, cx.executor()).unwrap();
+        let mut chunks = replay
+            .stream_completion(user_request("Hi"))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        assert!(chunks.next().now_or_never().is_none());
+        cx.executor().advance_clock(Duration::from_millis(10));
+        assert_eq!(
+            chunks.next().now_or_never().unwrap().unwrap().unwrap(),
+            "Hi"
+        );
+        assert!(chunks.next().now_or_never().is_none());
+        cx.executor().advance_clock(Duration::from_millis(990));
+        let error = chunks.next().now_or_never().unwrap().unwrap().unwrap_err();
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 502);
+        assert!(chunks.next().now_or_never().unwrap().is_none());
+    }
+
+    #[gpui::test]
+    async fn test_gzipped_stream() {
+        // Compress each event on its own and flush after it, so the first event
+        // can be decoded before the rest of the body has been sent.
+        let mut encoder = async_compression::futures::write::GzipEncoder::new(Vec::new());
+        let mut encoded_chunks = Vec::new();
+        for event in [
+            format!("data: {}\n\n", content_event("Hello", None)),
+            format!("data: {}\n\n", content_event(" world", Some("stop"))),
+            "data: [DONE]\n\n".to_string(),
+        ] {
+            encoder.write_all(event.as_bytes()).await.unwrap();
+            encoder.flush().await.unwrap();
+            encoded_chunks.push(mem::take(encoder.get_mut()));
+        }
+        encoder.close().await.unwrap();
+        encoded_chunks.push(encoder.into_inner());
+
+        let (body_tx, body_rx) = futures::channel::mpsc::unbounded::<std::io::Result<Vec<u8>>>();
+        let body_rx = Mutex::new(Some(body_rx));
+        let http_client = FakeHttpClient::create(move |request| {
+            assert_eq!(request.headers()["Accept-Encoding"], "gzip");
+            let body = AsyncBody::from_reader(body_rx.lock().take().unwrap().into_async_read());
+            async move {
+                Ok(Response::builder()
+                    .status(200)
+                    .header("Content-Encoding", "gzip")
+                    .body(body)
+                    .unwrap())
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        let mut encoded_chunks = encoded_chunks.into_iter();
+        body_tx
+            .unbounded_send(Ok(encoded_chunks.next().unwrap()))
+            .unwrap();
+        let mut chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap();
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "Hello");
+
+        for chunk in encoded_chunks {
+            body_tx.unbounded_send(Ok(chunk)).unwrap();
+        }
+        drop(body_tx);
+        let rest = chunks.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(rest.concat(), " world");
+    }
+
+    #[gpui::test]
+    async fn test_non_json_error_body() {
+        let page = format!(
+            "<html><head><title>502 Bad Gateway</title></head><body>{}</body></html>",
+            "<center>nginx</center>".repeat(50)
+        );
+        let http_client = FakeHttpClient::create(move |_| {
+            let page = page.clone();
+            async move { Ok(Response::builder().status(502).body(page.into()).unwrap()) }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        provider.set_max_retries(0);
+
+        let error = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("502 Bad Gateway"), "{error}");
+        assert!(error.contains("<title>"), "{error}");
+        assert!(error.ends_with('…'), "{error}");
+        assert!(error.len() < 300, "{error}");
+    }
+
+    #[gpui::test]
+    async fn test_classified_errors() {
+        let provider_with_error = |status: u16, body: serde_json::Value| {
+            let http_client = FakeHttpClient::create(move |_| {
+                let body = body.to_string();
+                async move {
+                    Ok(Response::builder()
+                        .status(status)
+                        .header("retry-after", "20")
+                        .body(body.into())
+                        .unwrap())
+                }
+            });
+            let mut provider = OpenAiCompletionProvider::new(
+                OpenAiModel::FourOmni,
+                open_ai::OPEN_AI_API_URL.into(),
+                http_client,
+                None,
+                0,
+                Vec::new(),
+            );
+            provider.api_keys = vec!["sk-test".into()];
+            provider.set_max_retries(0);
+            provider
+        };
+        let error_body = |message: &str, code: Option<&str>| json!({ "error": { "message": message, "type": "invalid_request_error", "code": code } });
+
+        let error = provider_with_error(401, error_body("Incorrect API key provided", None))
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::Authentication { .. })
+        ));
+        // The underlying error and its message are kept.
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 401);
+        assert!(error.to_string().contains("Incorrect API key provided"));
+
+        let error = provider_with_error(429, error_body("Rate limit reached", None))
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            }) if *retry_after == Duration::from_secs(20)
+        ));
+
+        let error = provider_with_error(
+            400,
+            error_body(
+                "This model's maximum context length is 128000 tokens",
+                Some("context_length_exceeded"),
+            ),
+        )
+        .stream_completion(user_request("Hi"))
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::ContextLengthExceeded { .. })
+        ));
+
+        let error = provider_with_error(400, error_body("Invalid value for 'n'", None))
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::InvalidRequest(message)) if message.contains("Invalid value")
+        ));
+
+        let error = provider_with_error(500, error_body("The server had an error", None))
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::ServerError { status: 500, .. })
+        ));
+
+        let error = test_provider()
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::Authentication { .. })
+        ));
+    }
+
+    #[test]
+    fn test_model_low_speed_timeouts() {
+        let mut provider = test_provider();
+        provider.low_speed_timeout = Some(Duration::from_secs(10));
+        provider.set_model_low_speed_timeouts(HashMap::from_iter([
+            ("gpt-4".into(), Duration::from_secs(60)),
+            ("my-model".into(), Duration::from_secs(30)),
+        ]));
+        assert_eq!(
+            provider.low_speed_timeout_for(&OpenAiModel::Four),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            provider.low_speed_timeout_for(&OpenAiModel::Custom {
+                name: "my-model".into(),
+                max_tokens: 8192,
+                tokenizer: None,
+            }),
+            Some(Duration::from_secs(30))
+        );
+        // Models without an override use the provider's timeout.
+        assert_eq!(
+            provider.low_speed_timeout_for(&OpenAiModel::ThreePointFiveTurbo),
+            Some(Duration::from_secs(10))
+        );
+
+        let request = provider.request_config(&OpenAiModel::Four, "request".into(), None);
+        assert_eq!(request.low_speed_timeout, Some(Duration::from_secs(60)));
+    }
+
+    #[gpui::test]
+    async fn test_reasoning_idle_timeout() {
+        let reasoning_model = OpenAiModel::Custom {
+            name: "o1-preview".into(),
+            max_tokens: 128000,
+            tokenizer: None,
+        };
+        let mut provider = test_provider();
+        provider.low_speed_timeout = Some(Duration::from_secs(10));
+        assert_eq!(
+            provider.low_speed_timeout_for(&reasoning_model),
+            Some(Duration::from_secs(10))
+        );
+
+        provider.set_reasoning_idle_timeout(Some(Duration::from_secs(120)));
+        assert_eq!(
+            provider.low_speed_timeout_for(&reasoning_model),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            provider.low_speed_timeout_for(&OpenAiModel::FourOmni),
+            Some(Duration::from_secs(10))
+        );
+
+        // Heartbeats sent by the server while the model is thinking are skipped.
+        let http_client = FakeHttpClient::create(|_| async move {
+            let body = format!(
+                "{}data: {}\n\ndata: [DONE]\n\n",
+                ": keep-alive\n\n".repeat(100),
+                content_event("Done thinking", Some("stop"))
+            );
+            Ok(Response::builder().status(200).body(body.into()).unwrap())
+        });
+        provider.http_client = http_client;
+        provider.api_keys = vec!["sk-test".into()];
+        let mut request = user_request("Think hard");
+        request.model = LanguageModel::OpenAi(reasoning_model);
+        let chunks = provider
+            .stream_completion(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Done thinking"
+        );
+    }
+
+    #[test]
+    fn test_export_config_is_redacted() {
+        let mut provider = test_provider();
+        provider.api_keys = vec!["sk-secret-1234".into()];
+        provider.low_speed_timeout = Some(Duration::from_secs(30));
+
+        let snapshot = provider.export_config();
+        assert!(snapshot.authenticated);
+        assert_eq!(snapshot.api_url, open_ai::OPEN_AI_API_URL);
+        assert_eq!(snapshot.low_speed_timeout_secs, Some(30));
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(!json.contains("sk-secret"), "{json}");
+        assert!(!json.contains("1234"), "{json}");
+    }
+
+    #[test]
+    fn test_authentication_prompt_for_api_url() {
+        let instructions_for = |api_url, provider_name| {
+            authentication_instructions(api_url, provider_name)
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        for api_url in ["https://api.openai.com/v1", "https://api.openai.com/v1/"] {
+            assert!(is_default_api_url(api_url));
+            let instructions = instructions_for(api_url, "OpenAI");
+            assert!(instructions.contains("platform.openai.com"));
+            assert!(api_key_placeholder(api_url).starts_with("sk-"));
+        }
+
+        let api_url = "http://localhost:8080/v1";
+        assert!(!is_default_api_url(api_url));
+        let instructions = instructions_for(api_url, "localhost");
+        assert!(!instructions.contains("platform.openai.com"));
+        assert!(instructions.contains("your localhost API key"));
+        assert!(instructions.contains("leave this empty"));
+        assert!(!api_key_placeholder(api_url).starts_with("sk-"));
+    }
+
+    fn weather_tool() -> LanguageModelTool {
+        LanguageModelTool {
+            name: "get_weather".into(),
+            description: Some("Get the weather for a city".into()),
+            parameters: Some(json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+            })),
+        }
+    }
+
+    #[test]
+    fn test_tool_choice() {
+        let provider = test_provider();
+        for (tool_choice, expected) in [
+            (LanguageModelToolChoice::Auto, json!("auto")),
+            (LanguageModelToolChoice::None, json!("none")),
+            (LanguageModelToolChoice::Required, json!("required")),
+            (
+                LanguageModelToolChoice::Specific("get_weather".into()),
+                json!({ "type": "function", "function": { "name": "get_weather" } }),
+            ),
+        ] {
+            let mut request = user_request("What's the weather in Paris?");
+            request.tools = vec![weather_tool()];
+            request.tool_choice = Some(tool_choice);
+            let request =
+                serde_json::to_value(provider.to_open_ai_request(request).unwrap()).unwrap();
+            assert_eq!(request["tool_choice"], expected);
+            assert_eq!(request["tools"][0]["function"]["name"], "get_weather");
+        }
+
+        let request =
+            serde_json::to_value(provider.to_open_ai_request(user_request("Hi")).unwrap()).unwrap();
+        assert!(request.get("tool_choice").is_none());
+        assert!(request.get("tools").is_none());
+        // A tool named like one of the other choices is still chosen by its name
+        // after being sent over the wire.
+        let mut request = user_request("Hi");
+        request.tools = vec![LanguageModelTool {
+            name: "auto".into(),
+            ..weather_tool()
+        }];
+        request.tool_choice = Some(LanguageModelToolChoice::Specific("auto".into()));
+        let request = LanguageModelRequest::from_proto(request.to_proto(), request.model).unwrap();
+        assert_eq!(
+            request.tool_choice,
+            Some(LanguageModelToolChoice::Specific("auto".into()))
+        );
+
+        let mut request = user_request("Hi");
+        request.tools = vec![LanguageModelTool {
+            parameters: Some(json!(true)),
+            ..weather_tool()
+        }];
+        assert!(matches!(
+            provider.to_open_ai_request(request),
+            Err(CompletionError::UnsupportedContent(_))
+        ));
+    }
+
+    #[gpui::test]
+    async fn test_tool_choice_must_name_a_request_tool() {
+        let provider = provider_with_events(vec![content_event("Hi", Some("stop"))]);
+        let mut request = user_request("What's the weather in Paris?");
+        request.tools = vec![weather_tool()];
+        request.tool_choice = Some(LanguageModelToolChoice::Specific("get_time".into()));
+
+        let error = provider.stream_completion(request).await.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::UnknownToolChoice { name, declared })
+                if name == "get_time" && declared == &["get_weather"]
+        ));
+        assert_eq!(
+            error.downcast_ref::<CompletionError>().unwrap().to_string(),
+            r#"the tool choice names "get_time", but the request only declares ["get_weather"]"#
+        );
+    }
+
+    fn tool_call_event(
+        index: usize,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: &str,
+    ) -> serde_json::Value {
+        json!({
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "delta": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "index": index,
+                        "id": id,
+                        "function": { "name": name, "arguments": arguments },
+                    }],
+                },
+                "finish_reason": null,
+            }],
+        })
+    }
+
+    fn is_tool_call_delta(event: &Result<CompletionEvent>) -> bool {
+        matches!(event, Ok(CompletionEvent::ToolCallDelta { .. }))
+    }
+
+    #[gpui::test]
+    async fn test_tool_call_deltas() {
+        let provider = provider_with_events(vec![
+            tool_call_event(0, Some("call_1"), Some("get_weather"), ""),
+            tool_call_event(0, None, None, "{\"city\":"),
+            tool_call_event(0, None, None, "\"Paris\"}"),
+        ]);
+        let events = provider
+            .stream_completion_events(user_request("What's the weather?"))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        let delta = |id: Option<&str>, name: Option<&str>, arguments_fragment: &str| {
+            CompletionEvent::ToolCallDelta {
+                index: 0,
+                id: id.map(Into::into),
+                name: name.map(Into::into),
+                arguments_fragment: arguments_fragment.into(),
+            }
+        };
+        assert_eq!(
+            events[..4],
+            [
+                delta(Some("call_1"), Some("get_weather"), ""),
+                delta(None, None, "{\"city\":"),
+                delta(None, None, "\"Paris\"}"),
+                CompletionEvent::ToolCall {
+                    tool_call: LanguageModelToolCall {
+                        id: "call_1".into(),
+                        name: "get_weather".into(),
+                        arguments: "{\"city\":\"Paris\"}".into(),
+                    },
+                    is_complete: true,
+                },
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_incomplete_tool_call_at_stream_end() {
+        // The stream ends while the second tool call's arguments are still streaming.
+        let events = || {
+            vec![
+                tool_call_event(0, Some("call_1"), Some("get_weather"), "{\"city\":"),
+                tool_call_event(0, None, None, "\"Paris\"}"),
+                tool_call_event(1, Some("call_2"), Some("get_weather"), "{\"city\":\"Ly"),
+            ]
+        };
+
+        let provider = provider_with_events(events());
+        let events = provider
+            .stream_completion_events(user_request("What's the weather?"))
+            .await
+            .unwrap()
+            .filter(|event| futures::future::ready(!is_tool_call_delta(event)))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events[0].as_ref().unwrap(),
+            &CompletionEvent::ToolCall {
+                tool_call: LanguageModelToolCall {
+                    id: "call_1".into(),
+                    name: "get_weather".into(),
+                    arguments: "{\"city\":\"Paris\"}".into(),
+                },
+                is_complete: true,
+            }
+        );
+        assert!(matches!(
+            events[1].as_ref().unwrap_err().downcast_ref::<CompletionError>(),
+            Some(CompletionError::IncompleteToolCall { id, .. }) if id == "call_2"
+        ));
+        assert!(matches!(
+            events[2].as_ref().unwrap(),
+            CompletionEvent::StreamEnd(_)
+        ));
+
+        let mut provider = provider_with_events(events());
+        provider.set_incomplete_tool_call_behavior(IncompleteToolCallBehavior::EmitIncomplete);
+        let events = provider
+            .stream_completion_events(user_request("What's the weather?"))
+            .await
+            .unwrap()
+            .filter(|event| futures::future::ready(!is_tool_call_delta(event)))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events[1].as_ref().unwrap(),
+            &CompletionEvent::ToolCall {
+                tool_call: LanguageModelToolCall {
+                    id: "call_2".into(),
+                    name: "get_weather".into(),
+                    arguments: "{\"city\":\"Ly".into(),
+                },
+                is_complete: false,
+            }
+        );
+    }
+
+    #[gpui::test]
+    async fn test_non_streaming_fallback() {
+        // A proxy that fails streamed requests, but passes on those that aren't.
+        let http_client = FakeHttpClient::create(|request| async move {
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await.unwrap();
+            let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+            if body["stream"] == json!(true) {
+                return Ok(Response::builder()
+                    .status(502)
+                    .body("bad gateway".into())
+                    .unwrap());
+            }
+            assert!(body.get("stream_options").is_none());
+            let completion = json!({
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Hello" },
+                    "finish_reason": "stop",
+                }],
+                "usage": { "prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4 },
+            });
+            Ok(Response::builder()
+                .status(200)
+                .body(completion.to_string().into())
+                .unwrap())
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        provider.set_max_retries(0);
+
+        let error = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 502);
+
+        provider.set_non_streaming_fallback(true);
+        let events = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(events[0], CompletionEvent::Text("Hello".into()));
+        let Some(CompletionEvent::StreamEnd(stream_end)) = events.last() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        assert_eq!(stream_end.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(
+            stream_end.usage.map(|usage| usage.completion_tokens),
+            Some(1)
+        );
+        assert!(stream_end.path.non_streaming);
+    }
+
+    #[gpui::test]
+    async fn test_request_transform() {
+        let sent_bodies = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_bodies = sent_bodies.clone();
+            move |request| {
+                let sent_bodies = sent_bodies.clone();
+                async move {
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    sent_bodies
+                        .lock()
+                        .push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+                    let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        provider.set_default_max_tokens(HashMap::from_iter([("gpt-4o".into(), Some(1024))]));
+        provider.set_request_transform(Some(Arc::new(|request: &mut Request| {
+            request.stop.push("<|end|>".into());
+            request.max_tokens = None;
+        })));
+
+        provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        let sent_bodies = sent_bodies.lock();
+        assert_eq!(sent_bodies[0]["stop"], json!(["<|end|>"]));
+        assert!(sent_bodies[0].get("max_tokens").is_none());
+    }
+
+    #[test]
+    fn test_open_ai_usage_cost() {
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 500_000,
+            ..Default::default()
+        };
+        let cost = open_ai_usage_cost(&OpenAiModel::FourOmniMini, usage).unwrap();
+        assert!((cost - 0.45).abs() < 1e-9);
+        for model in OpenAiModel::iter() {
+            let cost = open_ai_usage_cost(&model, usage);
+            if matches!(model, OpenAiModel::Custom { .. }) {
+                assert_eq!(cost, None);
+            } else {
+                assert!(cost.unwrap() > 0., "{model:?}");
+            }
+        }
+
+        let price = open_ai_token_price(&OpenAiModel::Four).unwrap();
+        assert!((price.cost(usage) - (price.prompt + price.completion / 2.)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_open_ai_image_tokens() {
+        // Examples from OpenAI's vision documentation.
+        assert_eq!(open_ai_image_tokens(1024, 1024, ImageDetail::High), 765);
+        assert_eq!(open_ai_image_tokens(2048, 4096, ImageDetail::High), 1105);
+        assert_eq!(open_ai_image_tokens(4096, 8192, ImageDetail::Low), 85);
+
+        assert_eq!(open_ai_image_tokens(512, 512, ImageDetail::High), 255);
+        assert_eq!(
+            open_ai_image_tokens(2048, 4096, ImageDetail::Auto),
+            open_ai_image_tokens(2048, 4096, ImageDetail::High)
+        );
+    }
+
+    #[gpui::test]
+    async fn test_token_budget() {
+        let mut usage_event = content_event("Hi", Some("stop"));
+        usage_event["usage"] = json!({
+            "prompt_tokens": 10,
+            "completion_tokens": 2,
+            "total_tokens": 12,
+        });
+        let mut provider = provider_with_events(vec![usage_event]);
+        provider.set_token_budget(Some(30));
+        provider.set_token_price(Some(TokenPrice {
+            prompt: 5.,
+            completion: 15.,
+            cache_write: 5.,
+            cache_read: 5.,
+        }));
+
+        for _ in 0..3 {
+            provider
+                .stream_completion(user_request("Hi"))
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await;
+        }
+        let usage = provider.session_usage();
+        assert_eq!(usage.prompt_tokens, 30);
+        assert_eq!(usage.completion_tokens, 6);
+        assert_eq!(usage.requests, 3);
+        assert!((usage.estimated_cost.unwrap() - 0.00024).abs() < 1e-9);
+
+        let error = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::BudgetExceeded { budget: 30 })
+        ));
+        assert_eq!(provider.session_usage(), usage);
+    }
+
+    #[gpui::test]
+    async fn test_empty_api_key() {
+        let http_client = FakeHttpClient::create(|request| {
+            assert!(!request.headers().contains_key("Authorization"));
+            let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+            async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            "http://localhost:8080/v1".into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        // Saved for a server that doesn't require a key.
+        provider.api_keys = vec![String::new()];
+
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), "Hi");
+    }
+
+    #[gpui::test]
+    async fn test_api_url_without_scheme() {
+        let sent_uris = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_uris = sent_uris.clone();
+            move |request| {
+                sent_uris.lock().push(request.uri().to_string());
+                let body = format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None));
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            "api.example.com/v1".into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            sent_uris.lock().as_slice(),
+            &["https://api.example.com/v1/chat/completions".to_string()]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_usage_chunk() {
+        let sent_bodies = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_bodies = sent_bodies.clone();
+            move |request| {
+                let sent_bodies = sent_bodies.clone();
+                async move {
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    sent_bodies
+                        .lock()
+                        .push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+                    // The usage arrives in a chunk of its own, after the content.
+                    let usage_event = json!({
+                        "created": 0,
+                        "model": "gpt-4o",
+                        "choices": [],
+                        "usage": {
+                            "prompt_tokens": 8,
+                            "completion_tokens": 1,
+                            "total_tokens": 9,
+                        },
+                    });
+                    let body = format!(
+                        "data: {}\n\ndata: {usage_event}\n\ndata: [DONE]\n\n",
+                        content_event("Hi", Some("stop"))
+                    );
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        let events = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            sent_bodies.lock()[0]["stream_options"],
+            json!({ "include_usage": true })
+        );
+        assert_eq!(events[0], CompletionEvent::Text("Hi".into()));
+        let Some(CompletionEvent::StreamEnd(stream_end)) = events.last() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        assert_eq!(stream_end.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(
+            stream_end.usage,
+            Some(TokenUsage {
+                prompt_tokens: 8,
+                completion_tokens: 1,
                 ..Default::default()
+            })
+        );
+    }
+
+    #[gpui::test]
+    async fn test_finish_reason_in_trailing_chunk() {
+        // The finish reason arrives in a chunk without content, followed by one
+        // carrying only the usage.
+        let provider = provider_with_events(vec![
+            content_event("Once upon a", None),
+            json!({
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{ "index": 0, "delta": {}, "finish_reason": "length" }],
+            }),
+            json!({
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [],
+                "usage": { "prompt_tokens": 8, "completion_tokens": 3, "total_tokens": 11 },
+            }),
+        ]);
+        let events = provider
+            .stream_completion_events(user_request("Tell me a story"))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(events[0], CompletionEvent::Text("Once upon a".into()));
+        let Some(CompletionEvent::StreamEnd(stream_end)) = events.last() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        assert_eq!(stream_end.finish_reason.as_deref(), Some("length"));
+        assert!(stream_end.is_truncated());
+    }
+
+    #[gpui::test]
+    async fn test_stream_end_emitted_once() {
+        let mut final_event = content_event("", Some("stop"));
+        final_event["system_fingerprint"] = json!("fp_123");
+        final_event["usage"] = json!({
+            "prompt_tokens": 5,
+            "completion_tokens": 2,
+            "total_tokens": 7,
+        });
+        let provider = provider_with_events(vec![
+            content_event("Hel", None),
+            content_event("lo", None),
+            final_event,
+        ]);
+        let events = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let stream_ends = events
+            .iter()
+            .filter(|event| matches!(event, CompletionEvent::StreamEnd(_)))
+            .count();
+        assert_eq!(stream_ends, 1);
+        let Some(CompletionEvent::StreamEnd(mut stream_end)) = events.last().cloned() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        // A request id is generated for every completion.
+        assert!(stream_end.request_id.take().is_some());
+        assert_eq!(
+            stream_end,
+            StreamEnd {
+                finish_reason: Some("stop".into()),
+                usage: Some(TokenUsage {
+                    prompt_tokens: 5,
+                    completion_tokens: 2,
+                    ..Default::default()
+                }),
+                system_fingerprint: Some("fp_123".into()),
+                path: CompletionPath::default(),
+                request_id: None,
+            }
+        );
+
+        // Callers that only read text never see the end-of-stream metadata.
+        let chunks = provider
+            .stream_completion(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hello"
+        );
+    }
+
+    #[test]
+    fn test_default_max_tokens() {
+        let mut provider = test_provider();
+        provider.set_default_max_tokens(HashMap::from_iter([
+            ("gpt-4o".to_string(), Some(1024)),
+            ("gpt-4o-mini".to_string(), None),
+        ]));
+
+        let request = provider.to_open_ai_request(user_request("Hi")).unwrap();
+        assert_eq!(request.max_tokens, Some(1024));
+
+        let mut explicit = user_request("Hi");
+        explicit.max_tokens = Some(16);
+        let request = provider.to_open_ai_request(explicit).unwrap();
+        assert_eq!(request.max_tokens, Some(16));
+
+        let mut uncapped = user_request("Hi");
+        uncapped.model = LanguageModel::OpenAi(OpenAiModel::FourOmniMini);
+        let request = provider.to_open_ai_request(uncapped).unwrap();
+        assert_eq!(request.max_tokens, None);
+
+        let mut unlisted = user_request("Hi");
+        unlisted.model = LanguageModel::OpenAi(OpenAiModel::Four);
+        let request = provider.to_open_ai_request(unlisted).unwrap();
+        assert_eq!(request.max_tokens, None);
+    }
+
+    #[test]
+    fn test_sampling_parameters() {
+        let provider = test_provider();
+        let body =
+            |request| serde_json::to_value(provider.to_open_ai_request(request).unwrap()).unwrap();
+
+        let unset = body(user_request("Hi"));
+        for parameter in ["top_p", "frequency_penalty", "presence_penalty"] {
+            assert!(unset.get(parameter).is_none(), "{parameter} was serialized");
+        }
+
+        let mut request = user_request("Hi");
+        request.top_p = Some(0.5);
+        request.frequency_penalty = Some(0.25);
+        request.presence_penalty = Some(-1.);
+        let set = body(request);
+        assert_eq!(set["top_p"], json!(0.5));
+        assert_eq!(set["frequency_penalty"], json!(0.25));
+        assert_eq!(set["presence_penalty"], json!(-1.));
+    }
+
+    #[test]
+    fn test_image_inputs() {
+        let mut request = user_request("What's in these images?");
+        request.messages[0].images = vec![
+            LanguageModelImage::from_base64("image/png", "iVBORw0KGgo="),
+            LanguageModelImage {
+                url: "https://example.com/cat.jpg".into(),
+                detail: Some(ImageDetail::Low),
+                size: None,
             },
-        )
+        ];
+        let request = test_provider().to_open_ai_request(request).unwrap();
+        assert_eq!(
+            serde_json::to_value(&request.messages).unwrap(),
+            json!([{
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What's in these images?" },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": "data:image/png;base64,iVBORw0KGgo=" },
+                    },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": "https://example.com/cat.jpg", "detail": "low" },
+                    },
+                ],
+            }])
+        );
+
+        // Messages without images are still sent as plain text.
+        let request = test_provider()
+            .to_open_ai_request(user_request("Hi"))
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&request.messages).unwrap(),
+            json!([{ "role": "user", "content": "Hi" }])
+        );
     }
-}
 
-impl Render for AuthenticationPrompt {
-    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        const INSTRUCTIONS: [&str; 6] = [
-            "To use the assistant panel or inline assistant, you need to add your OpenAI API key.",
-            " - You can create an API key at: platform.openai.com/api-keys",
-            " - Make sure your OpenAI account has credits",
-            " - Having a subscription for another service like GitHub Copilot won't work.",
-            "",
-            "Paste your OpenAI API key below and hit enter to use the assistant:",
+    #[test]
+    fn test_seed() {
+        let provider = test_provider();
+        let request = provider.to_open_ai_request(user_request("Hi")).unwrap();
+        assert!(serde_json::to_value(&request)
+            .unwrap()
+            .get("seed")
+            .is_none());
+
+        let mut seeded = user_request("Hi");
+        seeded.seed = Some(42);
+        let request = provider.to_open_ai_request(seeded).unwrap();
+        assert_eq!(serde_json::to_value(&request).unwrap()["seed"], json!(42));
+    }
+
+    #[test]
+    fn test_json_mode() {
+        let provider = test_provider();
+        let json_request = |content: &str| {
+            let mut request = user_request(content);
+            request.response_format = Some(LanguageModelResponseFormat::JsonObject);
+            request
+        };
+
+        let request = provider
+            .to_open_ai_request(json_request("List three colors as a JSON array."))
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["response_format"],
+            json!({ "type": "json_object" })
+        );
+
+        let error = provider
+            .to_open_ai_request(json_request("List three colors."))
+            .unwrap_err();
+        assert!(matches!(error, CompletionError::JsonModeWithoutJsonPrompt));
+    }
+
+    #[test]
+    fn test_max_tokens_overflowing_context_window() {
+        let provider = test_provider();
+        let request = |max_tokens| {
+            let mut request = user_request("Hi");
+            request.model = LanguageModel::OpenAi(OpenAiModel::Four);
+            request.max_tokens = max_tokens;
+            request
+        };
+
+        assert!(provider.build_effective_request(request(None)).is_ok());
+        assert!(provider
+            .build_effective_request(request(Some(4096)))
+            .is_ok());
+
+        let error = provider
+            .build_effective_request(request(Some(8192)))
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::ContextWindowExceeded {
+                prompt_tokens: 1,
+                max_tokens: 8192,
+                context_window: 8192,
+            })
+        ));
+
+        // Prompts that don't fit on their own are rejected without `max_tokens`.
+        let mut long_request = request(None);
+        long_request.messages[0].content = "Hi ".repeat(9000);
+        let error = provider.build_effective_request(long_request).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::ContextWindowExceeded {
+                prompt_tokens,
+                max_tokens: 0,
+                context_window: 8192,
+            }) if *prompt_tokens > 8192
+        ));
+    }
+
+    #[gpui::test]
+    async fn test_truncation_strategy() {
+        let mut provider = provider_with_events(vec![content_event("Hello", Some("stop"))]);
+        let mut request = user_request("Hi");
+        request.model = LanguageModel::OpenAi(OpenAiModel::Custom {
+            name: "small-model".into(),
+            max_tokens: 50,
+            tokenizer: None,
+        });
+        request.max_tokens = Some(10);
+        request.messages.splice(
+            0..0,
+            [
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "word ".repeat(100),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: "Hello".into(),
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: false,
+                },
+            ],
+        );
+
+        let error = provider
+            .build_effective_request(request.clone())
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::ContextWindowExceeded { .. })
+        ));
+
+        provider.set_truncation_strategy(Some(TruncationStrategy::DropOldest));
+        let effective_request = provider.build_effective_request(request.clone()).unwrap();
+        assert_eq!(effective_request.messages.len(), 2);
+        let events = provider
+            .stream_completion_events(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let Some(CompletionEvent::StreamEnd(stream_end)) = events.last() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        assert_eq!(stream_end.path.dropped_messages, 1);
+    }
+
+    #[gpui::test]
+    async fn test_retries() {
+        // Each request is answered with the next status, the last one repeating.
+        let provider_with_statuses = |statuses: Vec<u16>| {
+            let requests = Arc::new(AtomicUsize::new(0));
+            let http_client = FakeHttpClient::create({
+                let requests = requests.clone();
+                move |_| {
+                    let ix = requests.fetch_add(1, SeqCst);
+                    let status = statuses[ix.min(statuses.len() - 1)];
+                    async move {
+                        if status == 200 {
+                            let body = format!(
+                                "data: {}\n\ndata: [DONE]\n\n",
+                                content_event("Hi", Some("stop"))
+                            );
+                            return Ok(Response::builder().status(200).body(body.into()).unwrap());
+                        }
+                        Ok(Response::builder()
+                            .status(status)
+                            .header("retry-after", "0")
+                            .body("try again later".into())
+                            .unwrap())
+                    }
+                }
+            });
+            let mut provider = OpenAiCompletionProvider::new(
+                OpenAiModel::FourOmni,
+                open_ai::OPEN_AI_API_URL.into(),
+                http_client,
+                None,
+                0,
+                Vec::new(),
+            );
+            provider.api_keys = vec!["sk-test".into()];
+            provider.set_retry_base_delay(Duration::ZERO);
+            (provider, requests)
+        };
+
+        let (provider, requests) = provider_with_statuses(vec![429, 503, 200]);
+        let events = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(requests.load(SeqCst), 3);
+        let Some(CompletionEvent::StreamEnd(stream_end)) = events.last() else {
+            panic!("expected the stream to end with a StreamEnd");
+        };
+        assert_eq!(stream_end.path.retries, 2);
+
+        // Retries are given up on after the configured number of them.
+        let (mut provider, requests) = provider_with_statuses(vec![500]);
+        provider.set_max_retries(1);
+        let error = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(requests.load(SeqCst), 2);
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 500);
+
+        // Errors that aren't transient aren't retried.
+        let (provider, requests) = provider_with_statuses(vec![400]);
+        assert!(provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .is_err());
+        assert_eq!(requests.load(SeqCst), 1);
+    }
+
+    #[gpui::test]
+    async fn test_key_rotation() {
+        let sent_keys = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_keys = sent_keys.clone();
+            move |request| {
+                let key = request
+                    .headers()
+                    .get("Authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                sent_keys.lock().push(key.clone());
+                async move {
+                    if key == "Bearer sk-limited" {
+                        return Ok(Response::builder()
+                            .status(429)
+                            .header("retry-after", "60")
+                            .body("rate limited".into())
+                            .unwrap());
+                    }
+                    let body = format!(
+                        "data: {}\n\ndata: [DONE]\n\n",
+                        content_event("Hi", Some("stop"))
+                    );
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-limited".into(), "sk-other".into()];
+
+        // The rate limited key is retried with the other one straight away, and
+        // then skipped while it cools down.
+        for _ in 0..2 {
+            provider
+                .stream_completion_events(user_request("Hi"))
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            sent_keys.lock().as_slice(),
+            &["Bearer sk-limited", "Bearer sk-other", "Bearer sk-other"]
+        );
+
+        let keys = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let now = Instant::now();
+        let mut rotation = KeyRotation::default();
+        let next_keys = |rotation: &mut KeyRotation, now| {
+            (0..3)
+                .map(|_| rotation.next_key(&keys, now).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(next_keys(&mut rotation, now), ["a", "b", "c"]);
+        rotation.cool_down("b", now + Duration::from_secs(10));
+        assert_eq!(next_keys(&mut rotation, now), ["a", "c", "a"]);
+        assert_eq!(
+            next_keys(&mut rotation, now + Duration::from_secs(10)),
+            ["b", "c", "a"]
+        );
+
+        // When every key is cooling down, the one that cools down soonest is used.
+        for (key, secs) in [("a", 30), ("b", 10), ("c", 20)] {
+            rotation.cool_down(key, now + Duration::from_secs(secs));
+        }
+        assert!(!rotation.has_usable_key(&keys, now));
+        assert_eq!(rotation.next_key(&keys, now).unwrap(), "b");
+        assert_eq!(KeyRotation::default().next_key(&[], now), None);
+    }
+
+    #[gpui::test]
+    async fn test_request_id() {
+        let sent_request_ids = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_request_ids = sent_request_ids.clone();
+            move |request| {
+                let request_id = request.headers()[open_ai::REQUEST_ID_HEADER]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                sent_request_ids.lock().push(request_id.clone());
+                async move {
+                    let mut response = Response::builder();
+                    // The server only returns an id of its own for some requests.
+                    if request_id == "ours-2" {
+                        response = response.header(open_ai::REQUEST_ID_HEADER, "req_server");
+                    }
+                    let body = format!(
+                        "data: {}\n\ndata: {{\"truncated\n\n",
+                        content_event("Hi", None)
+                    );
+                    Ok(response.status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        for (request_id, expected_request_id) in [("ours-1", "ours-1"), ("ours-2", "req_server")] {
+            let events = provider
+                .stream_completion_events_with_request_id(user_request("Hi"), request_id.into())
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await;
+            let error = events[1].as_ref().unwrap_err();
+            assert!(error
+                .to_string()
+                .contains(&format!("(request id: {expected_request_id})")));
+            let Some(Ok(CompletionEvent::StreamEnd(stream_end))) = events.last() else {
+                panic!("expected the stream to end with a StreamEnd");
+            };
+            assert_eq!(stream_end.request_id.as_deref(), Some(expected_request_id));
+        }
+        assert_eq!(*sent_request_ids.lock(), vec!["ours-1", "ours-2"]);
+    }
+
+    #[gpui::test]
+    async fn test_stream_cost_estimates() {
+        /// Counts every character as a token.
+        struct CharTokenizer;
+
+        impl Tokenizer for CharTokenizer {
+            fn count_tokens(&self, text: &str) -> usize {
+                text.chars().count()
+            }
+        }
+
+        let mut final_event = content_event("!", Some("stop"));
+        final_event["usage"] = json!({
+            "prompt_tokens": 10,
+            "completion_tokens": 4,
+            "total_tokens": 14,
+        });
+        let mut provider = provider_with_events(vec![
+            content_event("He", None),
+            content_event("llo", None),
+            final_event,
+        ]);
+        let price = TokenPrice {
+            prompt: 1.,
+            completion: 2.,
+            cache_write: 1.,
+            cache_read: 1.,
+        };
+        provider.set_token_price(Some(price));
+        provider.set_tokenizer(Some(Arc::new(CharTokenizer)));
+        provider.set_stream_cost_estimates(true);
+
+        let events = provider
+            .stream_completion_events(user_request("Hi"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let costs = events
+            .iter()
+            .filter_map(|event| match event {
+                CompletionEvent::Cost(cost) => Some(*cost),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let cost = |prompt_tokens, completion_tokens| {
+            price.cost(TokenUsage {
+                prompt_tokens,
+                completion_tokens,
+                ..Default::default()
+            })
+        };
+        let estimate = |completion_tokens| CostEstimate {
+            cost: cost(0, completion_tokens),
+            is_final: false,
+        };
+        assert_eq!(
+            costs,
+            vec![
+                estimate(2),
+                estimate(5),
+                estimate(6),
+                CostEstimate {
+                    cost: cost(10, 4),
+                    is_final: true,
+                },
+            ]
+        );
+        // Unlike the estimates, the final cost also covers the prompt.
+        assert!(costs[2].cost < costs[3].cost);
+        assert!(matches!(events.last(), Some(CompletionEvent::StreamEnd(_))));
+    }
+
+    #[gpui::test]
+    async fn test_prime_after_authentication() {
+        let sent_bodies = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_bodies = sent_bodies.clone();
+            move |request| {
+                let sent_bodies = sent_bodies.clone();
+                async move {
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    sent_bodies
+                        .lock()
+                        .push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+                    let body = format!(
+                        "data: {}\n\ndata: [DONE]\n\n",
+                        content_event("Hello", Some("length"))
+                    );
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+
+        // Priming is opt-in.
+        assert!(provider.set_api_key("sk-test".into()).is_none());
+        assert!(sent_bodies.lock().is_empty());
+
+        provider.set_prime_after_authentication(true);
+        let priming = provider.set_api_key("sk-test".into()).unwrap();
+        assert!(sent_bodies.lock().is_empty());
+        priming.await;
+        assert_eq!(sent_bodies.lock().len(), 1);
+        assert_eq!(sent_bodies.lock()[0]["max_tokens"], 1);
+
+        // Once the budget has been spent, the model isn't primed.
+        provider.set_token_budget(Some(0));
+        provider.set_api_key("sk-test".into()).unwrap().await;
+        assert_eq!(sent_bodies.lock().len(), 1);
+    }
+
+    #[gpui::test]
+    async fn test_fetch_models(cx: &mut TestAppContext) {
+        let http_client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/models");
+            let body = json!({
+                "object": "list",
+                "data": [
+                    { "id": "gpt-4o", "object": "model", "owned_by": "system" },
+                    { "id": "o1-mini", "object": "model", "owned_by": "system" },
+                    { "id": "text-embedding-3-small", "object": "model", "owned_by": "system" },
+                    { "id": "gpt-4o-audio-preview", "object": "model", "owned_by": "system" },
+                    { "id": "dall-e-3", "object": "model", "owned_by": "system" },
+                ],
+            });
+            Ok(Response::builder()
+                .status(200)
+                .body(body.to_string().into())
+                .unwrap())
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        let built_in_models = provider.available_models();
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+
+        let fetch_models = cx.update(|cx| {
+            cx.update_global::<CompletionProvider, _>(|provider, cx| {
+                provider
+                    .update_current_as::<_, OpenAiCompletionProvider>(|provider| {
+                        provider.fetch_models(cx)
+                    })
+                    .unwrap()
+            })
+        });
+        let expected = vec![
+            OpenAiModel::FourOmni,
+            OpenAiModel::Custom {
+                name: "o1-mini".into(),
+                max_tokens: LISTED_MODEL_MAX_TOKENS,
+                tokenizer: None,
+            },
         ];
+        assert_eq!(fetch_models.await.unwrap(), expected);
+        let available_models = cx.update(|cx| CompletionProvider::global(cx).available_models());
+        assert_eq!(
+            available_models,
+            expected
+                .into_iter()
+                .map(LanguageModel::OpenAi)
+                .collect::<Vec<_>>()
+        );
+        assert_ne!(available_models, built_in_models);
+    }
 
-        v_flex()
-            .p_4()
-            .size_full()
-            .on_action(cx.listener(Self::save_api_key))
-            .children(
-                INSTRUCTIONS.map(|instruction| Label::new(instruction).size(LabelSize::Small)),
-            )
-            .child(
-                h_flex()
-                    .w_full()
-                    .my_2()
-                    .px_2()
-                    .py_1()
-                    .bg(cx.theme().colors().editor_background)
-                    .rounded_md()
-                    .child(self.render_api_key_editor(cx)),
-            )
-            .child(
-                Label::new(
-                    "You can also assign the OPENAI_API_KEY environment variable and restart Zed.",
+    #[test]
+    fn test_is_rejected_api_key() {
+        for status in [401, 403] {
+            assert!(is_rejected_api_key(
+                &RequestError::new(status, "invalid key").into()
+            ));
+        }
+        // Not every API lists its models, so failing to do so doesn't mean the key
+        // is wrong.
+        assert!(!is_rejected_api_key(
+            &RequestError::new(404, "not found").into()
+        ));
+        assert!(!is_rejected_api_key(&anyhow!("connection refused")));
+    }
+
+    #[gpui::test]
+    async fn test_save_validated_api_key(cx: &mut TestAppContext) {
+        let authorizations = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let authorizations = authorizations.clone();
+            move |request| {
+                let authorization = request.headers()["Authorization"].to_str().unwrap();
+                let response = match request.uri().path() {
+                    "/v1/models" if authorization == "Bearer sk-typo" => {
+                        Response::builder().status(401).body(
+                            json!({ "error": { "message": "Incorrect API key" } })
+                                .to_string()
+                                .into(),
+                        )
+                    }
+                    "/v1/models" => Response::builder()
+                        .status(200)
+                        .body(json!({ "object": "list", "data": [] }).to_string().into()),
+                    _ => {
+                        authorizations.lock().push(authorization.to_string());
+                        Response::builder().status(200).body(
+                            format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None))
+                                .into(),
+                        )
+                    }
+                };
+                async move { Ok(response.unwrap()) }
+            }
+        });
+        let provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client.clone(),
+            None,
+            0,
+            Vec::new(),
+        );
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+        let save = |api_key: &'static str, cx: &mut TestAppContext| {
+            let http_client = http_client.clone();
+            let validate = async move {
+                let auth = ApiAuth::Bearer {
+                    api_key,
+                    organization: None,
+                };
+                list_models(http_client.as_ref(), open_ai::OPEN_AI_API_URL, auth, None).await
+            };
+            let mut cx = cx.to_async();
+            async move {
+                save_validated_api_key(
+                    Some(validate),
+                    open_ai::OPEN_AI_API_URL.into(),
+                    api_key.into(),
+                    "OpenAI".into(),
+                    &mut cx,
                 )
-                .size(LabelSize::Small),
-            )
-            .child(
-                h_flex()
-                    .gap_2()
-                    .child(Label::new("Click on").size(LabelSize::Small))
-                    .child(Icon::new(IconName::ZedAssistant).size(IconSize::XSmall))
-                    .child(
-                        Label::new("in the status bar to close this panel.").size(LabelSize::Small),
-                    ),
+                .await
+            }
+        };
+
+        // A rejected key isn't saved, so completions still need one.
+        let error = save("sk-typo", cx).await.unwrap_err();
+        assert!(error.contains("Incorrect API key"), "{error}");
+        assert!(!cx.update(|cx| CompletionProvider::global(cx).is_authenticated()));
+
+        save("sk-valid", cx).await.unwrap();
+        let response = cx
+            .update(|cx| CompletionProvider::global(cx).stream_completion(user_request("Hi"), cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.try_collect::<Vec<_>>().await.unwrap().concat(),
+            "Hi"
+        );
+        assert_eq!(
+            authorizations.lock().as_slice(),
+            &["Bearer sk-valid".to_string()]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_masked_api_key_prompt(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            theme::init(theme::LoadThemes::JustBase, cx);
+            language::init(cx);
+            project::Project::init_settings(cx);
+            editor::init(cx);
+        });
+        let authorizations = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let authorizations = authorizations.clone();
+            move |request| {
+                let authorization = request.headers()["Authorization"].to_str().unwrap();
+                let body = if request.uri().path() == "/v1/models" {
+                    json!({ "object": "list", "data": [] }).to_string()
+                } else {
+                    authorizations.lock().push(authorization.to_string());
+                    format!("data: {}\n\ndata: [DONE]\n\n", content_event("Hi", None))
+                };
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client.clone(),
+            None,
+            0,
+            Vec::new(),
+        );
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+
+        let (prompt, cx) = cx.add_window_view(|cx| {
+            AuthenticationPrompt::new(
+                open_ai::OPEN_AI_API_URL.into(),
+                "OpenAI".into(),
+                None,
+                http_client,
+                None,
+                AuthHeaderStyle::Bearer,
+                None,
+                true,
+                cx,
             )
-            .into_any()
+        });
+        let redacted = |prompt: &View<AuthenticationPrompt>, cx: &mut VisualTestContext| {
+            prompt.update(cx, |prompt, cx| {
+                prompt.api_key.update(cx, |editor, cx| {
+                    let snapshot = editor.snapshot(cx);
+                    !editor
+                        .redacted_ranges(
+                            editor::Anchor::min()..editor::Anchor::max(),
+                            &snapshot.display_snapshot,
+                            cx,
+                        )
+                        .is_empty()
+                })
+            })
+        };
+        prompt.update(cx, |prompt, cx| {
+            prompt
+                .api_key
+                .update(cx, |editor, cx| editor.set_text("sk-secret", cx));
+        });
+        assert!(redacted(&prompt, cx));
+        prompt.update(cx, |prompt, cx| prompt.toggle_api_key_visibility(cx));
+        assert!(!redacted(&prompt, cx));
+        prompt.update(cx, |prompt, cx| prompt.toggle_api_key_visibility(cx));
+        assert!(redacted(&prompt, cx));
+
+        // The key is only masked on screen, so the one that's saved and sent is the
+        // one that was entered.
+        prompt.update(cx, |prompt, cx| prompt.save_api_key(&menu::Confirm, cx));
+        cx.run_until_parked();
+        assert_eq!(prompt.update(cx, |prompt, _| prompt.error.clone()), None);
+        let response = cx
+            .update(|cx| CompletionProvider::global(cx).stream_completion(user_request("Hi"), cx))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.try_collect::<Vec<_>>().await.unwrap().concat(),
+            "Hi"
+        );
+        assert_eq!(
+            authorizations.lock().as_slice(),
+            &["Bearer sk-secret".to_string()]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_check_connection(cx: &mut TestAppContext) {
+        let provider_with_statuses = |models_status: Option<u16>, completions_status: u16| {
+            let http_client = FakeHttpClient::create(move |request| async move {
+                let status = if request.uri().path().ends_with("/models") {
+                    let Some(status) = models_status else {
+                        // The server never responds.
+                        return futures::future::pending().await;
+                    };
+                    status
+                } else {
+                    completions_status
+                };
+                Ok(Response::builder()
+                    .status(status)
+                    .body(json!({ "object": "list", "data": [] }).to_string().into())
+                    .unwrap())
+            });
+            let mut provider = OpenAiCompletionProvider::new(
+                OpenAiModel::FourOmni,
+                open_ai::OPEN_AI_API_URL.into(),
+                http_client,
+                None,
+                0,
+                Vec::new(),
+            );
+            provider.api_keys = vec!["sk-test".into()];
+            provider.set_max_retries(0);
+            provider
+        };
+
+        for (status, expected) in [
+            (200, ConnectionStatus::Ok),
+            (401, ConnectionStatus::Unauthorized),
+            (404, ConnectionStatus::Unreachable),
+            (503, ConnectionStatus::Unreachable),
+        ] {
+            let provider = provider_with_statuses(Some(status), status);
+            let check = cx.update(|cx| provider.check_connection(cx));
+            assert_eq!(check.await.unwrap(), expected, "{status}");
+        }
+
+        // APIs that don't list their models are checked with a completion instead.
+        let provider = provider_with_statuses(Some(404), 200);
+        let check = cx.update(|cx| provider.check_connection(cx));
+        assert_eq!(check.await.unwrap(), ConnectionStatus::Ok);
+        let provider = provider_with_statuses(Some(404), 401);
+        let check = cx.update(|cx| provider.check_connection(cx));
+        assert_eq!(check.await.unwrap(), ConnectionStatus::Unauthorized);
+
+        // As are Azure deployments, which never list them.
+        let mut provider = provider_with_statuses(None, 200);
+        provider.set_azure_deployment(Some(AzureDeployment {
+            deployment: "gpt-4o".into(),
+            api_version: "2024-02-01".into(),
+        }));
+        let check = cx.update(|cx| provider.check_connection(cx));
+        assert_eq!(check.await.unwrap(), ConnectionStatus::Ok);
+
+        let check = cx.update(|cx| test_provider().check_connection(cx));
+        assert_eq!(check.await.unwrap(), ConnectionStatus::Unauthorized);
+
+        let provider = provider_with_statuses(None, 200);
+        let check = cx.update(|cx| provider.check_connection(cx));
+        cx.executor().advance_clock(CONNECTION_CHECK_TIMEOUT);
+        assert_eq!(check.await.unwrap(), ConnectionStatus::Unreachable);
+    }
+
+    #[gpui::test]
+    async fn test_moderation(cx: &mut TestAppContext) {
+        let sent_paths = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_paths = sent_paths.clone();
+            move |request| {
+                let sent_paths = sent_paths.clone();
+                async move {
+                    let path = request.uri().path().to_string();
+                    sent_paths.lock().push(path.clone());
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    let body = if path.ends_with("/moderations") {
+                        let input = serde_json::from_str::<serde_json::Value>(&body).unwrap()
+                            ["input"]
+                            .as_str()
+                            .unwrap()
+                            .to_string();
+                        let flagged = input.contains("hurt");
+                        json!({ "results": [{
+                            "flagged": flagged,
+                            "categories": { "harassment": false, "violence": flagged },
+                            "category_scores": {
+                                "harassment": 0.01,
+                                "violence": if flagged { 0.9 } else { 0.02 },
+                            },
+                        }] })
+                        .to_string()
+                    } else {
+                        format!(
+                            "data: {}\n\ndata: [DONE]\n\n",
+                            content_event("Hi", Some("stop"))
+                        )
+                    };
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+
+        let result = cx
+            .update(|cx| provider.moderate("I'll hurt you".into(), cx))
+            .await
+            .unwrap();
+        assert!(result.flagged);
+        assert_eq!(result.flagged_categories(), vec!["violence"]);
+        assert_eq!(result.category_scores["violence"], 0.9);
+        sent_paths.lock().clear();
+
+        // Moderation is opt-in, so by default nothing but the completion is sent.
+        let chunks = provider
+            .stream_completion(user_request("I'll hurt you"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hi"
+        );
+        assert_eq!(sent_paths.lock().as_slice(), &["/v1/chat/completions"]);
+        sent_paths.lock().clear();
+
+        provider.set_moderate_input(true);
+        let chunks = provider
+            .stream_completion(user_request("Hello"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hi"
+        );
+        assert_eq!(
+            sent_paths.lock().as_slice(),
+            &["/v1/moderations", "/v1/chat/completions"]
+        );
+        sent_paths.lock().clear();
+
+        let error = provider
+            .stream_completion(user_request("I'll hurt you"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<CompletionError>(),
+            Some(CompletionError::Blocked { categories }) if categories == &["violence"]
+        ));
+        assert_eq!(sent_paths.lock().as_slice(), &["/v1/moderations"]);
+        sent_paths.lock().clear();
+
+        // Azure deployments have no moderation endpoint, so their input is sent as is.
+        provider.set_azure_deployment(Some(AzureDeployment {
+            deployment: "gpt-4o".into(),
+            api_version: "2024-02-01".into(),
+        }));
+        let chunks = provider
+            .stream_completion(user_request("I'll hurt you"))
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Hi"
+        );
+        assert_eq!(
+            sent_paths.lock().as_slice(),
+            &["/v1/openai/deployments/gpt-4o/chat/completions"]
+        );
+        assert!(cx
+            .update(|cx| provider.moderate("Hello".into(), cx))
+            .await
+            .is_err());
+    }
+
+    #[gpui::test]
+    async fn test_reload_models(cx: &mut TestAppContext) {
+        // The account gains access to another model after the first listing.
+        let listings = Arc::new(AtomicUsize::new(0));
+        let http_client = FakeHttpClient::create({
+            let listings = listings.clone();
+            move |_| {
+                let ids = match listings.fetch_add(1, SeqCst) {
+                    0 => vec!["gpt-4o"],
+                    _ => vec!["gpt-4o", "gpt-4o-mini"],
+                };
+                let data = ids
+                    .into_iter()
+                    .map(|id| json!({ "id": id, "object": "model", "owned_by": "system" }))
+                    .collect::<Vec<_>>();
+                let body = json!({ "object": "list", "data": data }).to_string();
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            open_ai::OPEN_AI_API_URL.into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+
+        let reload = cx.update(|cx| CompletionProvider::global(cx).reload_models(cx));
+        assert_eq!(reload.await.unwrap(), 1);
+        let reload = cx.update(|cx| CompletionProvider::global(cx).reload_models(cx));
+        assert_eq!(reload.await.unwrap(), 2);
+        assert_eq!(
+            cx.update(|cx| CompletionProvider::global(cx).available_models()),
+            vec![
+                LanguageModel::OpenAi(OpenAiModel::FourOmni),
+                LanguageModel::OpenAi(OpenAiModel::FourOmniMini),
+            ]
+        );
+
+        // Models configured in the settings are offered ahead of the fetched ones,
+        // and take the place of those with the same name.
+        let configured_model = |name: &str| OpenAiModel::Custom {
+            name: name.into(),
+            max_tokens: 1000,
+            tokenizer: None,
+        };
+        cx.update(|cx| {
+            cx.update_global::<CompletionProvider, _>(|provider, _| {
+                provider.update_current_as::<_, OpenAiCompletionProvider>(|provider| {
+                    provider.set_available_models(vec![
+                        configured_model("ft:gpt-4o:acme"),
+                        configured_model("gpt-4o-mini"),
+                    ])
+                })
+            })
+        });
+        let reload = cx.update(|cx| CompletionProvider::global(cx).reload_models(cx));
+        assert_eq!(reload.await.unwrap(), 3);
+        assert_eq!(
+            cx.update(|cx| CompletionProvider::global(cx).available_models()),
+            vec![
+                LanguageModel::OpenAi(configured_model("ft:gpt-4o:acme")),
+                LanguageModel::OpenAi(configured_model("gpt-4o-mini")),
+                LanguageModel::OpenAi(OpenAiModel::FourOmni),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_fetch_models_unsupported(cx: &mut TestAppContext) {
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(Response::builder()
+                .status(404)
+                .body("404 page not found".into())
+                .unwrap())
+        });
+        let mut provider = OpenAiCompletionProvider::new(
+            OpenAiModel::FourOmni,
+            "https://proxy.example.com/v1".into(),
+            http_client,
+            None,
+            0,
+            Vec::new(),
+        );
+        provider.api_keys = vec!["sk-test".into()];
+        let built_in_models = provider.available_models();
+        cx.update(|cx| {
+            cx.set_global(CompletionProvider::new(
+                Arc::new(parking_lot::RwLock::new(provider)),
+                None,
+            ))
+        });
+
+        let fetch_models = cx.update(|cx| {
+            cx.update_global::<CompletionProvider, _>(|provider, cx| {
+                provider
+                    .update_current_as::<_, OpenAiCompletionProvider>(|provider| {
+                        provider.fetch_models(cx)
+                    })
+                    .unwrap()
+            })
+        });
+        let error = fetch_models.await.unwrap_err();
+        assert_eq!(error.downcast_ref::<RequestError>().unwrap().status, 404);
+        assert_eq!(
+            cx.update(|cx| CompletionProvider::global(cx).available_models()),
+            built_in_models
+        );
     }
 }
@@ -0,0 +1,300 @@
+use crate::{
+    count_open_ai_tokens, CompletionError, CompletionEvent, CompletionProvider, ConnectionStatus,
+    LanguageModelCompletionProvider, OpenAiCompletionProvider,
+};
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::BoxStream};
+use gpui::{AnyView, AppContext, Task};
+use http::{HttpClient, Url};
+use language_model::{LanguageModel, LanguageModelRequest};
+use open_ai::{AuthHeaderStyle, Model as OpenAiModel};
+use std::{sync::Arc, time::Duration};
+use ui::prelude::*;
+use util::ResultExt;
+
+/// A provider for any API that serves OpenAI's `/chat/completions` endpoint, such
+/// as Mistral, Together, Groq, Perplexity or OpenRouter. Requests are built and
+/// streamed exactly as they are for OpenAI; only the endpoint, the header the key
+/// is sent in and the models differ.
+pub struct OpenAiCompatibleCompletionProvider {
+    inner: OpenAiCompletionProvider,
+}
+
+impl OpenAiCompatibleCompletionProvider {
+    /// Completes with `model` from the API at `api_url`, offering `available_models`
+    /// to choose from. The models are usually [`OpenAiModel::Custom`], since the
+    /// API's names for them differ from OpenAI's.
+    pub fn new(
+        model: OpenAiModel,
+        api_url: String,
+        auth_header: AuthHeaderStyle,
+        available_models: Vec<OpenAiModel>,
+        http_client: Arc<dyn HttpClient>,
+        low_speed_timeout: Option<Duration>,
+        settings_version: usize,
+    ) -> Self {
+        let mut inner = OpenAiCompletionProvider::new(
+            model,
+            api_url,
+            http_client,
+            low_speed_timeout,
+            settings_version,
+            available_models,
+        );
+        inner.set_auth_header(auth_header);
+        Self { inner }
+    }
+
+    pub fn update(
+        &mut self,
+        model: OpenAiModel,
+        api_url: String,
+        auth_header: AuthHeaderStyle,
+        available_models: Vec<OpenAiModel>,
+        low_speed_timeout: Option<Duration>,
+        settings_version: usize,
+    ) {
+        self.inner
+            .update(model, api_url, low_speed_timeout, settings_version);
+        self.inner.set_auth_header(auth_header);
+        self.inner.set_available_models(available_models);
+    }
+
+    /// The OpenAI provider that requests are made with, for configuring anything
+    /// beyond what's set up here.
+    pub fn open_ai_provider(&mut self) -> &mut OpenAiCompletionProvider {
+        &mut self.inner
+    }
+
+    pub(crate) fn set_api_key(&mut self, api_key: String) -> Option<BoxFuture<'static, ()>> {
+        self.inner.set_api_key(api_key)
+    }
+}
+
+impl LanguageModelCompletionProvider for OpenAiCompatibleCompletionProvider {
+    fn available_models(&self) -> Vec<LanguageModel> {
+        self.inner.available_models()
+    }
+
+    fn settings_version(&self) -> usize {
+        self.inner.settings_version()
+    }
+
+    fn is_authenticated(&self) -> bool {
+        self.inner.is_authenticated()
+    }
+
+    fn set_api_key_override(&mut self, api_key: Option<String>) {
+        self.inner.set_api_key_override(api_key);
+    }
+
+    fn reload_models(&self, cx: &AppContext) -> Task<Result<usize>> {
+        self.inner.reload_models(cx)
+    }
+
+    fn check_connection(&self, cx: &AppContext) -> Task<Result<ConnectionStatus>> {
+        self.inner.check_connection(cx)
+    }
+
+    fn authenticate(&self, cx: &AppContext) -> Task<Result<()>> {
+        if self.is_authenticated() {
+            return Task::ready(Ok(()));
+        }
+
+        // Keys are stored per API URL, and there's no environment variable to
+        // fall back to, since it would be specific to each API.
+        let api_url = self.inner.api_url().to_string();
+        cx.spawn(|mut cx| async move {
+            let (_, api_key) = cx
+                .update(|cx| cx.read_credentials(&api_url))?
+                .await?
+                .ok_or_else(|| CompletionError::Authentication {
+                    message: "credentials not found".into(),
+                })?;
+            let api_key = String::from_utf8(api_key)?;
+            cx.update_global::<CompletionProvider, _>(|provider, cx| {
+                let priming =
+                    provider.update_current_as::<_, Self>(|provider| provider.set_api_key(api_key));
+                if let Some(Some(priming)) = priming {
+                    cx.background_executor().spawn(priming).detach();
+                }
+            })
+        })
+    }
+
+    fn reset_credentials(&self, cx: &AppContext) -> Task<Result<()>> {
+        let delete_credentials = cx.delete_credentials(self.inner.api_url());
+        cx.spawn(|mut cx| async move {
+            delete_credentials.await.log_err();
+            cx.update_global::<CompletionProvider, _>(|provider, _cx| {
+                provider.update_current_as::<_, Self>(|provider| {
+                    provider.inner.clear_api_key();
+                });
+            })
+        })
+    }
+
+    fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
+        // The API is referred to by its host, and there's no environment variable
+        // its key is read from.
+        let provider_name = Url::parse(self.inner.api_url())
+            .ok()
+            .and_then(|url| {
+                url.host_str()
+                    .map(|host| SharedString::from(host.to_string()))
+            })
+            .unwrap_or_else(|| self.inner.api_url().to_string().into());
+        self.inner
+            .authentication_prompt_for(provider_name, None, cx)
+    }
+
+    fn model(&self) -> LanguageModel {
+        self.inner.model()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        // There's no knowing which tokenizer an arbitrary API's models use, so
+        // tokens are counted as they would be for GPT-4, unless the model
+        // configures an encoding.
+        count_open_ai_tokens(request, cx.background_executor())
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.inner.stream_completion(request)
+    }
+
+    fn stream_completion_events(
+        &self,
+        request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.inner.stream_completion_events(request)
+    }
+
+    fn stream_completion_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.inner.stream_completion_with_api_key(request, api_key)
+    }
+
+    fn stream_completion_events_with_api_key(
+        &self,
+        request: LanguageModelRequest,
+        api_key: Option<String>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        self.inner
+            .stream_completion_events_with_api_key(request, api_key)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use gpui::TestAppContext;
+    use http::{FakeHttpClient, Response};
+    use language_model::{LanguageModelRequestMessage, Role};
+    use parking_lot::Mutex;
+    use serde_json::json;
+
+    fn mistral_model() -> OpenAiModel {
+        OpenAiModel::Custom {
+            name: "mistral-large-latest".into(),
+            max_tokens: 128000,
+            tokenizer: None,
+        }
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion(cx: &mut TestAppContext) {
+        let sent_requests = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_requests = sent_requests.clone();
+            move |request| {
+                sent_requests.lock().push((
+                    request.uri().to_string(),
+                    request
+                        .headers()
+                        .get("x-api-key")
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| value.to_string()),
+                    request.headers().contains_key("Authorization"),
+                ));
+                let event = json!({
+                    "created": 0,
+                    "model": "mistral-large-latest",
+                    "choices": [{
+                        "index": 0,
+                        "delta": { "role": "assistant", "content": "Bonjour" },
+                        "finish_reason": "stop",
+                    }],
+                });
+                let body = format!("data: {event}\n\ndata: [DONE]\n\n");
+                async move { Ok(Response::builder().status(200).body(body.into()).unwrap()) }
+            }
+        });
+        let mut provider = OpenAiCompatibleCompletionProvider::new(
+            mistral_model(),
+            "https://api.mistral.ai/v1".into(),
+            AuthHeaderStyle::Header("x-api-key".into()),
+            vec![mistral_model()],
+            http_client,
+            None,
+            0,
+        );
+        provider.set_api_key("mistral-key".into());
+        assert_eq!(
+            provider.available_models(),
+            vec![LanguageModel::OpenAi(mistral_model())]
+        );
+
+        let request = LanguageModelRequest {
+            model: provider.model(),
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hello".into(),
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+                images: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let token_count = cx
+            .update(|cx| provider.count_tokens(request.clone(), cx))
+            .await
+            .unwrap();
+        assert!(token_count > 0);
+
+        let chunks = provider
+            .stream_completion(request)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks.into_iter().collect::<Result<String>>().unwrap(),
+            "Bonjour"
+        );
+        assert_eq!(
+            sent_requests.lock().as_slice(),
+            &[(
+                "https://api.mistral.ai/v1/chat/completions".to_string(),
+                Some("mistral-key".to_string()),
+                false,
+            )]
+        );
+    }
+}
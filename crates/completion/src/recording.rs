@@ -0,0 +1,413 @@
+use crate::{
+    chat_completion_events, count_open_ai_tokens, CompletionEvent, IncompleteToolCallBehavior,
+    LanguageModel, LanguageModelCompletionProvider, LanguageModelRequest, StreamEnd,
+};
+use anyhow::{anyhow, Context as _, Result};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use gpui::{AnyView, AppContext, BackgroundExecutor, EmptyView, Task, VisualContext};
+use open_ai::{Request, RequestError, ResponseStreamEvent};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use ui::WindowContext;
+use util::ResultExt;
+
+/// A line of a recording. Each is tagged with the id of the completion it belongs
+/// to, since the lines of concurrent completions are interleaved.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RecordedLine {
+    Request {
+        completion: String,
+        request: serde_json::Value,
+    },
+    Chunk {
+        completion: String,
+        /// When the chunk arrived, relative to the start of the response.
+        elapsed_ms: u64,
+        chunk: serde_json::Value,
+    },
+    Error {
+        completion: String,
+        elapsed_ms: u64,
+        status: Option<u16>,
+        message: String,
+    },
+    End {
+        completion: String,
+        elapsed_ms: u64,
+    },
+    /// The request failed before a response started streaming.
+    Failed {
+        completion: String,
+        status: Option<u16>,
+        message: String,
+    },
+}
+
+/// Writes the requests a provider sends and the chunks streamed back in response
+/// to a JSONL file, one line per request or chunk, so that the completions can be
+/// replayed with [`ReplayCompletionProvider`]. Lines are buffered, and only flushed
+/// once a completion ends or fails.
+pub struct CompletionRecorder {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl CompletionRecorder {
+    /// Records to the file at `path`, appending to it if it exists.
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open recording {path:?}"))?;
+        Ok(Self::from_writer(BufWriter::new(file)))
+    }
+
+    pub fn from_writer(writer: impl 'static + Write + Send) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    fn write(&self, line: &RecordedLine) {
+        let flush = matches!(
+            line,
+            RecordedLine::Error { .. } | RecordedLine::End { .. } | RecordedLine::Failed { .. }
+        );
+        let result = serde_json::to_string(line)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut writer = self.writer.lock();
+                writeln!(writer, "{line}")?;
+                if flush {
+                    writer.flush()?;
+                }
+                Ok(())
+            });
+        result.context("failed to record completion").log_err();
+    }
+
+    fn write_request(&self, completion: String, request: &Request) {
+        self.write(&RecordedLine::Request {
+            completion,
+            request: serde_json::to_value(request).unwrap_or_default(),
+        });
+    }
+
+    /// Records `request` and the `error` it failed with before its response started.
+    pub(crate) fn record_failure(
+        &self,
+        completion: String,
+        request: &Request,
+        error: &anyhow::Error,
+    ) {
+        self.write_request(completion.clone(), request);
+        self.write(&RecordedLine::Failed {
+            completion,
+            status: error_status(error),
+            message: error.to_string(),
+        });
+    }
+
+    /// Records `request` and passes its `response` through unchanged, recording
+    /// each chunk as it arrives.
+    pub(crate) fn record(
+        self: &Arc<Self>,
+        completion: String,
+        request: &Request,
+        response: impl 'static + Send + Stream<Item = Result<ResponseStreamEvent>>,
+    ) -> BoxStream<'static, Result<ResponseStreamEvent>> {
+        self.write_request(completion.clone(), request);
+
+        let started_at = Instant::now();
+        let this = self.clone();
+        response
+            .map(Some)
+            .chain(stream::once(async { None }))
+            .filter_map(move |chunk| {
+                let elapsed_ms = started_at.elapsed().as_millis() as u64;
+                let completion = completion.clone();
+                let line = match &chunk {
+                    Some(Ok(chunk)) => RecordedLine::Chunk {
+                        completion,
+                        elapsed_ms,
+                        chunk: serde_json::to_value(chunk).unwrap_or_default(),
+                    },
+                    Some(Err(error)) => RecordedLine::Error {
+                        completion,
+                        elapsed_ms,
+                        status: error_status(error),
+                        message: error.to_string(),
+                    },
+                    None => RecordedLine::End {
+                        completion,
+                        elapsed_ms,
+                    },
+                };
+                this.write(&line);
+                future::ready(chunk)
+            })
+            .boxed()
+    }
+}
+
+fn error_status(error: &anyhow::Error) -> Option<u16> {
+    error
+        .downcast_ref::<RequestError>()
+        .map(|error| error.status)
+}
+
+/// The error a recorded request or stream failed with.
+fn recorded_error(status: Option<u16>, message: String) -> anyhow::Error {
+    match status {
+        Some(status) => RequestError::new(status, message).into(),
+        None => anyhow!(message),
+    }
+}
+
+/// A recorded chunk, or the error or end of the stream it belonged to.
+enum RecordedChunk {
+    Chunk(serde_json::Value),
+    Error {
+        status: Option<u16>,
+        message: String,
+    },
+    End,
+}
+
+struct RecordedCompletion {
+    id: String,
+    chunks: Vec<(Duration, RecordedChunk)>,
+    /// The error the request failed with before it started streaming, if it did.
+    failure: Option<(Option<u16>, String)>,
+}
+
+/// A provider that replays the completions in a recording made with
+/// [`CompletionRecorder`] instead of making requests, so that a completion a user
+/// saw can be reproduced exactly.
+///
+/// Completions are replayed in the order they were recorded, regardless of the
+/// requests they're replayed for. Each chunk is streamed at the same point in the
+/// response as it was recorded at, so that bugs depending on how chunks are split
+/// or timed reproduce.
+pub struct ReplayCompletionProvider {
+    completions: Mutex<VecDeque<RecordedCompletion>>,
+    executor: BackgroundExecutor,
+}
+
+impl ReplayCompletionProvider {
+    pub fn load(path: &Path, executor: BackgroundExecutor) -> Result<Self> {
+        let recording = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read recording {path:?}"))?;
+        Self::new(&recording, executor)
+    }
+
+    /// Replays `recording`, the contents of a JSONL file written by a [`CompletionRecorder`].
+    pub fn new(recording: &str, executor: BackgroundExecutor) -> Result<Self> {
+        let mut completions = Vec::<RecordedCompletion>::new();
+        for (ix, line) in recording.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line = serde_json::from_str::<RecordedLine>(line)
+                .with_context(|| format!("invalid recording on line {}", ix + 1))?;
+            let (id, elapsed_ms, chunk) = match line {
+                RecordedLine::Request { completion, .. } => {
+                    completions.push(RecordedCompletion {
+                        id: completion,
+                        chunks: Vec::new(),
+                        failure: None,
+                    });
+                    continue;
+                }
+                RecordedLine::Failed {
+                    completion,
+                    status,
+                    message,
+                } => {
+                    completions
+                        .iter_mut()
+                        .rev()
+                        .find(|recorded| recorded.id == completion)
+                        .ok_or_else(|| {
+                            anyhow!("line {} precedes its completion's request", ix + 1)
+                        })?
+                        .failure = Some((status, message));
+                    continue;
+                }
+                RecordedLine::Chunk {
+                    completion,
+                    elapsed_ms,
+                    chunk,
+                } => (completion, elapsed_ms, RecordedChunk::Chunk(chunk)),
+                RecordedLine::Error {
+                    completion,
+                    elapsed_ms,
+                    status,
+                    message,
+                } => (
+                    completion,
+                    elapsed_ms,
+                    RecordedChunk::Error { status, message },
+                ),
+                RecordedLine::End {
+                    completion,
+                    elapsed_ms,
+                } => (completion, elapsed_ms, RecordedChunk::End),
+            };
+            completions
+                .iter_mut()
+                .rev()
+                .find(|completion| completion.id == id)
+                .ok_or_else(|| anyhow!("line {} precedes its completion's request", ix + 1))?
+                .chunks
+                .push((Duration::from_millis(elapsed_ms), chunk));
+        }
+        Ok(Self {
+            completions: Mutex::new(completions.into()),
+            executor,
+        })
+    }
+
+    /// How many recorded completions are left to replay.
+    pub fn remaining_completions(&self) -> usize {
+        self.completions.lock().len()
+    }
+
+    fn replay(&self) -> Result<BoxStream<'static, Result<CompletionEvent>>> {
+        let completion = self
+            .completions
+            .lock()
+            .pop_front()
+            .ok_or_else(|| anyhow!("the recording has no completions left to replay"))?;
+        if let Some((status, message)) = completion.failure {
+            return Err(recorded_error(status, message));
+        }
+
+        // Wait out the time between each chunk and the one before it.
+        let mut previous = Duration::ZERO;
+        let chunks = completion
+            .chunks
+            .into_iter()
+            .map(|(elapsed, chunk)| {
+                let delay = elapsed.saturating_sub(previous);
+                previous = previous.max(elapsed);
+                (delay, chunk)
+            })
+            .collect::<Vec<_>>();
+        let executor = self.executor.clone();
+        let chunks = stream::iter(chunks)
+            .then(move |(delay, chunk)| {
+                let executor = executor.clone();
+                async move {
+                    if !delay.is_zero() {
+                        executor.timer(delay).await;
+                    }
+                    chunk
+                }
+            })
+            .scan(false, |failed, chunk| {
+                // Live responses end after an error, so replayed ones do too.
+                let chunk = match chunk {
+                    _ if *failed => None,
+                    RecordedChunk::Chunk(chunk) => Some(
+                        serde_json::from_value::<ResponseStreamEvent>(chunk)
+                            .context("invalid recorded chunk"),
+                    ),
+                    RecordedChunk::Error { status, message } => {
+                        *failed = true;
+                        Some(Err(recorded_error(status, message)))
+                    }
+                    RecordedChunk::End => None,
+                };
+                future::ready(chunk)
+            });
+        Ok(chat_completion_events(
+            chunks,
+            StreamEnd {
+                request_id: Some(completion.id),
+                ..Default::default()
+            },
+            IncompleteToolCallBehavior::default(),
+        ))
+    }
+}
+
+impl LanguageModelCompletionProvider for ReplayCompletionProvider {
+    fn available_models(&self) -> Vec<LanguageModel> {
+        vec![LanguageModel::default()]
+    }
+
+    fn settings_version(&self) -> usize {
+        0
+    }
+
+    fn is_authenticated(&self) -> bool {
+        true
+    }
+
+    fn authenticate(&self, _cx: &AppContext) -> Task<Result<()>> {
+        Task::ready(Ok(()))
+    }
+
+    fn authentication_prompt(&self, cx: &mut WindowContext) -> AnyView {
+        cx.new_view(|_| EmptyView).into()
+    }
+
+    fn reset_credentials(&self, _cx: &AppContext) -> Task<Result<()>> {
+        Task::ready(Ok(()))
+    }
+
+    fn model(&self) -> LanguageModel {
+        LanguageModel::default()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        count_open_ai_tokens(request, cx.background_executor())
+    }
+
+    fn stream_completion(
+        &self,
+        _request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        let chunks = self.replay().map(|events| {
+            events
+                .filter_map(|event| {
+                    future::ready(match event {
+                        Ok(CompletionEvent::Text(chunk)) => Some(Ok(chunk)),
+                        Ok(_) => None,
+                        Err(error) => Some(Err(error)),
+                    })
+                })
+                .boxed()
+        });
+        future::ready(chunks).boxed()
+    }
+
+    fn stream_completion_events(
+        &self,
+        _request: LanguageModelRequest,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<CompletionEvent>>>> {
+        future::ready(self.replay()).boxed()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
@@ -1,4 +1,6 @@
+use anyhow::Result;
 use collections::HashMap;
+use futures::{stream, Stream, StreamExt};
 use ordered_float::OrderedFloat;
 use std::{
     cmp,
@@ -223,6 +225,37 @@ impl StreamingDiff {
     }
 }
 
+/// Diffs a stream of completion chunks against `old` as they arrive, yielding
+/// the hunks needed to turn `old` into the model's output so far. The remaining
+/// hunks are flushed once the chunk stream ends.
+pub fn stream_hunks(
+    old: String,
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+) -> impl 'static + Send + Stream<Item = Result<Hunk>> {
+    let mut diff = Some(StreamingDiff::new(old));
+    chunks
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |chunk| {
+            let hunks = match chunk {
+                Some(Ok(chunk)) => diff
+                    .as_mut()
+                    .map_or(Vec::new(), |diff| diff.push_new(&chunk))
+                    .into_iter()
+                    .map(Ok)
+                    .collect(),
+                Some(Err(error)) => vec![Err(error)],
+                None => diff
+                    .take()
+                    .map_or(Vec::new(), |diff| diff.finish())
+                    .into_iter()
+                    .map(Ok)
+                    .collect(),
+            };
+            stream::iter(hunks)
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
@@ -288,4 +321,38 @@ mod tests {
         }
         assert_eq!(patched, new);
     }
+
+    #[gpui::test]
+    async fn test_stream_hunks() {
+        let old = "fn main() {\n    println!(\"Hello\");\n}\n".to_string();
+        let chunks = [
+            "fn ma",
+            "in() {\n    let name = \"world\";\n",
+            "    println!(\"Hello, ",
+            "{name}\");\n}\n",
+        ];
+        let new = chunks.concat();
+
+        let hunks = stream_hunks(
+            old.clone(),
+            futures::stream::iter(chunks.map(|chunk| Ok(chunk.to_string()))),
+        )
+        .collect::<Vec<_>>()
+        .await;
+
+        let mut old_ix = 0;
+        let mut patched = String::new();
+        for hunk in hunks {
+            match hunk.unwrap() {
+                Hunk::Keep { len } => {
+                    patched.push_str(&old[old_ix..old_ix + len]);
+                    old_ix += len;
+                }
+                Hunk::Remove { len } => old_ix += len,
+                Hunk::Insert { text } => patched.push_str(&text),
+            }
+        }
+        assert_eq!(old_ix, old.len());
+        assert_eq!(patched, new);
+    }
 }
@@ -0,0 +1,62 @@
+use crate::open_ai::image_tokens_for;
+use anyhow::Result;
+use language_model::{LanguageModelRequest, LanguageModelRequestMessage};
+use tiktoken_rs::CoreBPE;
+
+/// Counts tokens on the client, for providers whose models don't use
+/// tiktoken's encodings (e.g. a Llama model served by an OpenAI-compatible
+/// server).
+pub trait Tokenizer: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Counts tokens with the tiktoken encoding of an OpenAI model.
+pub struct TiktokenTokenizer(CoreBPE);
+
+impl TiktokenTokenizer {
+    pub fn for_model(model: &str) -> Result<Self> {
+        Ok(Self(tiktoken_rs::get_bpe_from_model(model)?))
+    }
+
+    /// Returns the ids of the tokens `text` is encoded as.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.0
+            .encode_ordinary(text)
+            .into_iter()
+            .map(|token| token as u32)
+            .collect()
+    }
+}
+
+impl Tokenizer for TiktokenTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        self.0.encode_ordinary(text).len()
+    }
+}
+
+/// Counts the tokens of every message's content, images and tool calls in
+/// `request`.
+pub fn count_request_tokens(tokenizer: &dyn Tokenizer, request: &LanguageModelRequest) -> usize {
+    request
+        .messages
+        .iter()
+        .map(|message| count_message_tokens(tokenizer, message))
+        .sum()
+}
+
+/// Counts the tokens of `message`'s content, images and tool calls.
+pub fn count_message_tokens(
+    tokenizer: &dyn Tokenizer,
+    message: &LanguageModelRequestMessage,
+) -> usize {
+    tokenizer.count_tokens(&message.content)
+        + message.images.iter().map(image_tokens_for).sum::<usize>()
+        + message
+            .tool_calls
+            .iter()
+            .map(|tool_call| {
+                tokenizer.count_tokens(&tool_call.name)
+                    + tokenizer.count_tokens(&tool_call.arguments)
+            })
+            .sum::<usize>()
+}
@@ -0,0 +1,462 @@
+use crate::ToolArgumentError;
+use language_model::LanguageModelTool;
+use serde_json::{Map, Value};
+
+/// How deeply schemas may nest, counting the `$ref`s followed, before validation
+/// gives up on them. Guards against schemas that refer to themselves.
+const MAX_SCHEMA_DEPTH: usize = 64;
+
+/// Accumulates the argument fragments of a tool call as they stream in with
+/// [`crate::CompletionEvent::ToolCallDelta`], so the arguments can be shown before
+/// the call finishes.
+#[derive(Clone, Debug, Default)]
+pub struct StreamingToolArguments {
+    arguments: String,
+}
+
+impl StreamingToolArguments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, arguments_fragment: &str) {
+        self.arguments.push_str(arguments_fragment);
+    }
+
+    /// The arguments streamed so far, as the model wrote them.
+    pub fn text(&self) -> &str {
+        &self.arguments
+    }
+
+    /// The arguments streamed so far, parsed as though the JSON ended where the
+    /// stream is up to. See [`parse_partial_json`].
+    pub fn parse_partial(&self) -> Option<Value> {
+        parse_partial_json(&self.arguments)
+    }
+
+    /// Parses the completed arguments and validates them against `tool`'s schema.
+    pub fn finish(&self, tool: &LanguageModelTool) -> Result<Value, ToolArgumentError> {
+        parse_tool_arguments(&self.arguments, tool)
+    }
+}
+
+/// Parses the arguments of a finished call to `tool` and validates them against
+/// the tool's schema. Tools without a schema accept any valid JSON.
+///
+/// Providers don't apply this to the [`crate::CompletionEvent::ToolCall`]s they
+/// stream, whose arguments are passed on as the model wrote them. Callers that run
+/// tools should validate the arguments with this before doing so.
+///
+/// Only the parts of JSON schema that tool schemas use in practice are checked:
+/// `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`,
+/// `items`, `anyOf`, `oneOf`, `allOf` and local `$ref`s. Other keywords are ignored.
+pub fn parse_tool_arguments(
+    arguments: &str,
+    tool: &LanguageModelTool,
+) -> Result<Value, ToolArgumentError> {
+    // Models sometimes pass no arguments at all to tools that don't take any.
+    let arguments = if arguments.trim().is_empty() {
+        "{}"
+    } else {
+        arguments
+    };
+    let value = serde_json::from_str::<Value>(arguments).map_err(|error| {
+        ToolArgumentError::InvalidJson {
+            tool: tool.name.clone(),
+            message: error.to_string(),
+        }
+    })?;
+    if let Some(schema) = &tool.parameters {
+        validate(&value, schema, schema, &mut String::new(), 0).map_err(|(path, message)| {
+            ToolArgumentError::SchemaViolation {
+                tool: tool.name.clone(),
+                path: if path.is_empty() { "/".into() } else { path },
+                message,
+            }
+        })?;
+    }
+    Ok(value)
+}
+
+/// Parses the prefix of a JSON document, such as the arguments of a tool call that
+/// are still streaming, by closing any string, array and object left open. Trailing
+/// input that can't be completed, such as half of an object's key or a truncated
+/// `true`, is dropped. Returns `None` if nothing can be parsed yet.
+pub fn parse_partial_json(json: &str) -> Option<Value> {
+    if let Ok(value) = serde_json::from_str(json) {
+        return Some(value);
+    }
+
+    // Where the input can be cut off and still be closed into valid JSON: before
+    // each comma, dropping the incomplete element after it, and after each opening
+    // bracket, leaving the container empty.
+    let mut boundaries = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for (ix, char) in json.char_indices() {
+        if in_string {
+            match char {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match char {
+            '"' => in_string = true,
+            ',' => boundaries.push(ix),
+            '{' | '[' => boundaries.push(ix + 1),
+            _ => {}
+        }
+    }
+
+    let mut end = json.len();
+    loop {
+        if let Ok(value) = serde_json::from_str(&close_partial_json(&json[..end])) {
+            return Some(value);
+        }
+        end = loop {
+            let boundary = boundaries.pop()?;
+            if boundary < end {
+                break boundary;
+            }
+        };
+    }
+}
+
+/// Appends whatever closes the strings, arrays and objects left open in `json`.
+fn close_partial_json(json: &str) -> String {
+    let mut closed = json.to_string();
+    let mut open = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for char in json.chars() {
+        if in_string {
+            match char {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match char {
+            '"' => in_string = true,
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            '}' | ']' => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+    if in_string {
+        if escaped {
+            closed.pop();
+        }
+        closed.push('"');
+    }
+    closed.extend(open.into_iter().rev());
+    closed
+}
+
+/// Checks `value` against `schema`, returning the JSON pointer to the first part
+/// of `value` that doesn't match, and why.
+fn validate(
+    value: &Value,
+    schema: &Value,
+    root: &Value,
+    path: &mut String,
+    depth: usize,
+) -> Result<(), (String, String)> {
+    if depth > MAX_SCHEMA_DEPTH {
+        return violation(path, "the schema is nested too deeply to check");
+    }
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return violation(path, "no value is allowed here"),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let Some(referenced) = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+        else {
+            return violation(
+                path,
+                format!("the schema refers to {reference:?}, which is missing"),
+            );
+        };
+        validate(value, referenced, root, path, depth + 1)?;
+    }
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            types => types.as_str().into_iter().collect(),
+        };
+        if !types.is_empty() && !types.iter().any(|ty| has_type(value, ty)) {
+            return violation(
+                path,
+                format!("expected {}, got {}", types.join(" or "), type_name(value)),
+            );
+        }
+    }
+    if let Some(Value::Array(variants)) = schema.get("enum") {
+        if !variants.contains(value) {
+            return violation(path, format!("{value} isn't one of the allowed values"));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return violation(path, format!("expected {constant}, got {value}"));
+        }
+    }
+
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        for schema in schemas {
+            validate(value, schema, root, path, depth + 1)?;
+        }
+    }
+    let matching = |schemas: &[Value]| {
+        schemas
+            .iter()
+            .filter(|schema| validate(value, schema, root, &mut path.clone(), depth + 1).is_ok())
+            .count()
+    };
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if matching(schemas) == 0 {
+            return violation(path, "the value doesn't match any of the allowed schemas");
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+        match matching(schemas) {
+            0 => return violation(path, "the value doesn't match any of the allowed schemas"),
+            1 => {}
+            count => {
+                return violation(
+                    path,
+                    format!("the value matches {count} of the schemas, but must match only one"),
+                )
+            }
+        }
+    }
+
+    if let Value::Object(object) = value {
+        validate_object(object, schema, root, path, depth)?;
+    }
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (ix, item) in items.iter().enumerate() {
+            let len = path.len();
+            path.push_str(&format!("/{ix}"));
+            validate(item, item_schema, root, path, depth + 1)?;
+            path.truncate(len);
+        }
+    }
+    Ok(())
+}
+
+fn validate_object(
+    object: &Map<String, Value>,
+    schema: &Map<String, Value>,
+    root: &Value,
+    path: &mut String,
+    depth: usize,
+) -> Result<(), (String, String)> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for key in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(key) {
+                return violation(path, format!("missing required property {key:?}"));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let additional_properties = schema.get("additionalProperties");
+    for (key, property) in object {
+        let property_schema = properties
+            .and_then(|properties| properties.get(key))
+            .or(additional_properties);
+        let Some(property_schema) = property_schema else {
+            continue;
+        };
+        let len = path.len();
+        path.push('/');
+        path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        let result = match property_schema {
+            Value::Bool(false) => violation(path, format!("unexpected property {key:?}")),
+            property_schema => validate(property, property_schema, root, path, depth + 1),
+        };
+        path.truncate(len);
+        result?;
+    }
+    Ok(())
+}
+
+fn violation(path: &str, message: impl Into<String>) -> Result<(), (String, String)> {
+    Err((path.to_string(), message.into()))
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64()
+                || value.is_u64()
+                || value.as_f64().map_or(false, |number| number.fract() == 0.)
+        }
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn edit_tool() -> LanguageModelTool {
+        LanguageModelTool {
+            name: "edit".into(),
+            description: None,
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string" },
+                    "edits": {
+                        "type": "array",
+                        "items": { "$ref": "#/definitions/Edit" },
+                    },
+                },
+                "required": ["path", "edits"],
+                "additionalProperties": false,
+                "definitions": {
+                    "Edit": {
+                        "type": "object",
+                        "properties": {
+                            "kind": { "enum": ["insert", "delete"] },
+                            "line": { "type": "integer" },
+                        },
+                        "required": ["kind"],
+                    },
+                },
+            })),
+        }
+    }
+
+    #[test]
+    fn test_parse_partial_json() {
+        let json = r#"{"path": "src/main.rs", "edits": [{"kind": "insert", "line": 12}]}"#;
+        let prefixes = (0..=json.len())
+            .filter(|end| json.is_char_boundary(*end))
+            .map(|end| parse_partial_json(&json[..end]))
+            .collect::<Vec<_>>();
+        assert_eq!(prefixes[0], None);
+        assert_eq!(prefixes[1], Some(json!({})));
+        assert_eq!(
+            prefixes.last().unwrap(),
+            &Some(serde_json::from_str(json).unwrap())
+        );
+
+        let at = |prefix: &str| parse_partial_json(prefix).unwrap();
+        assert_eq!(at(r#"{"path": "src/ma"#), json!({ "path": "src/ma" }));
+        assert_eq!(at(r#"{"path": "src\"#), json!({ "path": "src" }));
+        assert_eq!(
+            at(r#"{"path": "src/main.rs", "ed"#),
+            json!({ "path": "src/main.rs" })
+        );
+        assert_eq!(
+            at(r#"{"path": "src/main.rs", "edits": [{"kind": "insert", "line": 1"#),
+            json!({ "path": "src/main.rs", "edits": [{ "kind": "insert", "line": 1 }] })
+        );
+        assert_eq!(at(r#"{"done": tr"#), json!({}));
+        assert_eq!(at(r#"[1, 2, "#), json!([1, 2]));
+    }
+
+    #[test]
+    fn test_streaming_tool_arguments() {
+        let mut arguments = StreamingToolArguments::new();
+        assert_eq!(arguments.parse_partial(), None);
+        arguments.push(r#"{"path": "src/"#);
+        assert_eq!(arguments.parse_partial(), Some(json!({ "path": "src/" })));
+        arguments.push(r#"lib.rs", "edits": [{"kind": "delete"}]}"#);
+        assert_eq!(
+            arguments.finish(&edit_tool()).unwrap(),
+            json!({ "path": "src/lib.rs", "edits": [{ "kind": "delete" }] })
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_arguments() {
+        let tool = edit_tool();
+        let error = |arguments: &str| parse_tool_arguments(arguments, &tool).unwrap_err();
+
+        assert!(matches!(
+            error(r#"{"path": "a.rs""#),
+            ToolArgumentError::InvalidJson { tool, .. } if tool == "edit"
+        ));
+        assert!(matches!(
+            error(r#"{"path": "a.rs"}"#),
+            ToolArgumentError::SchemaViolation { path, message, .. }
+                if path == "/" && message == r#"missing required property "edits""#
+        ));
+        assert!(matches!(
+            error(r#"{"path": "a.rs", "edits": [{"kind": "insert", "line": "1"}]}"#),
+            ToolArgumentError::SchemaViolation { path, message, .. }
+                if path == "/edits/0/line" && message == "expected integer, got string"
+        ));
+        assert!(matches!(
+            error(r#"{"path": "a.rs", "edits": [{"kind": "replace"}]}"#),
+            ToolArgumentError::SchemaViolation { path, .. } if path == "/edits/0/kind"
+        ));
+        assert!(matches!(
+            error(r#"{"path": "a.rs", "edits": [], "force": true}"#),
+            ToolArgumentError::SchemaViolation { path, message, .. }
+                if path == "/force" && message == r#"unexpected property "force""#
+        ));
+
+        // `oneOf` requires exactly one of its schemas to match, unlike `anyOf`.
+        let tool = LanguageModelTool {
+            parameters: Some(json!({
+                "oneOf": [
+                    { "type": "integer" },
+                    { "type": "number" },
+                ],
+            })),
+            ..tool
+        };
+        assert_eq!(parse_tool_arguments("1.5", &tool).unwrap(), json!(1.5));
+        assert!(matches!(
+            parse_tool_arguments("1", &tool).unwrap_err(),
+            ToolArgumentError::SchemaViolation { message, .. }
+                if message == "the value matches 2 of the schemas, but must match only one"
+        ));
+
+        // Tools without a schema take any arguments, and empty arguments are taken
+        // to be an empty object.
+        let tool = LanguageModelTool {
+            parameters: None,
+            ..tool
+        };
+        assert_eq!(parse_tool_arguments("[1]", &tool).unwrap(), json!([1]));
+        assert_eq!(parse_tool_arguments("", &tool).unwrap(), json!({}));
+    }
+}
@@ -0,0 +1,1124 @@
+use crate::{
+    CompletionEvent, CostEstimate, StreamEnd, TokenLogprob, TokenPrice, TokenUsage, Tokenizer,
+};
+use anyhow::{anyhow, Result};
+use futures::{
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use gpui::BackgroundExecutor;
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// A lightweight transform applied to each chunk of text as it is streamed.
+pub type MapChunk = Arc<dyn Fn(String) -> String + Send + Sync>;
+
+/// How often [`smooth`] emits a chunk while it has buffered content.
+const SMOOTH_TICK: Duration = Duration::from_millis(16);
+
+/// The most content [`smooth`] will hold back, expressed as time at the target rate.
+/// Once the buffer grows beyond this, emission speeds up so the UI never falls far
+/// behind the underlying stream.
+const SMOOTH_MAX_LAG: Duration = Duration::from_millis(500);
+
+/// Paces the emission of a chunked text stream at roughly `chars_per_second`.
+///
+/// Incoming chunks are buffered and released a few characters at a time on a fixed
+/// tick. Whatever remains buffered is flushed as soon as the underlying stream ends,
+/// and errors are forwarded as soon as they are observed.
+pub fn smooth(
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+    chars_per_second: usize,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<String>> {
+    struct State {
+        chunks: BoxStream<'static, Result<String>>,
+        buffer: VecDeque<char>,
+        chunks_done: bool,
+        wait_for_tick: bool,
+        executor: BackgroundExecutor,
+    }
+
+    let chars_per_tick =
+        (chars_per_second as u128 * SMOOTH_TICK.as_millis() / 1000).max(1) as usize;
+    let max_buffered =
+        (chars_per_second as u128 * SMOOTH_MAX_LAG.as_millis() / 1000).max(1) as usize;
+
+    let state = State {
+        chunks: chunks.boxed(),
+        buffer: VecDeque::new(),
+        chunks_done: false,
+        wait_for_tick: false,
+        executor,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+        loop {
+            if state.wait_for_tick {
+                state.executor.timer(SMOOTH_TICK).await;
+                state.wait_for_tick = false;
+            }
+
+            // Take everything the underlying stream has ready without blocking on it.
+            while !state.chunks_done {
+                match state.chunks.next().now_or_never() {
+                    Some(Some(Ok(chunk))) => state.buffer.extend(chunk.chars()),
+                    Some(Some(Err(error))) => return Some((Err(error), state)),
+                    Some(None) => state.chunks_done = true,
+                    None => break,
+                }
+            }
+
+            if state.buffer.is_empty() {
+                if state.chunks_done {
+                    return None;
+                }
+                match state.chunks.next().await {
+                    Some(Ok(chunk)) => state.buffer.extend(chunk.chars()),
+                    Some(Err(error)) => return Some((Err(error), state)),
+                    None => state.chunks_done = true,
+                }
+                continue;
+            }
+
+            if state.chunks_done {
+                let remaining = state.buffer.drain(..).collect::<String>();
+                return Some((Ok(remaining), state));
+            }
+
+            let len = state.buffer.len();
+            let count = chars_per_tick
+                .max(len.saturating_sub(max_buffered))
+                .min(len);
+            let chunk = state.buffer.drain(..count).collect::<String>();
+            state.wait_for_tick = true;
+            return Some((Ok(chunk), state));
+        }
+    })
+    .boxed()
+}
+
+/// How finely a completion's text is chunked when streamed to the caller.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamGranularity {
+    /// Chunks are passed through as the model streamed them, usually a token at a time.
+    #[default]
+    Token,
+    /// Chunks are re-aggregated so that each one ends on whitespace, i.e. only whole
+    /// words are emitted. The trailing partial word is flushed when the stream ends.
+    Word,
+}
+
+pub fn with_granularity(
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+    granularity: StreamGranularity,
+) -> BoxStream<'static, Result<String>> {
+    match granularity {
+        StreamGranularity::Token => chunks.boxed(),
+        StreamGranularity::Word => {
+            let mut partial_word = String::new();
+            chunks
+                .map(Some)
+                .chain(stream::once(async { None }))
+                .filter_map(move |chunk| {
+                    let output = match chunk {
+                        Some(Ok(chunk)) => {
+                            partial_word.push_str(&chunk);
+                            match partial_word.rfind(char::is_whitespace) {
+                                Some(ix) => {
+                                    let end =
+                                        ix + partial_word[ix..].chars().next().unwrap().len_utf8();
+                                    let rest = partial_word.split_off(end);
+                                    Some(Ok(mem::replace(&mut partial_word, rest)))
+                                }
+                                None => None,
+                            }
+                        }
+                        Some(Err(error)) => Some(Err(error)),
+                        None if partial_word.is_empty() => None,
+                        None => Some(Ok(mem::take(&mut partial_word))),
+                    };
+                    async move { output }
+                })
+                .boxed()
+        }
+    }
+}
+
+/// What [`restrict_to_ascii`] does with characters outside of ASCII.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AsciiPolicy {
+    /// Replace each character with its closest ASCII equivalent, or `?` if it has none.
+    #[default]
+    Transliterate,
+    /// Fail the stream at the first non-ASCII character.
+    Reject,
+}
+
+/// Counts the substitutions made by [`restrict_to_ascii`]. Once the stream has
+/// ended, this reports whether its output differs from what the model produced.
+#[derive(Clone, Debug, Default)]
+pub struct AsciiSubstitutions(Arc<AtomicUsize>);
+
+impl AsciiSubstitutions {
+    pub fn count(&self) -> usize {
+        self.0.load(SeqCst)
+    }
+}
+
+/// Restricts a chunked text stream to ASCII, chunk by chunk.
+pub fn restrict_to_ascii(
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+    policy: AsciiPolicy,
+) -> (BoxStream<'static, Result<String>>, AsciiSubstitutions) {
+    let substitutions = AsciiSubstitutions::default();
+    let stream = chunks
+        .map({
+            let substitutions = substitutions.clone();
+            move |chunk| {
+                let chunk = chunk?;
+                if chunk.is_ascii() {
+                    return Ok(chunk);
+                }
+
+                let mut output = String::with_capacity(chunk.len());
+                for character in chunk.chars() {
+                    if character.is_ascii() {
+                        output.push(character);
+                    } else if policy == AsciiPolicy::Reject {
+                        return Err(anyhow!("output contains non-ASCII character {character:?}"));
+                    } else {
+                        output.push_str(transliterate(character));
+                        substitutions.0.fetch_add(1, SeqCst);
+                    }
+                }
+                Ok(output)
+            }
+        })
+        .boxed();
+    (stream, substitutions)
+}
+
+fn transliterate(character: char) -> &'static str {
+    match character {
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' | '″' => "\"",
+        '–' | '—' | '−' => "-",
+        '…' => "...",
+        '\u{a0}' | '\u{2002}'..='\u{200a}' => " ",
+        '•' => "*",
+        '×' => "x",
+        'à'..='å' => "a",
+        'À'..='Å' => "A",
+        'ç' => "c",
+        'Ç' => "C",
+        'è'..='ë' => "e",
+        'È'..='Ë' => "E",
+        'ì'..='ï' => "i",
+        'Ì'..='Ï' => "I",
+        'ñ' => "n",
+        'Ñ' => "N",
+        'ò'..='ö' | 'ø' => "o",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'ù'..='ü' => "u",
+        'Ù'..='Ü' => "U",
+        'ý' | 'ÿ' => "y",
+        'Ý' => "Y",
+        'ß' => "ss",
+        'æ' => "ae",
+        'Æ' => "AE",
+        _ => "?",
+    }
+}
+
+/// Replaces typographic quotes with straight ones and en and em dashes with
+/// hyphens, e.g. so that code the model "helpfully" typeset can be inserted into a
+/// buffer as is. This would also change any prose in the stream, so only apply it
+/// to output that's meant to be code.
+pub fn normalize_punctuation(
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+) -> BoxStream<'static, Result<String>> {
+    chunks
+        .map(|chunk| {
+            let chunk = chunk?;
+            if chunk.is_ascii() {
+                return Ok(chunk);
+            }
+            Ok(chunk
+                .chars()
+                .map(|character| match character {
+                    '‘' | '’' | '‚' | '‛' | '′' => '\'',
+                    '“' | '”' | '„' | '‟' | '″' => '"',
+                    '–' | '—' => '-',
+                    character => character,
+                })
+                .collect())
+        })
+        .boxed()
+}
+
+/// Code blocks longer than this are tagged with whatever language can be inferred
+/// from their beginning, rather than held back until the closing fence arrives.
+const MAX_UNTAGGED_CODE_BLOCK_LEN: usize = 2048;
+
+/// Rewrites code blocks opened with a bare ```` ``` ```` fence so their opening
+/// fence carries a language inferred from the block's content. Blocks whose
+/// language can't be confidently inferred are left untouched.
+pub fn infer_code_block_languages(
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+) -> BoxStream<'static, Result<String>> {
+    let mut inference = CodeBlockLanguageInference::default();
+    chunks
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .filter_map(move |chunk| {
+            let output = match chunk {
+                Some(Ok(chunk)) => Ok(inference.push(&chunk)),
+                Some(Err(error)) => Err(error),
+                None => Ok(inference.finish()),
+            };
+            async move {
+                match output {
+                    Ok(output) if output.is_empty() => None,
+                    output => Some(output),
+                }
+            }
+        })
+        .boxed()
+}
+
+#[derive(Default)]
+struct CodeBlockLanguageInference {
+    state: FenceState,
+    /// The current, incomplete line.
+    line: String,
+    /// How many bytes of `line` have already been emitted.
+    line_emitted_len: usize,
+}
+
+#[derive(Default)]
+enum FenceState {
+    #[default]
+    Text,
+    Code,
+    UntaggedCode {
+        fence: String,
+        code: String,
+    },
+}
+
+impl CodeBlockLanguageInference {
+    fn push(&mut self, chunk: &str) -> String {
+        let mut output = String::new();
+        for text in chunk.split_inclusive('\n') {
+            self.line.push_str(text);
+            if self.line.ends_with('\n') {
+                self.end_line(&mut output);
+            }
+        }
+
+        // Partial lines are emitted right away, unless they might turn out to be a
+        // bare opening fence or belong to a block whose fence is being held back.
+        let hold = match self.state {
+            FenceState::Text => {
+                let line = self.line.trim_start();
+                "```".starts_with(line) || line.starts_with("```")
+            }
+            FenceState::Code => false,
+            FenceState::UntaggedCode { .. } => true,
+        };
+        if !hold {
+            output.push_str(&self.line[self.line_emitted_len..]);
+            self.line_emitted_len = self.line.len();
+        }
+        output
+    }
+
+    fn finish(&mut self) -> String {
+        let line = mem::take(&mut self.line);
+        let unemitted = &line[mem::take(&mut self.line_emitted_len)..];
+        match mem::take(&mut self.state) {
+            FenceState::UntaggedCode { fence, code } => {
+                let mut output = tag_fence(&fence, &code);
+                output.push_str(&code);
+                output.push_str(unemitted);
+                output
+            }
+            FenceState::Text | FenceState::Code => unemitted.to_string(),
+        }
+    }
+
+    fn end_line(&mut self, output: &mut String) {
+        let line = mem::take(&mut self.line);
+        let unemitted = &line[mem::take(&mut self.line_emitted_len)..];
+        let is_bare_fence = line.trim() == "```";
+        self.state = match mem::take(&mut self.state) {
+            FenceState::Text if is_bare_fence => FenceState::UntaggedCode {
+                fence: line,
+                code: String::new(),
+            },
+            FenceState::Text => {
+                output.push_str(unemitted);
+                if line.trim_start().starts_with("```") {
+                    FenceState::Code
+                } else {
+                    FenceState::Text
+                }
+            }
+            FenceState::Code => {
+                output.push_str(unemitted);
+                if is_bare_fence {
+                    FenceState::Text
+                } else {
+                    FenceState::Code
+                }
+            }
+            FenceState::UntaggedCode { fence, mut code } => {
+                if is_bare_fence {
+                    output.push_str(&tag_fence(&fence, &code));
+                    output.push_str(&code);
+                    output.push_str(&line);
+                    FenceState::Text
+                } else {
+                    code.push_str(&line);
+                    if code.len() >= MAX_UNTAGGED_CODE_BLOCK_LEN {
+                        output.push_str(&tag_fence(&fence, &code));
+                        output.push_str(&code);
+                        FenceState::Code
+                    } else {
+                        FenceState::UntaggedCode { fence, code }
+                    }
+                }
+            }
+        };
+    }
+}
+
+fn tag_fence(fence: &str, code: &str) -> String {
+    match infer_code_language(code) {
+        Some(language) => fence.replacen("```", &format!("```{language}"), 1),
+        None => fence.to_string(),
+    }
+}
+
+/// Snippets that are characteristic of each language. They're matched as plain
+/// substrings, so each one should be unlikely to appear in the other languages.
+const LANGUAGE_SIGNALS: &[(&str, &[&str])] = &[
+    (
+        "rust",
+        &[
+            "fn ",
+            "let mut ",
+            "impl ",
+            "pub fn ",
+            "println!",
+            "use std::",
+            "#[derive",
+            "&self",
+            "-> ",
+        ],
+    ),
+    (
+        "python",
+        &[
+            "def ", "elif ", "self.", "__init__", "print(", "import ", "None:",
+        ],
+    ),
+    ("go", &["func ", "package ", ":= ", "fmt."]),
+    (
+        "typescript",
+        &["interface ", ": string", ": number", "export ", "=> "],
+    ),
+    (
+        "javascript",
+        &["function ", "console.log", "require(", "=> ", "const "],
+    ),
+    ("bash", &["#!/bin/", "echo ", "fi\n", "done\n", "$("]),
+];
+
+/// Conservatively infers the language of a code snippet, returning `None` unless
+/// one language is clearly more likely than the others.
+fn infer_code_language(code: &str) -> Option<&'static str> {
+    let mut scores = LANGUAGE_SIGNALS
+        .iter()
+        .map(|(language, signals)| {
+            let score = signals
+                .iter()
+                .filter(|signal| code.contains(*signal))
+                .count();
+            (*language, score)
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|(_, a), (_, b)| b.cmp(a));
+    match scores.as_slice() {
+        [(language, best), (_, runner_up), ..] if *best >= 2 && best > runner_up => Some(*language),
+        _ => None,
+    }
+}
+
+/// The tags some models wrap their reasoning in when emitting it inline with
+/// their answer, rather than in a separate field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReasoningTags {
+    pub open: String,
+    pub close: String,
+}
+
+impl Default for ReasoningTags {
+    fn default() -> Self {
+        Self {
+            open: "<think>".into(),
+            close: "</think>".into(),
+        }
+    }
+}
+
+/// Moves text enclosed in `tags` out of [`CompletionEvent::Text`] events and into
+/// [`CompletionEvent::Reasoning`] ones, leaving the rest as answer text. Tags may be
+/// split across chunks; text that could be the start of a tag is held back until
+/// the next chunk shows whether it is one.
+pub fn extract_reasoning(
+    events: impl 'static + Send + Stream<Item = Result<CompletionEvent>>,
+    tags: ReasoningTags,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut extraction = ReasoningExtraction {
+        tags,
+        in_reasoning: false,
+        pending: String::new(),
+    };
+    events
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |event| {
+            let events = match event {
+                Some(Ok(CompletionEvent::Text(text))) => extraction.push(&text),
+                Some(Ok(event)) => {
+                    let mut events = extraction.flush();
+                    events.push(Ok(event));
+                    events
+                }
+                Some(Err(error)) => vec![Err(error)],
+                None => extraction.flush(),
+            };
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+struct ReasoningExtraction {
+    tags: ReasoningTags,
+    in_reasoning: bool,
+    /// Text that hasn't been emitted yet, because it may be the start of a tag.
+    pending: String,
+}
+
+impl ReasoningExtraction {
+    fn push(&mut self, text: &str) -> Vec<Result<CompletionEvent>> {
+        self.pending.push_str(text);
+        let mut events = Vec::new();
+        loop {
+            let tag = if self.in_reasoning {
+                &self.tags.close
+            } else {
+                &self.tags.open
+            };
+            if let Some(ix) = self.pending.find(tag.as_str()) {
+                let tag_len = tag.len();
+                let text = self.pending[..ix].to_string();
+                self.pending.drain(..ix + tag_len);
+                events.extend(self.event(text));
+                self.in_reasoning = !self.in_reasoning;
+            } else {
+                // Hold back the longest suffix that could still become the tag.
+                let held_len = (1..tag.len().min(self.pending.len() + 1))
+                    .rev()
+                    .find(|&len| {
+                        let start = self.pending.len() - len;
+                        self.pending.is_char_boundary(start)
+                            && tag.starts_with(&self.pending[start..])
+                    })
+                    .unwrap_or(0);
+                let text: String = self
+                    .pending
+                    .drain(..self.pending.len() - held_len)
+                    .collect();
+                events.extend(self.event(text));
+                return events;
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Vec<Result<CompletionEvent>> {
+        let text = mem::take(&mut self.pending);
+        self.event(text).into_iter().collect()
+    }
+
+    fn event(&self, text: String) -> Option<Result<CompletionEvent>> {
+        if text.is_empty() {
+            None
+        } else if self.in_reasoning {
+            Some(Ok(CompletionEvent::Reasoning(text)))
+        } else {
+            Some(Ok(CompletionEvent::Text(text)))
+        }
+    }
+}
+
+/// Ends the text of `events` where any of the `stop` sequences first appears, and
+/// holds back text that could be the start of one until the next chunk shows
+/// whether it is. Servers clip stop sequences themselves, but may stream part of
+/// one before deciding to, which would otherwise flash up in the UI.
+///
+/// [`CompletionEvent::Logprobs`] are held back with the text of their tokens, and
+/// those of tokens that were clipped are dropped. Other events pass straight
+/// through, leaving the held back text pending until the stream ends, so a stop
+/// sequence split around one is still clipped.
+pub fn hold_back_stop_sequences(
+    events: impl 'static + Send + Stream<Item = Result<CompletionEvent>>,
+    stop: Vec<String>,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut hold_back = StopSequenceHoldBack {
+        stop,
+        stopped: false,
+        pending: String::new(),
+        pending_logprobs: Vec::new(),
+        unmatched_len: 0,
+    };
+    events
+        .map(Some)
+        .chain(stream::once(async { None }))
+        .flat_map(move |event| {
+            let events = match event {
+                Some(Ok(CompletionEvent::Text(text))) => hold_back.push(&text),
+                Some(Ok(CompletionEvent::Logprobs(logprobs))) => hold_back.push_logprobs(logprobs),
+                Some(Ok(event @ CompletionEvent::StreamEnd(_))) => {
+                    let mut events = hold_back.finish();
+                    events.push(Ok(event));
+                    events
+                }
+                Some(Ok(event)) => vec![Ok(event)],
+                Some(Err(error)) => vec![Err(error)],
+                None => hold_back.finish(),
+            };
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+struct StopSequenceHoldBack {
+    stop: Vec<String>,
+    /// Whether a stop sequence has been seen, after which text is dropped.
+    stopped: bool,
+    /// Text that hasn't been emitted yet, because it may be the start of a stop
+    /// sequence.
+    pending: String,
+    /// The log probabilities of tokens whose text hasn't all been emitted yet.
+    pending_logprobs: Vec<TokenLogprob>,
+    /// How many bytes of the text emitted so far aren't covered by the log
+    /// probabilities emitted so far.
+    unmatched_len: usize,
+}
+
+impl StopSequenceHoldBack {
+    fn push(&mut self, text: &str) -> Vec<Result<CompletionEvent>> {
+        if self.stopped {
+            return Vec::new();
+        }
+        self.pending.push_str(text);
+        let stop_ix = self
+            .stop
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(ix) = stop_ix {
+            self.pending.truncate(ix);
+            self.stopped = true;
+            return self.flush();
+        }
+
+        // Hold back the longest suffix that could still become a stop sequence.
+        let held_len = self
+            .stop
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len().min(self.pending.len() + 1))
+                    .rev()
+                    .find(|&len| {
+                        let start = self.pending.len() - len;
+                        self.pending.is_char_boundary(start)
+                            && stop.starts_with(&self.pending[start..])
+                    })
+            })
+            .max()
+            .unwrap_or(0);
+        let text: String = self
+            .pending
+            .drain(..self.pending.len() - held_len)
+            .collect();
+        self.emit(text)
+    }
+
+    fn push_logprobs(&mut self, logprobs: Vec<TokenLogprob>) -> Vec<Result<CompletionEvent>> {
+        self.pending_logprobs.extend(logprobs);
+        let events = self.release_logprobs().into_iter().collect();
+        // Any that are left belong to the text clipped at the stop sequence.
+        if self.stopped {
+            self.pending_logprobs.clear();
+        }
+        events
+    }
+
+    fn flush(&mut self) -> Vec<Result<CompletionEvent>> {
+        let text = mem::take(&mut self.pending);
+        self.emit(text)
+    }
+
+    fn finish(&mut self) -> Vec<Result<CompletionEvent>> {
+        let mut events = self.flush();
+        // Tokens whose text didn't line up with the chunks are passed on as is.
+        if !self.stopped && !self.pending_logprobs.is_empty() {
+            events.push(Ok(CompletionEvent::Logprobs(mem::take(
+                &mut self.pending_logprobs,
+            ))));
+        }
+        events
+    }
+
+    fn emit(&mut self, text: String) -> Vec<Result<CompletionEvent>> {
+        let mut events = Vec::new();
+        if !text.is_empty() {
+            self.unmatched_len += text.len();
+            events.push(Ok(CompletionEvent::Text(text)));
+        }
+        events.extend(self.release_logprobs());
+        events
+    }
+
+    /// Emits the log probabilities of the tokens whose text has been emitted.
+    fn release_logprobs(&mut self) -> Option<Result<CompletionEvent>> {
+        let mut released = 0;
+        for logprob in &self.pending_logprobs {
+            if logprob.token.len() > self.unmatched_len {
+                break;
+            }
+            self.unmatched_len -= logprob.token.len();
+            released += 1;
+        }
+        if released == 0 {
+            return None;
+        }
+        Some(Ok(CompletionEvent::Logprobs(
+            self.pending_logprobs.drain(..released).collect(),
+        )))
+    }
+}
+
+/// An item of a stream passed through [`detect_first_json_object`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonStreamEvent {
+    Text(String),
+    /// The first JSON object in the stream, emitted right after the chunk that
+    /// completes it.
+    JsonComplete(serde_json::Value),
+}
+
+/// Passes a chunked text stream through unchanged, and also emits the first JSON
+/// object found in it as soon as its closing brace arrives. Text around the object,
+/// such as prose introducing or following it, is ignored when looking for it.
+pub fn detect_first_json_object(
+    chunks: impl 'static + Send + Stream<Item = Result<String>>,
+) -> BoxStream<'static, Result<JsonStreamEvent>> {
+    let mut detector = JsonObjectDetector::default();
+    chunks
+        .flat_map(move |chunk| {
+            let events = match chunk {
+                Ok(chunk) => {
+                    let object = detector.push(&chunk);
+                    let mut events = vec![Ok(JsonStreamEvent::Text(chunk))];
+                    events.extend(object.map(|object| Ok(JsonStreamEvent::JsonComplete(object))));
+                    events
+                }
+                Err(error) => vec![Err(error)],
+            };
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+#[derive(Default)]
+struct JsonObjectDetector {
+    /// The text of the candidate object so far, starting at its opening brace.
+    object: String,
+    /// How deeply nested in braces and brackets the end of `object` is.
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    done: bool,
+}
+
+impl JsonObjectDetector {
+    fn push(&mut self, chunk: &str) -> Option<serde_json::Value> {
+        let mut result = None;
+        for character in chunk.chars() {
+            if self.done {
+                break;
+            }
+            if self.depth == 0 {
+                if character == '{' {
+                    self.object.push(character);
+                    self.depth = 1;
+                }
+                continue;
+            }
+
+            self.object.push(character);
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if character == '\\' {
+                    self.escaped = true;
+                } else if character == '"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+            match character {
+                '"' => self.in_string = true,
+                '{' | '[' => self.depth += 1,
+                '}' | ']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        // Braces that balance without forming valid JSON were just
+                        // prose, so keep looking for an object after them.
+                        if let Ok(object) = serde_json::from_str(&mem::take(&mut self.object)) {
+                            result = Some(object);
+                            self.done = true;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+}
+
+/// Follows each chunk of text or reasoning in `events` with a [`CompletionEvent::Cost`]
+/// estimating what the completion has cost so far, by counting the chunks' tokens
+/// against `price`'s completion price. Once the provider reports the completion's
+/// usage, a final cost computed from it, including the prompt, is emitted before
+/// the end of the stream.
+pub fn with_cost_estimates(
+    events: impl 'static + Send + Stream<Item = Result<CompletionEvent>>,
+    tokenizer: Arc<dyn Tokenizer>,
+    price: TokenPrice,
+) -> BoxStream<'static, Result<CompletionEvent>> {
+    let mut completion_tokens = 0;
+    events
+        .flat_map(move |event| {
+            let cost = match &event {
+                Ok(CompletionEvent::Text(text) | CompletionEvent::Reasoning(text)) => {
+                    completion_tokens += tokenizer.count_tokens(text);
+                    Some(CostEstimate {
+                        cost: price.cost(TokenUsage {
+                            completion_tokens: completion_tokens as u32,
+                            ..Default::default()
+                        }),
+                        is_final: false,
+                    })
+                }
+                Ok(CompletionEvent::StreamEnd(StreamEnd {
+                    usage: Some(usage), ..
+                })) => Some(CostEstimate {
+                    cost: price.cost(*usage),
+                    is_final: true,
+                }),
+                _ => None,
+            };
+            let events = match cost {
+                Some(cost) if cost.is_final => vec![Ok(CompletionEvent::Cost(cost)), event],
+                Some(cost) => vec![event, Ok(CompletionEvent::Cost(cost))],
+                None => vec![event],
+            };
+            stream::iter(events)
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use gpui::TestAppContext;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[gpui::test]
+    async fn test_smooth(cx: &mut TestAppContext) {
+        let executor = cx.executor();
+        let (tx, rx) = mpsc::unbounded::<Result<String>>();
+        // One character per tick.
+        let chars_per_second = 1000 / SMOOTH_TICK.as_millis() as usize;
+        let mut smoothed = smooth(rx, chars_per_second, executor.clone());
+
+        let emitted = Arc::new(Mutex::new(Vec::new()));
+        let _task = executor.spawn({
+            let emitted = emitted.clone();
+            async move {
+                while let Some(chunk) = smoothed.next().await {
+                    emitted.lock().push(chunk.unwrap());
+                }
+            }
+        });
+
+        tx.unbounded_send(Ok("hello world".into())).unwrap();
+        executor.run_until_parked();
+        assert_eq!(*emitted.lock(), vec!["h"]);
+
+        executor.advance_clock(SMOOTH_TICK);
+        executor.run_until_parked();
+        executor.advance_clock(SMOOTH_TICK);
+        executor.run_until_parked();
+        assert_eq!(*emitted.lock(), vec!["h", "e", "l"]);
+
+        // Ending the stream flushes everything that's still buffered on the next tick.
+        tx.unbounded_send(Ok("!".into())).unwrap();
+        drop(tx);
+        executor.advance_clock(SMOOTH_TICK);
+        executor.run_until_parked();
+        assert_eq!(*emitted.lock(), vec!["h", "e", "l", "lo world!"]);
+    }
+
+    #[gpui::test]
+    async fn test_smooth_catches_up(cx: &mut TestAppContext) {
+        let executor = cx.executor();
+        let chars_per_second = 1000 / SMOOTH_TICK.as_millis() as usize;
+        let max_buffered = chars_per_second * SMOOTH_MAX_LAG.as_millis() as usize / 1000;
+        let text = "x".repeat(max_buffered + 10);
+        let (tx, rx) = mpsc::unbounded::<Result<String>>();
+        tx.unbounded_send(Ok(text)).unwrap();
+        let mut smoothed = smooth(rx, chars_per_second, executor.clone());
+
+        // A backlog beyond the allowed lag is emitted at once rather than a tick at a time.
+        let first = smoothed.next().await.unwrap().unwrap();
+        assert_eq!(first.len(), 10);
+        drop(tx);
+    }
+
+    #[gpui::test]
+    async fn test_infer_code_block_languages() {
+        let text = "Here's an example:\n\n```\nfn main() {\n    let mut count = 0;\n    count += 1;\n    println!(\"{count}\");\n}\n```\n\nAnd some prose:\n```\nhello there\n```\n";
+        // Split the text at awkward points, including the middle of each fence.
+        let chunks = text
+            .as_bytes()
+            .chunks(5)
+            .map(|chunk| Ok(String::from_utf8(chunk.to_vec()).unwrap()))
+            .collect::<Vec<_>>();
+        let output = infer_code_block_languages(stream::iter(chunks))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<String>>()
+            .unwrap();
+        assert_eq!(output, text.replacen("```\nfn main", "```rust\nfn main", 1));
+    }
+
+    #[gpui::test]
+    async fn test_word_granularity() {
+        let tokens = ["Hel", "lo", " wor", "ld,", " how", " are", " y", "ou", "?"];
+        let chunks = with_granularity(
+            stream::iter(tokens.map(|token| Ok(token.to_string()))),
+            StreamGranularity::Word,
+        )
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(chunks, vec!["Hello ", "world, ", "how ", "are ", "you?"]);
+    }
+
+    #[gpui::test]
+    async fn test_restrict_to_ascii() {
+        let chunks = || {
+            stream::iter(
+                ["It’s a “café”", " in Zürich", " — naïve… 🦀", " ok"]
+                    .map(|chunk| Ok(chunk.into())),
+            )
+        };
+
+        let (stream, substitutions) = restrict_to_ascii(chunks(), AsciiPolicy::Transliterate);
+        let output = stream.collect::<Vec<_>>().await;
+        assert_eq!(
+            output.into_iter().collect::<Result<Vec<_>>>().unwrap(),
+            vec!["It's a \"cafe\"", " in Zurich", " - naive... ?", " ok"]
+        );
+        assert_eq!(substitutions.count(), 9);
+
+        let (stream, substitutions) = restrict_to_ascii(chunks(), AsciiPolicy::Reject);
+        let output = stream.collect::<Vec<_>>().await;
+        assert!(output[0].is_err());
+        assert_eq!(substitutions.count(), 0);
+    }
+
+    #[gpui::test]
+    async fn test_extract_reasoning() {
+        let chunks = [
+            "<th",
+            "ink>Let me",
+            " think about this.</thi",
+            "nk>The answer",
+            " is 4 <",
+            " 5.",
+        ];
+        let events = extract_reasoning(
+            stream::iter(chunks.map(|chunk| Ok(CompletionEvent::Text(chunk.into())))),
+            ReasoningTags::default(),
+        )
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Reasoning("Let me".into()),
+                CompletionEvent::Reasoning(" think about this.".into()),
+                CompletionEvent::Text("The answer".into()),
+                CompletionEvent::Text(" is 4 ".into()),
+                CompletionEvent::Text("< 5.".into()),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_detect_first_json_object() {
+        let chunks = [
+            "Here you go {see below}: {\"name\": \"Ada\", ",
+            "\"tags\": [\"a}\", \"\\\"b{\"], \"address\": {\"ci",
+            "ty\": \"London\"}",
+            "}\n\nLet me know if you",
+            " need anything {else}.",
+        ];
+        let events = detect_first_json_object(stream::iter(chunks.map(|chunk| Ok(chunk.into()))))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let mut expected = chunks
+            .map(|chunk| JsonStreamEvent::Text(chunk.into()))
+            .to_vec();
+        expected.insert(
+            4,
+            JsonStreamEvent::JsonComplete(serde_json::json!({
+                "name": "Ada",
+                "tags": ["a}", "\"b{"],
+                "address": { "city": "London" },
+            })),
+        );
+        assert_eq!(events, expected);
+    }
+
+    #[gpui::test]
+    async fn test_normalize_punctuation() {
+        let chunks = [
+            "let name = “Zed",
+            "”;\nlet c = ‘x’; // a — b – c",
+            "’s café",
+        ];
+        let output = normalize_punctuation(stream::iter(chunks.map(|chunk| Ok(chunk.into()))))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<String>>()
+            .unwrap();
+        assert_eq!(
+            output,
+            "let name = \"Zed\";\nlet c = 'x'; // a - b - c's café"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_hold_back_stop_sequences_with_logprobs() {
+        let logprobs = |tokens: &[&str]| {
+            CompletionEvent::Logprobs(
+                tokens
+                    .iter()
+                    .map(|token| TokenLogprob {
+                        token: token.to_string(),
+                        logprob: -0.5,
+                        top_logprobs: Vec::new(),
+                    })
+                    .collect(),
+            )
+        };
+        let events = stream::iter(
+            vec![
+                CompletionEvent::Text("Hello".into()),
+                logprobs(&["Hello"]),
+                CompletionEvent::Text(" EN".into()),
+                logprobs(&[" EN"]),
+                CompletionEvent::Text("D more".into()),
+                logprobs(&["D", " more"]),
+                CompletionEvent::StreamEnd(StreamEnd::default()),
+            ]
+            .into_iter()
+            .map(Ok),
+        );
+        let events = hold_back_stop_sequences(events, vec!["END".into()])
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        // Log probabilities follow the text of their tokens, and those of the
+        // tokens that were clipped, in part or whole, are dropped.
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Text("Hello".into()),
+                logprobs(&["Hello"]),
+                CompletionEvent::Text(" ".into()),
+                CompletionEvent::StreamEnd(StreamEnd::default()),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_hold_back_stop_sequence_around_other_events() {
+        let events = stream::iter(
+            vec![
+                CompletionEvent::Text("Hello EN".into()),
+                CompletionEvent::Reasoning("Nearly done.".into()),
+                CompletionEvent::Text("D more".into()),
+                CompletionEvent::StreamEnd(StreamEnd::default()),
+            ]
+            .into_iter()
+            .map(Ok),
+        );
+        let events = hold_back_stop_sequences(events, vec!["END".into()])
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Text("Hello ".into()),
+                CompletionEvent::Reasoning("Nearly done.".into()),
+                CompletionEvent::StreamEnd(StreamEnd::default()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{anthropic_token_price, open_ai_token_price, TokenPrice, TokenUsage};
+use language_model::LanguageModel;
+use parking_lot::Mutex;
+use std::{collections::BTreeMap, sync::Arc};
+
+/// The usage of a model, or of several, accumulated by a [`UsageTracker`] or by a
+/// provider over its session.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// How many completions finished.
+    pub requests: u64,
+    /// The estimated cost of the tokens, in dollars, or `None` if none of them were
+    /// spent on a model with a known price.
+    pub estimated_cost: Option<f64>,
+}
+
+impl ModelUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub(crate) fn record(&mut self, usage: Option<TokenUsage>, price: Option<TokenPrice>) {
+        self.requests += 1;
+        let Some(usage) = usage else {
+            return;
+        };
+        self.prompt_tokens += usage.total_prompt_tokens() as u64;
+        self.completion_tokens += usage.completion_tokens as u64;
+        if let Some(price) = price {
+            *self.estimated_cost.get_or_insert(0.) += price.cost(usage);
+        }
+    }
+
+    fn add(&mut self, other: &ModelUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.requests += other.requests;
+        if let Some(cost) = other.estimated_cost {
+            *self.estimated_cost.get_or_insert(0.) += cost;
+        }
+    }
+}
+
+/// Accumulates the tokens spent, and what they're estimated to have cost, by every
+/// completion streamed through the [`crate::CompletionProvider`] this session.
+///
+/// Clones share the same totals, so a handle can be kept, e.g. by the UI, to query
+/// them as completions finish on background tasks.
+#[derive(Clone, Default)]
+pub struct UsageTracker {
+    usage: Arc<Mutex<BTreeMap<String, ModelUsage>>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a finished completion from `model`, and the tokens it reported using,
+    /// if it did. Tokens are priced at the model's list price, if it has one.
+    pub fn record(&self, model: &LanguageModel, usage: Option<TokenUsage>) {
+        let price = match model {
+            LanguageModel::OpenAi(model) => open_ai_token_price(model),
+            LanguageModel::Anthropic(model) => Some(anthropic_token_price(model)),
+            _ => None,
+        };
+        self.usage
+            .lock()
+            .entry(model.id().to_string())
+            .or_default()
+            .record(usage, price);
+    }
+
+    /// The usage of each model that has completed a request, keyed by model id.
+    pub fn usage_by_model(&self) -> BTreeMap<String, ModelUsage> {
+        self.usage.lock().clone()
+    }
+
+    /// The usage of every model combined. Its cost only covers the models with a
+    /// known price.
+    pub fn total(&self) -> ModelUsage {
+        let mut total = ModelUsage::default();
+        for usage in self.usage.lock().values() {
+            total.add(usage);
+        }
+        total
+    }
+
+    /// Forgets all of the usage recorded so far, e.g. to start a new session.
+    pub fn reset(&self) {
+        self.usage.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use open_ai::Model as OpenAiModel;
+
+    #[test]
+    fn test_usage_tracker() {
+        let tracker = UsageTracker::new();
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            completion_tokens: 100_000,
+            ..Default::default()
+        };
+        let custom_model = LanguageModel::OpenAi(OpenAiModel::Custom {
+            name: "llama-3".into(),
+            max_tokens: 8192,
+            tokenizer: None,
+        });
+        tracker.record(
+            &LanguageModel::OpenAi(OpenAiModel::FourOmniMini),
+            Some(usage),
+        );
+        tracker.clone().record(&custom_model, Some(usage));
+        tracker.record(&custom_model, None);
+
+        let usage_by_model = tracker.usage_by_model();
+        assert_eq!(
+            usage_by_model["gpt-4o-mini"],
+            ModelUsage {
+                prompt_tokens: 1_000_000,
+                completion_tokens: 100_000,
+                requests: 1,
+                estimated_cost: Some(0.21),
+            }
+        );
+        assert_eq!(
+            usage_by_model["llama-3"],
+            ModelUsage {
+                prompt_tokens: 1_000_000,
+                completion_tokens: 100_000,
+                requests: 2,
+                estimated_cost: None,
+            }
+        );
+
+        let total = tracker.total();
+        assert_eq!(total.total_tokens(), 2_200_000);
+        assert_eq!(total.requests, 3);
+        assert_eq!(total.estimated_cost, Some(0.21));
+
+        tracker.reset();
+        assert_eq!(tracker.total(), ModelUsage::default());
+    }
+}
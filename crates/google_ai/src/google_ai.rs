@@ -4,9 +4,43 @@ use anyhow::{anyhow, Result};
 use futures::{io::BufReader, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, StreamExt};
 use http::HttpClient;
 use serde::{Deserialize, Serialize};
+use strum::EnumIter;
 
 pub const API_URL: &str = "https://generativelanguage.googleapis.com";
 
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, EnumIter)]
+pub enum Model {
+    #[default]
+    #[serde(rename = "gemini-1.5-pro")]
+    Gemini15Pro,
+    #[serde(rename = "gemini-1.5-flash")]
+    Gemini15Flash,
+}
+
+impl Model {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Model::Gemini15Pro => "gemini-1.5-pro",
+            Model::Gemini15Flash => "gemini-1.5-flash",
+        }
+    }
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Model::Gemini15Pro => "Gemini 1.5 Pro",
+            Model::Gemini15Flash => "Gemini 1.5 Flash",
+        }
+    }
+
+    pub fn max_token_count(&self) -> usize {
+        match self {
+            Model::Gemini15Pro => 2_097_152,
+            Model::Gemini15Flash => 1_048_576,
+        }
+    }
+}
+
 pub async fn stream_generate_content(
     client: Arc<dyn HttpClient>,
     api_url: &str,
@@ -52,14 +86,15 @@ pub async fn stream_generate_content(
     }
 }
 
-pub async fn count_tokens<T: HttpClient>(
+pub async fn count_tokens<T: HttpClient + ?Sized>(
     client: &T,
     api_url: &str,
     api_key: &str,
+    model: &str,
     request: CountTokensRequest,
 ) -> Result<CountTokensResponse> {
     let uri = format!(
-        "{}/v1beta/models/gemini-pro:countTokens?key={}",
+        "{}/v1beta/models/{model}:countTokens?key={}",
         api_url, api_key
     );
     let request = serde_json::to_string(&request)?;
@@ -95,15 +130,37 @@ pub enum Task {
 #[serde(rename_all = "camelCase")]
 pub struct GenerateContentRequest {
     pub contents: Vec<Content>,
+    /// Instructions for the model, which Gemini takes separately from the
+    /// conversation's contents rather than as a message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<SystemInstruction>,
     pub generation_config: Option<GenerationConfig>,
     pub safety_settings: Option<Vec<SafetySetting>>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemInstruction {
+    pub parts: Vec<Part>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GenerateContentResponse {
     pub candidates: Option<Vec<GenerateContentCandidate>>,
     pub prompt_feedback: Option<PromptFeedback>,
+    /// The tokens used so far, reported with each chunk of a streamed response.
+    #[serde(default)]
+    pub usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u32,
+    #[serde(default)]
+    pub candidates_token_count: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -124,7 +181,7 @@ pub struct Content {
     pub role: Role,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum Role {
     User,
@@ -2,12 +2,205 @@ use crate::{
     model::{CloudModel, LanguageModel},
     role::Role,
 };
+use anyhow::{anyhow, Context as _, Result};
+use open_ai::ImageDetail;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Default, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct LanguageModelToolCall {
+    pub id: String,
+    pub name: String,
+    /// The arguments the model passed to the tool, as a JSON-encoded string.
+    pub arguments: String,
+}
+
+impl LanguageModelToolCall {
+    pub fn to_proto(&self) -> proto::ToolCall {
+        proto::ToolCall {
+            id: self.id.clone(),
+            variant: Some(proto::tool_call::Variant::Function(
+                proto::tool_call::FunctionCall {
+                    name: self.name.clone(),
+                    arguments: self.arguments.clone(),
+                },
+            )),
+        }
+    }
+
+    pub fn from_proto(tool_call: proto::ToolCall) -> Result<Self> {
+        match tool_call.variant {
+            Some(proto::tool_call::Variant::Function(function)) => Ok(Self {
+                id: tool_call.id,
+                name: function.name,
+                arguments: function.arguments,
+            }),
+            None => Err(anyhow!("tool call {} has no function", tool_call.id)),
+        }
+    }
+}
+
+/// A tool the model may call.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct LanguageModelTool {
+    pub name: String,
+    pub description: Option<String>,
+    /// The JSON schema of the tool's arguments.
+    pub parameters: Option<serde_json::Value>,
+}
+
+impl LanguageModelTool {
+    pub fn to_proto(&self) -> proto::ChatCompletionTool {
+        proto::ChatCompletionTool {
+            variant: Some(proto::chat_completion_tool::Variant::Function(
+                proto::chat_completion_tool::FunctionObject {
+                    name: self.name.clone(),
+                    description: self.description.clone(),
+                    parameters: self
+                        .parameters
+                        .as_ref()
+                        .map(|parameters| parameters.to_string()),
+                },
+            )),
+        }
+    }
+
+    pub fn from_proto(tool: proto::ChatCompletionTool) -> Result<Self> {
+        match tool.variant {
+            Some(proto::chat_completion_tool::Variant::Function(function)) => Ok(Self {
+                parameters: function
+                    .parameters
+                    .map(|parameters| serde_json::from_str(&parameters))
+                    .transpose()
+                    .with_context(|| {
+                        format!(
+                            "failed to deserialize the parameters of {:?}",
+                            function.name
+                        )
+                    })?,
+                name: function.name,
+                description: function.description,
+            }),
+            None => Err(anyhow!("tool has no function")),
+        }
+    }
+}
+
+/// Whether, and which, tools the model should call.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LanguageModelToolChoice {
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model must not call any tools.
+    None,
+    /// The model must call at least one tool.
+    Required,
+    /// The model must call the named tool.
+    Specific(String),
+}
+
+impl LanguageModelToolChoice {
+    /// Over the wire a specific choice is sent as the tool's name, and the other
+    /// choices by their OpenAI names. Whether it names a tool is sent separately,
+    /// see [`Self::names_tool`].
+    pub fn to_proto(&self) -> String {
+        match self {
+            Self::Auto => "auto".into(),
+            Self::None => "none".into(),
+            Self::Required => "required".into(),
+            Self::Specific(name) => name.clone(),
+        }
+    }
+
+    /// Whether the choice names a specific tool.
+    pub fn names_tool(&self) -> bool {
+        matches!(self, Self::Specific(_))
+    }
+
+    /// Clients that predate `names_tool` being sent only send a tool's name when
+    /// it isn't one of the other choices.
+    pub fn from_proto(tool_choice: String, names_tool: bool) -> Self {
+        if names_tool {
+            return Self::Specific(tool_choice);
+        }
+        match tool_choice.as_str() {
+            "auto" => Self::Auto,
+            "none" => Self::None,
+            "required" => Self::Required,
+            _ => Self::Specific(tool_choice),
+        }
+    }
+}
+
+/// An image attached to a message, for models that accept image inputs.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct LanguageModelImage {
+    /// A remote URL, or a base64-encoded `data:` URL.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+    /// The image's dimensions, if known. They're only used to estimate how many
+    /// tokens the image costs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<ImageSize>,
+}
+
+/// The dimensions of an image, in pixels.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl LanguageModelImage {
+    /// An image of the given media type (e.g. `image/png`), from its base64-encoded
+    /// contents.
+    pub fn from_base64(media_type: &str, data: &str) -> Self {
+        Self {
+            url: format!("data:{media_type};base64,{data}"),
+            detail: None,
+            size: None,
+        }
+    }
+
+    /// The image's media type and base64-encoded contents, if it's a base64
+    /// `data:` URL rather than a remote one.
+    pub fn as_base64(&self) -> Option<(&str, &str)> {
+        self.url.strip_prefix("data:")?.split_once(";base64,")
+    }
+}
+
+/// The format the model must respond in.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LanguageModelResponseFormat {
+    Text,
+    /// A single valid JSON object. OpenAI requires the messages to ask for JSON
+    /// when this is set.
+    JsonObject,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct LanguageModelRequestMessage {
     pub role: Role,
     pub content: String,
+    /// Tool calls made by the assistant during this turn. An assistant turn may
+    /// carry both text content and tool calls.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<LanguageModelToolCall>,
+    /// For [`Role::Tool`] messages, the id of the tool call this is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Images attached to a user message, which are sent after its text content.
+    /// Providers and models that don't accept image inputs ignore them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<LanguageModelImage>,
+    /// Whether to cache the prompt up to and including this message, so that
+    /// requests repeating it, e.g. a long system prompt, are cheaper and faster.
+    /// Only Anthropic supports this; other providers ignore it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cache: bool,
 }
 
 impl LanguageModelRequestMessage {
@@ -15,18 +208,80 @@ impl LanguageModelRequestMessage {
         proto::LanguageModelRequestMessage {
             role: self.role.to_proto() as i32,
             content: self.content.clone(),
-            tool_calls: Vec::new(),
-            tool_call_id: None,
+            tool_calls: self
+                .tool_calls
+                .iter()
+                .map(|tool_call| tool_call.to_proto())
+                .collect(),
+            tool_call_id: self.tool_call_id.clone(),
+            cache: self.cache,
         }
     }
+
+    /// Images aren't sent over the wire, so the message has none.
+    pub fn from_proto(message: proto::LanguageModelRequestMessage) -> Result<Self> {
+        Ok(Self {
+            role: Role::from_proto(message.role),
+            content: message.content,
+            tool_calls: message
+                .tool_calls
+                .into_iter()
+                .map(LanguageModelToolCall::from_proto)
+                .collect::<Result<_>>()?,
+            tool_call_id: message.tool_call_id,
+            images: Vec::new(),
+            cache: message.cache,
+        })
+    }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct LanguageModelRequest {
     pub model: LanguageModel,
     pub messages: Vec<LanguageModelRequestMessage>,
     pub stop: Vec<String>,
     pub temperature: f32,
+    /// The maximum number of tokens to generate. When `None`, the provider's
+    /// default applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<LanguageModelTool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<LanguageModelToolChoice>,
+    /// Nucleus sampling: only tokens within the top `top_p` of probability mass
+    /// are considered. It's usually adjusted instead of `temperature`, not as well.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    /// Between -2.0 and 2.0. Positive values penalize tokens by how often they've
+    /// appeared so far, making verbatim repetition less likely.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// Between -2.0 and 2.0. Positive values penalize tokens that have appeared at
+    /// all so far, encouraging the model to move on to new topics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<LanguageModelResponseFormat>,
+    /// Asks the model to sample deterministically, so that repeating a request with
+    /// the same seed returns the same completion. This is best effort; OpenAI
+    /// reports the backend configuration that served the completion alongside it,
+    /// and only completions served by the same configuration are comparable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Makes the tokens with the given ids more or less likely to be sampled, by a
+    /// bias between -100 (effectively banning the token) and 100 (effectively
+    /// forcing it). Token ids depend on the model's tokenizer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, i32>>,
+    /// Asks the provider to report the log probability of each generated token,
+    /// alongside the text.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub logprobs: bool,
+    /// How many of the most likely alternatives to report for each generated
+    /// token. Implies `logprobs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
 }
 
 impl LanguageModelRequest {
@@ -36,17 +291,52 @@ impl LanguageModelRequest {
             messages: self.messages.iter().map(|m| m.to_proto()).collect(),
             stop: self.stop.clone(),
             temperature: self.temperature,
-            tool_choice: None,
-            tools: Vec::new(),
+            tool_choice: self.tool_choice.as_ref().map(|choice| choice.to_proto()),
+            tool_choice_names_tool: self
+                .tool_choice
+                .as_ref()
+                .map_or(false, LanguageModelToolChoice::names_tool),
+            tools: self.tools.iter().map(|tool| tool.to_proto()).collect(),
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
         }
     }
 
+    /// A request for `model`, whose id was sent along with the rest of the request.
+    pub fn from_proto(
+        request: proto::CompleteWithLanguageModel,
+        model: LanguageModel,
+    ) -> Result<Self> {
+        Ok(Self {
+            model,
+            messages: request
+                .messages
+                .into_iter()
+                .map(LanguageModelRequestMessage::from_proto)
+                .collect::<Result<_>>()?,
+            stop: request.stop,
+            temperature: request.temperature,
+            tools: request
+                .tools
+                .into_iter()
+                .map(LanguageModelTool::from_proto)
+                .collect::<Result<_>>()?,
+            tool_choice: request.tool_choice.map(|tool_choice| {
+                LanguageModelToolChoice::from_proto(tool_choice, request.tool_choice_names_tool)
+            }),
+            max_tokens: request.max_tokens,
+            top_p: request.top_p,
+            ..Default::default()
+        })
+    }
+
     /// Before we send the request to the server, we can perform fixups on it appropriate to the model.
     pub fn preprocess(&mut self) {
         match &self.model {
             LanguageModel::OpenAi(_) => {}
             LanguageModel::Anthropic(_) => {}
             LanguageModel::Ollama(_) => {}
+            LanguageModel::Google(_) => {}
             LanguageModel::Cloud(model) => match model {
                 CloudModel::Claude3Opus
                 | CloudModel::Claude3Sonnet
@@ -62,18 +352,28 @@ impl LanguageModelRequest {
     pub fn preprocess_anthropic(&mut self) {
         let mut new_messages: Vec<LanguageModelRequestMessage> = Vec::new();
         let mut system_message = String::new();
+        let mut cache_system_message = false;
 
         for message in self.messages.drain(..) {
-            if message.content.is_empty() {
-                continue;
-            }
-
             match message.role {
+                // Tool results keep their own messages, even empty ones, so that each
+                // call still has a result to be matched up with.
+                Role::Tool => new_messages.push(message),
                 Role::User | Role::Assistant => {
+                    if message.content.is_empty()
+                        && message.tool_calls.is_empty()
+                        && message.images.is_empty()
+                    {
+                        continue;
+                    }
+
                     if let Some(last_message) = new_messages.last_mut() {
                         if last_message.role == message.role {
                             last_message.content.push_str("\n\n");
                             last_message.content.push_str(&message.content);
+                            last_message.tool_calls.extend(message.tool_calls);
+                            last_message.images.extend(message.images);
+                            last_message.cache |= message.cache;
                             continue;
                         }
                     }
@@ -81,10 +381,14 @@ impl LanguageModelRequest {
                     new_messages.push(message);
                 }
                 Role::System => {
+                    if message.content.is_empty() {
+                        continue;
+                    }
                     if !system_message.is_empty() {
                         system_message.push_str("\n\n");
                     }
                     system_message.push_str(&message.content);
+                    cache_system_message |= message.cache;
                 }
             }
         }
@@ -95,6 +399,10 @@ impl LanguageModelRequest {
                 LanguageModelRequestMessage {
                     role: Role::System,
                     content: system_message,
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                    images: Vec::new(),
+                    cache: cache_system_message,
                 },
             );
         }
@@ -7,6 +7,8 @@ pub enum Role {
     User,
     Assistant,
     System,
+    /// The result of a tool call, fed back to the model in a follow-up request.
+    Tool,
 }
 
 impl Role {
@@ -15,7 +17,7 @@ impl Role {
             Some(proto::LanguageModelRole::LanguageModelUser) => Role::User,
             Some(proto::LanguageModelRole::LanguageModelAssistant) => Role::Assistant,
             Some(proto::LanguageModelRole::LanguageModelSystem) => Role::System,
-            Some(proto::LanguageModelRole::LanguageModelTool) => Role::System,
+            Some(proto::LanguageModelRole::LanguageModelTool) => Role::Tool,
             None => Role::User,
         }
     }
@@ -25,6 +27,7 @@ impl Role {
             Role::User => proto::LanguageModelRole::LanguageModelUser,
             Role::Assistant => proto::LanguageModelRole::LanguageModelAssistant,
             Role::System => proto::LanguageModelRole::LanguageModelSystem,
+            Role::Tool => proto::LanguageModelRole::LanguageModelTool,
         }
     }
 
@@ -32,7 +35,7 @@ impl Role {
         match self {
             Role::User => Role::Assistant,
             Role::Assistant => Role::System,
-            Role::System => Role::User,
+            Role::System | Role::Tool => Role::User,
         }
     }
 }
@@ -43,6 +46,7 @@ impl Display for Role {
             Role::User => write!(f, "user"),
             Role::Assistant => write!(f, "assistant"),
             Role::System => write!(f, "system"),
+            Role::Tool => write!(f, "tool"),
         }
     }
 }
@@ -53,6 +57,7 @@ impl From<Role> for ollama::Role {
             Role::User => ollama::Role::User,
             Role::Assistant => ollama::Role::Assistant,
             Role::System => ollama::Role::System,
+            Role::Tool => ollama::Role::User,
         }
     }
 }
@@ -63,6 +68,7 @@ impl From<Role> for open_ai::Role {
             Role::User => open_ai::Role::User,
             Role::Assistant => open_ai::Role::Assistant,
             Role::System => open_ai::Role::System,
+            Role::Tool => open_ai::Role::Tool,
         }
     }
 }
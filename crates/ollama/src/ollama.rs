@@ -125,10 +125,19 @@ pub struct ChatResponseDelta {
     #[allow(unused)]
     pub created_at: String,
     pub message: ChatMessage,
-    #[allow(unused)]
     pub done_reason: Option<String>,
-    #[allow(unused)]
     pub done: bool,
+    /// How many tokens the prompt was, reported on the final delta.
+    #[serde(default)]
+    pub prompt_eval_count: Option<u32>,
+    /// How many tokens were generated, reported on the final delta.
+    #[serde(default)]
+    pub eval_count: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -187,9 +196,7 @@ pub async fn stream_chat_completion(
             .lines()
             .filter_map(|line| async move {
                 match line {
-                    Ok(line) => {
-                        Some(serde_json::from_str(&line).context("Unable to parse chat response"))
-                    }
+                    Ok(line) => parse_chat_response_line(&line),
                     Err(e) => Some(Err(e.into())),
                 }
             })
@@ -206,6 +213,19 @@ pub async fn stream_chat_completion(
     }
 }
 
+/// Parses a line of the newline-delimited JSON that `/api/chat` streams, skipping
+/// blank lines. Ollama reports errors that happen mid-stream, such as the model
+/// failing to load, as a line with just an `error`.
+fn parse_chat_response_line(line: &str) -> Option<Result<ChatResponseDelta>> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    if let Ok(ErrorResponse { error }) = serde_json::from_str(line) {
+        return Some(Err(anyhow!("Ollama error: {error}")));
+    }
+    Some(serde_json::from_str(line).context("Unable to parse chat response"))
+}
+
 pub async fn get_models(
     client: &dyn HttpClient,
     api_url: &str,
@@ -1,14 +1,99 @@
 use anyhow::{anyhow, Context, Result};
-use futures::{io::BufReader, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, StreamExt};
-use http::{AsyncBody, HttpClient, Method, Request as HttpRequest};
-use isahc::config::Configurable;
-use serde::{Deserialize, Serialize};
+use async_compression::futures::bufread::GzipDecoder;
+use futures::{
+    io::BufReader, stream::BoxStream, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, Stream,
+    StreamExt,
+};
+use http::{AsyncBody, HttpClient, Method, Request as HttpRequest, Response as HttpResponse};
+use isahc::{
+    config::{Configurable, VersionNegotiation},
+    http::{request::Builder as RequestBuilder, Version},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{Map, Value};
-use std::{convert::TryFrom, future::Future, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
 use strum::EnumIter;
 
 pub const OPEN_AI_API_URL: &str = "https://api.openai.com/v1";
 
+/// The header a request's id is sent in. OpenAI returns its own id for the
+/// request in the same header.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The error returned when OpenAI responds to a request with an error status.
+#[derive(Debug)]
+pub struct RequestError {
+    pub status: u16,
+    /// How long the server asked for the request not to be retried, from its
+    /// `Retry-After` header.
+    pub retry_after: Option<Duration>,
+    /// The machine-readable code OpenAI gave for the error, such as
+    /// `context_length_exceeded`, if its response included one.
+    pub code: Option<String>,
+    message: String,
+}
+
+impl RequestError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            retry_after: None,
+            code: None,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Parses a `Retry-After` header given in seconds. The HTTP date form isn't
+/// supported, since OpenAI doesn't send it.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let seconds = value.trim().parse::<f64>().ok()?;
+    (seconds.is_finite() && seconds >= 0.).then(|| Duration::from_secs_f64(seconds))
+}
+
+/// The events streamed back in response to a request.
+pub struct EventStream<T> {
+    /// Identifies the request. This is the id returned by the server if there was
+    /// one, since that's what support will ask for, or else the id it was sent with.
+    pub request_id: Option<String>,
+    events: BoxStream<'static, Result<T>>,
+}
+
+impl<T> Stream for EventStream<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+/// Which HTTP version requests are sent with.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersionPreference {
+    /// Let the client and server negotiate, preferring HTTP/2 where available.
+    #[default]
+    Negotiate,
+    /// Always use HTTP/1.1. Useful behind proxies that mishandle HTTP/2 streams.
+    Http1Only,
+}
+
 fn is_none_or_empty<T: AsRef<[U]>, U>(opt: &Option<T>) -> bool {
     opt.as_ref().map_or(true, |v| v.as_ref().is_empty())
 }
@@ -62,7 +147,14 @@ pub enum Model {
     #[serde(rename = "gpt-4o-mini", alias = "gpt-4o-mini-2024-07-18")]
     FourOmniMini,
     #[serde(rename = "custom")]
-    Custom { name: String, max_tokens: usize },
+    Custom {
+        name: String,
+        max_tokens: usize,
+        /// The tiktoken encoding that matches the model's tokenizer, such as
+        /// `o200k_base`. When unset, tokens are counted as they would be for GPT-4.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tokenizer: Option<String>,
+    },
 }
 
 impl Model {
@@ -109,6 +201,17 @@ impl Model {
             Self::Custom { max_tokens, .. } => *max_tokens,
         }
     }
+
+    /// Whether this is one of the reasoning models, which can spend a long time
+    /// thinking before they stream anything back.
+    pub fn is_reasoning_model(&self) -> bool {
+        match self {
+            Self::Custom { name, .. } => ["o1", "o3", "o4"]
+                .iter()
+                .any(|prefix| name == prefix || name.starts_with(&format!("{prefix}-"))),
+            _ => false,
+        }
+    }
 }
 
 fn serialize_model<S>(model: &Model, serializer: S) -> Result<S::Ok, S::Error>
@@ -121,35 +224,162 @@ where
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Request {
     #[serde(serialize_with = "serialize_model")]
     pub model: Model,
     pub messages: Vec<RequestMessage>,
     pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
     pub stop: Vec<String>,
     pub temperature: f32,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub tool_choice: Option<String>,
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Biases the likelihood of the given token ids, by between -100 and 100.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, i32>>,
+    /// Whether to return the log probability of each generated token.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub logprobs: bool,
+    /// How many of the most likely alternatives to return for each token, up to
+    /// 20. Requires `logprobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamOptions {
+    /// Whether to stream a final chunk, with no choices, that reports the request's
+    /// token usage.
+    pub include_usage: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ToolChoice {
+    Auto,
+    None,
+    Required,
+    Function { name: String },
 }
 
-#[derive(Debug, Serialize)]
+impl Serialize for ToolChoice {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::None => serializer.serialize_str("none"),
+            Self::Required => serializer.serialize_str("required"),
+            Self::Function { name } => {
+                serde_json::json!({ "type": "function", "function": { "name": name } })
+                    .serialize(serializer)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct FunctionDefinition {
     pub name: String,
     pub description: Option<String>,
     pub parameters: Option<Map<String, Value>>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Clone, Serialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ToolDefinition {
     #[allow(dead_code)]
     Function { function: FunctionDefinition },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/// The level of detail an image is processed at by OpenAI's vision models.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Low,
+    High,
+    /// Lets the model choose.
+    #[default]
+    Auto,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ImageUrl {
+    /// A remote URL, or a base64-encoded `data:` URL.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+/// A part of a message whose content mixes text and images.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagePart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// The content of a user message: either plain text, or a list of parts for
+/// messages that include images.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Plain(String),
+    Multipart(Vec<MessagePart>),
+}
+
+impl MessageContent {
+    /// The content's text, without any images.
+    pub fn text(&self) -> String {
+        match self {
+            Self::Plain(text) => text.clone(),
+            Self::Multipart(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    MessagePart::Text { text } => Some(text.as_str()),
+                    MessagePart::ImageUrl { .. } => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for MessageContent {
+    fn from(text: String) -> Self {
+        Self::Plain(text)
+    }
+}
+
+impl From<&str> for MessageContent {
+    fn from(text: &str) -> Self {
+        Self::Plain(text.to_string())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "role", rename_all = "lowercase")]
 pub enum RequestMessage {
     Assistant {
@@ -158,7 +388,7 @@ pub enum RequestMessage {
         tool_calls: Vec<ToolCall>,
     },
     User {
-        content: String,
+        content: MessageContent,
     },
     System {
         content: String,
@@ -169,20 +399,20 @@ pub enum RequestMessage {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct ToolCall {
     pub id: String,
     #[serde(flatten)]
     pub content: ToolCallContent,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ToolCallContent {
     Function { function: FunctionContent },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct FunctionContent {
     pub name: String,
     pub arguments: String,
@@ -213,26 +443,296 @@ pub struct FunctionChunk {
     pub arguments: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ChoiceDelta {
     pub index: u32,
     pub delta: ResponseMessageDelta,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
     pub finish_reason: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChoiceLogprobs {
+    /// The log probabilities of the tokens of the delta's content, in order.
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    /// The most likely tokens at this position, which may not include `token`.
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ResponseStreamEvent {
     pub created: u32,
     pub model: String,
     pub choices: Vec<ChoiceDelta>,
     pub usage: Option<Usage>,
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+}
+
+/// A chat completion returned whole, by a request that wasn't streamed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatCompletion {
+    pub created: u32,
+    pub model: String,
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatCompletionChoice {
+    pub index: u32,
+    pub message: ChatCompletionMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<ChoiceLogprobs>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatCompletionMessage {
+    pub role: Option<Role>,
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl From<ChatCompletion> for ResponseStreamEvent {
+    /// The completion as the single event it would have been streamed as.
+    fn from(completion: ChatCompletion) -> Self {
+        Self {
+            created: completion.created,
+            model: completion.model,
+            choices: completion
+                .choices
+                .into_iter()
+                .map(|choice| ChoiceDelta {
+                    index: choice.index,
+                    delta: ResponseMessageDelta {
+                        role: choice.message.role,
+                        content: choice.message.content,
+                        tool_calls: Some(
+                            choice
+                                .message
+                                .tool_calls
+                                .into_iter()
+                                .enumerate()
+                                .map(|(index, tool_call)| {
+                                    let ToolCallContent::Function { function } = tool_call.content;
+                                    ToolCallChunk {
+                                        index,
+                                        id: Some(tool_call.id),
+                                        function: Some(FunctionChunk {
+                                            name: Some(function.name),
+                                            arguments: Some(function.arguments),
+                                        }),
+                                    }
+                                })
+                                .collect(),
+                        ),
+                    },
+                    logprobs: choice.logprobs,
+                    finish_reason: choice.finish_reason,
+                })
+                .collect(),
+            usage: completion.usage,
+            system_fingerprint: completion.system_fingerprint,
+        }
+    }
+}
+
+/// An input item for the Responses API, which replaces Chat Completions'
+/// `messages` with a list of typed items.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseInputItem {
+    Message {
+        role: Role,
+        content: String,
+    },
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+}
+
+/// A request to the `/responses` endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResponsesRequest {
+    #[serde(serialize_with = "serialize_model")]
+    pub model: Model,
+    pub input: Vec<ResponseInputItem>,
+    pub stream: bool,
+    pub temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResponsesError {
+    pub code: Option<String>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResponsesUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResponsesResponse {
+    pub id: String,
+    pub status: Option<String>,
+    pub error: Option<ResponsesError>,
+    pub usage: Option<ResponsesUsage>,
+}
+
+/// A server-sent event streamed back from the `/responses` endpoint.
+///
+/// Only the events we act on are modeled; everything else is [`ResponsesStreamEvent::Other`].
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum ResponsesStreamEvent {
+    #[serde(rename = "response.output_text.delta")]
+    OutputTextDelta {
+        output_index: usize,
+        content_index: usize,
+        delta: String,
+    },
+    #[serde(rename = "response.completed")]
+    Completed { response: ResponsesResponse },
+    #[serde(rename = "response.failed")]
+    Failed { response: ResponsesResponse },
+    #[serde(rename = "error")]
+    Error {
+        code: Option<String>,
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Normalizes a user-provided API URL, prepending `https://` when it has no
+/// scheme and removing any trailing slashes. Schemes other than `http` and
+/// `https` are rejected.
+pub fn normalize_api_url(api_url: &str) -> Result<String> {
+    let api_url = api_url.trim().trim_end_matches('/');
+    match api_url.split_once("://") {
+        None => Ok(format!("https://{api_url}")),
+        Some((scheme, _))
+            if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+        {
+            Ok(api_url.to_string())
+        }
+        Some((scheme, _)) => Err(anyhow!(
+            "unsupported scheme {scheme:?} in API URL {api_url:?}, expected http or https"
+        )),
+    }
+}
+
+/// The chat completions URL of the OpenAI-compatible API at `api_url`.
+pub fn chat_completions_url(api_url: &str) -> Result<String> {
+    Ok(format!("{}/chat/completions", normalize_api_url(api_url)?))
+}
+
+/// The embeddings URL of the OpenAI-compatible API at `api_url`.
+pub fn embeddings_url(api_url: &str) -> Result<String> {
+    Ok(format!("{}/embeddings", normalize_api_url(api_url)?))
+}
+
+/// How a request is authenticated. An empty key is treated as missing, for servers
+/// that don't require one, so no key header is sent.
+#[derive(Clone, Copy, Debug)]
+pub enum ApiAuth<'a> {
+    /// An `Authorization: Bearer` header, as OpenAI expects. The organization, if
+    /// there is one, is sent in the `OpenAI-Organization` header, to bill it and
+    /// give access to its models.
+    Bearer {
+        api_key: &'a str,
+        organization: Option<&'a str>,
+    },
+    /// An `api-key` header, as Azure OpenAI expects.
+    ApiKey(&'a str),
+    /// The key as the value of the header called `name`.
+    Header { name: &'a str, api_key: &'a str },
+}
+
+/// Which header an OpenAI-compatible API expects its key in.
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthHeaderStyle {
+    /// `Authorization: Bearer <key>`, as OpenAI expects.
+    #[default]
+    Bearer,
+    /// `api-key: <key>`, as Azure OpenAI expects.
+    ApiKey,
+    /// The key as the value of the named header, such as `x-api-key`.
+    Header(String),
+}
+
+impl AuthHeaderStyle {
+    /// Authenticates with `api_key`. The organization is only sent with bearer
+    /// authentication, since it's specific to OpenAI.
+    pub fn auth<'a>(&'a self, api_key: &'a str, organization: Option<&'a str>) -> ApiAuth<'a> {
+        match self {
+            Self::Bearer => ApiAuth::Bearer {
+                api_key,
+                organization,
+            },
+            Self::ApiKey => ApiAuth::ApiKey(api_key),
+            Self::Header(name) => ApiAuth::Header { name, api_key },
+        }
+    }
+}
+
+/// An Azure OpenAI deployment. Azure serves each model from a named deployment of
+/// a resource, rather than taking the model's name in the request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AzureDeployment {
+    pub deployment: String,
+    /// The `api-version` requested, e.g. `2024-02-01`.
+    pub api_version: String,
+}
+
+impl AzureDeployment {
+    /// The chat completions URL of the deployment on the resource at `endpoint`,
+    /// e.g. `https://{resource}.openai.azure.com`.
+    pub fn chat_completions_url(&self, endpoint: &str) -> Result<String> {
+        Ok(format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            normalize_api_url(endpoint)?,
+            self.deployment,
+            self.api_version
+        ))
+    }
 }
 
 pub async fn stream_completion(
@@ -241,44 +741,351 @@ pub async fn stream_completion(
     api_key: &str,
     request: Request,
     low_speed_timeout: Option<Duration>,
-) -> Result<BoxStream<'static, Result<ResponseStreamEvent>>> {
-    let uri = format!("{api_url}/chat/completions");
-    let mut request_builder = HttpRequest::builder()
-        .method(Method::POST)
+    http_version: HttpVersionPreference,
+    request_id: Option<&str>,
+) -> Result<EventStream<ResponseStreamEvent>> {
+    stream_completion_with_auth(
+        client,
+        chat_completions_url(api_url)?,
+        ApiAuth::Bearer {
+            api_key,
+            organization: None,
+        },
+        request,
+        low_speed_timeout,
+        http_version,
+        request_id,
+    )
+    .await
+}
+
+/// Streams a chat completion from `uri`, for APIs that differ from OpenAI's in how
+/// completions are addressed or authenticated.
+pub async fn stream_completion_with_auth(
+    client: &dyn HttpClient,
+    uri: String,
+    auth: ApiAuth<'_>,
+    request: Request,
+    low_speed_timeout: Option<Duration>,
+    http_version: HttpVersionPreference,
+    request_id: Option<&str>,
+) -> Result<EventStream<ResponseStreamEvent>> {
+    stream_events(
+        client,
+        uri,
+        auth,
+        serde_json::to_string(&request)?,
+        low_speed_timeout,
+        http_version,
+        request_id,
+    )
+    .await
+}
+
+/// Requests a chat completion from `uri` without streaming it, for servers or
+/// proxies that can't stream. The completion is returned as a stream of the single
+/// event it would have been streamed as.
+pub async fn complete_with_auth(
+    client: &dyn HttpClient,
+    uri: String,
+    auth: ApiAuth<'_>,
+    mut request: Request,
+    low_speed_timeout: Option<Duration>,
+    http_version: HttpVersionPreference,
+    request_id: Option<&str>,
+) -> Result<EventStream<ResponseStreamEvent>> {
+    request.stream = false;
+    request.stream_options = None;
+    let (response, request_id) = send_request(
+        client,
+        uri,
+        auth,
+        serde_json::to_string(&request)?,
+        low_speed_timeout,
+        http_version,
+        request_id,
+    )
+    .await?;
+    let mut body = String::new();
+    decoded_body(response).read_to_string(&mut body).await?;
+    let completion = serde_json::from_str::<ChatCompletion>(&body)?;
+    Ok(EventStream {
+        request_id,
+        events: futures::stream::iter([Ok(completion.into())]).boxed(),
+    })
+}
+
+pub async fn stream_response(
+    client: &dyn HttpClient,
+    api_url: &str,
+    auth: ApiAuth<'_>,
+    request: ResponsesRequest,
+    low_speed_timeout: Option<Duration>,
+    http_version: HttpVersionPreference,
+    request_id: Option<&str>,
+) -> Result<EventStream<ResponsesStreamEvent>> {
+    let uri = format!("{}/responses", normalize_api_url(api_url)?);
+    stream_events(
+        client,
+        uri,
+        auth,
+        serde_json::to_string(&request)?,
+        low_speed_timeout,
+        http_version,
+        request_id,
+    )
+    .await
+}
+
+/// A model listed by the `/models` endpoint.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct ModelListing {
+    pub id: String,
+    #[serde(default)]
+    pub owned_by: String,
+}
+
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelListing>,
+}
+
+/// Lists the models that the API key can access at `api_url`. Not every
+/// OpenAI-compatible API implements this.
+pub async fn list_models(
+    client: &dyn HttpClient,
+    api_url: &str,
+    auth: ApiAuth<'_>,
+    low_speed_timeout: Option<Duration>,
+) -> Result<Vec<ModelListing>> {
+    let uri = format!("{}/models", normalize_api_url(api_url)?);
+    let request_builder = HttpRequest::builder()
+        .method(Method::GET)
         .uri(uri)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key));
+        .header("Accept", "application/json");
+    let mut request_builder = authorize(request_builder, auth);
 
     if let Some(low_speed_timeout) = low_speed_timeout {
         request_builder = request_builder.low_speed_timeout(100, low_speed_timeout);
     };
 
-    let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
+    let request = request_builder.body(AsyncBody::default())?;
     let mut response = client.send(request).await?;
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+
     if response.status().is_success() {
-        let reader = BufReader::new(response.into_body());
-        Ok(reader
-            .lines()
-            .filter_map(|line| async move {
-                match line {
-                    Ok(line) => {
-                        let line = line.strip_prefix("data: ")?;
-                        if line == "[DONE]" {
-                            None
-                        } else {
-                            match serde_json::from_str(line) {
-                                Ok(response) => Some(Ok(response)),
-                                Err(error) => Some(Err(anyhow!(error))),
-                            }
+        let response: ModelsResponse =
+            serde_json::from_str(&body).context("Unable to parse OpenAI model listing")?;
+        Ok(response.data)
+    } else {
+        Err(RequestError::new(
+            response.status().as_u16(),
+            format!(
+                "Failed to list OpenAI models: {} {}",
+                response.status(),
+                error_body_snippet(&body),
+            ),
+        )
+        .into())
+    }
+}
+
+#[derive(Serialize)]
+struct ModerationRequest<'a> {
+    input: &'a str,
+}
+
+/// The `/moderations` endpoint's verdict on a text.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ModerationResult {
+    /// Whether any of the categories were flagged.
+    pub flagged: bool,
+    /// Whether each category, such as `harassment` or `self-harm`, was flagged.
+    #[serde(default)]
+    pub categories: BTreeMap<String, bool>,
+    /// How confident the model is that the text belongs to each category, from 0
+    /// to 1, for applying thresholds other than OpenAI's.
+    #[serde(default)]
+    pub category_scores: BTreeMap<String, f64>,
+}
+
+impl ModerationResult {
+    /// The categories that were flagged, in alphabetical order.
+    pub fn flagged_categories(&self) -> Vec<String> {
+        self.categories
+            .iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.clone())
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+/// Classifies `input` with the `/moderations` endpoint, which is free to use.
+pub async fn moderate(
+    client: &dyn HttpClient,
+    api_url: &str,
+    auth: ApiAuth<'_>,
+    input: &str,
+) -> Result<ModerationResult> {
+    let uri = format!("{}/moderations", normalize_api_url(api_url)?);
+    let request_builder = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json");
+    let body = serde_json::to_string(&ModerationRequest { input })?;
+    let request = authorize(request_builder, auth).body(AsyncBody::from(body))?;
+    let mut response = client.send(request).await?;
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+
+    if response.status().is_success() {
+        let response: ModerationResponse =
+            serde_json::from_str(&body).context("Unable to parse OpenAI moderation response")?;
+        response
+            .results
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("OpenAI returned no moderation results"))
+    } else {
+        Err(RequestError::new(
+            response.status().as_u16(),
+            format!(
+                "Failed to moderate input: {} {}",
+                response.status(),
+                error_body_snippet(&body),
+            ),
+        )
+        .into())
+    }
+}
+
+fn authorize(request_builder: RequestBuilder, auth: ApiAuth<'_>) -> RequestBuilder {
+    match auth {
+        ApiAuth::Bearer {
+            api_key,
+            organization,
+        } => {
+            let request_builder = if api_key.is_empty() {
+                request_builder
+            } else {
+                request_builder.header("Authorization", format!("Bearer {}", api_key))
+            };
+            match organization {
+                Some(organization) => request_builder.header("OpenAI-Organization", organization),
+                None => request_builder,
+            }
+        }
+        ApiAuth::ApiKey("") | ApiAuth::Header { api_key: "", .. } => request_builder,
+        ApiAuth::ApiKey(api_key) => request_builder.header("api-key", api_key),
+        ApiAuth::Header { name, api_key } => request_builder.header(name, api_key),
+    }
+}
+
+async fn stream_events<T: 'static + Send + DeserializeOwned>(
+    client: &dyn HttpClient,
+    uri: String,
+    auth: ApiAuth<'_>,
+    body: String,
+    low_speed_timeout: Option<Duration>,
+    http_version: HttpVersionPreference,
+    request_id: Option<&str>,
+) -> Result<EventStream<T>> {
+    let (response, request_id) = send_request(
+        client,
+        uri,
+        auth,
+        body,
+        low_speed_timeout,
+        http_version,
+        request_id,
+    )
+    .await?;
+    let events = decoded_body(response)
+        .lines()
+        .filter_map(|line| async move {
+            match line {
+                Ok(line) => {
+                    let line = line.strip_prefix("data: ")?;
+                    if line == "[DONE]" {
+                        None
+                    } else {
+                        match serde_json::from_str(line) {
+                            Ok(response) => Some(Ok(response)),
+                            Err(error) => Some(Err(anyhow!(error))),
                         }
                     }
-                    Err(error) => Some(Err(anyhow!(error))),
                 }
-            })
-            .boxed())
+                Err(error) => Some(Err(anyhow!(error))),
+            }
+        })
+        .boxed();
+    Ok(EventStream { request_id, events })
+}
+
+/// Sends a request with `body`, returning the response if it succeeded, along with
+/// the id of the request.
+async fn send_request(
+    client: &dyn HttpClient,
+    uri: String,
+    auth: ApiAuth<'_>,
+    body: String,
+    low_speed_timeout: Option<Duration>,
+    http_version: HttpVersionPreference,
+    request_id: Option<&str>,
+) -> Result<(HttpResponse<AsyncBody>, Option<String>)> {
+    // Responses are decompressed by `decoded_body` rather than by isahc, so that
+    // they're decompressed the same way whichever client sends the request.
+    let request_builder = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .header("Accept-Encoding", "gzip")
+        .automatic_decompression(false);
+    let mut request_builder = authorize(request_builder, auth);
+    if let Some(request_id) = request_id {
+        request_builder = request_builder.header(REQUEST_ID_HEADER, request_id);
+    }
+
+    if let Some(low_speed_timeout) = low_speed_timeout {
+        request_builder = request_builder.low_speed_timeout(100, low_speed_timeout);
+    };
+
+    // The preference is also attached to the request so that clients other than
+    // isahc can honor it.
+    request_builder = request_builder.extension(http_version);
+    if http_version == HttpVersionPreference::Http1Only {
+        request_builder = request_builder
+            .version(Version::HTTP_11)
+            .version_negotiation(VersionNegotiation::http11());
+    }
+
+    let request = request_builder.body(AsyncBody::from(body))?;
+    let response = client.send(request).await?;
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .or(request_id)
+        .map(str::to_string);
+    let status = response.status();
+    if status.is_success() {
+        Ok((response, request_id))
     } else {
+        let retry_after = response
+            .headers()
+            .get("retry-after")
+            .and_then(|retry_after| retry_after.to_str().ok())
+            .and_then(parse_retry_after);
         let mut body = String::new();
-        response.body_mut().read_to_string(&mut body).await?;
+        decoded_body(response).read_to_string(&mut body).await?;
 
         #[derive(Deserialize)]
         struct OpenAiResponse {
@@ -288,24 +1095,59 @@ pub async fn stream_completion(
         #[derive(Deserialize)]
         struct OpenAiError {
             message: String,
+            #[serde(default)]
+            code: Option<String>,
         }
 
-        match serde_json::from_str::<OpenAiResponse>(&body) {
-            Ok(response) if !response.error.message.is_empty() => Err(anyhow!(
-                "Failed to connect to OpenAI API: {}",
-                response.error.message,
-            )),
+        let request_id = request_id
+            .map(|request_id| format!(" (request id: {request_id})"))
+            .unwrap_or_default();
+        let error = serde_json::from_str::<OpenAiResponse>(&body)
+            .ok()
+            .map(|response| response.error);
+        let code = error.as_ref().and_then(|error| error.code.clone());
+        let message = match error {
+            Some(error) if !error.message.is_empty() => format!(
+                "Failed to connect to OpenAI API: {}{request_id}",
+                error.message,
+            ),
 
-            _ => Err(anyhow!(
-                "Failed to connect to OpenAI API: {} {}",
-                response.status(),
-                body,
-            )),
+            _ => format!(
+                "Failed to connect to OpenAI API: {} {}{request_id}",
+                status,
+                error_body_snippet(&body),
+            ),
+        };
+        Err(RequestError {
+            status: status.as_u16(),
+            retry_after,
+            code,
+            message,
         }
+        .into())
     }
 }
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+/// The body of `response`, decompressed as it's read if the server gzipped it. Each
+/// chunk is decompressed as soon as it arrives, so streamed events can still be
+/// parsed one by one.
+fn decoded_body(response: HttpResponse<AsyncBody>) -> Pin<Box<dyn AsyncBufRead + Send>> {
+    let gzipped = response
+        .headers()
+        .get("Content-Encoding")
+        .and_then(|encoding| encoding.to_str().ok())
+        .map_or(false, |encoding| {
+            encoding.trim().eq_ignore_ascii_case("gzip")
+        });
+    let body = BufReader::new(response.into_body());
+    if gzipped {
+        Box::pin(BufReader::new(GzipDecoder::new(body)))
+    } else {
+        Box::pin(body)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OpenAiEmbeddingModel {
     #[serde(rename = "text-embedding-3-small")]
     TextEmbedding3Small,
@@ -313,6 +1155,20 @@ pub enum OpenAiEmbeddingModel {
     TextEmbedding3Large,
 }
 
+impl OpenAiEmbeddingModel {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::TextEmbedding3Small => "text-embedding-3-small",
+            Self::TextEmbedding3Large => "text-embedding-3-large",
+        }
+    }
+
+    /// The most tokens a single input can have.
+    pub fn max_input_tokens(&self) -> usize {
+        8191
+    }
+}
+
 #[derive(Serialize)]
 struct OpenAiEmbeddingRequest<'a> {
     model: OpenAiEmbeddingModel,
@@ -326,6 +1182,9 @@ pub struct OpenAiEmbeddingResponse {
 
 #[derive(Deserialize)]
 pub struct OpenAiEmbedding {
+    /// The position of the embedded text among the request's inputs.
+    #[serde(default)]
+    pub index: usize,
     pub embedding: Vec<f32>,
 }
 
@@ -336,20 +1195,39 @@ pub fn embed<'a>(
     model: OpenAiEmbeddingModel,
     texts: impl IntoIterator<Item = &'a str>,
 ) -> impl 'static + Future<Output = Result<OpenAiEmbeddingResponse>> {
-    let uri = format!("{api_url}/embeddings");
+    embed_with_auth(
+        client,
+        api_url,
+        ApiAuth::Bearer {
+            api_key,
+            organization: None,
+        },
+        model,
+        texts,
+    )
+}
 
+/// Like [`embed`], but authenticates with `auth`, for APIs that expect the key
+/// elsewhere than OpenAI does.
+pub fn embed_with_auth<'a>(
+    client: &dyn HttpClient,
+    api_url: &str,
+    auth: ApiAuth<'_>,
+    model: OpenAiEmbeddingModel,
+    texts: impl IntoIterator<Item = &'a str>,
+) -> impl 'static + Future<Output = Result<OpenAiEmbeddingResponse>> {
     let request = OpenAiEmbeddingRequest {
         model,
         input: texts.into_iter().collect(),
     };
     let body = AsyncBody::from(serde_json::to_string(&request).unwrap());
-    let request = HttpRequest::builder()
-        .method(Method::POST)
-        .uri(uri)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .body(body)
-        .map(|request| client.send(request));
+    let request = embeddings_url(api_url).and_then(|uri| {
+        let request_builder = HttpRequest::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        Ok(client.send(authorize(request_builder, auth).body(body)?))
+    });
 
     async move {
         let mut response = request?.await?;
@@ -369,3 +1247,41 @@ pub fn embed<'a>(
         }
     }
 }
+
+/// The most characters of an unrecognized error body to include in an error.
+const MAX_ERROR_BODY_SNIPPET_LEN: usize = 200;
+
+/// Error bodies that aren't OpenAI's JSON error envelope (e.g. an HTML page
+/// served by a gateway) are truncated before being surfaced.
+fn error_body_snippet(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(MAX_ERROR_BODY_SNIPPET_LEN) {
+        Some((ix, _)) => format!("{}…", &body[..ix]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_api_url() {
+        assert_eq!(
+            normalize_api_url("api.example.com/v1").unwrap(),
+            "https://api.example.com/v1"
+        );
+        assert_eq!(
+            normalize_api_url("http://localhost:8080/v1/").unwrap(),
+            "http://localhost:8080/v1"
+        );
+        assert_eq!(
+            normalize_api_url("https://api.openai.com/v1").unwrap(),
+            "https://api.openai.com/v1"
+        );
+        assert!(normalize_api_url("ftp://api.example.com/v1")
+            .unwrap_err()
+            .to_string()
+            .contains("unsupported scheme"));
+    }
+}
@@ -1,15 +1,30 @@
 use crate::{Embedding, EmbeddingProvider, TextToEmbed};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use completion::OpenAiCompletionProvider;
 use futures::{future::BoxFuture, FutureExt};
 use http::HttpClient;
+use open_ai::AuthHeaderStyle;
 pub use open_ai::OpenAiEmbeddingModel;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use tiktoken_rs::CoreBPE;
+use util::ResultExt;
+
+/// The most texts OpenAI embeds in a single request.
+/// From https://platform.openai.com/docs/api-reference/embeddings/create
+const MAX_BATCH_SIZE: usize = 2048;
+
+/// Roughly how many characters a token spans, for estimating token counts when
+/// tiktoken can't count them.
+const ESTIMATED_CHARS_PER_TOKEN: usize = 4;
 
 pub struct OpenAiEmbeddingProvider {
     client: Arc<dyn HttpClient>,
     model: OpenAiEmbeddingModel,
     api_url: String,
     api_key: String,
+    organization_id: Option<String>,
+    auth_header: AuthHeaderStyle,
+    batch_size: usize,
 }
 
 impl OpenAiEmbeddingProvider {
@@ -24,32 +39,191 @@ impl OpenAiEmbeddingProvider {
             model,
             api_url,
             api_key,
+            organization_id: None,
+            auth_header: AuthHeaderStyle::default(),
+            batch_size: MAX_BATCH_SIZE,
         }
     }
+
+    /// Requests embeddings from the same API, with the same credentials, as
+    /// `provider` requests completions with. Fails if it isn't authenticated yet.
+    pub fn for_completion_provider(
+        provider: &OpenAiCompletionProvider,
+        model: OpenAiEmbeddingModel,
+    ) -> Result<Self> {
+        let connection = provider.api_connection()?;
+        let mut this = Self::new(
+            connection.http_client,
+            model,
+            connection.api_url,
+            connection.api_key,
+        );
+        this.set_auth(connection.auth_header, connection.organization_id);
+        Ok(this)
+    }
+
+    /// Sends the key in the header `auth_header` describes, billing the request to
+    /// `organization_id` if it's set.
+    pub fn set_auth(&mut self, auth_header: AuthHeaderStyle, organization_id: Option<String>) {
+        self.auth_header = auth_header;
+        self.organization_id = organization_id;
+    }
+
+    /// Limits how many texts are embedded per request. Larger inputs are split
+    /// into batches, which are requested one after another.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+    }
+
+    /// Counts the tokens `text` is embedded as, which must be at most
+    /// [`Self::max_input_tokens`].
+    pub fn count_tokens(&self, text: &str) -> usize {
+        match cl100k_base() {
+            Some(bpe) => bpe.encode_ordinary(text).len(),
+            None => text.chars().count().div_ceil(ESTIMATED_CHARS_PER_TOKEN),
+        }
+    }
+
+    /// The most tokens a single text can have.
+    pub fn max_input_tokens(&self) -> usize {
+        self.model.max_input_tokens()
+    }
 }
 
 impl EmbeddingProvider for OpenAiEmbeddingProvider {
     fn embed<'a>(&'a self, texts: &'a [TextToEmbed<'a>]) -> BoxFuture<'a, Result<Vec<Embedding>>> {
-        let embed = open_ai::embed(
-            self.client.as_ref(),
-            &self.api_url,
-            &self.api_key,
-            self.model,
-            texts.iter().map(|to_embed| to_embed.text),
-        );
+        let auth = self
+            .auth_header
+            .auth(&self.api_key, self.organization_id.as_deref());
+        let batches = texts
+            .chunks(self.batch_size)
+            .map(|batch| {
+                let embed = open_ai::embed_with_auth(
+                    self.client.as_ref(),
+                    &self.api_url,
+                    auth,
+                    self.model,
+                    batch.iter().map(|to_embed| to_embed.text),
+                );
+                (batch.len(), embed)
+            })
+            .collect::<Vec<_>>();
         async move {
-            let response = embed.await?;
-            Ok(response
-                .data
-                .into_iter()
-                .map(|data| Embedding::new(data.embedding))
-                .collect())
+            let mut embeddings = Vec::with_capacity(texts.len());
+            for (len, embed) in batches {
+                let mut response = embed.await?;
+                if response.data.len() != len {
+                    return Err(anyhow!(
+                        "expected {len} embeddings, but got {}",
+                        response.data.len()
+                    ));
+                }
+                // The API doesn't promise to return the embeddings in input order.
+                response.data.sort_by_key(|data| data.index);
+                embeddings.extend(
+                    response
+                        .data
+                        .into_iter()
+                        .map(|data| Embedding::new(data.embedding)),
+                );
+            }
+            Ok(embeddings)
         }
         .boxed()
     }
 
     fn batch_size(&self) -> usize {
-        // From https://platform.openai.com/docs/api-reference/embeddings/create
-        2048
+        self.batch_size
+    }
+}
+
+/// The encoding OpenAI's embedding models use, which is loaded once.
+fn cl100k_base() -> Option<&'static CoreBPE> {
+    static CL100K_BASE: OnceLock<Option<CoreBPE>> = OnceLock::new();
+    CL100K_BASE
+        .get_or_init(|| tiktoken_rs::cl100k_base().log_err())
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt;
+    use http::{FakeHttpClient, Response};
+    use parking_lot::Mutex;
+    use serde_json::json;
+
+    #[gpui::test]
+    async fn test_embed_in_batches() {
+        let sent_inputs = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_inputs = sent_inputs.clone();
+            move |request| {
+                let sent_inputs = sent_inputs.clone();
+                async move {
+                    assert_eq!(
+                        request.uri().to_string(),
+                        "https://api.openai.com/v1/embeddings"
+                    );
+                    let mut body = String::new();
+                    request.into_body().read_to_string(&mut body).await.unwrap();
+                    let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+                    assert_eq!(body["model"], "text-embedding-3-small");
+                    let inputs = body["input"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|input| input.as_str().unwrap().to_string())
+                        .collect::<Vec<_>>();
+
+                    // Respond out of order, as the API may.
+                    let data = inputs
+                        .iter()
+                        .enumerate()
+                        .rev()
+                        .map(|(index, input)| {
+                            json!({ "index": index, "embedding": [input.len() as f32, 1.] })
+                        })
+                        .collect::<Vec<_>>();
+                    sent_inputs.lock().push(inputs);
+                    let body = json!({ "data": data }).to_string();
+                    Ok(Response::builder().status(200).body(body.into()).unwrap())
+                }
+            }
+        });
+        let mut provider = OpenAiEmbeddingProvider::new(
+            http_client,
+            OpenAiEmbeddingModel::TextEmbedding3Small,
+            // The URL is normalized like the completion providers' URLs.
+            "api.openai.com/v1/".into(),
+            "sk-test".into(),
+        );
+        provider.set_batch_size(2);
+        assert_eq!(provider.batch_size(), 2);
+
+        let texts = ["a", "bb", "ccc"].map(TextToEmbed::new);
+        let embeddings = provider.embed(&texts).await.unwrap();
+        assert_eq!(
+            embeddings,
+            vec![
+                Embedding::new(vec![1., 1.]),
+                Embedding::new(vec![2., 1.]),
+                Embedding::new(vec![3., 1.])
+            ]
+        );
+        assert_eq!(
+            sent_inputs.lock().as_slice(),
+            &[
+                vec!["a".to_string(), "bb".to_string()],
+                vec!["ccc".to_string()]
+            ]
+        );
+
+        let embeddings = provider.embed(&[]).await.unwrap();
+        assert!(embeddings.is_empty());
+        assert_eq!(sent_inputs.lock().len(), 2);
+
+        assert_eq!(provider.count_tokens("hello world"), 2);
+        assert_eq!(provider.max_input_tokens(), 8191);
     }
 }